@@ -1,12 +1,13 @@
 #!/bin/bash
 # PantherOS QEMU Run Script
 # 
-# Usage: ./run.sh [debug|release]
+# Usage: [INITRD=initramfs.cpio] [SMP=4] ./run.sh [debug|release]
 
 set -e
 
 MODE="${1:-release}"
-KERNEL="target/aarch64-unknown-none/${MODE}/pantheros"
+SMP="${SMP:-4}"
+KERNEL="target/aarch64-unknown-none-softfloat/${MODE}/pantheros"
 
 # Build if needed
 if [ ! -f "$KERNEL" ] || [ "$(find src -newer "$KERNEL" 2>/dev/null)" ]; then
@@ -18,6 +19,15 @@ if [ ! -f "$KERNEL" ] || [ "$(find src -newer "$KERNEL" 2>/dev/null)" ]; then
     fi
 fi
 
+# Embed the symbol table used for backtraces
+python3 scripts/ksymtab.py "$KERNEL"
+
+# Optional initramfs (CPIO newc)
+INITRD_ARGS=()
+if [ -n "${INITRD:-}" ]; then
+    INITRD_ARGS=(-initrd "$INITRD")
+fi
+
 echo "Starting QEMU..."
 echo "Press Ctrl+A then X to exit"
 echo ""
@@ -25,6 +35,8 @@ echo ""
 qemu-system-aarch64 \
     -machine virt \
     -cpu cortex-a72 \
+    -smp "$SMP" \
     -m 128M \
     -nographic \
-    -kernel "$KERNEL"
+    -kernel "$KERNEL" \
+    "${INITRD_ARGS[@]}"
@@ -0,0 +1,119 @@
+#!/usr/bin/env python3
+"""Embed a compact symbol table into a linked PantherOS kernel.
+
+Usage: scripts/ksymtab.py <kernel-elf>
+
+Reads the function symbols with llvm-nm and writes them into the
+`.ksymtab` section reserved by linker.ld, in the format parsed by
+src/backtrace.rs:
+
+    Header  { magic: "KSYM", count: u32 }
+    Entry   { addr: u64, size: u32, name: u32 }   x count, sorted by addr
+    Names   NUL-terminated strings; `name` is an offset into this area
+
+The section keeps its size, so no address in the image changes.
+"""
+
+import os
+import re
+import shutil
+import struct
+import subprocess
+import sys
+import tempfile
+
+# Long generic instantiations are cut to keep the table small
+MAX_NAME = 120
+
+# Legacy Rust mangling leaves a `::h<hash>` suffix after demangling
+HASH_SUFFIX = re.compile(r"::h[0-9a-f]{16}$")
+
+
+def llvm_tool(name):
+    """Find an LLVM tool, preferring the rustup llvm-tools component."""
+    sysroot = subprocess.run(
+        ["rustc", "--print", "sysroot"], capture_output=True, text=True
+    ).stdout.strip()
+    host = re.search(
+        r"^host: (\S+)$",
+        subprocess.run(["rustc", "-vV"], capture_output=True, text=True).stdout,
+        re.M,
+    )
+    if sysroot and host:
+        path = os.path.join(sysroot, "lib", "rustlib", host.group(1), "bin", name)
+        if os.path.exists(path):
+            return path
+
+    path = shutil.which(name)
+    if path is None:
+        sys.exit(f"ksymtab: {name} not found (rustup component add llvm-tools-preview)")
+    return path
+
+
+def nm(elf):
+    out = subprocess.run(
+        [llvm_tool("llvm-nm"), "--defined-only", "--demangle", "-S", elf],
+        capture_output=True,
+        text=True,
+        check=True,
+    ).stdout
+    return out.splitlines()
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(__doc__)
+    elf = sys.argv[1]
+
+    section = {}
+    symbols = {}
+    for line in nm(elf):
+        # "<addr> [<size>] <type> <name>"
+        fields = line.split(None, 3)
+        if len(fields) == 3:
+            addr, kind, name = fields
+            size = "0"
+        else:
+            addr, size, kind, name = fields
+
+        if name in ("__ksymtab_start", "__ksymtab_end"):
+            section[name] = int(addr, 16)
+        if kind not in ("t", "T") or name.startswith("$"):
+            continue
+
+        name = HASH_SUFFIX.sub("", name)[:MAX_NAME]
+        addr = int(addr, 16)
+        # Aliases: keep the sized, first-seen name
+        if addr not in symbols or symbols[addr][0] == 0:
+            symbols[addr] = (int(size, 16), name)
+
+    if len(section) != 2:
+        sys.exit("ksymtab: __ksymtab_start/__ksymtab_end not found in " + elf)
+    capacity = section["__ksymtab_end"] - section["__ksymtab_start"]
+
+    names = bytearray()
+    entries = bytearray()
+    for addr in sorted(symbols):
+        size, name = symbols[addr]
+        entries += struct.pack("<QII", addr, min(size, 0xFFFF_FFFF), len(names))
+        names += name.encode() + b"\0"
+
+    blob = b"KSYM" + struct.pack("<I", len(symbols)) + entries + names
+    used = len(blob)
+    if used > capacity:
+        sys.exit(f"ksymtab: table needs {used} bytes, .ksymtab holds {capacity}")
+    blob += bytes(capacity - used)
+
+    with tempfile.NamedTemporaryFile(suffix=".ksymtab") as tmp:
+        tmp.write(blob)
+        tmp.flush()
+        subprocess.run(
+            [llvm_tool("llvm-objcopy"), f"--update-section=.ksymtab={tmp.name}", elf],
+            check=True,
+        )
+
+    print(f"ksymtab: {len(symbols)} symbols, {used}/{capacity} bytes")
+
+
+if __name__ == "__main__":
+    main()
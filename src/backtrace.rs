@@ -0,0 +1,210 @@
+//! Kernel Backtraces
+//!
+//! Frame-pointer based stack unwinding, symbolized with a symbol table
+//! embedded in the kernel image.
+//!
+//! # Frame Records
+//! The kernel is built with `-C force-frame-pointers=yes`, so every
+//! function stores a frame record and points x29 at it:
+//!
+//! ```text
+//! x29 ──► ┌──────────────┐
+//!         │ caller's x29 │  [fp + 0]
+//!         │ return addr  │  [fp + 8]
+//!         └──────────────┘
+//! ```
+//!
+//! # Symbol Table
+//! The linker script reserves a `.ksymtab` section that
+//! `scripts/ksymtab.py` fills after linking:
+//!
+//! ```text
+//! Header  { magic: "KSYM", count: u32 }
+//! Entry   { addr: u64, size: u32, name: u32 }   × count, sorted by addr
+//! Names   NUL-terminated strings; `name` is an offset into this area
+//! ```
+//!
+//! Cargo has no post-link hook, so only `run.sh` runs the script: an
+//! image from a plain `cargo build` keeps the section zeroed and its
+//! traces print raw addresses only.
+//!
+//! # Security Properties
+//! - Frame pointers are validated (aligned, inside kernel RAM or the
+//!   kernel stack region, strictly increasing) before every dereference,
+//!   so a corrupt chain ends the trace instead of faulting inside the
+//!   panic path
+//! - The walk stops at a return address outside kernel text
+//! - The walk is bounded by `MAX_FRAMES`
+
+use core::arch::asm;
+use core::mem::size_of;
+
+use crate::kprintln;
+use crate::mm::address::{KERNEL_VIRT_BASE, PHYS_MEM_BASE, PHYS_MEM_END};
+use crate::mm::kstack;
+
+/// Maximum number of frames printed.
+const MAX_FRAMES: usize = 32;
+
+/// "KSYM" in little-endian byte order.
+const KSYMTAB_MAGIC: u32 = u32::from_le_bytes(*b"KSYM");
+
+/// Symbol table header.
+#[repr(C)]
+struct Header {
+    magic: u32,
+    count: u32,
+}
+
+/// Symbol table entry.
+#[repr(C)]
+struct Entry {
+    addr: u64,
+    size: u32,
+    name: u32,
+}
+
+extern "C" {
+    static __ksymtab_start: u8;
+    static __ksymtab_end: u8;
+    static __text_start: u8;
+    static __text_end: u8;
+}
+
+/// The embedded symbol table as a byte slice.
+fn ksymtab() -> &'static [u8] {
+    // SAFETY: Both symbols are defined by the linker script around the
+    // reserved `.ksymtab` section, which is never written at runtime.
+    unsafe {
+        let start = &raw const __ksymtab_start;
+        let end = &raw const __ksymtab_end;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// Look up the symbol containing `addr`.
+///
+/// Returns the symbol name and the offset of `addr` into it.
+pub fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    let table = ksymtab();
+    if table.len() < size_of::<Header>() {
+        return None;
+    }
+
+    // SAFETY: The section is 8-byte aligned and at least a header long.
+    let header = unsafe { &*(table.as_ptr() as *const Header) };
+    if header.magic != KSYMTAB_MAGIC {
+        return None;
+    }
+
+    let count = header.count as usize;
+    let entries_end = size_of::<Header>() + count * size_of::<Entry>();
+    if entries_end > table.len() {
+        return None;
+    }
+
+    // SAFETY: Bounds checked above; entries follow the 8-byte header and
+    // are 8-byte aligned.
+    let entries = unsafe {
+        core::slice::from_raw_parts(
+            table.as_ptr().add(size_of::<Header>()) as *const Entry,
+            count,
+        )
+    };
+    let names = &table[entries_end..];
+
+    // Last entry starting at or below `addr`
+    let index = entries.partition_point(|e| e.addr as usize <= addr);
+    let entry = entries.get(index.checked_sub(1)?)?;
+
+    let offset = addr - entry.addr as usize;
+    // Size 0 (assembly labels) extends to the next symbol
+    if entry.size != 0 && offset >= entry.size as usize {
+        return None;
+    }
+
+    let name = names.get(entry.name as usize..)?;
+    let len = name.iter().position(|&b| b == 0)?;
+    let name = core::str::from_utf8(&name[..len]).ok()?;
+
+    Some((name, offset))
+}
+
+/// Whether `addr` is in the kernel's code.
+fn in_kernel_text(addr: usize) -> bool {
+    let start = &raw const __text_start as usize;
+    let end = &raw const __text_end as usize;
+    (start..end).contains(&addr)
+}
+
+/// Whether `fp` may point at a frame record.
+fn valid_frame_pointer(fp: usize) -> bool {
+    let ram_start = KERNEL_VIRT_BASE + PHYS_MEM_BASE;
+    let ram_end = KERNEL_VIRT_BASE + PHYS_MEM_END;
+    let record_end = fp.wrapping_add(2 * size_of::<usize>());
+
+    fp % 16 == 0
+        && ((fp >= ram_start && record_end <= ram_end)
+            || (kstack::is_stack(fp) && kstack::is_stack(record_end - 1)))
+}
+
+/// Print one frame.
+fn print_frame(index: usize, pc: usize, lookup: usize) {
+    match symbolize(lookup) {
+        Some((name, offset)) => {
+            kprintln!("  #{:<2} 0x{:016x} {}+0x{:x}", index, pc, name, offset + (pc - lookup))
+        }
+        None => kprintln!("  #{:<2} 0x{:016x} <unknown>", index, pc),
+    }
+}
+
+/// Print a backtrace starting at `pc` with frame pointer `fp`.
+///
+/// `pc` is the faulting or current instruction; return addresses found
+/// on the stack are looked up at `lr - 4` so calls at the end of a
+/// function resolve to the caller, not its neighbour.
+pub fn print_from(pc: usize, mut fp: usize) {
+    kprintln!("Backtrace:");
+    print_frame(0, pc, pc);
+
+    for index in 1..MAX_FRAMES {
+        if !valid_frame_pointer(fp) {
+            return;
+        }
+
+        // SAFETY: `fp` is aligned and the record lies inside kernel RAM
+        // or a kernel stack (checked above).
+        let (next_fp, lr) = unsafe {
+            let record = fp as *const usize;
+            (record.read(), record.add(1).read())
+        };
+
+        // Also ends the walk at the zeroed link of the first frame
+        if !in_kernel_text(lr) {
+            return;
+        }
+        // Text never starts below 4, so this cannot wrap
+        print_frame(index, lr, lr - 4);
+
+        // Stacks grow down: callers' records are at higher addresses
+        if next_fp <= fp {
+            return;
+        }
+        fp = next_fp;
+    }
+
+    kprintln!("  ... (truncated)");
+}
+
+/// Print a backtrace of the caller.
+#[inline(always)]
+pub fn print() {
+    let fp: usize;
+    let pc: usize;
+    // SAFETY: Reads the frame pointer and the current PC only.
+    unsafe {
+        asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+        asm!("adr {}, .", out(reg) pc, options(nomem, nostack, preserves_flags));
+    }
+    print_from(pc, fp);
+}
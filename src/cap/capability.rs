@@ -128,6 +128,9 @@ pub enum CapabilityType {
 
     /// Untyped memory (for retyping).
     Untyped = 10,
+
+    /// Shared memory object (mappable into several address spaces).
+    SharedMemory = 11,
 }
 
 /// A capability: an unforgeable token granting access to a kernel object.
@@ -0,0 +1,248 @@
+//! Capability Derivation Tree (CDT)
+//!
+//! Records which capability every capability was derived from, across
+//! all CSpaces, so that revoking a capability finds everything derived
+//! from it, wherever it was copied to.
+//!
+//! # Design
+//! - Every CNode slot is a tree node, as is every slot of a capability
+//!   in transit in an IPC message (`cap::transfer`). Links point at slots
+//!   directly; slots are unlinked before their memory is freed
+//! - A node has a parent and a doubly linked list of children
+//! - Capabilities made by the kernel (`thread::create_cap`,
+//!   `untyped::create_cap`) and inserted into a slot are roots
+//! - Deleting a node hands its children to its parent, so revoking an
+//!   ancestor still reaches them
+//! - One global lock covers every node, so a revoke that spans several
+//!   CSpaces is atomic: no CPU can copy a capability out of the subtree
+//!   while it is being torn down
+//!
+//! # Security Properties
+//! - Every derived capability is reachable from the capability it was
+//!   derived from until it is deleted
+//! - Revocation never deletes the revoked capability itself, so its
+//!   holder keeps its own access
+
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::ptr::NonNull;
+
+use super::cspace::RawCapability;
+use crate::lock_class;
+use crate::sync::{TicketLock, TicketLockGuard};
+
+/// Protects the contents and links of every slot.
+static TREE: TicketLock<()> = TicketLock::new((), lock_class!("capability tree"));
+
+/// Take the tree lock; every `Slot` access must hold it.
+#[track_caller]
+pub(super) fn lock() -> TicketLockGuard<'static, ()> {
+    TREE.lock()
+}
+
+type Link = Option<NonNull<Slot>>;
+
+/// A capability slot and its place in the derivation tree.
+///
+/// Fields are cells: other CSpaces' slots are reached through links, so
+/// the owner's `&mut` is not the only way in. The tree lock serializes
+/// all of it.
+#[derive(Debug)]
+pub(super) struct Slot {
+    cap: Cell<RawCapability>,
+    parent: Cell<Link>,
+    first_child: Cell<Link>,
+    prev: Cell<Link>,
+    next: Cell<Link>,
+}
+
+/// Follow a link.
+fn node<'a>(link: NonNull<Slot>) -> &'a Slot {
+    // SAFETY: Linked slots are unlinked before their CNode is freed, and
+    // links are only followed with the tree lock held.
+    unsafe { link.as_ref() }
+}
+
+impl Slot {
+    /// An empty, unlinked slot.
+    pub(super) const fn new() -> Self {
+        Self {
+            cap: Cell::new(RawCapability::null()),
+            parent: Cell::new(None),
+            first_child: Cell::new(None),
+            prev: Cell::new(None),
+            next: Cell::new(None),
+        }
+    }
+
+    /// The capability in the slot (null if empty).
+    #[inline]
+    pub(super) fn cap(&self) -> RawCapability {
+        self.cap.get()
+    }
+
+    /// Store `cap` in this empty slot as a root.
+    pub(super) fn insert_root(&self, cap: RawCapability) {
+        debug_assert!(self.cap().is_null());
+        self.cap.set(cap);
+    }
+
+    /// Store `cap` in this empty slot as a child of `parent`.
+    pub(super) fn insert_child(&self, parent: &Slot, cap: RawCapability) {
+        debug_assert!(self.cap().is_null() && parent.cap().is_valid());
+        self.cap.set(cap);
+        self.link_under(parent);
+    }
+
+    /// Empty the slot and return its capability; its children move up to
+    /// its parent (or become roots).
+    pub(super) fn remove(&self) -> RawCapability {
+        self.unlink();
+        let parent = self.parent.take();
+
+        let mut next = self.first_child.take();
+        while let Some(link) = next {
+            let child = node(link);
+            next = child.next.get();
+            child.prev.set(None);
+            child.next.set(None);
+            match parent {
+                Some(parent) => child.link_under(node(parent)),
+                None => child.parent.set(None),
+            }
+        }
+
+        self.cap.replace(RawCapability::null())
+    }
+
+    /// Move this slot's capability, and its place in the tree, into the
+    /// empty slot `dst`.
+    pub(super) fn move_to(&self, dst: &Slot) {
+        debug_assert!(dst.cap().is_null() && dst.parent.get().is_none());
+        let there = NonNull::from(dst);
+
+        // Take over the place among the siblings
+        let (prev, next) = (self.prev.take(), self.next.take());
+        match (prev, self.parent.get()) {
+            (Some(prev), _) => node(prev).next.set(Some(there)),
+            (None, Some(parent)) => node(parent).first_child.set(Some(there)),
+            (None, None) => {}
+        }
+        if let Some(next) = next {
+            node(next).prev.set(Some(there));
+        }
+        dst.prev.set(prev);
+        dst.next.set(next);
+        dst.parent.set(self.parent.take());
+
+        // And the children
+        let first = self.first_child.take();
+        let mut child = first;
+        while let Some(link) = child {
+            node(link).parent.set(Some(there));
+            child = node(link).next.get();
+        }
+        dst.first_child.set(first);
+
+        dst.cap.set(self.cap.replace(RawCapability::null()));
+    }
+
+    /// Empty every slot derived from this one and return their
+    /// capabilities; this slot keeps its own.
+    pub(super) fn revoke(&self) -> Vec<RawCapability> {
+        let mut revoked = Vec::new();
+        while let Some(mut leaf) = self.first_child.get() {
+            // Leaves first, so nothing is handed back up to this slot
+            while let Some(child) = node(leaf).first_child.get() {
+                leaf = child;
+            }
+            revoked.push(node(leaf).remove());
+        }
+        revoked
+    }
+
+    /// Push this slot onto the front of `parent`'s children.
+    fn link_under(&self, parent: &Slot) {
+        let this = NonNull::from(self);
+        let head = parent.first_child.replace(Some(this));
+        if let Some(head) = head {
+            node(head).prev.set(Some(this));
+        }
+        self.parent.set(Some(NonNull::from(parent)));
+        self.prev.set(None);
+        self.next.set(head);
+    }
+
+    /// Take this slot out of its parent's children.
+    fn unlink(&self) {
+        let (prev, next) = (self.prev.take(), self.next.take());
+        if let Some(next) = next {
+            node(next).prev.set(prev);
+        }
+        match (prev, self.parent.get()) {
+            (Some(prev), _) => node(prev).next.set(next),
+            (None, Some(parent)) => node(parent).first_child.set(next),
+            (None, None) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cap::CapabilityType;
+
+    fn cap(badge: u64) -> RawCapability {
+        RawCapability {
+            cap_type: CapabilityType::Endpoint,
+            badge,
+            ..RawCapability::null()
+        }
+    }
+
+    #[test]
+    fn test_delete_hands_children_to_parent() {
+        let slots: [Slot; 4] = core::array::from_fn(|_| Slot::new());
+        slots[0].insert_root(cap(0));
+        slots[1].insert_child(&slots[0], cap(1));
+        slots[2].insert_child(&slots[1], cap(2));
+        slots[3].insert_child(&slots[1], cap(3));
+
+        assert_eq!(slots[1].remove().badge, 1);
+        assert!(slots[1].cap().is_null());
+        let revoked = slots[0].revoke();
+        let mut badges: Vec<u64> = revoked.iter().map(|cap| cap.badge).collect();
+        badges.sort_unstable();
+        assert_eq!(badges, [2, 3]);
+    }
+
+    #[test]
+    fn test_revoke_keeps_the_revoked_slot() {
+        let slots: [Slot; 4] = core::array::from_fn(|_| Slot::new());
+        slots[0].insert_root(cap(0));
+        slots[1].insert_child(&slots[0], cap(1));
+        slots[2].insert_child(&slots[1], cap(2));
+        slots[3].insert_child(&slots[0], cap(3));
+
+        assert_eq!(slots[1].revoke().len(), 1);
+        assert_eq!(slots[0].revoke().len(), 2);
+        assert_eq!(slots[0].cap().badge, 0);
+        assert!(slots[1..].iter().all(|slot| slot.cap().is_null()));
+        assert!(slots[0].revoke().is_empty());
+    }
+
+    #[test]
+    fn test_move_keeps_the_place_in_the_tree() {
+        let slots: [Slot; 5] = core::array::from_fn(|_| Slot::new());
+        slots[0].insert_root(cap(0));
+        slots[1].insert_child(&slots[0], cap(1));
+        slots[2].insert_child(&slots[0], cap(2));
+        slots[3].insert_child(&slots[1], cap(3));
+
+        slots[1].move_to(&slots[4]);
+        assert!(slots[1].cap().is_null());
+        assert_eq!(slots[4].revoke()[0].badge, 3);
+        assert_eq!(slots[0].revoke().len(), 2);
+        assert!(slots[4].cap().is_null() && slots[2].cap().is_null());
+    }
+}
@@ -0,0 +1,393 @@
+//! CNodes
+//!
+//! A CNode is a kernel object holding `2^radix` capability slots. CNodes
+//! can hold capabilities to other CNodes, so a CSpace is a tree of CNodes
+//! below a root CNode capability, and capability pointers (cptrs) are
+//! resolved through it by guard and radix bits.
+//!
+//! # Addressing
+//! A cptr is resolved over its low `depth` bits, most significant first:
+//! ```text
+//! ┌─────────────┬─────────────┬─────────────┬─────────────┐
+//! │ root guard  │ root index  │ child guard │ child index │
+//! └─────────────┴─────────────┴─────────────┴─────────────┘
+//!   depth bits of the cptr                             bit 0
+//! ```
+//! - Every CNode capability carries a guard in its badge word: bits the
+//!   cptr must match before the CNode's radix bits pick a slot
+//! - Resolution ends once all `depth` bits are used; a slot reached with
+//!   bits left must hold a CNode capability to continue
+//! - A new process's root CNode has 64 slots and a 58-bit zero guard, so
+//!   at depth 64 cptr `n` is simply slot `n`
+//!
+//! # Memory
+//! Slots of retyped CNodes live in the untyped memory they were made
+//! from, 64 bytes each; only the root CNodes the kernel makes for new
+//! processes come from the heap.
+//!
+//! # Security Properties
+//! - Every bit of the cptr up to `depth` is checked against a guard or
+//!   used as an index, so each slot has exactly one address per depth
+//! - Guards are validated when set, so a CNode capability can never
+//!   claim more than 64 bits
+//! - A CNode capability is never stored in its own CNode or a CNode
+//!   below it, so CNodes form no reference cycles and every CNode is
+//!   destroyed once the capabilities to it are deleted
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::capability::{CapabilityType, Rights};
+use super::cdt::{self, Slot};
+use super::cspace::{CSpaceError, RawCapability};
+use super::object;
+use crate::mm::address::{phys_to_kernel_virt, PAGE_SHIFT, PAGE_SIZE};
+use crate::mm::{free_frame, PhysAddr};
+
+/// Log2 of the memory one slot takes in a retyped CNode.
+pub const SLOT_BITS: u8 = 6;
+
+/// Largest CNode radix (64 Ki slots, 4 MiB).
+pub const CNODE_MAX_RADIX: u8 = 16;
+
+/// Radix of the root CNode of a new process.
+pub const ROOT_RADIX: u8 = 6;
+
+/// Longest guard; leaves room for the guard size in the badge word.
+pub const GUARD_MAX_BITS: u8 = 58;
+
+/// Bits a cptr can hold.
+pub const WORD_BITS: u8 = 64;
+
+const _: () = assert!(size_of::<Slot>() <= 1 << SLOT_BITS);
+
+/// Guard of a CNode capability, stored in its badge word: size in the
+/// low 6 bits, value above.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guard {
+    bits: u8,
+    value: u64,
+}
+
+impl Guard {
+    /// A guard of `bits` bits matching `value`; `None` if it does not fit.
+    pub const fn new(bits: u8, value: u64) -> Option<Self> {
+        if bits > GUARD_MAX_BITS || (bits < 64 && value >> bits != 0) {
+            return None;
+        }
+        Some(Self { bits, value })
+    }
+
+    /// Decode the guard stored in a CNode capability's badge.
+    pub const fn from_badge(badge: u64) -> Option<Self> {
+        Self::new((badge & 0x3F) as u8, badge >> 6)
+    }
+
+    /// Encode as a badge word.
+    pub const fn to_badge(self) -> u64 {
+        self.value << 6 | self.bits as u64
+    }
+}
+
+/// Where a CNode's slots live.
+enum Storage {
+    Heap(Box<[Slot]>),
+    /// `pages` frames at `base`, each with a reference owned by the CNode.
+    Untyped {
+        base: PhysAddr,
+        pages: usize,
+    },
+}
+
+/// A CNode: `2^radix` capability slots.
+pub struct CNode {
+    radix: u8,
+    storage: Storage,
+    /// Last walk of `contains` that reached this CNode.
+    seen: Cell<u64>,
+    /// Next CNode still to visit in that walk.
+    next: Cell<*const CNode>,
+}
+
+// SAFETY: The slots and walk state are cells only accessed with the
+// derivation tree locked, so CPUs never touch them at the same time.
+unsafe impl Send for CNode {}
+// SAFETY: As above.
+unsafe impl Sync for CNode {}
+
+impl CNode {
+    /// A CNode with `2^radix` empty slots on the kernel heap.
+    pub fn new(radix: u8) -> Self {
+        debug_assert!((1..=CNODE_MAX_RADIX).contains(&radix));
+        Self {
+            radix,
+            storage: Storage::Heap((0..1usize << radix).map(|_| Slot::new()).collect()),
+            seen: Cell::new(0),
+            next: Cell::new(ptr::null()),
+        }
+    }
+
+    /// Build a CNode in the zeroed memory at `base`, whose frames each
+    /// carry one reference for it (as `Untyped::carve` hands them out).
+    pub fn adopt(base: PhysAddr, radix: u8) -> Self {
+        // SAFETY: The memory is ours, mapped by the direct map, large
+        // enough (`size_bits`) and aligned to a page.
+        unsafe {
+            let slots = phys_to_kernel_virt(base).as_mut_ptr::<Slot>();
+            for i in 0..1usize << radix {
+                slots.add(i).write(Slot::new());
+            }
+        }
+        Self {
+            radix,
+            storage: Storage::Untyped {
+                base,
+                pages: (1 << Self::size_bits(radix)) / PAGE_SIZE,
+            },
+            seen: Cell::new(0),
+            next: Cell::new(ptr::null()),
+        }
+    }
+
+    /// Log2 of the untyped memory a CNode of `radix` takes.
+    pub fn size_bits(radix: u8) -> u8 {
+        (radix + SLOT_BITS).max(PAGE_SHIFT as u8)
+    }
+
+    pub(super) fn slots(&self) -> &[Slot] {
+        match &self.storage {
+            Storage::Heap(slots) => slots,
+            Storage::Untyped { base, .. } => {
+                // SAFETY: `adopt` initialized this many slots there, and
+                // they live as long as the CNode.
+                unsafe {
+                    let slots = phys_to_kernel_virt(*base).as_mut_ptr::<Slot>();
+                    core::slice::from_raw_parts(slots, 1 << self.radix)
+                }
+            }
+        }
+    }
+}
+
+impl CNode {
+    /// Empty every slot and return the capabilities; children derived
+    /// elsewhere move up to their parents.
+    fn take_caps(&self) -> Vec<RawCapability> {
+        let _tree = cdt::lock();
+        self.slots()
+            .iter()
+            .filter(|slot| slot.cap().is_valid())
+            .map(Slot::remove)
+            .collect()
+    }
+}
+
+impl Drop for CNode {
+    fn drop(&mut self) {
+        // Unlink everything before the slots are freed. CNodes whose last
+        // capability was here are emptied in this loop rather than in
+        // their own drop, so a chain of nested CNodes is torn down
+        // without recursing once per level
+        let mut pending = self.take_caps();
+        while let Some(cap) = pending.pop() {
+            if cap.cap_type != CapabilityType::CNode {
+                object::release(cap);
+                continue;
+            }
+            // SAFETY: CNode capabilities carry a pointer from
+            // Arc::into_raw, and the removed capability hands over the
+            // reference it owned.
+            let cnode = unsafe { Arc::from_raw(cap.object_ptr as *const CNode) };
+            if let Some(cnode) = Arc::into_inner(cnode) {
+                pending.extend(cnode.take_caps());
+            }
+        }
+
+        if let Storage::Untyped { base, pages } = self.storage {
+            for i in 0..pages {
+                free_frame(base.add(i * PAGE_SIZE));
+            }
+        }
+    }
+}
+
+/// Wrap `cnode` in a capability with `guard`.
+///
+/// The returned capability owns one reference to the object.
+pub fn create_cap(cnode: CNode, guard: Guard, rights: Rights) -> RawCapability {
+    RawCapability {
+        cap_type: CapabilityType::CNode,
+        object_ptr: Arc::into_raw(Arc::new(cnode)) as usize,
+        rights,
+        badge: guard.to_badge(),
+        generation: 0,
+    }
+}
+
+/// The CNode behind a CNode capability.
+///
+/// The capability must be in a slot (or a CSpace root), and the
+/// derivation tree locked, for as long as the reference is used.
+fn cnode_of<'a>(cap: &RawCapability) -> Option<&'a CNode> {
+    if cap.cap_type != CapabilityType::CNode {
+        return None;
+    }
+    // SAFETY: CNode capabilities carry a pointer from Arc::into_raw, and
+    // the stored capability holds a reference (see above).
+    Some(unsafe { &*(cap.object_ptr as *const CNode) })
+}
+
+/// Check that `guard` suits the CNode `cap` refers to.
+pub(super) fn valid_guard(cap: &RawCapability, guard: Guard) -> bool {
+    cnode_of(cap).is_some_and(|cnode| guard.bits + cnode.radix <= WORD_BITS)
+}
+
+/// Slots of the CNode behind a CNode capability; the tree lock must be
+/// held.
+pub(super) fn slots<'a>(cap: &RawCapability) -> Option<&'a [Slot]> {
+    cnode_of(cap).map(CNode::slots)
+}
+
+/// Number of `contains` walks so far, naming the CNodes each reached.
+static WALKS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether `slot` is in the CNode behind `cap` or in a CNode below it,
+/// where storing `cap` would make the CNode keep itself alive.
+///
+/// Walks every CNode reachable from `cap` once, chaining them through
+/// their own walk state rather than allocating. The tree lock must be
+/// held.
+pub(super) fn contains(cap: &RawCapability, slot: &Slot) -> bool {
+    let Some(first) = cnode_of(cap) else {
+        return false;
+    };
+    let walk = WALKS.fetch_add(1, Ordering::Relaxed) + 1;
+    first.seen.set(walk);
+    first.next.set(ptr::null());
+
+    let mut pending: *const CNode = first;
+    // SAFETY: Every CNode chained here is held by a capability in a slot
+    // the tree lock keeps in place.
+    while let Some(cnode) = unsafe { pending.as_ref() } {
+        pending = cnode.next.get();
+        let slots = cnode.slots();
+        if slots.as_ptr_range().contains(&ptr::from_ref(slot)) {
+            return true;
+        }
+        for child in slots.iter().filter_map(|slot| cnode_of(&slot.cap())) {
+            if child.seen.get() != walk {
+                child.seen.set(walk);
+                child.next.set(pending);
+                pending = child;
+            }
+        }
+    }
+    false
+}
+
+/// The low `count` bits of `value >> shift`.
+fn bits(value: u64, shift: u8, count: u8) -> u64 {
+    if count == 0 {
+        return 0;
+    }
+    (value >> shift) & (u64::MAX >> (64 - count))
+}
+
+/// Resolve the low `depth` bits of `cptr` from the CNode capability
+/// `root`.
+///
+/// Must be called with the derivation tree locked; the slot may only be
+/// used while it stays locked.
+pub(super) fn resolve<'a>(
+    root: &RawCapability,
+    cptr: u64,
+    depth: u8,
+) -> Result<&'a Slot, CSpaceError> {
+    if !(1..=WORD_BITS).contains(&depth) {
+        return Err(CSpaceError::InvalidSlot);
+    }
+
+    let mut cap = *root;
+    let mut remaining = depth;
+    loop {
+        let cnode = cnode_of(&cap).ok_or(CSpaceError::DepthMismatch)?;
+        let guard = Guard::from_badge(cap.badge).ok_or(CSpaceError::GuardMismatch)?;
+        let used = guard.bits + cnode.radix;
+        if used > remaining {
+            return Err(CSpaceError::DepthMismatch);
+        }
+        let rest = remaining - used;
+
+        if bits(cptr, rest + cnode.radix, guard.bits) != guard.value {
+            return Err(CSpaceError::GuardMismatch);
+        }
+        let slot = &cnode.slots()[bits(cptr, rest, cnode.radix) as usize];
+        if rest == 0 {
+            return Ok(slot);
+        }
+
+        cap = slot.cap();
+        remaining = rest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guard_round_trips_through_badge() {
+        let guard = Guard::new(58, 0x123).unwrap();
+        assert_eq!(Guard::from_badge(guard.to_badge()), Some(guard));
+        assert!(Guard::new(4, 0x10).is_none());
+        assert!(Guard::new(GUARD_MAX_BITS + 1, 0).is_none());
+    }
+
+    #[test]
+    fn test_deep_chain_drops_iteratively() {
+        let guard = Guard::new(0, 0).unwrap();
+        let mut top = create_cap(CNode::new(1), guard, Rights::ALL);
+        for _ in 0..10_000 {
+            let parent = CNode::new(1);
+            {
+                let _tree = cdt::lock();
+                parent.slots()[0].insert_root(top);
+            }
+            top = create_cap(parent, guard, Rights::ALL);
+        }
+        // Would overflow the stack if each level dropped the next
+        object::release(top);
+    }
+
+    #[test]
+    fn test_contains_finds_nested_slots() {
+        let guard = Guard::new(0, 0).unwrap();
+        let inner = create_cap(CNode::new(1), guard, Rights::ALL);
+        let outer = create_cap(CNode::new(1), guard, Rights::ALL);
+        let other = CNode::new(1);
+        {
+            let _tree = cdt::lock();
+            let outer_slots = slots(&outer).unwrap();
+            let inner_slots = slots(&inner).unwrap();
+            outer_slots[0].insert_root(inner);
+
+            assert!(contains(&outer, &outer_slots[1]));
+            assert!(contains(&outer, &inner_slots[1]));
+            assert!(!contains(&inner, &outer_slots[1]));
+            assert!(!contains(&outer, &other.slots()[0]));
+        }
+        object::release(outer);
+    }
+
+    #[test]
+    fn test_bits_extracts_fields() {
+        assert_eq!(bits(0xABCD, 4, 8), 0xBC);
+        assert_eq!(bits(u64::MAX, 0, 64), u64::MAX);
+        assert_eq!(bits(0xFF, 3, 0), 0);
+    }
+}
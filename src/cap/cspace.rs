@@ -1,65 +1,83 @@
 //! Capability Space (CSpace)
 //!
 //! A CSpace is a data structure that holds capabilities for a process.
-//! It provides indexed access to capabilities via "slots".
+//! It provides access to capabilities via "slots" named by capability
+//! pointers (cptrs).
 //!
 //! # Design
-//! - Fixed-size array of capability slots
-//! - Slots are addressed by CapSlot indices
-//! - Operations: lookup, insert, delete, derive
-
-use super::capability::{Capability, CapabilityType, Rights};
-
-/// Number of slots in a CSpace.
-/// Using 64 for now to keep it simple.
-pub const CSPACE_SIZE: usize = 64;
-
-/// A slot index in a CSpace.
+//! - A tree of CNodes (`cap::cnode`) below a root CNode capability;
+//!   slots are addressed by a cptr and the number of its bits to resolve
+//! - A new CSpace has a 64-slot root whose guard makes cptr `n` at depth
+//!   64 name slot `n`
+//! - Operations: lookup, insert, delete, derive, revoke
+//! - Each occupied slot owns one reference to its object (`cap::object`)
+//! - Each slot is a node of the derivation tree (`cap::cdt`); derived
+//!   capabilities are children of their source
+
+use super::capability::{CapabilityType, Rights};
+use super::cdt::{self, Slot};
+use super::cnode::{self, CNode, Guard, GUARD_MAX_BITS, ROOT_RADIX, WORD_BITS};
+use super::object;
+
+/// A slot address in a CSpace: a capability pointer and the number of
+/// its low bits to resolve.
 ///
-/// This is a newtype to prevent using arbitrary integers as slot indices.
+/// This is a newtype to prevent using arbitrary integers as slot
+/// addresses.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
-#[repr(transparent)]
-pub struct CapSlot(u32);
+pub struct CapSlot {
+    cptr: u64,
+    depth: u8,
+}
 
 impl CapSlot {
-    /// Create a new slot index.
-    ///
-    /// Returns None if the index is out of range.
+    /// Address `cptr` resolved over all 64 bits.
     #[inline]
-    pub const fn new(index: u32) -> Option<Self> {
-        if (index as usize) < CSPACE_SIZE {
-            Some(Self(index))
-        } else {
-            None
+    pub const fn new(cptr: u64) -> Self {
+        Self {
+            cptr,
+            depth: WORD_BITS,
         }
     }
 
-    /// Create a slot index without bounds checking.
+    /// Address the low `depth` bits of `cptr`.
     ///
-    /// # Safety
-    /// The caller must ensure `index < CSPACE_SIZE`.
+    /// Returns None if the depth is out of range or `cptr` has bits set
+    /// above it.
+    #[inline]
+    pub const fn with_depth(cptr: u64, depth: u8) -> Option<Self> {
+        if depth == 0 || depth > WORD_BITS || (depth < WORD_BITS && cptr >> depth != 0) {
+            return None;
+        }
+        Some(Self { cptr, depth })
+    }
+
+    /// The capability pointer.
     #[inline]
-    pub const unsafe fn new_unchecked(index: u32) -> Self {
-        Self(index)
+    pub const fn cptr(self) -> u64 {
+        self.cptr
     }
 
-    /// Get the index value.
+    /// The slot `n` cptrs further on, at the same depth.
     #[inline]
-    pub const fn index(self) -> usize {
-        self.0 as usize
+    pub const fn offset(self, n: u64) -> Option<Self> {
+        match self.cptr.checked_add(n) {
+            Some(cptr) => Self::with_depth(cptr, self.depth),
+            None => None,
+        }
     }
 
     /// Reserved slot for null capability.
-    pub const NULL: Self = Self(0);
+    pub const NULL: Self = Self::new(0);
 
     /// Reserved slot for reply capability.
-    pub const REPLY: Self = Self(1);
+    pub const REPLY: Self = Self::new(1);
 
     /// Reserved slot for caller capability.
-    pub const CALLER: Self = Self(2);
+    pub const CALLER: Self = Self::new(2);
 
     /// First user-available slot.
-    pub const FIRST_USER: Self = Self(3);
+    pub const FIRST_USER: Self = Self::new(3);
 }
 
 /// Error type for CSpace operations.
@@ -75,6 +93,14 @@ pub enum CSpaceError {
     InsufficientRights,
     /// The capability type doesn't match.
     TypeMismatch,
+    /// The depth does not end at a slot, or a CNode was expected.
+    DepthMismatch,
+    /// The cptr does not match a CNode's guard.
+    GuardMismatch,
+    /// The guard does not fit the CNode.
+    InvalidGuard,
+    /// A CNode capability would be stored inside its own CNode.
+    CNodeCycle,
 }
 
 impl core::fmt::Display for CSpaceError {
@@ -85,6 +111,10 @@ impl core::fmt::Display for CSpaceError {
             Self::SlotEmpty => write!(f, "slot is empty"),
             Self::InsufficientRights => write!(f, "insufficient rights"),
             Self::TypeMismatch => write!(f, "capability type mismatch"),
+            Self::DepthMismatch => write!(f, "depth does not match the CSpace"),
+            Self::GuardMismatch => write!(f, "guard mismatch"),
+            Self::InvalidGuard => write!(f, "invalid guard"),
+            Self::CNodeCycle => write!(f, "CNode would hold a capability to itself"),
         }
     }
 }
@@ -155,28 +185,42 @@ impl core::fmt::Debug for RawCapability {
 /// Capability Space for a process.
 ///
 /// Contains all capabilities accessible to a process.
-/// Capabilities are accessed via slot indices.
+/// Capabilities are accessed via slot addresses resolved from the root
+/// CNode.
 #[derive(Debug)]
 pub struct CSpace {
-    /// Array of capability slots.
-    slots: [RawCapability; CSPACE_SIZE],
+    /// Capability to the root CNode, owning one reference to it.
+    root: RawCapability,
 }
 
 impl CSpace {
     /// Create a new empty CSpace.
-    pub const fn new() -> Self {
-        const NULL: RawCapability = RawCapability::null();
+    pub fn new() -> Self {
+        let guard = Guard::new(GUARD_MAX_BITS, 0).unwrap();
         Self {
-            slots: [NULL; CSPACE_SIZE],
+            root: cnode::create_cap(CNode::new(ROOT_RADIX), guard, Rights::ALL),
         }
     }
 
+    /// Resolve `slot` to a slot in this CSpace.
+    ///
+    /// Must be called with the derivation tree locked; the slot may only
+    /// be used while it stays locked.
+    pub(super) fn resolve(&self, slot: CapSlot) -> Result<&Slot, CSpaceError> {
+        cnode::resolve(&self.root, slot.cptr, slot.depth)
+    }
+
     /// Look up a capability in a slot.
     ///
-    /// Returns the raw capability if the slot is valid and non-empty.
+    /// Returns a copy of the raw capability if the slot is valid and
+    /// non-empty. The copy owns no reference: it is only valid until the
+    /// slot is next changed.
     #[inline]
-    pub fn lookup(&self, slot: CapSlot) -> Result<&RawCapability, CSpaceError> {
-        let cap = &self.slots[slot.index()];
+    pub fn lookup(&self, slot: CapSlot) -> Result<RawCapability, CSpaceError> {
+        let cap = {
+            let _tree = cdt::lock();
+            self.resolve(slot)?.cap()
+        };
         if cap.is_null() {
             Err(CSpaceError::SlotEmpty)
         } else {
@@ -184,63 +228,152 @@ impl CSpace {
         }
     }
 
-    /// Look up a capability with type checking.
-    pub fn lookup_typed(
+    /// Run `f` on the capability in `slot` while it cannot be deleted,
+    /// for example to take a reference to its object.
+    ///
+    /// `f` runs with the derivation tree locked and must not use any
+    /// CSpace.
+    pub fn with_cap<R>(
         &self,
         slot: CapSlot,
-        expected_type: CapabilityType,
-    ) -> Result<&RawCapability, CSpaceError> {
-        let cap = self.lookup(slot)?;
-        if cap.cap_type != expected_type {
-            return Err(CSpaceError::TypeMismatch);
+        f: impl FnOnce(&RawCapability) -> R,
+    ) -> Result<R, CSpaceError> {
+        let _tree = cdt::lock();
+        let cap = self.resolve(slot)?.cap();
+        if cap.is_null() {
+            return Err(CSpaceError::SlotEmpty);
+        }
+        Ok(f(&cap))
+    }
+
+    /// Whether `slot` is one of the root slots below
+    /// `CapSlot::FIRST_USER`, which only the kernel fills.
+    pub fn is_reserved(&self, slot: CapSlot) -> Result<bool, CSpaceError> {
+        let _tree = cdt::lock();
+        let slot = self.resolve(slot)?;
+        Ok(self.is_reserved_slot(slot))
+    }
+
+    /// Take the capability out of the reserved root slot `slot` (below
+    /// `CapSlot::FIRST_USER`), whatever the root's guard, handing its
+    /// reference to the caller.
+    pub fn take_reserved(&mut self, slot: CapSlot) -> Option<RawCapability> {
+        let _tree = cdt::lock();
+        let slot = self.reserved_slot(slot);
+        slot.cap().is_valid().then(|| slot.remove())
+    }
+
+    /// Put `cap` into the reserved root slot `slot` and return the
+    /// capability it held, for the caller to release.
+    pub fn replace_reserved(&mut self, slot: CapSlot, cap: RawCapability) -> Option<RawCapability> {
+        let _tree = cdt::lock();
+        let slot = self.reserved_slot(slot);
+        let old = slot.cap().is_valid().then(|| slot.remove());
+        slot.insert_root(cap);
+        old
+    }
+
+    /// Set the guard of the root CNode.
+    ///
+    /// With a shorter guard the root uses fewer cptr bits, and the rest
+    /// address CNodes stored in it.
+    pub fn set_guard(&mut self, guard: Guard) -> Result<(), CSpaceError> {
+        if !cnode::valid_guard(&self.root, guard) {
+            return Err(CSpaceError::InvalidGuard);
         }
-        Ok(cap)
+        self.root.badge = guard.to_badge();
+        Ok(())
     }
 
     /// Insert a capability into a slot.
     ///
-    /// Fails if the slot is already occupied.
+    /// The slot takes over the reference owned by `cap`, as a root of the
+    /// derivation tree. Fails if the slot is already occupied.
     pub fn insert(&mut self, slot: CapSlot, cap: RawCapability) -> Result<(), CSpaceError> {
-        if !self.slots[slot.index()].is_null() {
+        let _tree = cdt::lock();
+        let slot = self.resolve(slot)?;
+        if slot.cap().is_valid() {
             return Err(CSpaceError::SlotOccupied);
         }
-        self.slots[slot.index()] = cap;
+        slot.insert_root(cap);
         Ok(())
     }
 
-    /// Replace a capability in a slot.
+    /// Insert a capability made from the object in `parent`, as its
+    /// child in the derivation tree.
     ///
-    /// Returns the old capability.
-    pub fn replace(&mut self, slot: CapSlot, cap: RawCapability) -> RawCapability {
-        core::mem::replace(&mut self.slots[slot.index()], cap)
+    /// The slot takes over the reference owned by `cap`. Fails if the
+    /// slot is occupied or `parent` no longer holds a capability to
+    /// `parent_object`.
+    pub fn insert_child(
+        &mut self,
+        parent: CapSlot,
+        parent_object: usize,
+        slot: CapSlot,
+        cap: RawCapability,
+    ) -> Result<(), CSpaceError> {
+        let _tree = cdt::lock();
+        let parent = self.resolve(parent)?;
+        let slot = self.resolve(slot)?;
+        if parent.cap().is_null() || parent.cap().object_ptr != parent_object {
+            return Err(CSpaceError::SlotEmpty);
+        }
+        if slot.cap().is_valid() {
+            return Err(CSpaceError::SlotOccupied);
+        }
+        slot.insert_child(parent, cap);
+        Ok(())
     }
 
     /// Delete a capability from a slot.
     ///
-    /// Returns the deleted capability, or error if slot is empty.
-    pub fn delete(&mut self, slot: CapSlot) -> Result<RawCapability, CSpaceError> {
-        if self.slots[slot.index()].is_null() {
-            return Err(CSpaceError::SlotEmpty);
-        }
-        Ok(core::mem::replace(
-            &mut self.slots[slot.index()],
-            RawCapability::null(),
-        ))
+    /// Capabilities derived from it move up to its parent, so revoking
+    /// an ancestor still reaches them. Drops the slot's object reference,
+    /// destroying the object if this was the last capability to it.
+    /// Returns error if slot is empty.
+    pub fn delete(&mut self, slot: CapSlot) -> Result<(), CSpaceError> {
+        let cap = {
+            let _tree = cdt::lock();
+            let slot = self.resolve(slot)?;
+            if slot.cap().is_null() {
+                return Err(CSpaceError::SlotEmpty);
+            }
+            slot.remove()
+        };
+        // Outside the lock: destroying an object may delete capabilities
+        object::release(cap);
+        Ok(())
     }
 
-    /// Find a free slot.
+    /// Delete every capability derived from the one in `slot`, in any
+    /// CSpace, keeping `slot` itself.
     ///
-    /// Returns the first empty slot >= start_from.
-    pub fn find_free(&self, start_from: CapSlot) -> Option<CapSlot> {
-        for i in start_from.index()..CSPACE_SIZE {
-            if self.slots[i].is_null() {
-                return Some(CapSlot(i as u32));
+    /// Needs REVOKE on the capability. Returns how many capabilities were
+    /// deleted.
+    pub fn revoke(&mut self, slot: CapSlot) -> Result<usize, CSpaceError> {
+        let revoked = {
+            let _tree = cdt::lock();
+            let slot = self.resolve(slot)?;
+            let cap = slot.cap();
+            if cap.is_null() {
+                return Err(CSpaceError::SlotEmpty);
             }
-        }
-        None
+            if !cap.rights.contains(Rights::REVOKE) {
+                return Err(CSpaceError::InsufficientRights);
+            }
+            slot.revoke()
+        };
+        let count = revoked.len();
+        revoked.into_iter().for_each(object::release);
+        Ok(count)
     }
 
     /// Derive a capability to a new slot with reduced rights.
+    ///
+    /// The new capability becomes a child of the source in the
+    /// derivation tree. For CNode capabilities the badge is the guard
+    /// (see `cap::cnode`) and must fit the CNode, and the destination
+    /// may not be in that CNode or below it.
     pub fn derive(
         &mut self,
         src_slot: CapSlot,
@@ -248,8 +381,15 @@ impl CSpace {
         new_rights: Rights,
         new_badge: u64,
     ) -> Result<(), CSpaceError> {
+        let _tree = cdt::lock();
+        let parent = self.resolve(src_slot)?;
+        let dst = self.resolve(dst_slot)?;
+
         // Check source capability
-        let src = self.lookup(src_slot)?;
+        let src = parent.cap();
+        if src.is_null() {
+            return Err(CSpaceError::SlotEmpty);
+        }
 
         // Must have grant right to derive
         if !src.rights.contains(Rights::GRANT) {
@@ -261,11 +401,23 @@ impl CSpace {
             return Err(CSpaceError::InsufficientRights);
         }
 
+        // A CNode's guard must leave its slots addressable
+        if src.cap_type == CapabilityType::CNode
+            && !Guard::from_badge(new_badge).is_some_and(|guard| cnode::valid_guard(&src, guard))
+        {
+            return Err(CSpaceError::InvalidGuard);
+        }
+
         // Check destination is free
-        if !self.slots[dst_slot.index()].is_null() {
+        if dst.cap().is_valid() {
             return Err(CSpaceError::SlotOccupied);
         }
 
+        // A CNode inside itself could never be destroyed
+        if cnode::contains(&src, dst) {
+            return Err(CSpaceError::CNodeCycle);
+        }
+
         // Create derived capability
         let derived = RawCapability {
             cap_type: src.cap_type,
@@ -275,18 +427,28 @@ impl CSpace {
             generation: src.generation,
         };
 
-        self.slots[dst_slot.index()] = derived;
+        object::retain(&derived);
+        dst.insert_child(parent, derived);
         Ok(())
     }
 
-    /// Check if a slot has a capability with the required rights.
-    pub fn check_rights(&self, slot: CapSlot, required: Rights) -> Result<(), CSpaceError> {
-        let cap = self.lookup(slot)?;
-        if cap.rights.contains(required) {
-            Ok(())
-        } else {
-            Err(CSpaceError::InsufficientRights)
-        }
+    /// Whether `slot` is a reserved root slot; the tree lock must be held.
+    pub(super) fn is_reserved_slot(&self, slot: &Slot) -> bool {
+        let reserved = CapSlot::FIRST_USER.cptr as usize;
+        self.root_slots()[..reserved]
+            .iter()
+            .any(|root| core::ptr::eq(root, slot))
+    }
+
+    /// The root slot of a reserved `CapSlot`; the tree lock must be held.
+    fn reserved_slot(&self, slot: CapSlot) -> &Slot {
+        assert!(slot.cptr < CapSlot::FIRST_USER.cptr, "not a reserved slot");
+        &self.root_slots()[slot.cptr as usize]
+    }
+
+    /// Slots of the root CNode; the tree lock must be held.
+    fn root_slots(&self) -> &[Slot] {
+        cnode::slots(&self.root).unwrap_or_default()
     }
 }
 
@@ -295,3 +457,10 @@ impl Default for CSpace {
         Self::new()
     }
 }
+
+impl Drop for CSpace {
+    fn drop(&mut self) {
+        // The root CNode empties its slots when its last capability goes
+        object::release(self.root);
+    }
+}
@@ -3,17 +3,29 @@
 //! Implements an object-capability model inspired by seL4.
 //!
 //! # Design
-//! - Each process has a CSpace (Capability Space)
+//! - Each process has a CSpace (Capability Space), a tree of CNodes
+//!   addressed by guarded capability pointers (`cnode`)
 //! - Capabilities are unforgeable tokens that grant access to objects
 //! - Capabilities can be derived (minted) with reduced rights
+//! - A derivation tree (`cdt`) links every derived capability to its
+//!   source, across CSpaces
+//! - New kernel objects are made by retyping untyped memory (`retype`)
+//! - IPC messages carry derived capabilities between CSpaces
+//!   (`transfer`)
 //!
 //! # Security Properties
 //! - Capabilities cannot be forged or guessed
 //! - Rights can only be reduced, never increased
-//! - Revocation is supported via generation numbers
+//! - Revoking a capability deletes everything derived from it
+//! - Objects stay alive while any capability references them
 
 pub mod capability;
+mod cdt;
+pub mod cnode;
 pub mod cspace;
+pub mod object;
+pub mod retype;
+pub mod transfer;
 
 pub use capability::{Capability, CapabilityType, Rights};
-pub use cspace::{CSpace, CapSlot};
+pub use cspace::{CSpace, CSpaceError, CapSlot};
@@ -0,0 +1,86 @@
+//! Kernel Object Lifetime
+//!
+//! A `RawCapability` is a plain value, so the CSpace code tells the
+//! referenced object explicitly when a capability is copied or destroyed.
+//!
+//! # Design
+//! - Reference-counted objects live in an `Arc`; `object_ptr` is the
+//!   pointer from `Arc::into_raw`
+//! - Every stored capability owns exactly one strong reference
+//! - Objects without kernel-side state (markers, null) are ignored
+
+use alloc::sync::Arc;
+
+use crate::ipc::endpoint::{Endpoint, Reply};
+use crate::ipc::notification::Notification;
+use crate::mm::shm::SharedMemory;
+use crate::mm::untyped::{Page, Untyped};
+use crate::thread::Thread;
+
+use super::capability::CapabilityType;
+use super::cnode::CNode;
+use super::cspace::RawCapability;
+
+/// Take an additional reference for a copy of `cap`.
+///
+/// Must be called whenever a capability is duplicated into a new slot.
+pub fn retain(cap: &RawCapability) {
+    // SAFETY: Capabilities of these types always carry a pointer from
+    // Arc::into_raw of the matching object, and `cap` itself holds a
+    // reference.
+    unsafe {
+        match cap.cap_type {
+            CapabilityType::SharedMemory => {
+                Arc::increment_strong_count(cap.object_ptr as *const SharedMemory)
+            }
+            CapabilityType::Thread => Arc::increment_strong_count(cap.object_ptr as *const Thread),
+            CapabilityType::Untyped => {
+                Arc::increment_strong_count(cap.object_ptr as *const Untyped)
+            }
+            CapabilityType::Frame | CapabilityType::PageTable => {
+                Arc::increment_strong_count(cap.object_ptr as *const Page)
+            }
+            CapabilityType::CNode => Arc::increment_strong_count(cap.object_ptr as *const CNode),
+            CapabilityType::Endpoint => {
+                Arc::increment_strong_count(cap.object_ptr as *const Endpoint)
+            }
+            CapabilityType::Reply => Arc::increment_strong_count(cap.object_ptr as *const Reply),
+            CapabilityType::Notification => {
+                Arc::increment_strong_count(cap.object_ptr as *const Notification)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Drop the reference owned by `cap`, destroying the object if it was
+/// the last one.
+///
+/// `cap` must not be used afterwards.
+pub fn release(cap: RawCapability) {
+    // SAFETY: The capability owned one strong reference, which is given
+    // up here.
+    unsafe {
+        match cap.cap_type {
+            CapabilityType::SharedMemory => {
+                Arc::decrement_strong_count(cap.object_ptr as *const SharedMemory)
+            }
+            CapabilityType::Thread => Arc::decrement_strong_count(cap.object_ptr as *const Thread),
+            CapabilityType::Untyped => {
+                Arc::decrement_strong_count(cap.object_ptr as *const Untyped)
+            }
+            CapabilityType::Frame | CapabilityType::PageTable => {
+                Arc::decrement_strong_count(cap.object_ptr as *const Page)
+            }
+            CapabilityType::CNode => Arc::decrement_strong_count(cap.object_ptr as *const CNode),
+            CapabilityType::Endpoint => {
+                Arc::decrement_strong_count(cap.object_ptr as *const Endpoint)
+            }
+            CapabilityType::Reply => Arc::decrement_strong_count(cap.object_ptr as *const Reply),
+            CapabilityType::Notification => {
+                Arc::decrement_strong_count(cap.object_ptr as *const Notification)
+            }
+            _ => {}
+        }
+    }
+}
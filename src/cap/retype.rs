@@ -0,0 +1,307 @@
+//! Retyping Untyped Memory
+//!
+//! Turns part of an untyped block into new kernel objects and places
+//! capabilities to them into a CSpace, as children of the untyped
+//! capability in the derivation tree.
+//!
+//! # Object Sizes
+//! | Object       | Memory                             |
+//! |--------------|------------------------------------|
+//! | Untyped      | `2^size_bits` (4 KiB-16 MiB)       |
+//! | Thread       | one kernel stack (16 KiB)          |
+//! | Frame        | one page                           |
+//! | PageTable    | one page                           |
+//! | CNode        | 64 bytes per slot, at least a page |
+//! | Endpoint     | none                               |
+//! | Notification | none                               |
+//! | SharedMemory | `2^size_bits` (4 KiB-4 MiB)        |
+//!
+//! On top of that, each object's `Arc` (a TCB, an endpoint's queues)
+//! takes an `ObjectBlock` of its size rounded up to a power of two,
+//! carved after the objects. Nothing comes from the kernel heap, so what
+//! a process makes the kernel spend is bounded by its untyped memory.
+//!
+//! # Security Properties
+//! - New capabilities get all rights and can be derived from as usual
+//! - Revoking the untyped capability deletes every capability to the new
+//!   objects; the memory is only carved again once they are all gone
+//! - The destination slots are checked before any memory is carved
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::capability::{CapabilityType, Rights};
+use super::cnode::{self, CNode, Guard, CNODE_MAX_RADIX};
+use super::cspace::{CSpaceError, CapSlot, RawCapability};
+use super::object;
+use crate::ipc::endpoint::{self, Endpoint};
+use crate::ipc::notification::{self, Notification};
+use crate::mm::address::PAGE_SHIFT;
+use crate::mm::kstack::{KernelStack, KSTACK_PAGES, KSTACK_SIZE};
+use crate::mm::shm::{self, SharedMemory, SHM_MAX_BITS};
+use crate::mm::untyped::{self, ObjectBlock, Page, Untyped, UntypedError};
+use crate::mm::{free_frame, PhysAddr, PAGE_SIZE};
+use crate::process::Process;
+use crate::thread::{self, Thread};
+
+/// Most objects one retype can make.
+pub const RETYPE_MAX_COUNT: usize = 256;
+
+/// Kernel objects that can be made from untyped memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectType {
+    Untyped,
+    Thread,
+    Frame,
+    PageTable,
+    CNode,
+    Endpoint,
+    Notification,
+    SharedMemory,
+}
+
+impl ObjectType {
+    /// Decode an object type, numbered as `CapabilityType`.
+    pub fn from_raw(raw: usize) -> Option<Self> {
+        const UNTYPED: usize = CapabilityType::Untyped as usize;
+        const THREAD: usize = CapabilityType::Thread as usize;
+        const FRAME: usize = CapabilityType::Frame as usize;
+        const PAGE_TABLE: usize = CapabilityType::PageTable as usize;
+        const CNODE: usize = CapabilityType::CNode as usize;
+        const ENDPOINT: usize = CapabilityType::Endpoint as usize;
+        const NOTIFICATION: usize = CapabilityType::Notification as usize;
+        const SHARED_MEMORY: usize = CapabilityType::SharedMemory as usize;
+
+        match raw {
+            UNTYPED => Some(Self::Untyped),
+            THREAD => Some(Self::Thread),
+            FRAME => Some(Self::Frame),
+            PAGE_TABLE => Some(Self::PageTable),
+            CNODE => Some(Self::CNode),
+            ENDPOINT => Some(Self::Endpoint),
+            NOTIFICATION => Some(Self::Notification),
+            SHARED_MEMORY => Some(Self::SharedMemory),
+            _ => None,
+        }
+    }
+
+    /// Log2 of the memory one object takes besides its `Arc`, given the
+    /// `size_bits` argument (the size of an untyped or shared memory
+    /// object, the radix of a CNode); `None` if it takes none.
+    fn memory_bits(self, size_bits: u8) -> Result<Option<u8>, RetypeError> {
+        match self {
+            Self::Untyped => Ok(Some(size_bits)),
+            Self::Thread => Ok(Some(KSTACK_SIZE.trailing_zeros() as u8)),
+            Self::Frame | Self::PageTable => Ok(Some(PAGE_SHIFT as u8)),
+            Self::Endpoint | Self::Notification => Ok(None),
+            Self::CNode if (1..=CNODE_MAX_RADIX).contains(&size_bits) => {
+                Ok(Some(CNode::size_bits(size_bits)))
+            }
+            Self::CNode => Err(UntypedError::InvalidSize.into()),
+            Self::SharedMemory if (PAGE_SHIFT as u8..=SHM_MAX_BITS).contains(&size_bits) => {
+                Ok(Some(size_bits))
+            }
+            Self::SharedMemory => Err(UntypedError::InvalidSize.into()),
+        }
+    }
+
+    /// Log2 of the `ObjectBlock` holding one object's `Arc`.
+    fn block_bits(self) -> u8 {
+        match self {
+            Self::Untyped => untyped::block_bits::<Untyped>(),
+            Self::Thread => untyped::block_bits::<Thread>(),
+            Self::Frame | Self::PageTable => untyped::block_bits::<Page>(),
+            Self::CNode => untyped::block_bits::<CNode>(),
+            Self::Endpoint => untyped::block_bits::<Endpoint>(),
+            Self::Notification => untyped::block_bits::<Notification>(),
+            Self::SharedMemory => untyped::block_bits::<SharedMemory>(),
+        }
+    }
+}
+
+/// Error type for retype operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetypeError {
+    /// A slot is invalid, occupied or of the wrong type.
+    CSpace(CSpaceError),
+    /// Carving the memory failed.
+    Untyped(UntypedError),
+    /// The count is zero, too large, or runs past the cptrs of the
+    /// destination depth.
+    InvalidCount,
+    /// The kernel could not set up an object.
+    OutOfMemory,
+    /// The process has exited.
+    ProcessExited,
+}
+
+impl From<CSpaceError> for RetypeError {
+    fn from(e: CSpaceError) -> Self {
+        Self::CSpace(e)
+    }
+}
+
+impl From<UntypedError> for RetypeError {
+    fn from(e: UntypedError) -> Self {
+        Self::Untyped(e)
+    }
+}
+
+impl core::fmt::Display for RetypeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::CSpace(e) => write!(f, "{}", e),
+            Self::Untyped(e) => write!(f, "{}", e),
+            Self::InvalidCount => write!(f, "invalid object count"),
+            Self::OutOfMemory => write!(f, "out of kernel memory"),
+            Self::ProcessExited => write!(f, "process has exited"),
+        }
+    }
+}
+
+/// Make `count` objects of type `object` from the untyped capability in
+/// `untyped` and place capabilities to them into `dst` onwards, all in
+/// `process`'s CSpace.
+///
+/// `size_bits` gives the size of variable-sized objects (log2 bytes for
+/// untyped and shared memory, log2 slots for CNodes) and is ignored for the others. The
+/// destination slots are `count` consecutive cptrs at `dst`'s depth.
+/// New threads belong to `process` and stay inactive until started;
+/// new CNodes have no guard.
+pub fn retype(
+    process: &Arc<Process>,
+    untyped: CapSlot,
+    object: ObjectType,
+    size_bits: u8,
+    dst: CapSlot,
+    count: usize,
+) -> Result<(), RetypeError> {
+    if count == 0 || count > RETYPE_MAX_COUNT || dst.offset(count as u64 - 1).is_none() {
+        return Err(RetypeError::InvalidCount);
+    }
+    let slots = (0..count as u64).filter_map(|i| dst.offset(i));
+
+    let source = process
+        .with_cspace(|cspace| {
+            for slot in slots.clone() {
+                match cspace.lookup(slot) {
+                    Err(CSpaceError::SlotEmpty) => {}
+                    Ok(_) => return Err(CSpaceError::SlotOccupied),
+                    Err(e) => return Err(e),
+                }
+            }
+            cspace
+                .with_cap(untyped, |cap| {
+                    if !cap.rights.contains(Rights::WRITE) {
+                        return Err(CSpaceError::InsufficientRights);
+                    }
+                    untyped::from_cap(cap).ok_or(CSpaceError::TypeMismatch)
+                })
+                .and_then(|found| found)
+        })
+        .ok_or(RetypeError::ProcessExited)??;
+
+    let mut caps = Vec::new();
+    caps.try_reserve_exact(count)
+        .map_err(|_| RetypeError::OutOfMemory)?;
+
+    let memory = match object.memory_bits(size_bits)? {
+        Some(bits) => Some((source.carve(bits, count)?, bits)),
+        None => None,
+    };
+    // Frames of the objects from `first` on, which no object owns yet
+    let free_memory = |first: usize| {
+        if let Some((base, bits)) = memory {
+            for page in (first << bits..count << bits).step_by(PAGE_SIZE) {
+                free_frame(base.add(page));
+            }
+        }
+    };
+    let block_bits = object.block_bits();
+    let blocks = source.carve_blocks(block_bits, count).inspect_err(|_| free_memory(0))?;
+    let block = |i: usize| ObjectBlock::new(blocks.add(i << block_bits), block_bits);
+
+    for i in 0..count {
+        let addr = memory.map(|(base, bits)| base.add(i << bits));
+        match create(process, &source, object, addr, size_bits, block(i)) {
+            Ok(cap) => caps.push(cap),
+            Err(e) => {
+                // `create` gave up its own frames; drop those of the
+                // objects never made
+                free_memory(i + 1);
+                (i + 1..count).map(block).for_each(drop);
+                caps.into_iter().for_each(object::release);
+                return Err(e);
+            }
+        }
+    }
+
+    let parent = Arc::as_ptr(&source) as usize;
+    let mut caps = caps.into_iter();
+    let inserted = process.with_cspace(|cspace| {
+        slots
+            .zip(caps.by_ref())
+            .try_for_each(|(slot, cap)| cspace.insert_child(untyped, parent, slot, cap))
+    });
+    // Whatever did not make it into the CSpace is destroyed again
+    caps.for_each(object::release);
+    inserted.ok_or(RetypeError::ProcessExited)??;
+    Ok(())
+}
+
+/// Make one object from `source` with its memory at `addr` (`None` for
+/// types without any), whose frames each carry a reference for it, and
+/// its `Arc` in `block`; return a capability owning the object.
+///
+/// On failure the frame references and the block are dropped.
+fn create(
+    process: &Arc<Process>,
+    source: &Arc<Untyped>,
+    object: ObjectType,
+    addr: Option<PhysAddr>,
+    size_bits: u8,
+    block: ObjectBlock,
+) -> Result<RawCapability, RetypeError> {
+    // `retype` passes memory for every type whose `memory_bits` is `Some`
+    let memory = || addr.expect("object type without memory");
+
+    match object {
+        ObjectType::Untyped => {
+            let parent = source.clone();
+            Ok(block.place::<Untyped, _>(|| {
+                untyped::create_cap(Untyped::adopt(memory(), size_bits, parent), Rights::ALL)
+            }))
+        }
+        ObjectType::Frame => Ok(block.place::<Page, _>(|| {
+            untyped::create_page_cap(Page::new(memory()), CapabilityType::Frame, Rights::ALL)
+        })),
+        ObjectType::PageTable => Ok(block.place::<Page, _>(|| {
+            untyped::create_page_cap(Page::new(memory()), CapabilityType::PageTable, Rights::ALL)
+        })),
+        ObjectType::CNode => Ok(block.place::<CNode, _>(|| {
+            cnode::create_cap(
+                CNode::adopt(memory(), size_bits),
+                Guard::new(0, 0).unwrap(),
+                Rights::ALL,
+            )
+        })),
+        ObjectType::Endpoint => Ok(block.place::<Endpoint, _>(|| {
+            endpoint::create_cap(Endpoint::new(), Rights::ALL)
+        })),
+        ObjectType::Notification => Ok(block.place::<Notification, _>(|| {
+            notification::create_cap(Notification::new(), Rights::ALL)
+        })),
+        ObjectType::SharedMemory => Ok(block.place::<SharedMemory, _>(|| {
+            shm::create_cap(SharedMemory::adopt(memory(), size_bits), Rights::ALL)
+        })),
+        ObjectType::Thread => {
+            let frames: [PhysAddr; KSTACK_PAGES] =
+                core::array::from_fn(|i| memory().add(i * PAGE_SIZE));
+            let kstack = KernelStack::with_frames(frames).map_err(|_| RetypeError::OutOfMemory)?;
+            let thread = block
+                .place::<Thread, _>(|| Thread::new_inactive(process.clone(), kstack))
+                .map_err(|_| RetypeError::ProcessExited)?;
+            Ok(thread::create_cap(thread, Rights::ALL))
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Capability Transfer
+//!
+//! Capabilities sent with an IPC message travel between CSpaces in slots
+//! of their own. The sender derives them into those slots from its
+//! CSpace; the receiver moves them into slots of its CSpace once the
+//! message arrives.
+//!
+//! # Design
+//! - Each slot in transit is a node of the derivation tree, a child of
+//!   the capability it was derived from; moving it out keeps that place,
+//!   so the receiver's copy stays a child of the sender's capability
+//! - Received capabilities go into consecutive empty slots from the one
+//!   the receiver names. Whatever does not fit, or is never received, is
+//!   deleted with the message
+//!
+//! # Security Properties
+//! - Sending a capability derives it, so it needs GRANT on it, like
+//!   `derive`; the copy's rights are reduced to a mask given by the sender
+//!   (the rights of the endpoint capability it sends through)
+//! - Revoking the sender's capability deletes the copy, whether it is
+//!   still in transit or received already
+//! - Reply capabilities and reserved slots are never sent or received
+//!   into
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::capability::{CapabilityType, Rights};
+use super::cdt::{self, Slot};
+use super::cnode;
+use super::cspace::{CSpace, CSpaceError, CapSlot, RawCapability};
+use super::object;
+
+/// Most capabilities one message can carry.
+pub const MAX_TRANSFER_CAPS: usize = 3;
+
+/// Capabilities in transit with a message.
+pub struct CapTransfer {
+    /// Boxed, as the derivation tree links to the slots.
+    slots: Box<[Slot; MAX_TRANSFER_CAPS]>,
+}
+
+// SAFETY: The slots are cells only accessed with the derivation tree
+// locked, so CPUs never touch them at the same time.
+unsafe impl Send for CapTransfer {}
+// SAFETY: As above.
+unsafe impl Sync for CapTransfer {}
+
+impl Drop for CapTransfer {
+    fn drop(&mut self) {
+        let caps: Vec<RawCapability> = {
+            let _tree = cdt::lock();
+            self.slots
+                .iter()
+                .filter(|slot| slot.cap().is_valid())
+                .map(Slot::remove)
+                .collect()
+        };
+        caps.into_iter().for_each(object::release);
+    }
+}
+
+impl fmt::Debug for CapTransfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CapTransfer").finish_non_exhaustive()
+    }
+}
+
+impl CSpace {
+    /// Derive the capabilities in `slots` for sending, with their rights
+    /// reduced to `mask`.
+    ///
+    /// Fails, sending nothing, if any slot is empty, reserved or holds a
+    /// capability without GRANT.
+    pub fn send_caps(&self, slots: &[CapSlot], mask: Rights) -> Result<CapTransfer, CSpaceError> {
+        debug_assert!(slots.len() <= MAX_TRANSFER_CAPS);
+        let transfer = CapTransfer {
+            slots: Box::new(core::array::from_fn(|_| Slot::new())),
+        };
+
+        let _tree = cdt::lock();
+        let mut sources = Vec::with_capacity(slots.len());
+        for &slot in slots {
+            let source = self.resolve(slot)?;
+            let cap = source.cap();
+            if cap.is_null() {
+                return Err(CSpaceError::SlotEmpty);
+            }
+            if self.is_reserved_slot(source) || cap.cap_type == CapabilityType::Reply {
+                return Err(CSpaceError::InvalidSlot);
+            }
+            if !cap.rights.contains(Rights::GRANT) {
+                return Err(CSpaceError::InsufficientRights);
+            }
+            sources.push(source);
+        }
+
+        for (source, slot) in sources.into_iter().zip(transfer.slots.iter()) {
+            let cap = source.cap();
+            let derived = RawCapability {
+                rights: cap.rights.intersect(mask),
+                ..cap
+            };
+            object::retain(&derived);
+            slot.insert_child(source, derived);
+        }
+        Ok(transfer)
+    }
+
+    /// Move the capabilities of `transfer` into consecutive empty slots
+    /// from `dst`, and return how many were moved.
+    ///
+    /// Stops at the first slot that does not resolve, is occupied, is
+    /// reserved or lies in the CNode the capability refers to (or below
+    /// it); capabilities left over are deleted.
+    pub fn receive_caps(&mut self, transfer: CapTransfer, dst: CapSlot) -> usize {
+        let tree = cdt::lock();
+        let mut received = 0;
+        // Revoked on the way: nothing to receive
+        for slot in transfer.slots.iter().filter(|slot| slot.cap().is_valid()) {
+            let Some(target) = dst
+                .offset(received as u64)
+                .and_then(|target| self.resolve(target).ok())
+            else {
+                break;
+            };
+            if target.cap().is_valid()
+                || self.is_reserved_slot(target)
+                || cnode::contains(&slot.cap(), target)
+            {
+                break;
+            }
+            slot.move_to(target);
+            received += 1;
+        }
+        drop(tree);
+        drop(transfer);
+        received
+    }
+}
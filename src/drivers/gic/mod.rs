@@ -0,0 +1,288 @@
+//! ARM Generic Interrupt Controller (GICv2 / GICv3)
+//!
+//! Supports both interrupt controller generations found on QEMU virt:
+//! - GICv2 (default): memory-mapped distributor + CPU interface
+//! - GICv3 (`gic-version=3`): distributor + per-CPU redistributors,
+//!   CPU interface through ICC_* system registers
+//!
+//! The version is detected at boot from the distributor's ArchRev field
+//! (GICD_PIDR2), so the same kernel image runs on either configuration.
+//!
+//! # Interrupt IDs
+//! - 0-15: SGIs (software generated, used for IPIs)
+//! - 16-31: PPIs (per-CPU peripherals, e.g. the generic timer)
+//! - 32-1019: SPIs (shared peripherals, e.g. UART)
+//!
+//! # Security Considerations
+//! - Handlers are stored as plain function pointers in atomics, so IRQ
+//!   dispatch never takes a lock that interrupted code might hold
+//! - IRQ numbers are validated against the controller's limit
+//! - Unhandled interrupts are disabled to prevent interrupt storms
+
+mod v2;
+mod v3;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Once;
+
+use crate::kprintln;
+use crate::mm::address::KERNEL_VIRT_BASE;
+
+/// QEMU virt distributor base (shared by both versions).
+const GICD_BASE: usize = 0x0800_0000;
+/// QEMU virt GICv2 CPU interface base.
+const GICC_BASE: usize = 0x0801_0000;
+/// QEMU virt GICv3 redistributor region base.
+const GICR_BASE: usize = 0x080A_0000;
+
+/// Peripheral ID2 register (architecture revision in bits [7:4]).
+const GICD_PIDR2: usize = 0xFFE8;
+
+/// Largest valid interrupt ID + 1 (1020-1023 are special).
+pub const MAX_IRQS: usize = 1020;
+
+/// First shared peripheral interrupt.
+pub const SPI_BASE: u32 = 32;
+
+/// Number of software generated interrupts.
+pub const NUM_SGIS: u32 = 16;
+
+/// Default priority for all interrupts (lower value = higher priority).
+pub const DEFAULT_PRIORITY: u8 = 0xA0;
+
+/// Default priority mask: every interrupt above this priority is taken.
+pub const DEFAULT_PRIORITY_MASK: u8 = 0xF0;
+
+/// Interrupt handler function, called with the interrupt ID.
+pub type IrqHandler = fn(u32);
+
+/// Error type for interrupt controller operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The interrupt ID is out of range for this controller.
+    InvalidIrq,
+    /// A handler is already registered for this interrupt.
+    AlreadyRegistered,
+    /// The controller has not been initialized.
+    NotInitialized,
+}
+
+impl core::fmt::Display for IrqError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidIrq => write!(f, "invalid interrupt number"),
+            Self::AlreadyRegistered => write!(f, "interrupt handler already registered"),
+            Self::NotInitialized => write!(f, "interrupt controller not initialized"),
+        }
+    }
+}
+
+/// Operations implemented by each GIC generation.
+trait GicOps: Sync {
+    /// Number of interrupt IDs supported (including SGIs and PPIs).
+    fn num_irqs(&self) -> u32;
+    /// Initialize the distributor (once, on the boot CPU).
+    fn init_distributor(&self);
+    /// Initialize the calling CPU's interface (and redistributor).
+    fn init_cpu(&self);
+    /// Enable forwarding of an interrupt.
+    fn enable(&self, irq: u32);
+    /// Disable forwarding of an interrupt.
+    fn disable(&self, irq: u32);
+    /// Set an interrupt's priority.
+    fn set_priority(&self, irq: u32, priority: u8);
+    /// Set the calling CPU's priority mask: only interrupts with a
+    /// priority value strictly lower than `mask` are signalled to it.
+    fn set_priority_mask(&self, mask: u8);
+    /// Acknowledge the highest priority pending interrupt.
+    ///
+    /// Returns the raw acknowledge value (to be passed to `eoi`) and the
+    /// interrupt ID.
+    fn ack(&self) -> (u32, u32);
+    /// Signal end of interrupt.
+    fn eoi(&self, iar: u32);
+    /// Send a software generated interrupt to a CPU by index.
+    fn send_sgi(&self, sgi: u32, cpu: usize);
+}
+
+/// The detected interrupt controller.
+enum Gic {
+    V2(v2::GicV2),
+    V3(v3::GicV3),
+}
+
+impl Gic {
+    fn ops(&self) -> &dyn GicOps {
+        match self {
+            Gic::V2(gic) => gic,
+            Gic::V3(gic) => gic,
+        }
+    }
+
+    fn version(&self) -> u32 {
+        match self {
+            Gic::V2(_) => 2,
+            Gic::V3(_) => 3,
+        }
+    }
+}
+
+/// The global interrupt controller, set once by `init`.
+static GIC: Once<Gic> = Once::new();
+
+/// Registered handlers, stored as function pointer addresses (0 = none).
+static HANDLERS: [AtomicUsize; MAX_IRQS] = [const { AtomicUsize::new(0) }; MAX_IRQS];
+
+// ============================================================================
+// MMIO helpers
+// ============================================================================
+
+/// Translate a device physical address into the kernel direct map.
+#[inline]
+const fn mmio(phys: usize) -> usize {
+    KERNEL_VIRT_BASE + phys
+}
+
+/// Read a 32-bit device register.
+#[inline]
+fn read32(addr: usize) -> u32 {
+    // SAFETY: Only called with GIC register addresses inside the
+    // direct-mapped device region.
+    unsafe { core::ptr::read_volatile(addr as *const u32) }
+}
+
+/// Write a 32-bit device register.
+#[inline]
+fn write32(addr: usize, value: u32) {
+    // SAFETY: As for read32.
+    unsafe { core::ptr::write_volatile(addr as *mut u32, value) }
+}
+
+/// Read a 64-bit device register.
+#[inline]
+fn read64(addr: usize) -> u64 {
+    // SAFETY: As for read32.
+    unsafe { core::ptr::read_volatile(addr as *const u64) }
+}
+
+/// Write a 64-bit device register.
+#[inline]
+fn write64(addr: usize, value: u64) {
+    // SAFETY: As for read32.
+    unsafe { core::ptr::write_volatile(addr as *mut u64, value) }
+}
+
+/// Write an 8-bit device register (byte-accessible priority/target fields).
+#[inline]
+fn write8(addr: usize, value: u8) {
+    // SAFETY: As for read32.
+    unsafe { core::ptr::write_volatile(addr as *mut u8, value) }
+}
+
+// ============================================================================
+// Public API
+// ============================================================================
+
+/// Detect and initialize the interrupt controller on the boot CPU.
+///
+/// Configures the distributor and the boot CPU's interface. IRQs stay
+/// masked at the CPU (DAIF) until the caller enables them.
+pub fn init() {
+    let gic = GIC.call_once(|| {
+        let arch_rev = (read32(mmio(GICD_BASE + GICD_PIDR2)) >> 4) & 0xF;
+        if arch_rev >= 3 {
+            Gic::V3(v3::GicV3::new(mmio(GICD_BASE), mmio(GICR_BASE)))
+        } else {
+            Gic::V2(v2::GicV2::new(mmio(GICD_BASE), mmio(GICC_BASE)))
+        }
+    });
+
+    let ops = gic.ops();
+    ops.init_distributor();
+    ops.init_cpu();
+
+    kprintln!(
+        "[BOOT] GICv{} initialized ({} IRQs)",
+        gic.version(),
+        ops.num_irqs()
+    );
+}
+
+/// Initialize the calling secondary CPU's interrupt interface.
+pub fn init_cpu() -> Result<(), IrqError> {
+    controller()?.init_cpu();
+    Ok(())
+}
+
+/// Get the controller, failing if `init` has not run.
+fn controller() -> Result<&'static dyn GicOps, IrqError> {
+    GIC.get().map(Gic::ops).ok_or(IrqError::NotInitialized)
+}
+
+/// Validate an interrupt ID against the controller.
+fn check_irq(irq: u32) -> Result<&'static dyn GicOps, IrqError> {
+    let ops = controller()?;
+    if irq >= ops.num_irqs() || irq as usize >= MAX_IRQS {
+        return Err(IrqError::InvalidIrq);
+    }
+    Ok(ops)
+}
+
+/// Register a handler for an interrupt.
+///
+/// The interrupt still has to be unmasked with `enable_irq`.
+pub fn register_irq(irq: u32, handler: IrqHandler) -> Result<(), IrqError> {
+    check_irq(irq)?;
+
+    HANDLERS[irq as usize]
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map(|_| ())
+        .map_err(|_| IrqError::AlreadyRegistered)
+}
+
+/// Unmask an interrupt at the controller.
+pub fn enable_irq(irq: u32) -> Result<(), IrqError> {
+    check_irq(irq)?.enable(irq);
+    Ok(())
+}
+
+/// Send a software generated interrupt (0-15) to a CPU by index.
+pub fn send_sgi(sgi: u32, cpu: usize) -> Result<(), IrqError> {
+    if sgi >= NUM_SGIS {
+        return Err(IrqError::InvalidIrq);
+    }
+    controller()?.send_sgi(sgi, cpu);
+    Ok(())
+}
+
+/// Dispatch all pending interrupts to their registered handlers.
+///
+/// Called from the IRQ exception vectors with interrupts masked.
+pub fn handle_irq() {
+    let Ok(ops) = controller() else {
+        return;
+    };
+
+    loop {
+        let (iar, irq) = ops.ack();
+
+        // 1020-1023 are special IDs (1023 = spurious / nothing pending)
+        if irq as usize >= MAX_IRQS {
+            break;
+        }
+
+        let handler = HANDLERS[irq as usize].load(Ordering::Acquire);
+        if handler != 0 {
+            // SAFETY: Non-zero entries are only ever stored from a valid
+            // `IrqHandler` in `register_irq`.
+            let handler: IrqHandler = unsafe { core::mem::transmute(handler) };
+            handler(irq);
+        } else {
+            kprintln!("[IRQ] Unhandled interrupt {}, disabling", irq);
+            ops.disable(irq);
+        }
+
+        ops.eoi(iar);
+    }
+}
@@ -0,0 +1,130 @@
+//! GICv2 Backend
+//!
+//! Memory-mapped distributor (GICD) and CPU interface (GICC).
+//! SGIs and PPIs (0-31) are banked per CPU in the distributor.
+
+use super::{read32, write32, write8, GicOps, DEFAULT_PRIORITY, DEFAULT_PRIORITY_MASK};
+
+/// Distributor register offsets.
+mod gicd {
+    pub const CTLR: usize = 0x000;
+    pub const TYPER: usize = 0x004;
+    pub const ISENABLER: usize = 0x100;
+    pub const ICENABLER: usize = 0x180;
+    pub const ICPENDR: usize = 0x280;
+    pub const ICACTIVER: usize = 0x380;
+    pub const IPRIORITYR: usize = 0x400;
+    pub const ITARGETSR: usize = 0x800;
+    pub const ICFGR: usize = 0xC00;
+    pub const SGIR: usize = 0xF00;
+}
+
+/// CPU interface register offsets.
+mod gicc {
+    pub const CTLR: usize = 0x000;
+    pub const PMR: usize = 0x004;
+    pub const BPR: usize = 0x008;
+    pub const IAR: usize = 0x00C;
+    pub const EOIR: usize = 0x010;
+}
+
+/// A GICv2 interrupt controller.
+pub struct GicV2 {
+    /// Distributor base (kernel virtual).
+    gicd: usize,
+    /// CPU interface base (kernel virtual).
+    gicc: usize,
+}
+
+impl GicV2 {
+    pub const fn new(gicd: usize, gicc: usize) -> Self {
+        Self { gicd, gicc }
+    }
+
+    /// Set or clear the bit for `irq` in a 1-bit-per-IRQ register bank.
+    fn write_bit(&self, bank: usize, irq: u32) {
+        let reg = self.gicd + bank + (irq as usize / 32) * 4;
+        write32(reg, 1 << (irq % 32));
+    }
+}
+
+impl GicOps for GicV2 {
+    fn num_irqs(&self) -> u32 {
+        let lines = read32(self.gicd + gicd::TYPER) & 0x1F;
+        (32 * (lines + 1)).min(super::MAX_IRQS as u32)
+    }
+
+    fn init_distributor(&self) {
+        let num_irqs = self.num_irqs();
+
+        // Disable forwarding while reconfiguring
+        write32(self.gicd + gicd::CTLR, 0);
+
+        // SPIs: disabled, not pending, level triggered, default priority,
+        // routed to CPU 0
+        for irq in (super::SPI_BASE..num_irqs).step_by(32) {
+            let idx = irq as usize / 32 * 4;
+            write32(self.gicd + gicd::ICENABLER + idx, u32::MAX);
+            write32(self.gicd + gicd::ICPENDR + idx, u32::MAX);
+            write32(self.gicd + gicd::ICACTIVER + idx, u32::MAX);
+        }
+        for irq in (super::SPI_BASE..num_irqs).step_by(16) {
+            write32(self.gicd + gicd::ICFGR + irq as usize / 16 * 4, 0);
+        }
+        for irq in super::SPI_BASE..num_irqs {
+            self.set_priority(irq, DEFAULT_PRIORITY);
+            write8(self.gicd + gicd::ITARGETSR + irq as usize, 0x01);
+        }
+
+        write32(self.gicd + gicd::CTLR, 1);
+    }
+
+    fn init_cpu(&self) {
+        // Banked SGIs/PPIs: disable PPIs, enable SGIs (used for IPIs)
+        write32(self.gicd + gicd::ICENABLER, 0xFFFF_0000);
+        write32(self.gicd + gicd::ISENABLER, 0x0000_FFFF);
+        for irq in 0..super::SPI_BASE {
+            self.set_priority(irq, DEFAULT_PRIORITY);
+        }
+
+        self.set_priority_mask(DEFAULT_PRIORITY_MASK);
+        write32(self.gicc + gicc::BPR, 0);
+        write32(self.gicc + gicc::CTLR, 1);
+    }
+
+    fn enable(&self, irq: u32) {
+        self.write_bit(gicd::ISENABLER, irq);
+    }
+
+    fn disable(&self, irq: u32) {
+        self.write_bit(gicd::ICENABLER, irq);
+    }
+
+    fn set_priority(&self, irq: u32, priority: u8) {
+        write8(self.gicd + gicd::IPRIORITYR + irq as usize, priority);
+    }
+
+    fn set_priority_mask(&self, mask: u8) {
+        write32(self.gicc + gicc::PMR, mask as u32);
+    }
+
+    fn ack(&self) -> (u32, u32) {
+        let iar = read32(self.gicc + gicc::IAR);
+        (iar, iar & 0x3FF)
+    }
+
+    fn eoi(&self, iar: u32) {
+        write32(self.gicc + gicc::EOIR, iar);
+    }
+
+    fn send_sgi(&self, sgi: u32, cpu: usize) {
+        // Target list filter 0: the CPUs in the target list
+        let value = (1 << (16 + (cpu % 8))) | sgi;
+
+        // SAFETY: Barrier only; orders prior memory writes before the IPI.
+        unsafe {
+            core::arch::asm!("dsb ishst", options(nostack, preserves_flags));
+        }
+        write32(self.gicd + gicd::SGIR, value);
+    }
+}
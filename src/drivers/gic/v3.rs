@@ -0,0 +1,254 @@
+//! GICv3 Backend
+//!
+//! Memory-mapped distributor (GICD) for SPIs, one redistributor (GICR)
+//! per CPU for SGIs/PPIs, and the CPU interface via ICC_* system
+//! registers. Affinity routing (ARE) is always enabled.
+
+use core::arch::asm;
+
+use super::{read32, read64, write32, write64, write8, GicOps, DEFAULT_PRIORITY, DEFAULT_PRIORITY_MASK};
+
+/// Distributor register offsets.
+mod gicd {
+    pub const CTLR: usize = 0x0000;
+    pub const TYPER: usize = 0x0004;
+    pub const IGROUPR: usize = 0x0080;
+    pub const ISENABLER: usize = 0x0100;
+    pub const ICENABLER: usize = 0x0180;
+    pub const ICPENDR: usize = 0x0280;
+    pub const ICACTIVER: usize = 0x0380;
+    pub const IPRIORITYR: usize = 0x0400;
+    pub const ICFGR: usize = 0x0C00;
+    pub const IROUTER: usize = 0x6000;
+}
+
+/// Redistributor RD_base register offsets.
+mod gicr {
+    pub const WAKER: usize = 0x0014;
+    pub const TYPER: usize = 0x0008;
+}
+
+/// Redistributor SGI_base register offsets (second 64 KiB frame).
+mod gicr_sgi {
+    pub const BASE: usize = 0x1_0000;
+    pub const IGROUPR0: usize = 0x0080;
+    pub const ISENABLER0: usize = 0x0100;
+    pub const ICENABLER0: usize = 0x0180;
+    pub const IPRIORITYR: usize = 0x0400;
+    pub const ICFGR1: usize = 0x0C04;
+}
+
+/// GICD_CTLR: register write pending.
+const CTLR_RWP: u32 = 1 << 31;
+/// GICD_CTLR: affinity routing + both groups enabled. Valid for both the
+/// single-security-state and non-secure views of the register.
+const CTLR_ENABLE: u32 = (1 << 4) | (1 << 1) | 1;
+
+/// GICR_WAKER bits.
+const WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// GICR_TYPER: this is the last redistributor in the region.
+const TYPER_LAST: u64 = 1 << 4;
+/// GICR_TYPER: virtual LPIs supported (GICv4, 4 frames per redistributor).
+const TYPER_VLPIS: u64 = 1 << 1;
+
+/// Upper bound on redistributors scanned during lookup.
+const MAX_REDISTRIBUTORS: usize = 256;
+
+/// Read an ICC system register by its encoded name.
+macro_rules! read_icc {
+    ($reg:literal) => {{
+        let value: u64;
+        // SAFETY: ICC registers are accessible at EL1 once SRE is set.
+        unsafe { asm!(concat!("mrs {v}, ", $reg), v = out(reg) value, options(nomem, nostack)) };
+        value
+    }};
+}
+
+/// Write an ICC system register by its encoded name.
+macro_rules! write_icc {
+    ($reg:literal, $value:expr) => {{
+        let value: u64 = $value;
+        // SAFETY: ICC registers are accessible at EL1 once SRE is set.
+        unsafe { asm!(concat!("msr ", $reg, ", {v}"), v = in(reg) value, options(nomem, nostack)) };
+    }};
+}
+
+/// Read the calling CPU's affinity in GICR_TYPER layout (Aff3.Aff2.Aff1.Aff0).
+fn current_affinity() -> u32 {
+    let mpidr: u64;
+    // SAFETY: MPIDR_EL1 is always readable at EL1.
+    unsafe { asm!("mrs {v}, mpidr_el1", v = out(reg) mpidr, options(nomem, nostack)) };
+    ((mpidr & 0xFF_FFFF) | ((mpidr >> 8) & 0xFF00_0000)) as u32
+}
+
+/// A GICv3 interrupt controller.
+pub struct GicV3 {
+    /// Distributor base (kernel virtual).
+    gicd: usize,
+    /// Start of the redistributor region (kernel virtual).
+    gicr: usize,
+}
+
+impl GicV3 {
+    pub const fn new(gicd: usize, gicr: usize) -> Self {
+        Self { gicd, gicr }
+    }
+
+    /// Wait for a distributor register write to take effect.
+    fn wait_rwp(&self) {
+        while read32(self.gicd + gicd::CTLR) & CTLR_RWP != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Find the redistributor (RD_base) of the calling CPU.
+    fn local_redistributor(&self) -> usize {
+        let affinity = current_affinity();
+        let mut rd = self.gicr;
+
+        for _ in 0..MAX_REDISTRIBUTORS {
+            let typer = read64(rd + gicr::TYPER);
+            if (typer >> 32) as u32 == affinity {
+                return rd;
+            }
+            if typer & TYPER_LAST != 0 {
+                break;
+            }
+            rd += if typer & TYPER_VLPIS != 0 { 0x4_0000 } else { 0x2_0000 };
+        }
+
+        panic!("GICv3: no redistributor for affinity {:#x}", affinity);
+    }
+
+    /// SGI_base frame of the calling CPU's redistributor.
+    fn local_sgi_base(&self) -> usize {
+        self.local_redistributor() + gicr_sgi::BASE
+    }
+}
+
+impl GicOps for GicV3 {
+    fn num_irqs(&self) -> u32 {
+        let lines = read32(self.gicd + gicd::TYPER) & 0x1F;
+        (32 * (lines + 1)).min(super::MAX_IRQS as u32)
+    }
+
+    fn init_distributor(&self) {
+        let num_irqs = self.num_irqs();
+
+        write32(self.gicd + gicd::CTLR, 0);
+        self.wait_rwp();
+
+        // SPIs: group 1, disabled, not pending, level triggered
+        for irq in (super::SPI_BASE..num_irqs).step_by(32) {
+            let idx = irq as usize / 32 * 4;
+            write32(self.gicd + gicd::IGROUPR + idx, u32::MAX);
+            write32(self.gicd + gicd::ICENABLER + idx, u32::MAX);
+            write32(self.gicd + gicd::ICPENDR + idx, u32::MAX);
+            write32(self.gicd + gicd::ICACTIVER + idx, u32::MAX);
+        }
+        for irq in (super::SPI_BASE..num_irqs).step_by(16) {
+            write32(self.gicd + gicd::ICFGR + irq as usize / 16 * 4, 0);
+        }
+
+        // Default priority, routed to the boot CPU
+        let affinity = current_affinity() as u64;
+        let route = (affinity & 0xFF_FFFF) | ((affinity & 0xFF00_0000) << 8);
+        for irq in super::SPI_BASE..num_irqs {
+            self.set_priority(irq, DEFAULT_PRIORITY);
+            write64(self.gicd + gicd::IROUTER + irq as usize * 8, route);
+        }
+        self.wait_rwp();
+
+        write32(self.gicd + gicd::CTLR, CTLR_ENABLE);
+        self.wait_rwp();
+    }
+
+    fn init_cpu(&self) {
+        let rd = self.local_redistributor();
+
+        // Wake the redistributor
+        let waker = read32(rd + gicr::WAKER);
+        write32(rd + gicr::WAKER, waker & !WAKER_PROCESSOR_SLEEP);
+        while read32(rd + gicr::WAKER) & WAKER_CHILDREN_ASLEEP != 0 {
+            core::hint::spin_loop();
+        }
+
+        // SGIs/PPIs: group 1, PPIs disabled and level triggered, SGIs enabled
+        let sgi = rd + gicr_sgi::BASE;
+        write32(sgi + gicr_sgi::IGROUPR0, u32::MAX);
+        write32(sgi + gicr_sgi::ICENABLER0, 0xFFFF_0000);
+        write32(sgi + gicr_sgi::ISENABLER0, 0x0000_FFFF);
+        write32(sgi + gicr_sgi::ICFGR1, 0);
+        for irq in 0..super::SPI_BASE {
+            write8(sgi + gicr_sgi::IPRIORITYR + irq as usize, DEFAULT_PRIORITY);
+        }
+
+        // Enable the system register interface
+        let sre = read_icc!("S3_0_C12_C12_5");
+        write_icc!("S3_0_C12_C12_5", sre | 1);
+        // SAFETY: Synchronize the SRE change before using ICC registers.
+        unsafe { asm!("isb", options(nomem, nostack)) };
+
+        self.set_priority_mask(DEFAULT_PRIORITY_MASK);
+        write_icc!("S3_0_C12_C12_3", 0); // ICC_BPR1_EL1
+        write_icc!("S3_0_C12_C12_4", 0); // ICC_CTLR_EL1: EOImode 0
+        write_icc!("S3_0_C12_C12_7", 1); // ICC_IGRPEN1_EL1
+        // SAFETY: Synchronize the CPU interface configuration.
+        unsafe { asm!("isb", options(nomem, nostack)) };
+    }
+
+    fn enable(&self, irq: u32) {
+        let bit = 1 << (irq % 32);
+        if irq < super::SPI_BASE {
+            write32(self.local_sgi_base() + gicr_sgi::ISENABLER0, bit);
+        } else {
+            write32(self.gicd + gicd::ISENABLER + (irq as usize / 32) * 4, bit);
+        }
+    }
+
+    fn disable(&self, irq: u32) {
+        let bit = 1 << (irq % 32);
+        if irq < super::SPI_BASE {
+            write32(self.local_sgi_base() + gicr_sgi::ICENABLER0, bit);
+        } else {
+            write32(self.gicd + gicd::ICENABLER + (irq as usize / 32) * 4, bit);
+            self.wait_rwp();
+        }
+    }
+
+    fn set_priority(&self, irq: u32, priority: u8) {
+        if irq < super::SPI_BASE {
+            write8(self.local_sgi_base() + gicr_sgi::IPRIORITYR + irq as usize, priority);
+        } else {
+            write8(self.gicd + gicd::IPRIORITYR + irq as usize, priority);
+        }
+    }
+
+    fn set_priority_mask(&self, mask: u8) {
+        write_icc!("S3_0_C4_C6_0", mask as u64); // ICC_PMR_EL1
+    }
+
+    fn ack(&self) -> (u32, u32) {
+        let iar = read_icc!("S3_0_C12_C12_0") as u32; // ICC_IAR1_EL1
+        (iar, iar & 0x00FF_FFFF)
+    }
+
+    fn eoi(&self, iar: u32) {
+        write_icc!("S3_0_C12_C12_1", iar as u64); // ICC_EOIR1_EL1
+    }
+
+    fn send_sgi(&self, sgi: u32, cpu: usize) {
+        let intid = (sgi as u64 & 0xF) << 24;
+        // QEMU virt numbers CPUs as Aff1 = cpu / 16, Aff0 = cpu % 16
+        let aff1 = (cpu as u64 / 16) & 0xFF;
+        let value = intid | (aff1 << 16) | (1 << (cpu % 16));
+
+        // SAFETY: Barrier only; orders prior memory writes before the IPI.
+        unsafe { asm!("dsb ishst", options(nostack, preserves_flags)) };
+        write_icc!("S3_0_C12_C11_5", value); // ICC_SGI1R_EL1
+        // SAFETY: Synchronize the SGI generation.
+        unsafe { asm!("isb", options(nomem, nostack)) };
+    }
+}
@@ -5,4 +5,7 @@
 //! - Input validation on all public interfaces
 //! - No panics on invalid input (return errors)
 
+pub mod gic;
+pub mod psci;
+pub mod timer;
 pub mod uart;
@@ -0,0 +1,207 @@
+//! PSCI (Power State Coordination Interface)
+//!
+//! Firmware interface used to power CPUs on. The calling convention
+//! ("conduit") is HVC when a hypervisor (or QEMU itself) implements PSCI
+//! and SMC when EL3 firmware does; the device tree's `/psci` node says
+//! which.
+//!
+//! # Calls Used
+//! - PSCI_VERSION: probe that the firmware answers at all
+//! - CPU_ON: start a powered-off CPU at a physical entry point, MMU off,
+//!   with a context value in x0
+//! - AFFINITY_INFO: ask whether a CPU is on
+//!
+//! # Security Properties
+//! - The conduit is only used after the device tree named it; a missing
+//!   or unknown method leaves PSCI disabled instead of guessing
+//! - Firmware return codes are checked and mapped to `PsciError`
+
+use core::arch::asm;
+use core::fmt;
+
+use spin::Once;
+
+use crate::fdt;
+
+/// PSCI function IDs (SMC64 calling convention where applicable).
+mod function {
+    pub const PSCI_VERSION: u64 = 0x8400_0000;
+    pub const CPU_ON: u64 = 0xC400_0003;
+    pub const AFFINITY_INFO: u64 = 0xC400_0004;
+}
+
+/// How PSCI calls reach the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conduit {
+    /// `hvc #0`, handled at EL2.
+    Hvc,
+    /// `smc #0`, handled at EL3.
+    Smc,
+}
+
+/// Errors returned by PSCI calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciError {
+    /// No usable PSCI firmware was found.
+    Unavailable,
+    /// The function is not implemented.
+    NotSupported,
+    /// A parameter (such as the target MPIDR) is invalid.
+    InvalidParameters,
+    /// The caller may not perform the operation.
+    Denied,
+    /// The CPU is already on.
+    AlreadyOn,
+    /// A CPU_ON for this CPU is still in progress.
+    OnPending,
+    /// The firmware failed internally.
+    InternalFailure,
+    /// The target is not present.
+    NotPresent,
+    /// The target is disabled.
+    Disabled,
+    /// The entry point address is invalid.
+    InvalidAddress,
+    /// A return code outside the specification.
+    Unknown(i32),
+}
+
+impl PsciError {
+    /// Map a negative PSCI return code.
+    fn from_code(code: i32) -> Self {
+        match code {
+            -1 => Self::NotSupported,
+            -2 => Self::InvalidParameters,
+            -3 => Self::Denied,
+            -4 => Self::AlreadyOn,
+            -5 => Self::OnPending,
+            -6 => Self::InternalFailure,
+            -7 => Self::NotPresent,
+            -8 => Self::Disabled,
+            -9 => Self::InvalidAddress,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for PsciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unavailable => write!(f, "PSCI unavailable"),
+            Self::NotSupported => write!(f, "not supported"),
+            Self::InvalidParameters => write!(f, "invalid parameters"),
+            Self::Denied => write!(f, "denied"),
+            Self::AlreadyOn => write!(f, "CPU already on"),
+            Self::OnPending => write!(f, "CPU_ON pending"),
+            Self::InternalFailure => write!(f, "internal failure"),
+            Self::NotPresent => write!(f, "not present"),
+            Self::Disabled => write!(f, "disabled"),
+            Self::InvalidAddress => write!(f, "invalid address"),
+            Self::Unknown(code) => write!(f, "unknown error {}", code),
+        }
+    }
+}
+
+/// Power state of a CPU, from AFFINITY_INFO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffinityState {
+    On,
+    Off,
+    OnPending,
+}
+
+/// The conduit, once detected.
+static CONDUIT: Once<Conduit> = Once::new();
+
+/// Issue a PSCI call with up to three arguments.
+fn call(function: u64, a1: u64, a2: u64, a3: u64) -> Result<u64, PsciError> {
+    let conduit = *CONDUIT.get().ok_or(PsciError::Unavailable)?;
+    let mut result = function;
+    // SAFETY: The device tree said this conduit reaches PSCI firmware,
+    // which follows the SMC calling convention: x0-x3 are arguments,
+    // x0-x17 may be clobbered, nothing else changes.
+    unsafe {
+        match conduit {
+            Conduit::Hvc => asm!(
+                "hvc #0",
+                inout("x0") result,
+                inout("x1") a1 => _,
+                inout("x2") a2 => _,
+                inout("x3") a3 => _,
+                out("x4") _, out("x5") _, out("x6") _, out("x7") _,
+                out("x8") _, out("x9") _, out("x10") _, out("x11") _,
+                out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+                out("x16") _, out("x17") _,
+                options(nostack)
+            ),
+            Conduit::Smc => asm!(
+                "smc #0",
+                inout("x0") result,
+                inout("x1") a1 => _,
+                inout("x2") a2 => _,
+                inout("x3") a3 => _,
+                out("x4") _, out("x5") _, out("x6") _, out("x7") _,
+                out("x8") _, out("x9") _, out("x10") _, out("x11") _,
+                out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+                out("x16") _, out("x17") _,
+                options(nostack)
+            ),
+        }
+    }
+
+    // Return codes are 32-bit signed values
+    let code = result as u32 as i32;
+    if code < 0 {
+        Err(PsciError::from_code(code))
+    } else {
+        Ok(result)
+    }
+}
+
+/// Detect the conduit from the device tree and check the firmware.
+///
+/// Returns the PSCI version as (major, minor).
+pub fn init() -> Result<(u16, u16), PsciError> {
+    let psci = fdt::boot()
+        .and_then(|dt| dt.find_node(b"psci"))
+        .ok_or(PsciError::Unavailable)?;
+    // PSCI 0.2+ function IDs; plain "arm,psci" (0.1) uses its own IDs
+    if !psci.has_string(b"compatible", b"arm,psci-0.2")
+        && !psci.has_string(b"compatible", b"arm,psci-1.0")
+    {
+        return Err(PsciError::Unavailable);
+    }
+    let conduit = match psci.property(b"method") {
+        Some(b"hvc\0") => Conduit::Hvc,
+        Some(b"smc\0") => Conduit::Smc,
+        _ => return Err(PsciError::Unavailable),
+    };
+    CONDUIT.call_once(|| conduit);
+
+    let version = call(function::PSCI_VERSION, 0, 0, 0)?;
+    Ok(((version >> 16) as u16, version as u16))
+}
+
+/// The conduit in use, if PSCI was found.
+pub fn conduit() -> Option<Conduit> {
+    CONDUIT.get().copied()
+}
+
+/// Power on the CPU with affinity `mpidr`.
+///
+/// It starts at physical address `entry` with the MMU off, at the
+/// kernel's exception level, and `context` in x0.
+pub fn cpu_on(mpidr: u64, entry: u64, context: u64) -> Result<(), PsciError> {
+    call(function::CPU_ON, mpidr, entry, context).map(|_| ())
+}
+
+/// Power state of the CPU with affinity `mpidr`.
+pub fn affinity_info(mpidr: u64) -> Result<AffinityState, PsciError> {
+    // Lowest affinity level: the CPU itself
+    match call(function::AFFINITY_INFO, mpidr, 0, 0)? {
+        0 => Ok(AffinityState::On),
+        1 => Ok(AffinityState::Off),
+        2 => Ok(AffinityState::OnPending),
+        code => Err(PsciError::Unknown(code as i32)),
+    }
+}
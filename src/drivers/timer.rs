@@ -0,0 +1,243 @@
+//! ARM Generic Timer Driver
+//!
+//! Drives the EL1 virtual timer (CNTV_*) and delivers its interrupt
+//! through the GIC. The virtual timer works the same with or without a
+//! hypervisor and its counter is what `time::Instant` is built on.
+//!
+//! # Tick Modes
+//! Every CPU's timer has its own mode:
+//! - **Periodic**: fires every `period`; the compare value advances by a
+//!   fixed number of counter ticks so the tick rate does not drift
+//! - **One-shot**: fires once at a deadline and then stays masked until
+//!   reprogrammed (tickless operation). An idle CPU switches to it with
+//!   the next deadline it has, or none, and resumes the periodic tick
+//!   once it has work again
+//!
+//! Each expiry calls the tick handler installed with `set_tick_handler`,
+//! which is how the scheduler gets its time slices.
+//!
+//! # Registers
+//! - CNTFRQ_EL0: counter frequency (set by firmware/QEMU)
+//! - CNTVCT_EL0: 64-bit monotonic counter
+//! - CNTV_CVAL_EL0: absolute compare value
+//! - CNTV_CTL_EL0: ENABLE, IMASK, ISTATUS
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use super::gic::{self, IrqError};
+use crate::kprintln;
+use crate::percpu::PerCpu;
+use crate::smp::MAX_CPUS;
+use crate::time::{self, Duration, Instant};
+
+/// Timer control register bits.
+mod ctl {
+    /// Timer enabled.
+    pub const ENABLE: u64 = 1 << 0;
+    /// Interrupt masked.
+    pub const IMASK: u64 = 1 << 1;
+}
+
+/// GIC interrupt ID of the EL1 virtual timer (PPI 27).
+const TIMER_IRQ: u32 = 27;
+
+/// Tick mode of a CPU's timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TickMode {
+    /// Timer stopped.
+    Off = 0,
+    /// Fires every programmed period.
+    Periodic = 1,
+    /// Fires once at the programmed deadline.
+    OneShot = 2,
+}
+
+/// Function called on every timer expiry (in IRQ context).
+pub type TickHandler = fn();
+
+/// Tick mode of each CPU.
+static MODE: PerCpu<AtomicU8> =
+    PerCpu::new([const { AtomicU8::new(TickMode::Off as u8) }; MAX_CPUS]);
+
+/// Periodic interval in counter ticks; 0 until `start_periodic`.
+static PERIOD_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Registered tick handler (function pointer address, 0 = none).
+static TICK_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Read the counter frequency in Hz.
+#[inline]
+pub fn frequency() -> u64 {
+    let freq: u64;
+    // SAFETY: CNTFRQ_EL0 is always readable.
+    unsafe { asm!("mrs {v}, cntfrq_el0", v = out(reg) freq, options(nomem, nostack)) };
+    freq
+}
+
+/// Read the virtual counter.
+///
+/// The ISB keeps the read from being speculated ahead of earlier code.
+#[inline]
+pub fn counter() -> u64 {
+    let count: u64;
+    // SAFETY: The virtual counter is readable at EL1.
+    unsafe {
+        asm!("isb", "mrs {v}, cntvct_el0", v = out(reg) count, options(nomem, nostack));
+    }
+    count
+}
+
+/// Write the timer control register.
+#[inline]
+fn write_ctl(value: u64) {
+    // SAFETY: The EL1 virtual timer registers are accessible at EL1.
+    unsafe {
+        asm!("msr cntv_ctl_el0, {v}", "isb", v = in(reg) value, options(nomem, nostack));
+    }
+}
+
+/// Write the absolute compare value.
+#[inline]
+fn write_cval(value: u64) {
+    // SAFETY: The EL1 virtual timer registers are accessible at EL1.
+    unsafe {
+        asm!("msr cntv_cval_el0, {v}", v = in(reg) value, options(nomem, nostack));
+    }
+}
+
+/// Read the absolute compare value.
+#[inline]
+fn read_cval() -> u64 {
+    let value: u64;
+    // SAFETY: The EL1 virtual timer registers are accessible at EL1.
+    unsafe {
+        asm!("mrs {v}, cntv_cval_el0", v = out(reg) value, options(nomem, nostack));
+    }
+    value
+}
+
+/// Initialize the timer on the boot CPU.
+///
+/// The timer starts disabled; use `start_periodic` or `set_oneshot`.
+pub fn init() -> Result<(), IrqError> {
+    write_ctl(ctl::IMASK);
+
+    gic::register_irq(TIMER_IRQ, handle_timer_irq)?;
+    gic::enable_irq(TIMER_IRQ)?;
+
+    kprintln!("[BOOT] Generic timer initialized ({} Hz)", frequency());
+    Ok(())
+}
+
+/// Enable the timer interrupt on a secondary CPU.
+pub fn init_cpu() -> Result<(), IrqError> {
+    write_ctl(ctl::IMASK);
+    gic::enable_irq(TIMER_IRQ)
+}
+
+/// Install the function called on every timer expiry.
+pub fn set_tick_handler(handler: TickHandler) {
+    TICK_HANDLER.store(handler as usize, Ordering::Release);
+}
+
+/// Tick mode of the calling CPU.
+pub fn mode() -> TickMode {
+    match MODE.with(|mode| mode.load(Ordering::Acquire)) {
+        1 => TickMode::Periodic,
+        2 => TickMode::OneShot,
+        _ => TickMode::Off,
+    }
+}
+
+/// Set the calling CPU's tick mode.
+fn set_mode(mode: TickMode) {
+    MODE.with(|slot| slot.store(mode as u8, Ordering::Release));
+}
+
+/// Interval of the periodic tick (zero if none was started).
+pub fn period() -> Duration {
+    time::ticks_to_duration(PERIOD_TICKS.load(Ordering::Relaxed))
+}
+
+/// Start a periodic tick with the given period on the calling CPU;
+/// secondary CPUs follow with `start_secondary`.
+///
+/// A zero period is rounded up to one counter tick.
+pub fn start_periodic(period: Duration) {
+    let period_ticks = time::duration_to_ticks(period).max(1);
+    PERIOD_TICKS.store(period_ticks, Ordering::Relaxed);
+    set_mode(TickMode::Periodic);
+
+    write_cval(counter().saturating_add(period_ticks));
+    write_ctl(ctl::ENABLE);
+}
+
+/// Start the calling secondary CPU's timer in the boot CPU's periodic
+/// mode, if a periodic tick is running.
+pub fn start_secondary() {
+    resume_periodic();
+}
+
+/// Go back to the periodic tick on the calling CPU after `set_oneshot`.
+///
+/// Does nothing if the tick is already running, or was never started.
+pub fn resume_periodic() {
+    let period = PERIOD_TICKS.load(Ordering::Relaxed);
+    if period == 0 || mode() == TickMode::Periodic {
+        return;
+    }
+    set_mode(TickMode::Periodic);
+    write_cval(counter().saturating_add(period));
+    write_ctl(ctl::ENABLE);
+}
+
+/// Replace the calling CPU's tick with a single expiry at `deadline`
+/// (tickless mode); with `None` the timer stays masked until
+/// reprogrammed.
+///
+/// A deadline in the past fires immediately.
+pub fn set_oneshot(deadline: Option<Instant>) {
+    let Some(deadline) = deadline else {
+        set_mode(TickMode::Off);
+        write_ctl(ctl::IMASK);
+        return;
+    };
+    set_mode(TickMode::OneShot);
+    write_cval(deadline.ticks());
+    write_ctl(ctl::ENABLE);
+}
+
+/// Timer interrupt handler.
+fn handle_timer_irq(_irq: u32) {
+    match mode() {
+        TickMode::Periodic => {
+            // Advance by whole periods to avoid drift; skip missed ticks
+            // instead of firing a burst of them.
+            let period = PERIOD_TICKS.load(Ordering::Relaxed);
+            let now = counter();
+            let mut next = read_cval().saturating_add(period);
+            if next <= now {
+                next = now.saturating_add(period);
+            }
+            write_cval(next);
+        }
+        TickMode::OneShot => {
+            // Keep the timer enabled but masked until reprogrammed
+            set_mode(TickMode::Off);
+            write_ctl(ctl::ENABLE | ctl::IMASK);
+        }
+        TickMode::Off => {
+            write_ctl(ctl::IMASK);
+            return;
+        }
+    }
+
+    let handler = TICK_HANDLER.load(Ordering::Acquire);
+    if handler != 0 {
+        // SAFETY: Only valid `TickHandler`s are stored in set_tick_handler.
+        let handler: TickHandler = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+}
@@ -11,15 +11,19 @@
 //! - Uses spinlock for thread-safe access
 //!
 //! # Memory Map (QEMU virt)
-//! - Base address: 0x0900_0000
+//! - Base address: 0x0900_0000, accessed through the kernel direct map
+//!   so output keeps working whatever TTBR0 holds
 //! - Register size: 0x1000 bytes
 
 use core::fmt::{self, Write};
 use core::marker::PhantomData;
-use spin::Mutex;
 
-/// QEMU virt machine PL011 UART base address
-const UART_BASE: usize = 0x0900_0000;
+use crate::mm::address::{KERNEL_VIRT_BASE, MMIO_BASE};
+use crate::lock_class;
+use crate::sync::IrqSpinLock;
+
+/// QEMU virt machine PL011 UART base address (direct-mapped by boot.S)
+const UART_BASE: usize = KERNEL_VIRT_BASE + MMIO_BASE;
 
 /// PL011 Register offsets
 mod regs {
@@ -93,6 +97,7 @@ impl Uart<Uninitialized> {
     ///
     /// SAFETY AUDIT: 2025-01-04
     /// - Base address 0x0900_0000 is guaranteed by QEMU virt machine specification
+    ///   and covered by the boot device block in the kernel half
     /// - Called only once during boot from kernel_main
     pub unsafe fn init(self) -> Uart<Initialized> {
         // PL011 is already initialized by QEMU, just consume self
@@ -229,8 +234,9 @@ impl Write for GlobalUart {
     }
 }
 
-/// Global UART instance protected by spinlock.
-pub static UART: Mutex<GlobalUart> = Mutex::new(GlobalUart::new());
+/// Global UART instance, shared with IRQ handlers that print.
+pub static UART: IrqSpinLock<GlobalUart> =
+    IrqSpinLock::new(GlobalUart::new(), lock_class!("uart"));
 
 // ============================================================================
 // Print Macros
@@ -0,0 +1,543 @@
+//! ESR_EL1 Decoding
+//!
+//! Decodes the Exception Syndrome Register into an exception class plus a
+//! structured view of the Instruction Specific Syndrome (ISS).
+//!
+//! # Layout
+//! ```text
+//!  63    37 36   32 31    26 25 24                    0
+//! ┌────────┬───────┬────────┬──┬───────────────────────┐
+//! │  RES0  │ ISS2  │   EC   │IL│          ISS          │
+//! └────────┴───────┴────────┴──┴───────────────────────┘
+//! ```
+//!
+//! The decoded form is used both for crash reports (`Display`) and for
+//! routing faults to the right handler.
+
+use core::fmt;
+
+/// Exception class extracted from ESR_EL1 (EC, bits [31:26])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ExceptionClass {
+    /// Unknown reason, including undefined instructions
+    Unknown = 0x00,
+    /// Trapped WFI/WFE
+    WfxTrap = 0x01,
+    /// Trapped MCR/MRC (coproc 0b1111, AArch32)
+    Cp15RtTrap = 0x03,
+    /// Trapped MCRR/MRRC (coproc 0b1111, AArch32)
+    Cp15RrtTrap = 0x04,
+    /// Trapped MCR/MRC (coproc 0b1110, AArch32)
+    Cp14RtTrap = 0x05,
+    /// Trapped LDC/STC (AArch32)
+    Cp14LsTrap = 0x06,
+    /// Access to SVE, Advanced SIMD or FP trapped by CPACR_EL1.FPEN
+    FpAccess = 0x07,
+    /// Trapped LD64B/ST64B
+    Ld64bTrap = 0x0A,
+    /// Trapped MRRC (coproc 0b1110, AArch32)
+    Cp14RrtTrap = 0x0C,
+    /// Branch Target Identification violation
+    BranchTarget = 0x0D,
+    /// Illegal execution state (PSTATE.IL set)
+    IllegalState = 0x0E,
+    /// SVC from AArch32
+    SvcAarch32 = 0x11,
+    /// SVC from AArch64
+    SvcAarch64 = 0x15,
+    /// HVC from AArch64
+    HvcAarch64 = 0x16,
+    /// SMC from AArch64
+    SmcAarch64 = 0x17,
+    /// Trapped MSR, MRS or system instruction
+    SysRegTrap = 0x18,
+    /// Access to SVE trapped by CPACR_EL1.ZEN
+    SveAccess = 0x19,
+    /// Pointer authentication failure (FEAT_FPAC)
+    PacFailure = 0x1C,
+    /// Access to SME trapped by CPACR_EL1.SMEN
+    SmeAccess = 0x1D,
+    InstructionAbortLowerEl = 0x20,
+    InstructionAbortSameEl = 0x21,
+    /// Misaligned PC
+    PcAlignment = 0x22,
+    DataAbortLowerEl = 0x24,
+    DataAbortSameEl = 0x25,
+    /// Misaligned SP
+    SpAlignment = 0x26,
+    /// Memory copy/set (FEAT_MOPS) exception
+    MemoryOps = 0x27,
+    /// Trapped FP exception (AArch32)
+    FpExceptionAarch32 = 0x28,
+    /// Trapped FP exception (AArch64)
+    FpExceptionAarch64 = 0x2C,
+    /// SError interrupt
+    SError = 0x2F,
+    BreakpointLowerEl = 0x30,
+    BreakpointSameEl = 0x31,
+    SoftwareStepLowerEl = 0x32,
+    SoftwareStepSameEl = 0x33,
+    WatchpointLowerEl = 0x34,
+    WatchpointSameEl = 0x35,
+    /// BKPT instruction (AArch32)
+    BkptAarch32 = 0x38,
+    /// BRK instruction (AArch64)
+    BrkAarch64 = 0x3C,
+    /// Unallocated EC value
+    Other = 0xFF,
+}
+
+impl From<u64> for ExceptionClass {
+    fn from(esr: u64) -> Self {
+        let ec = ((esr >> 26) & 0x3F) as u8;
+        match ec {
+            0x00 => ExceptionClass::Unknown,
+            0x01 => ExceptionClass::WfxTrap,
+            0x03 => ExceptionClass::Cp15RtTrap,
+            0x04 => ExceptionClass::Cp15RrtTrap,
+            0x05 => ExceptionClass::Cp14RtTrap,
+            0x06 => ExceptionClass::Cp14LsTrap,
+            0x07 => ExceptionClass::FpAccess,
+            0x0A => ExceptionClass::Ld64bTrap,
+            0x0C => ExceptionClass::Cp14RrtTrap,
+            0x0D => ExceptionClass::BranchTarget,
+            0x0E => ExceptionClass::IllegalState,
+            0x11 => ExceptionClass::SvcAarch32,
+            0x15 => ExceptionClass::SvcAarch64,
+            0x16 => ExceptionClass::HvcAarch64,
+            0x17 => ExceptionClass::SmcAarch64,
+            0x18 => ExceptionClass::SysRegTrap,
+            0x19 => ExceptionClass::SveAccess,
+            0x1C => ExceptionClass::PacFailure,
+            0x1D => ExceptionClass::SmeAccess,
+            0x20 => ExceptionClass::InstructionAbortLowerEl,
+            0x21 => ExceptionClass::InstructionAbortSameEl,
+            0x22 => ExceptionClass::PcAlignment,
+            0x24 => ExceptionClass::DataAbortLowerEl,
+            0x25 => ExceptionClass::DataAbortSameEl,
+            0x26 => ExceptionClass::SpAlignment,
+            0x27 => ExceptionClass::MemoryOps,
+            0x28 => ExceptionClass::FpExceptionAarch32,
+            0x2C => ExceptionClass::FpExceptionAarch64,
+            0x2F => ExceptionClass::SError,
+            0x30 => ExceptionClass::BreakpointLowerEl,
+            0x31 => ExceptionClass::BreakpointSameEl,
+            0x32 => ExceptionClass::SoftwareStepLowerEl,
+            0x33 => ExceptionClass::SoftwareStepSameEl,
+            0x34 => ExceptionClass::WatchpointLowerEl,
+            0x35 => ExceptionClass::WatchpointSameEl,
+            0x38 => ExceptionClass::BkptAarch32,
+            0x3C => ExceptionClass::BrkAarch64,
+            _ => ExceptionClass::Other,
+        }
+    }
+}
+
+impl ExceptionClass {
+    /// Human-readable description.
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Unknown => "undefined instruction / unknown reason",
+            Self::WfxTrap => "trapped WFI/WFE",
+            Self::Cp15RtTrap | Self::Cp15RrtTrap => "trapped CP15 access (AArch32)",
+            Self::Cp14RtTrap | Self::Cp14LsTrap | Self::Cp14RrtTrap => {
+                "trapped CP14 access (AArch32)"
+            }
+            Self::FpAccess => "FP/SIMD access trap",
+            Self::Ld64bTrap => "trapped LD64B/ST64B",
+            Self::BranchTarget => "branch target exception",
+            Self::IllegalState => "illegal execution state",
+            Self::SvcAarch32 => "SVC (AArch32)",
+            Self::SvcAarch64 => "SVC",
+            Self::HvcAarch64 => "HVC",
+            Self::SmcAarch64 => "SMC",
+            Self::SysRegTrap => "trapped MSR/MRS/system instruction",
+            Self::SveAccess => "SVE access trap",
+            Self::PacFailure => "pointer authentication failure",
+            Self::SmeAccess => "SME access trap",
+            Self::InstructionAbortLowerEl => "instruction abort (lower EL)",
+            Self::InstructionAbortSameEl => "instruction abort (same EL)",
+            Self::PcAlignment => "PC alignment fault",
+            Self::DataAbortLowerEl => "data abort (lower EL)",
+            Self::DataAbortSameEl => "data abort (same EL)",
+            Self::SpAlignment => "SP alignment fault",
+            Self::MemoryOps => "memory copy/set exception",
+            Self::FpExceptionAarch32 | Self::FpExceptionAarch64 => "trapped FP exception",
+            Self::SError => "SError",
+            Self::BreakpointLowerEl | Self::BreakpointSameEl => "hardware breakpoint",
+            Self::SoftwareStepLowerEl | Self::SoftwareStepSameEl => "software step",
+            Self::WatchpointLowerEl | Self::WatchpointSameEl => "watchpoint",
+            Self::BkptAarch32 => "BKPT (AArch32)",
+            Self::BrkAarch64 => "BRK",
+            Self::Other => "unallocated exception class",
+        }
+    }
+
+    /// Whether this is a data or instruction abort (ISS uses the abort layout).
+    pub fn is_abort(self) -> bool {
+        matches!(
+            self,
+            Self::InstructionAbortLowerEl
+                | Self::InstructionAbortSameEl
+                | Self::DataAbortLowerEl
+                | Self::DataAbortSameEl
+        )
+    }
+}
+
+/// Fault status code (DFSC/IFSC, ISS bits [5:0]) of an abort
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultStatus {
+    /// Output address larger than supported, at the given level
+    AddressSize(u8),
+    /// No valid translation, at the given level
+    Translation(u8),
+    /// Access flag clear, at the given level
+    AccessFlag(u8),
+    /// Access not permitted by the descriptor, at the given level
+    Permission(u8),
+    /// Synchronous external abort, not on a table walk
+    SyncExternal,
+    /// Synchronous tag check fault (MTE)
+    TagCheck,
+    /// Synchronous external abort on a table walk, at the given level
+    SyncExternalOnWalk(u8),
+    /// Parity/ECC error, not on a table walk
+    SyncParity,
+    /// Parity/ECC error on a table walk, at the given level
+    SyncParityOnWalk(u8),
+    /// Alignment fault
+    Alignment,
+    /// TLB conflict abort
+    TlbConflict,
+    /// Unsupported atomic hardware update
+    AtomicUpdate,
+    /// Any other (implementation defined or reserved) code
+    Other(u8),
+}
+
+impl FaultStatus {
+    /// Decode a 6-bit fault status code.
+    pub fn from_code(code: u8) -> Self {
+        let level = code & 0x3;
+        match code & 0x3F {
+            0x00..=0x03 => Self::AddressSize(level),
+            0x04..=0x07 => Self::Translation(level),
+            0x08..=0x0B => Self::AccessFlag(level),
+            0x0C..=0x0F => Self::Permission(level),
+            0x10 => Self::SyncExternal,
+            0x11 => Self::TagCheck,
+            0x14..=0x17 => Self::SyncExternalOnWalk(level),
+            0x18 => Self::SyncParity,
+            0x1C..=0x1F => Self::SyncParityOnWalk(level),
+            0x21 => Self::Alignment,
+            0x30 => Self::TlbConflict,
+            0x31 => Self::AtomicUpdate,
+            other => Self::Other(other),
+        }
+    }
+
+    /// Translation table level involved, if the code reports one.
+    pub fn level(self) -> Option<u8> {
+        match self {
+            Self::AddressSize(l)
+            | Self::Translation(l)
+            | Self::AccessFlag(l)
+            | Self::Permission(l)
+            | Self::SyncExternalOnWalk(l)
+            | Self::SyncParityOnWalk(l) => Some(l),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Self::AddressSize(_) => "address size fault",
+            Self::Translation(_) => "translation fault",
+            Self::AccessFlag(_) => "access flag fault",
+            Self::Permission(_) => "permission fault",
+            Self::SyncExternal => "synchronous external abort",
+            Self::TagCheck => "tag check fault",
+            Self::SyncExternalOnWalk(_) => "external abort on table walk",
+            Self::SyncParity => "parity/ECC error",
+            Self::SyncParityOnWalk(_) => "parity/ECC error on table walk",
+            Self::Alignment => "alignment fault",
+            Self::TlbConflict => "TLB conflict abort",
+            Self::AtomicUpdate => "unsupported atomic hardware update",
+            Self::Other(code) => return write!(f, "fault status 0x{:02x}", code),
+        };
+        write!(f, "{}", description)?;
+        if let Some(level) = self.level() {
+            write!(f, ", level {}", level)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decoded ISS of a data or instruction abort
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbortInfo {
+    /// Fault status code (DFSC/IFSC)
+    pub status: FaultStatus,
+    /// Write not Read (data aborts only)
+    pub write: bool,
+    /// Fault on a stage 2 walk for a stage 1 table access
+    pub s1ptw: bool,
+    /// Caused by a cache maintenance instruction (data aborts only)
+    pub cache_maintenance: bool,
+    /// External abort type (implementation defined)
+    pub external: bool,
+    /// FAR_EL1 holds a valid address (FnV clear)
+    pub far_valid: bool,
+    /// Instruction syndrome (ISV) for data aborts: access size and register
+    pub syndrome: Option<AccessSyndrome>,
+}
+
+/// Instruction syndrome of a data abort (valid when ISS.ISV is set)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessSyndrome {
+    /// Access size in bytes (1, 2, 4 or 8)
+    pub size: u8,
+    /// Sign-extended load
+    pub sign_extend: bool,
+    /// Transfer register number
+    pub register: u8,
+    /// 64-bit register width
+    pub sixty_four: bool,
+    /// Acquire/release semantics
+    pub acquire_release: bool,
+}
+
+/// Decoded ISS of a trapped MSR/MRS/system instruction (EC 0x18)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SysRegInfo {
+    pub op0: u8,
+    pub op1: u8,
+    pub crn: u8,
+    pub crm: u8,
+    pub op2: u8,
+    /// Transfer register number
+    pub rt: u8,
+    /// MRS (read) rather than MSR (write)
+    pub read: bool,
+}
+
+/// Decoded ISS of an SError interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SErrorInfo {
+    /// Implementation defined syndrome (IDS); other fields are then invalid
+    pub impl_defined: bool,
+    /// Asynchronous error type (AET)
+    pub error_type: u8,
+    /// External abort type (EA)
+    pub external: bool,
+    /// Fault status code (DFSC)
+    pub status: u8,
+}
+
+/// A raw ESR_EL1 value with decoding helpers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Esr(u64);
+
+impl Esr {
+    /// Wrap a raw ESR_EL1 value.
+    #[inline]
+    pub const fn new(raw: u64) -> Self {
+        Self(raw)
+    }
+
+    /// Exception class.
+    #[inline]
+    pub fn class(self) -> ExceptionClass {
+        ExceptionClass::from(self.0)
+    }
+
+    /// Raw EC field.
+    #[inline]
+    pub const fn ec(self) -> u8 {
+        ((self.0 >> 26) & 0x3F) as u8
+    }
+
+    /// Instruction Specific Syndrome (bits [24:0]).
+    #[inline]
+    pub const fn iss(self) -> u32 {
+        (self.0 & 0x01FF_FFFF) as u32
+    }
+
+    /// Immediate of SVC, HVC, SMC or BRK.
+    pub fn imm16(self) -> Option<u16> {
+        match self.class() {
+            ExceptionClass::SvcAarch64
+            | ExceptionClass::SvcAarch32
+            | ExceptionClass::HvcAarch64
+            | ExceptionClass::SmcAarch64
+            | ExceptionClass::BrkAarch64 => Some((self.iss() & 0xFFFF) as u16),
+            _ => None,
+        }
+    }
+
+    /// Decode the ISS of a data or instruction abort.
+    pub fn abort(self) -> Option<AbortInfo> {
+        let class = self.class();
+        if !class.is_abort() {
+            return None;
+        }
+
+        let iss = self.iss();
+        let data = matches!(
+            class,
+            ExceptionClass::DataAbortLowerEl | ExceptionClass::DataAbortSameEl
+        );
+        let bit = |n: u32| iss & (1 << n) != 0;
+
+        let syndrome = (data && bit(24)).then(|| AccessSyndrome {
+            size: 1 << ((iss >> 22) & 0x3),
+            sign_extend: bit(21),
+            register: ((iss >> 16) & 0x1F) as u8,
+            sixty_four: bit(15),
+            acquire_release: bit(14),
+        });
+
+        Some(AbortInfo {
+            status: FaultStatus::from_code((iss & 0x3F) as u8),
+            write: data && bit(6),
+            s1ptw: bit(7),
+            cache_maintenance: data && bit(8),
+            external: bit(9),
+            far_valid: !bit(10),
+            syndrome,
+        })
+    }
+
+    /// Decode the ISS of a trapped system register access.
+    pub fn sysreg(self) -> Option<SysRegInfo> {
+        if self.class() != ExceptionClass::SysRegTrap {
+            return None;
+        }
+
+        let iss = self.iss();
+        Some(SysRegInfo {
+            op0: ((iss >> 20) & 0x3) as u8,
+            op2: ((iss >> 17) & 0x7) as u8,
+            op1: ((iss >> 14) & 0x7) as u8,
+            crn: ((iss >> 10) & 0xF) as u8,
+            rt: ((iss >> 5) & 0x1F) as u8,
+            crm: ((iss >> 1) & 0xF) as u8,
+            read: iss & 1 != 0,
+        })
+    }
+
+    /// Decode the ISS of an SError.
+    ///
+    /// SErrors are delivered through their own vector, so this does not
+    /// check the class.
+    pub fn serror(self) -> SErrorInfo {
+        let iss = self.iss();
+        SErrorInfo {
+            impl_defined: iss & (1 << 24) != 0,
+            error_type: ((iss >> 10) & 0x7) as u8,
+            external: iss & (1 << 9) != 0,
+            status: (iss & 0x3F) as u8,
+        }
+    }
+}
+
+impl From<u64> for Esr {
+    fn from(raw: u64) -> Self {
+        Self(raw)
+    }
+}
+
+/// Human-readable one-line summary, e.g.
+/// `data abort (same EL): translation fault, level 3, write`
+impl fmt::Display for Esr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let class = self.class();
+        write!(f, "{}", class.description())?;
+
+        if let Some(abort) = self.abort() {
+            write!(f, ": {}", abort.status)?;
+            if class == ExceptionClass::DataAbortLowerEl || class == ExceptionClass::DataAbortSameEl
+            {
+                write!(f, ", {}", if abort.write { "write" } else { "read" })?;
+            }
+            if abort.s1ptw {
+                write!(f, ", stage 1 table walk")?;
+            }
+            if let Some(s) = abort.syndrome {
+                write!(f, ", {}-byte access via x{}", s.size, s.register)?;
+            }
+            if !abort.far_valid {
+                write!(f, ", FAR not valid")?;
+            }
+        } else if let Some(sys) = self.sysreg() {
+            write!(
+                f,
+                ": {} S{}_{}_C{}_C{}_{}, x{}",
+                if sys.read { "mrs" } else { "msr" },
+                sys.op0,
+                sys.op1,
+                sys.crn,
+                sys.crm,
+                sys.op2,
+                sys.rt
+            )?;
+        } else if let Some(imm) = self.imm16() {
+            write!(f, " #0x{:x}", imm)?;
+        } else if class == ExceptionClass::PacFailure {
+            let iss = self.iss();
+            write!(
+                f,
+                ": {} key {}",
+                if iss & 0b10 != 0 { "data" } else { "instruction" },
+                if iss & 0b01 != 0 { "B" } else { "A" }
+            )?;
+        } else if class == ExceptionClass::Other {
+            write!(f, " (EC 0x{:02x})", self.ec())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_class_decoding() {
+        assert_eq!(Esr::new(0x5600_0000).class(), ExceptionClass::SvcAarch64);
+        assert_eq!(Esr::new(0xF200_0000).class(), ExceptionClass::BrkAarch64);
+        assert_eq!(Esr::new(0x0800_0000).class(), ExceptionClass::Other);
+        assert_eq!(Esr::new(0x5600_002A).imm16(), Some(0x2A));
+    }
+
+    #[test]
+    fn test_data_abort_decoding() {
+        // Data abort, same EL, IL, WnR, translation fault level 3
+        let abort = Esr::new(0x9600_0047).abort().unwrap();
+        assert_eq!(abort.status, FaultStatus::Translation(3));
+        assert_eq!(abort.status.level(), Some(3));
+        assert!(abort.write);
+        assert!(abort.far_valid);
+        assert!(abort.syndrome.is_none());
+
+        // Instruction abort, lower EL, permission fault level 2, FnV
+        let abort = Esr::new(0x8200_040E).abort().unwrap();
+        assert_eq!(abort.status, FaultStatus::Permission(2));
+        assert!(!abort.write);
+        assert!(!abort.far_valid);
+    }
+
+    #[test]
+    fn test_sysreg_decoding() {
+        // mrs x1, S3_0_C1_C0_0 (SCTLR_EL1) trapped
+        let sys = Esr::new(0x6230_0421).sysreg().unwrap();
+        assert_eq!((sys.op0, sys.op1, sys.crn, sys.crm, sys.op2), (3, 0, 1, 0, 0));
+        assert_eq!(sys.rt, 1);
+        assert!(sys.read);
+    }
+}
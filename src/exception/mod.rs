@@ -0,0 +1,463 @@
+//! ARM64 Exception Handling
+//!
+//! Implements the exception vector table and handlers for ARM64.
+//!
+//! # Exception Levels
+//! - EL0: User applications
+//! - EL1: Kernel (where we run)
+//! - EL2: Hypervisor (QEMU)
+//!
+//! # Exception Types
+//! - Synchronous: SVC (syscalls), data aborts, instruction aborts
+//! - IRQ: Interrupt requests
+//! - FIQ: Fast interrupt requests
+//! - SError: System errors
+//!
+//! # Security Considerations
+//! - All exceptions from lower EL (user mode) are handled securely
+//! - Register state is preserved and restored
+//! - Every vector saves a full context and reports its source
+//! - Exceptions run on a dedicated exception stack, so kernel stack
+//!   overflows are detected and reported (see `stack`)
+//! - Invalid exception sources cause immediate halt
+
+pub mod esr;
+pub mod stack;
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+pub use esr::{Esr, ExceptionClass};
+
+use crate::drivers::gic;
+use crate::mm::kstack;
+use crate::percpu::PerCpu;
+use crate::smp::MAX_CPUS;
+use crate::process::{self, ExitReason};
+use crate::{backtrace, fpsimd, kprintln, sched, syscall};
+
+/// Exception context saved on the stack
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionContext {
+    /// General purpose registers x0-x30
+    pub gpr: [u64; 31],
+    /// Exception Link Register (return address)
+    pub elr: u64,
+    /// Saved Program Status Register
+    pub spsr: u64,
+    /// Exception Syndrome Register
+    pub esr: u64,
+    /// Fault Address Register
+    pub far: u64,
+    /// SP_EL0: the user stack pointer for lower-EL exceptions, the kernel
+    /// thread stack pointer for current-EL (SP0) exceptions
+    pub sp: u64,
+}
+
+/// Where an exception was taken from (vector table group).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ExceptionSource {
+    /// Current EL using SP_EL0.
+    CurrentElSp0 = 0,
+    /// Current EL using SP_ELx.
+    CurrentElSpx = 1,
+    /// Lower EL running AArch64.
+    LowerElAarch64 = 2,
+    /// Lower EL running AArch32.
+    LowerElAarch32 = 3,
+}
+
+/// Type of exception (vector table slot within a group).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ExceptionKind {
+    Synchronous = 0,
+    Irq = 1,
+    Fiq = 2,
+    SError = 3,
+}
+
+/// Decode the vector index passed by the entry code (source * 4 + kind).
+fn decode_vector(vector: u64) -> (ExceptionSource, ExceptionKind) {
+    let source = match (vector >> 2) & 0x3 {
+        0 => ExceptionSource::CurrentElSp0,
+        1 => ExceptionSource::CurrentElSpx,
+        2 => ExceptionSource::LowerElAarch64,
+        _ => ExceptionSource::LowerElAarch32,
+    };
+    let kind = match vector & 0x3 {
+        0 => ExceptionKind::Synchronous,
+        1 => ExceptionKind::Irq,
+        2 => ExceptionKind::Fiq,
+        _ => ExceptionKind::SError,
+    };
+    (source, kind)
+}
+
+/// SPSR_EL1.F: FIQ mask bit restored on ERET.
+const SPSR_F: u64 = 1 << 6;
+
+/// DAIF.I: IRQ mask bit.
+const DAIF_I: u64 = 1 << 7;
+
+/// Initialize exception handling
+///
+/// Sets up the exception vector table register (VBAR_EL1).
+///
+/// # Safety
+/// Must be called once during kernel initialization.
+/// The exception vectors must be properly aligned (2KB).
+///
+/// SAFETY AUDIT: 2025-01-04
+/// - Vector table is defined in boot.S with proper alignment
+/// - This function is only called once from kernel_main
+pub fn init() {
+    init_cpu();
+    kprintln!("[BOOT] Exception vectors installed");
+}
+
+/// Install the exception vectors on the calling CPU.
+///
+/// The boot CPU does this through `init`; secondaries call it directly,
+/// before anything can fault.
+pub fn init_cpu() {
+    extern "C" {
+        static __exception_vectors: u8;
+    }
+
+    // SAFETY: 
+    // - __exception_vectors is defined in boot.S with 2KB alignment
+    // - Writing to VBAR_EL1 is valid at EL1
+    // Audited: 2025-01-04
+    unsafe {
+        let vector_addr = &__exception_vectors as *const u8 as u64;
+        asm!(
+            "msr vbar_el1, {v}",
+            "isb",
+            v = in(reg) vector_addr,
+            options(nostack, preserves_flags)
+        );
+    }
+
+    // Vectors are in place: let asynchronous aborts (SError) be taken
+    // instead of staying pending forever.
+    // SAFETY: Clearing DAIF.A only unmasks SError delivery.
+    unsafe {
+        asm!("msr daifclr, #4", options(nostack, preserves_flags));
+    }
+}
+
+/// Handle synchronous exception from lower EL (user mode)
+///
+/// This is the main entry point for syscalls (SVC instruction). Other
+/// exception classes are routed by their decoded syndrome.
+///
+/// # Safety
+/// Called from assembly exception handler with valid context pointer.
+#[no_mangle]
+pub extern "C" fn handle_sync_exception_lower_el(ctx: &mut ExceptionContext) {
+    let esr = Esr::new(ctx.esr);
+
+    match esr.class() {
+        ExceptionClass::SvcAarch64 => {
+            // System call handler
+            let syscall_num = ctx.gpr[8] as usize; // x8 = syscall number
+            let result = syscall::dispatch(syscall_num, ctx);
+            ctx.gpr[0] = result as u64; // Return value in x0
+        }
+        ExceptionClass::FpAccess => {
+            // Lazy FP/SIMD switch: load this thread's registers and retry
+            if !fpsimd::handle_trap() {
+                kprintln!("[EXCEPTION] FP/SIMD access without a current thread");
+                process::exit_current(ExitReason::fault(ctx));
+            }
+        }
+        // Faults caused by the user program itself
+        ExceptionClass::DataAbortLowerEl
+        | ExceptionClass::InstructionAbortLowerEl
+        | ExceptionClass::Unknown
+        | ExceptionClass::IllegalState
+        | ExceptionClass::PcAlignment
+        | ExceptionClass::SpAlignment
+        | ExceptionClass::BranchTarget
+        | ExceptionClass::PacFailure
+        | ExceptionClass::SysRegTrap
+        | ExceptionClass::SveAccess
+        | ExceptionClass::SmeAccess
+        | ExceptionClass::FpExceptionAarch64
+        | ExceptionClass::BrkAarch64
+        | ExceptionClass::BreakpointLowerEl
+        | ExceptionClass::SoftwareStepLowerEl
+        | ExceptionClass::WatchpointLowerEl => {
+            user_fault(ctx, esr);
+        }
+        _ => {
+            kprintln!("[EXCEPTION] Unhandled exception from user mode");
+            user_fault(ctx, esr);
+        }
+    }
+
+    // Returning to user mode: a pending reschedule takes effect here
+    sched::preempt();
+}
+
+/// Report a fault caused by user code and terminate the faulting process.
+fn user_fault(ctx: &ExceptionContext, esr: Esr) -> ! {
+    kprintln!("[EXCEPTION] User fault: {}", esr);
+    kprintln!("[EXCEPTION] ELR: 0x{:016x}, ESR: 0x{:016x}", ctx.elr, ctx.esr);
+    if esr.abort().is_some_and(|abort| abort.far_valid) {
+        kprintln!("[EXCEPTION] FAR: 0x{:016x}", ctx.far);
+    }
+    process::exit_current(ExitReason::fault(ctx));
+}
+
+/// Check whether a kernel fault is a stack overflow into a guard page.
+fn is_stack_overflow(esr: Esr, far: u64) -> bool {
+    esr.class() == ExceptionClass::DataAbortSameEl
+        && esr.abort().is_some_and(|abort| abort.far_valid)
+        && kstack::is_guard(far as usize)
+}
+
+/// Print a kernel stack overflow report and halt.
+fn stack_overflow(ctx: &ExceptionContext, which: &str, sp: u64) -> ! {
+    kprintln!();
+    kprintln!("!!! KERNEL STACK OVERFLOW !!!");
+    kprintln!("Stack: {}", which);
+    if let Some((bottom, top)) = kstack::stack_bounds(ctx.far as usize) {
+        kprintln!("Bounds: 0x{:016x}-0x{:016x}", bottom, top);
+    }
+    kprintln!("SP:  0x{:016x}", sp);
+    kprintln!("FAR: 0x{:016x} (guard page)", ctx.far);
+    kprintln!("ELR: 0x{:016x}", ctx.elr);
+    backtrace::print_from(ctx.elr as usize, ctx.gpr[29] as usize);
+
+    halt();
+}
+
+/// Handle synchronous exception from current EL (kernel mode)
+///
+/// This should rarely happen in normal operation. Runs on the exception
+/// stack, so overflows of the interrupted kernel stack are reported
+/// cleanly.
+#[no_mangle]
+pub extern "C" fn handle_sync_exception_same_el(ctx: &ExceptionContext) {
+    let esr = Esr::new(ctx.esr);
+
+    if is_stack_overflow(esr, ctx.far) {
+        stack_overflow(ctx, "kernel thread", ctx.sp);
+    }
+
+    kprintln!("!!! KERNEL EXCEPTION !!!");
+    kprintln!("Exception: {}", esr);
+    if esr.class() == ExceptionClass::FpAccess {
+        kprintln!("FP/SIMD instruction in kernel code (kernel must stay FP-free)");
+    }
+    kprintln!("ESR: 0x{:016x}", ctx.esr);
+    kprintln!("ELR: 0x{:016x}", ctx.elr);
+    kprintln!("FAR: 0x{:016x}", ctx.far);
+    kprintln!("LR:  0x{:016x}", ctx.gpr[30]);
+    backtrace::print_from(ctx.elr as usize, ctx.gpr[29] as usize);
+
+    halt();
+}
+
+/// Handle synchronous exception taken on SP_EL1
+///
+/// Entered on the emergency stack: either the exception stack itself
+/// faulted (overflow, or a bug in a handler), or the CPU has not moved
+/// onto its guarded stacks yet. The entry code clobbers SP_EL0; `sp` is
+/// the SP_EL1 value at the time of the fault.
+#[no_mangle]
+pub extern "C" fn handle_exception_stack_fault(ctx: &mut ExceptionContext, _vector: u64, sp: u64) {
+    if !stack::ready() {
+        // Early boot: an ordinary kernel fault on the boot stack
+        handle_sync_exception_same_el(ctx);
+        halt();
+    }
+
+    let esr = Esr::new(ctx.esr);
+    if is_stack_overflow(esr, ctx.far) {
+        stack_overflow(ctx, "exception", sp);
+    }
+
+    kprintln!("!!! FAULT ON EXCEPTION STACK !!!");
+    kprintln!("Exception: {}", esr);
+    kprintln!("ESR: 0x{:016x}", ctx.esr);
+    kprintln!("ELR: 0x{:016x}", ctx.elr);
+    kprintln!("FAR: 0x{:016x}", ctx.far);
+    kprintln!("SP:  0x{:016x}", sp);
+    if let Some((bottom, top)) = stack::exception_stack_bounds() {
+        kprintln!("Exception stack: 0x{:016x}-0x{:016x}", bottom, top);
+    }
+    backtrace::print_from(ctx.elr as usize, ctx.gpr[29] as usize);
+
+    halt();
+}
+
+/// IRQ handlers running on each CPU (nesting depth).
+static IRQ_DEPTH: PerCpu<AtomicU32> = PerCpu::new([const { AtomicU32::new(0) }; MAX_CPUS]);
+
+/// IRQ exceptions taken by each CPU.
+static IRQ_COUNT: PerCpu<AtomicU64> = PerCpu::new([const { AtomicU64::new(0) }; MAX_CPUS]);
+
+/// Whether the calling CPU is running an IRQ handler.
+pub fn in_irq() -> bool {
+    IRQ_DEPTH.with(|depth| depth.load(Ordering::Relaxed) != 0)
+}
+
+/// IRQ exceptions taken so far by CPU `cpu`.
+pub fn irq_count(cpu: usize) -> u64 {
+    IRQ_COUNT.remote(cpu).load(Ordering::Relaxed)
+}
+
+/// Dispatch pending interrupts, accounting for them on this CPU.
+fn dispatch_irq() {
+    // IRQ context cannot be switched out, so the slots stay ours
+    IRQ_DEPTH.with(|depth| depth.fetch_add(1, Ordering::Relaxed));
+    IRQ_COUNT.with(|count| count.fetch_add(1, Ordering::Relaxed));
+    gic::handle_irq();
+    IRQ_DEPTH.with(|depth| depth.fetch_sub(1, Ordering::Relaxed));
+}
+
+/// Handle IRQ from lower EL
+///
+/// Dispatches pending interrupts through the GIC handler table, then
+/// switches threads if the tick used up the current time slice.
+#[no_mangle]
+pub extern "C" fn handle_irq_lower_el(_ctx: &mut ExceptionContext) {
+    dispatch_irq();
+    sched::preempt();
+}
+
+/// Handle IRQ from current EL
+///
+/// Dispatches pending interrupts through the GIC handler table.
+#[no_mangle]
+pub extern "C" fn handle_irq_same_el(_ctx: &ExceptionContext) {
+    dispatch_irq();
+}
+
+/// Handle FIQ from any EL
+///
+/// All interrupts are configured as IRQs, so an FIQ is unexpected.
+/// It is reported and FIQs are masked in the interrupted context so a
+/// stuck line cannot storm the CPU.
+#[no_mangle]
+pub extern "C" fn handle_fiq(ctx: &mut ExceptionContext, vector: u64) {
+    let (source, _) = decode_vector(vector);
+
+    kprintln!("[FIQ] Unexpected FIQ from {:?} at ELR 0x{:016x}, masking FIQs", source, ctx.elr);
+    ctx.spsr |= SPSR_F;
+}
+
+/// Handle SError (asynchronous abort) from any EL
+///
+/// SErrors are not recoverable: the faulting state is unknown. From a
+/// lower EL the offending process is stopped; from the kernel this is
+/// a panic.
+#[no_mangle]
+pub extern "C" fn handle_serror(ctx: &mut ExceptionContext, vector: u64) {
+    let (source, _) = decode_vector(vector);
+
+    let info = Esr::new(ctx.esr).serror();
+
+    kprintln!("!!! SERROR !!!");
+    kprintln!("Source: {:?}", source);
+    kprintln!(
+        "ESR: 0x{:016x} (IDS={}, AET={}, EA={}, DFSC=0x{:02x})",
+        ctx.esr,
+        info.impl_defined as u8,
+        info.error_type,
+        info.external as u8,
+        info.status
+    );
+    kprintln!("ELR: 0x{:016x}", ctx.elr);
+    kprintln!("FAR: 0x{:016x}", ctx.far);
+
+    match source {
+        ExceptionSource::LowerElAarch64 | ExceptionSource::LowerElAarch32 => {
+            process::exit_current(ExitReason::SError { esr: ctx.esr });
+        }
+        _ => panic!("Unrecoverable SError in kernel"),
+    }
+}
+
+/// Handle exceptions from vectors that should never be taken
+///
+/// - Current EL with SPx IRQ: IRQs stay masked on the exception stack
+/// - Lower EL AArch32: user processes are never started in AArch32
+#[no_mangle]
+pub extern "C" fn handle_unexpected_exception(ctx: &mut ExceptionContext, vector: u64) {
+    let (source, kind) = decode_vector(vector);
+
+    kprintln!("!!! UNEXPECTED EXCEPTION !!!");
+    kprintln!("Source: {:?}, Kind: {:?}", source, kind);
+    kprintln!("Exception: {}", Esr::new(ctx.esr));
+    kprintln!("ESR: 0x{:016x}", ctx.esr);
+    kprintln!("ELR: 0x{:016x}", ctx.elr);
+    kprintln!("FAR: 0x{:016x}", ctx.far);
+
+    panic!("Unexpected {:?} exception from {:?}", kind, source);
+}
+
+/// Unmask IRQs on the current CPU.
+#[inline]
+pub fn enable_irqs() {
+    // SAFETY: Clearing DAIF.I only allows IRQs to be taken; the vector
+    // table is installed before this is ever called.
+    unsafe {
+        asm!("msr daifclr, #2", options(nostack, preserves_flags));
+    }
+}
+
+/// Mask IRQs on the current CPU.
+#[inline]
+pub fn disable_irqs() {
+    // SAFETY: Setting DAIF.I only delays interrupts.
+    unsafe {
+        asm!("msr daifset, #2", options(nostack, preserves_flags));
+    }
+}
+
+/// Whether IRQs are unmasked on the current CPU.
+#[inline]
+pub fn irqs_enabled() -> bool {
+    let daif: u64;
+    // SAFETY: Reading DAIF has no side effects.
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack, preserves_flags));
+    }
+    daif & DAIF_I == 0
+}
+
+/// Mask IRQs and report whether they were unmasked before.
+#[inline]
+pub fn save_and_disable_irqs() -> bool {
+    let daif: u64;
+    // SAFETY: Reading DAIF and masking IRQs only delays interrupts.
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack, preserves_flags));
+        asm!("msr daifset, #2", options(nostack, preserves_flags));
+    }
+    daif & DAIF_I == 0
+}
+
+/// Undo `save_and_disable_irqs`.
+#[inline]
+pub fn restore_irqs(were_enabled: bool) {
+    if were_enabled {
+        enable_irqs();
+    }
+}
+
+/// Halt the CPU
+fn halt() -> ! {
+    loop {
+        // SAFETY: WFI is always safe
+        unsafe {
+            asm!("wfi", options(nostack, nomem));
+        }
+    }
+}
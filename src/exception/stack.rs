@@ -0,0 +1,90 @@
+//! Exception and Boot Stacks
+//!
+//! Owns the stacks described in boot.S: the per-CPU exception stack that
+//! SP_EL1 points at while the kernel runs, and the guarded kernel stack
+//! each CPU moves onto once memory management is up.
+//!
+//! # Design
+//! - Kernel code runs in EL1t (SPSel = 0) on a guarded `KernelStack`
+//! - Every exception switches to SP_EL1, so even a kernel stack overflow
+//!   is handled on a known-good stack
+//! - The exception stack is itself a guarded `KernelStack`; faults on it
+//!   are caught by the emergency stack in boot.S
+//! - The lower-EL entry code finds the exception stack top through the
+//!   CPU's `CpuLocal` (TPIDR_EL1)
+
+use core::arch::asm;
+
+use spin::Once;
+
+use crate::mm::KernelStack;
+use crate::smp::{self, MAX_CPUS};
+
+/// Exception stacks, by CPU (never freed).
+static EXCEPTION_STACKS: [Once<KernelStack>; MAX_CPUS] = [const { Once::new() }; MAX_CPUS];
+
+/// Guarded stacks the CPUs continue on after boot (never freed).
+static BOOT_STACKS: [Once<KernelStack>; MAX_CPUS] = [const { Once::new() }; MAX_CPUS];
+
+/// Whether the calling CPU has moved onto its guarded stacks.
+pub fn ready() -> bool {
+    BOOT_STACKS[smp::cpu_id()].is_completed()
+}
+
+/// Bounds `(bottom, top)` of the calling CPU's exception stack, once
+/// allocated.
+pub fn exception_stack_bounds() -> Option<(usize, usize)> {
+    EXCEPTION_STACKS[smp::cpu_id()]
+        .get()
+        .map(|stack| (stack.bottom().as_usize(), stack.top().as_usize()))
+}
+
+/// Allocate the exception stack of `cpu` and return its top.
+///
+/// Called by the boot CPU before starting a secondary, which enters the
+/// kernel on this stack.
+pub fn prepare(cpu: usize) -> usize {
+    EXCEPTION_STACKS[cpu]
+        .call_once(|| boot_alloc("exception"))
+        .top()
+        .as_usize()
+}
+
+/// Allocate a stack during boot, panicking on failure.
+fn boot_alloc(what: &str) -> KernelStack {
+    match KernelStack::new() {
+        Ok(stack) => stack,
+        Err(e) => panic!("Failed to allocate {} stack: {}", what, e),
+    }
+}
+
+/// Move the calling CPU onto guarded stacks and continue in `entry`.
+///
+/// SP_EL1 becomes the exception stack and the CPU switches to EL1t on a
+/// fresh kernel stack. The current stack (linker-provided on the boot
+/// CPU, the exception stack on secondaries) is abandoned, so this never
+/// returns.
+pub fn init(entry: extern "C" fn() -> !) -> ! {
+    let cpu = smp::this_cpu();
+    let exception_top = prepare(cpu.id());
+    cpu.set_exception_stack_top(exception_top);
+    let boot = BOOT_STACKS[cpu.id()].call_once(|| boot_alloc("boot"));
+
+    // SAFETY: Both stacks are mapped, 16-byte aligned and live forever.
+    // Nothing on the old stack is used after the switch: `entry` starts
+    // with an empty frame chain (x29 = x30 = 0) and never returns.
+    unsafe {
+        asm!(
+            "msr sp_el0, {stack}",
+            "mov sp, {exception}",
+            "msr spsel, #0",
+            "mov x29, xzr",
+            "mov x30, xzr",
+            "br {entry}",
+            stack = in(reg) boot.top().as_usize(),
+            exception = in(reg) exception_top,
+            entry = in(reg) entry as usize,
+            options(noreturn)
+        );
+    }
+}
@@ -0,0 +1,275 @@
+//! Flattened Device Tree
+//!
+//! Just enough of a read-only DTB walker to find what the boot loader put
+//! in `/chosen`. QEMU passes the blob's physical address in x0 and, for
+//! bare-metal images, also places it at the start of RAM.
+//!
+//! # Design
+//! - The blob is read in place through the direct map; nothing is copied
+//! - Only the structure and strings blocks are used; the memory
+//!   reservation block is skipped
+//! - The boot blob is found once, early, and its frames are reserved so
+//!   later users (PSCI, SMP) can still read it
+//!
+//! # Security Properties
+//! - The header is checked (magic, version, block bounds) before use
+//! - Every read is bounds-checked against its block, so a malformed blob
+//!   yields `None` instead of reading past it
+
+use core::ops::Range;
+
+use spin::Once;
+
+use crate::mm::address::{phys_to_kernel_virt, PHYS_MEM_BASE, PHYS_MEM_END};
+use crate::mm::frame::reserve_frames;
+use crate::mm::PhysAddr;
+
+/// `magic` of a flattened device tree (big-endian).
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/// Oldest blob version with the layout we read.
+const FDT_MIN_COMPAT_VERSION: u32 = 16;
+
+/// Size of the header fields we use.
+const HEADER_SIZE: usize = 40;
+
+// Structure block tokens
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// A validated device tree blob.
+pub struct DeviceTree<'a> {
+    size: usize,
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+/// Read a big-endian u32 at `offset`, if it is in bounds.
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let raw = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(raw.try_into().ok()?))
+}
+
+/// Round `offset` up to the 4-byte token alignment.
+fn align4(offset: usize) -> Option<usize> {
+    Some(offset.checked_add(3)? & !3)
+}
+
+/// The NUL-terminated string at `offset`.
+fn c_str(bytes: &[u8], offset: usize) -> Option<&[u8]> {
+    let rest = bytes.get(offset..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    Some(&rest[..len])
+}
+
+/// A node of the tree.
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    dt: &'a DeviceTree<'a>,
+    /// Name with unit address (`cpu@1`); empty for the root.
+    name: &'a [u8],
+    /// Nesting depth; the root is 1.
+    depth: usize,
+    /// Offset of the first token after the node's name.
+    body: usize,
+}
+
+impl<'a> Node<'a> {
+    /// Name without the unit address.
+    pub fn base_name(&self) -> &'a [u8] {
+        self.name.split(|&b| b == b'@').next().unwrap_or(self.name)
+    }
+
+    /// Value of property `name`.
+    pub fn property(&self, name: &[u8]) -> Option<&'a [u8]> {
+        // Properties come before subnodes, so stop at the first node token
+        let mut offset = self.body;
+        loop {
+            match self.dt.token(offset)? {
+                (Token::Prop { name: prop, value }, next) => {
+                    if prop == name {
+                        return Some(value);
+                    }
+                    offset = next;
+                }
+                (Token::Nop, next) => offset = next,
+                _ => return None,
+            }
+        }
+    }
+
+    /// Whether string-list property `name` contains `value`.
+    pub fn has_string(&self, name: &[u8], value: &[u8]) -> bool {
+        self.property(name)
+            .is_some_and(|list| list.split(|&b| b == 0).any(|item| item == value))
+    }
+
+    /// Direct subnodes, in order.
+    pub fn children(&self) -> impl Iterator<Item = Node<'a>> + 'a {
+        let depth = self.depth;
+        self.dt
+            .nodes_from(self.body, depth)
+            .take_while(move |node| node.depth > depth)
+            .filter(move |node| node.depth == depth + 1)
+    }
+}
+
+/// A structure block token.
+enum Token<'a> {
+    BeginNode(&'a [u8]),
+    EndNode,
+    Prop { name: &'a [u8], value: &'a [u8] },
+    Nop,
+    End,
+}
+
+impl<'a> DeviceTree<'a> {
+    /// Validate the blob at the start of `bytes`.
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if be32(bytes, 0)? != FDT_MAGIC || bytes.len() < HEADER_SIZE {
+            return None;
+        }
+        let total = be32(bytes, 4)? as usize;
+        let off_structs = be32(bytes, 8)? as usize;
+        let off_strings = be32(bytes, 12)? as usize;
+        let last_compat = be32(bytes, 24)?;
+        let size_strings = be32(bytes, 32)? as usize;
+        let size_structs = be32(bytes, 36)? as usize;
+
+        if last_compat > FDT_MIN_COMPAT_VERSION || total > bytes.len() {
+            return None;
+        }
+        let blob = &bytes[..total];
+        Some(Self {
+            size: total,
+            structs: blob.get(off_structs..off_structs.checked_add(size_structs)?)?,
+            strings: blob.get(off_strings..off_strings.checked_add(size_strings)?)?,
+        })
+    }
+
+    /// Size of the blob in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Decode the token at `offset`; returns it and the next offset.
+    fn token(&self, offset: usize) -> Option<(Token<'a>, usize)> {
+        let structs = self.structs;
+        let body = offset.checked_add(4)?;
+        match be32(structs, offset)? {
+            FDT_BEGIN_NODE => {
+                let name = c_str(structs, body)?;
+                Some((Token::BeginNode(name), align4(body + name.len() + 1)?))
+            }
+            FDT_END_NODE => Some((Token::EndNode, body)),
+            FDT_PROP => {
+                let len = be32(structs, body)? as usize;
+                let name = c_str(self.strings, be32(structs, body + 4)? as usize)?;
+                let value_start = body + 8;
+                let value = structs.get(value_start..value_start.checked_add(len)?)?;
+                Some((Token::Prop { name, value }, align4(value_start + len)?))
+            }
+            FDT_NOP => Some((Token::Nop, body)),
+            FDT_END => Some((Token::End, body)),
+            _ => None,
+        }
+    }
+
+    /// Nodes in document order, starting at `offset` inside a node at
+    /// `depth`. Stops at the end of the tree or at a malformed token.
+    fn nodes_from(&'a self, offset: usize, depth: usize) -> impl Iterator<Item = Node<'a>> + 'a {
+        let mut offset = Some(offset);
+        let mut depth = depth;
+        core::iter::from_fn(move || loop {
+            let (token, next) = self.token(offset?)?;
+            offset = Some(next);
+            match token {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    return Some(Node { dt: self, name, depth, body: next });
+                }
+                Token::EndNode => depth = depth.checked_sub(1)?,
+                Token::Prop { .. } | Token::Nop => {}
+                Token::End => {
+                    offset = None;
+                    return None;
+                }
+            }
+        })
+    }
+
+    /// The root node.
+    pub fn root(&'a self) -> Option<Node<'a>> {
+        // The structure block starts with the root's BEGIN_NODE
+        self.nodes_from(0, 0).next()
+    }
+
+    /// Top-level node `name` (matched without unit address).
+    pub fn find_node(&'a self, name: &[u8]) -> Option<Node<'a>> {
+        self.root()?.children().find(|node| node.base_name() == name)
+    }
+}
+
+/// Decode a 32- or 64-bit big-endian cell value.
+pub fn cell_value(value: &[u8]) -> Option<u64> {
+    match value.len() {
+        4 => Some(u32::from_be_bytes(value.try_into().ok()?) as u64),
+        8 => Some(u64::from_be_bytes(value.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+/// The device tree the kernel was booted with.
+static BOOT_DT: Once<DeviceTree<'static>> = Once::new();
+
+/// Find the boot device tree.
+///
+/// Tries the address the boot loader handed over in x0, then the start
+/// of RAM. The blob must lie entirely in RAM.
+fn locate(boot_arg: usize) -> Option<(usize, DeviceTree<'static>)> {
+    [boot_arg, PHYS_MEM_BASE].into_iter().find_map(|phys| {
+        if !(PHYS_MEM_BASE..PHYS_MEM_END - HEADER_SIZE).contains(&phys) || phys % 8 != 0 {
+            return None;
+        }
+        let ptr = phys_to_kernel_virt(PhysAddr::new(phys)).as_usize() as *const u8;
+        // SAFETY: The header lies in RAM, which the direct map covers.
+        let header = unsafe { core::slice::from_raw_parts(ptr, HEADER_SIZE) };
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total = be32(header, 4)? as usize;
+        if total < HEADER_SIZE || total > PHYS_MEM_END - phys {
+            return None;
+        }
+        // SAFETY: As above, for the whole blob; the boot loader handed it
+        // over and nothing in the kernel writes to it.
+        let dt = DeviceTree::parse(unsafe { core::slice::from_raw_parts(ptr, total) })?;
+        Some((phys, dt))
+    })
+}
+
+/// Find the boot device tree and keep its frames from being allocated.
+///
+/// `boot_arg` is x0 as passed by the boot loader. Must run right after
+/// the frame allocator is initialized.
+pub fn init(boot_arg: usize) -> Option<&'static DeviceTree<'static>> {
+    let (phys, dt) = locate(boot_arg)?;
+    reserve_frames(PhysAddr::new(phys), PhysAddr::new(phys + dt.size()));
+    Some(BOOT_DT.call_once(|| dt))
+}
+
+/// The boot device tree, if one was found.
+pub fn boot() -> Option<&'static DeviceTree<'static>> {
+    BOOT_DT.get()
+}
+
+/// Physical range of the initial ramdisk, from `/chosen`.
+pub fn initrd_range(dt: &DeviceTree<'_>) -> Option<Range<usize>> {
+    let chosen = dt.find_node(b"chosen")?;
+    let start = cell_value(chosen.property(b"linux,initrd-start")?)? as usize;
+    let end = cell_value(chosen.property(b"linux,initrd-end")?)? as usize;
+    (start < end).then_some(start..end)
+}
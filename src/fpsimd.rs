@@ -0,0 +1,273 @@
+//! FP/SIMD State Management
+//!
+//! The 32 128-bit Q registers plus FPCR/FPSR are not part of
+//! `ExceptionContext`. Saving 528 bytes on every exception would be
+//! wasteful when most threads never touch floating point, so the state is
+//! switched lazily.
+//!
+//! # Design
+//! - Every thread owns an `FpState` save area
+//! - Each CPU's registers belong to at most one `FpState` at a time (the
+//!   owner); owner and current save area are per-CPU
+//! - On a context switch, access is enabled only if the incoming thread
+//!   already owns the registers; otherwise CPACR_EL1.FPEN traps
+//! - The first FP/SIMD instruction after such a switch traps (EC 0x07);
+//!   the handler saves the previous owner, loads the current thread's
+//!   state and re-enables access
+//! - A thread may resume on another CPU, so the outgoing thread's live
+//!   registers are saved at switch-out (loading stays lazy), and a save
+//!   area is owned by at most one CPU: loading it anywhere clears its
+//!   ownership everywhere else
+//!
+//! # Kernel Policy
+//! The kernel is built for `aarch64-unknown-none-softfloat`, so the
+//! compiler never emits FP/SIMD instructions in kernel code and the user
+//! registers stay intact across syscalls and interrupts. The only FP/SIMD
+//! instructions in the kernel are the save/restore routines below. While
+//! no thread owns the registers, FPEN traps EL1 too, so a stray access
+//! from the kernel is caught as a same-EL fault.
+//!
+//! # Security Properties
+//! - A thread never observes another thread's FP/SIMD registers
+//! - New save areas are zeroed, so fresh threads start from a clean state
+
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::percpu::{self, PerCpu};
+use crate::smp::{self, MAX_CPUS};
+
+/// CPACR_EL1.FPEN field (bits [21:20]).
+const CPACR_FPEN_MASK: u64 = 0b11 << 20;
+
+/// FPEN = 0b11: no FP/SIMD instructions are trapped.
+const CPACR_FPEN_NONE: u64 = 0b11 << 20;
+
+/// FPEN = 0b00: FP/SIMD instructions trap at EL0 and EL1.
+const CPACR_FPEN_ALL: u64 = 0b00 << 20;
+
+/// Saved FP/SIMD register file of one thread.
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy)]
+pub struct FpState {
+    /// Vector registers q0-q31
+    pub q: [u128; 32],
+    /// Floating-point Control Register
+    pub fpcr: u64,
+    /// Floating-point Status Register
+    pub fpsr: u64,
+}
+
+// The save/restore routines hard-code these offsets.
+const _: () = assert!(core::mem::offset_of!(FpState, fpcr) == 512);
+const _: () = assert!(core::mem::offset_of!(FpState, fpsr) == 520);
+
+impl FpState {
+    /// Create a zeroed save area (all registers zero, default rounding).
+    pub const fn new() -> Self {
+        Self {
+            q: [0; 32],
+            fpcr: 0,
+            fpsr: 0,
+        }
+    }
+}
+
+impl Default for FpState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Save area whose contents are currently live in each CPU's registers.
+static OWNER: PerCpu<AtomicPtr<FpState>> =
+    PerCpu::new([const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS]);
+
+/// Save area of the thread running on each CPU.
+static CURRENT: PerCpu<AtomicPtr<FpState>> =
+    PerCpu::new([const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS]);
+
+/// Set CPACR_EL1.FPEN.
+fn set_fpen(fpen: u64) {
+    // SAFETY: CPACR_EL1 only controls FP/SIMD trapping; the ISB makes the
+    // new setting effective before the next FP/SIMD instruction.
+    unsafe {
+        let mut cpacr: u64;
+        asm!("mrs {}, cpacr_el1", out(reg) cpacr, options(nomem, nostack, preserves_flags));
+        cpacr = (cpacr & !CPACR_FPEN_MASK) | fpen;
+        asm!(
+            "msr cpacr_el1, {}",
+            "isb",
+            in(reg) cpacr,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+}
+
+/// Initialize FP/SIMD handling on the current CPU.
+///
+/// No thread owns the registers yet, so all accesses trap.
+pub fn init() {
+    OWNER.with(|owner| owner.store(ptr::null_mut(), Ordering::Relaxed));
+    CURRENT.with(|current| current.store(ptr::null_mut(), Ordering::Relaxed));
+    set_fpen(CPACR_FPEN_ALL);
+}
+
+/// Store the live registers into `state`.
+///
+/// # Safety
+/// FP/SIMD access must be enabled and `state` must be valid for writes.
+unsafe fn save(state: *mut FpState) {
+    // SAFETY: Caller guarantees access is enabled and `state` is valid.
+    // FpState is repr(C): q at offset 0, fpcr at 512, fpsr at 520.
+    unsafe {
+        asm!(
+            ".arch_extension fp",
+            ".arch_extension simd",
+            "stp q0, q1, [{s}, #0]",
+            "stp q2, q3, [{s}, #32]",
+            "stp q4, q5, [{s}, #64]",
+            "stp q6, q7, [{s}, #96]",
+            "stp q8, q9, [{s}, #128]",
+            "stp q10, q11, [{s}, #160]",
+            "stp q12, q13, [{s}, #192]",
+            "stp q14, q15, [{s}, #224]",
+            "stp q16, q17, [{s}, #256]",
+            "stp q18, q19, [{s}, #288]",
+            "stp q20, q21, [{s}, #320]",
+            "stp q22, q23, [{s}, #352]",
+            "stp q24, q25, [{s}, #384]",
+            "stp q26, q27, [{s}, #416]",
+            "stp q28, q29, [{s}, #448]",
+            "stp q30, q31, [{s}, #480]",
+            "mrs {t}, fpcr",
+            "str {t}, [{s}, #512]",
+            "mrs {t}, fpsr",
+            "str {t}, [{s}, #520]",
+            s = in(reg) state,
+            t = out(reg) _,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Load the registers from `state`.
+///
+/// # Safety
+/// FP/SIMD access must be enabled and `state` must be valid for reads.
+unsafe fn restore(state: *const FpState) {
+    // SAFETY: As for `save`. Kernel code never holds values in FP/SIMD
+    // registers (softfloat target), so overwriting them is invisible to it.
+    unsafe {
+        asm!(
+            ".arch_extension fp",
+            ".arch_extension simd",
+            "ldp q0, q1, [{s}, #0]",
+            "ldp q2, q3, [{s}, #32]",
+            "ldp q4, q5, [{s}, #64]",
+            "ldp q6, q7, [{s}, #96]",
+            "ldp q8, q9, [{s}, #128]",
+            "ldp q10, q11, [{s}, #160]",
+            "ldp q12, q13, [{s}, #192]",
+            "ldp q14, q15, [{s}, #224]",
+            "ldp q16, q17, [{s}, #256]",
+            "ldp q18, q19, [{s}, #288]",
+            "ldp q20, q21, [{s}, #320]",
+            "ldp q22, q23, [{s}, #352]",
+            "ldp q24, q25, [{s}, #384]",
+            "ldp q26, q27, [{s}, #416]",
+            "ldp q28, q29, [{s}, #448]",
+            "ldp q30, q31, [{s}, #480]",
+            "ldr {t}, [{s}, #512]",
+            "msr fpcr, {t}",
+            "ldr {t}, [{s}, #520]",
+            "msr fpsr, {t}",
+            s = in(reg) state,
+            t = out(reg) _,
+            options(nostack, preserves_flags, readonly)
+        );
+    }
+}
+
+/// Switch FP/SIMD context to the thread owning `next`.
+///
+/// Called by the scheduler on every context switch. No registers are
+/// moved here: access is simply enabled if `next` still owns them and
+/// trapped otherwise. `next` may be null for threads that never run at
+/// EL0 (e.g. the idle thread).
+///
+/// # Safety
+/// `next` must stay valid until it is passed to `release`: it may remain
+/// the register owner long after its thread was switched out.
+pub unsafe fn switch_to(next: *mut FpState) {
+    let guard = percpu::preempt_disable();
+    let prev = CURRENT.get(&guard).swap(next, Ordering::Relaxed);
+
+    // Owner == current means access is enabled and the registers may be
+    // newer than the save area
+    if !prev.is_null() && prev != next && OWNER.get(&guard).load(Ordering::Relaxed) == prev {
+        // SAFETY: Access is enabled (see above) and `prev` is live until
+        // `release`, which cannot run before its thread is switched out.
+        unsafe { save(prev) };
+    }
+
+    if !next.is_null() && OWNER.get(&guard).load(Ordering::Relaxed) == next {
+        set_fpen(CPACR_FPEN_NONE);
+    } else {
+        set_fpen(CPACR_FPEN_ALL);
+    }
+}
+
+/// Forget `state` before its thread is destroyed.
+///
+/// The live registers are discarded rather than saved, on whichever CPU
+/// holds them.
+pub fn release(state: *mut FpState) {
+    for owner in OWNER.iter() {
+        let _ = owner.compare_exchange(state, ptr::null_mut(), Ordering::Relaxed, Ordering::Relaxed);
+    }
+    // A dying thread is only ever current on the CPU destroying it
+    CURRENT.with(|current| {
+        if current.load(Ordering::Relaxed) == state {
+            current.store(ptr::null_mut(), Ordering::Relaxed);
+            set_fpen(CPACR_FPEN_ALL);
+        }
+    });
+}
+
+/// Handle an FP/SIMD access trap from user mode (EC 0x07).
+///
+/// Saves the previous owner's registers, loads the current thread's
+/// state and enables access. Returns `false` if no thread is current,
+/// in which case the access cannot be satisfied.
+pub fn handle_trap() -> bool {
+    // Taken with IRQs masked, so this stays on one CPU throughout
+    let current = CURRENT.with(|current| current.load(Ordering::Relaxed));
+    if current.is_null() {
+        return false;
+    }
+
+    set_fpen(CPACR_FPEN_NONE);
+
+    let owner = OWNER.with(|owner| owner.load(Ordering::Relaxed));
+    if owner != current {
+        // SAFETY: Access was enabled above. `owner` and `current` are live
+        // save areas: `switch_to`/`release` keep both pointers valid.
+        unsafe {
+            if !owner.is_null() {
+                save(owner);
+            }
+            restore(current);
+        }
+        OWNER.with(|owner| owner.store(current, Ordering::Relaxed));
+
+        // Registers elsewhere holding an older copy are no longer valid
+        let this = smp::cpu_id();
+        for (_, owner) in OWNER.iter().enumerate().filter(|&(cpu, _)| cpu != this) {
+            let _ = owner.compare_exchange(current, ptr::null_mut(), Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+
+    true
+}
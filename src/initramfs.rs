@@ -0,0 +1,319 @@
+//! Initial RAM Filesystem
+//!
+//! The boot loader (QEMU `-initrd`) loads a CPIO archive into RAM and
+//! records its range in the device tree. The kernel keeps the archive
+//! where it is, takes its frames out of the allocator and serves the
+//! files from it read-only: the loader starts programs from it, and user
+//! processes can read files with the `file_read` system call.
+//!
+//! # Archive Format
+//! "New ASCII" CPIO (`cpio -H newc`), as produced by
+//! `find . | cpio -o -H newc`:
+//! ```text
+//! ┌──────────────────────┬──────────┬─────┬──────────┬─────┐
+//! │ 110-byte header      │ name NUL │ pad │ data     │ pad │  ... repeated
+//! │ "070701" + 13 fields │          │ to 4│          │ to 4│
+//! └──────────────────────┴──────────┴─────┴──────────┴─────┘
+//! ```
+//! The last entry is named `TRAILER!!!`.
+//!
+//! # Security Properties
+//! - The whole archive is validated once at boot; every header field and
+//!   every name and data range is bounds-checked
+//! - Only regular files are exposed; names are normalized and compared
+//!   byte for byte (no path resolution)
+//! - The archive is never written after boot, so the `&'static` slices
+//!   handed out stay valid and unchanged
+
+use core::fmt;
+use core::ops::Range;
+
+use spin::Once;
+
+use crate::fdt;
+use crate::kprintln;
+use crate::mm::address::{phys_to_kernel_virt, PHYS_MEM_BASE, PHYS_MEM_END};
+use crate::mm::frame::reserve_frames;
+use crate::mm::PhysAddr;
+
+/// Magic of a newc header (no checksum).
+const NEWC_MAGIC: &[u8; 6] = b"070701";
+
+/// Size of a newc header: the magic and 13 8-digit hex fields.
+const HEADER_SIZE: usize = 110;
+
+/// Name of the entry that ends the archive.
+const TRAILER: &[u8] = b"TRAILER!!!";
+
+/// File type bits of `c_mode`.
+const S_IFMT: u32 = 0o170000;
+
+/// Regular file type.
+const S_IFREG: u32 = 0o100000;
+
+/// Errors from parsing an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioError {
+    /// A header, name or file body runs past the end of the archive.
+    Truncated,
+    /// A header does not start with the newc magic.
+    BadMagic,
+    /// A header field is not a hex number.
+    BadField,
+    /// A name is empty, not NUL-terminated or not UTF-8.
+    BadName,
+}
+
+impl fmt::Display for CpioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "Archive truncated"),
+            Self::BadMagic => write!(f, "Not a newc CPIO archive"),
+            Self::BadField => write!(f, "Malformed header field"),
+            Self::BadName => write!(f, "Malformed file name"),
+        }
+    }
+}
+
+/// A regular file in the archive.
+#[derive(Debug, Clone, Copy)]
+pub struct File<'a> {
+    /// Path without a leading `./` or `/`.
+    pub name: &'a str,
+    /// File contents.
+    pub data: &'a [u8],
+}
+
+/// One archive entry, of any type.
+struct Entry<'a> {
+    name: &'a [u8],
+    mode: u32,
+    data: &'a [u8],
+    /// Offset of the next header.
+    next: usize,
+}
+
+/// Round up to the 4-byte alignment of names and bodies.
+fn align4(offset: usize) -> Option<usize> {
+    Some(offset.checked_add(3)? & !3)
+}
+
+/// Parse one 8-digit hex header field.
+fn hex_field(header: &[u8], index: usize) -> Result<u32, CpioError> {
+    let start = NEWC_MAGIC.len() + index * 8;
+    let digits = core::str::from_utf8(&header[start..start + 8]).map_err(|_| CpioError::BadField)?;
+    u32::from_str_radix(digits, 16).map_err(|_| CpioError::BadField)
+}
+
+/// Strip the `./` or `/` prefix `cpio` leaves on names.
+fn normalize(name: &str) -> &str {
+    let name = name.strip_prefix("./").unwrap_or(name);
+    name.strip_prefix('/').unwrap_or(name)
+}
+
+/// A validated newc archive.
+#[derive(Debug, Clone, Copy)]
+pub struct Archive<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Archive<'a> {
+    /// Validate every entry of `bytes` up to the trailer.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, CpioError> {
+        let archive = Self { bytes };
+        let mut offset = 0;
+        loop {
+            let entry = archive.entry(offset)?;
+            if entry.name == TRAILER {
+                return Ok(archive);
+            }
+            core::str::from_utf8(entry.name).map_err(|_| CpioError::BadName)?;
+            offset = entry.next;
+        }
+    }
+
+    /// Decode the entry whose header starts at `offset`.
+    fn entry(&self, offset: usize) -> Result<Entry<'a>, CpioError> {
+        let end = offset.checked_add(HEADER_SIZE).ok_or(CpioError::Truncated)?;
+        let header = self.bytes.get(offset..end).ok_or(CpioError::Truncated)?;
+        if &header[..NEWC_MAGIC.len()] != NEWC_MAGIC {
+            return Err(CpioError::BadMagic);
+        }
+
+        // Fields: ino, mode, uid, gid, nlink, mtime, filesize, devmajor,
+        // devminor, rdevmajor, rdevminor, namesize, check
+        let mode = hex_field(header, 1)?;
+        let file_size = hex_field(header, 6)? as usize;
+        let name_size = hex_field(header, 11)? as usize;
+
+        // The name includes its NUL; header plus name is padded to 4
+        let name_end = end.checked_add(name_size).ok_or(CpioError::Truncated)?;
+        let name = self.bytes.get(end..name_end).ok_or(CpioError::Truncated)?;
+        let name = match name.split_last() {
+            Some((0, name)) if !name.is_empty() => name,
+            _ => return Err(CpioError::BadName),
+        };
+
+        let data_start = align4(name_end).ok_or(CpioError::Truncated)?;
+        let data_end = data_start.checked_add(file_size).ok_or(CpioError::Truncated)?;
+        let data = self.bytes.get(data_start..data_end).ok_or(CpioError::Truncated)?;
+
+        Ok(Entry {
+            name,
+            mode,
+            data,
+            next: align4(data_end).ok_or(CpioError::Truncated)?,
+        })
+    }
+
+    /// Regular files, in archive order.
+    pub fn files(&self) -> impl Iterator<Item = File<'a>> + 'a {
+        let archive = *self;
+        let mut offset = Some(0);
+        core::iter::from_fn(move || loop {
+            // `parse` checked every entry, so errors cannot happen here
+            let entry = archive.entry(offset?).ok()?;
+            if entry.name == TRAILER {
+                offset = None;
+                return None;
+            }
+            offset = Some(entry.next);
+            if entry.mode & S_IFMT == S_IFREG {
+                let name = core::str::from_utf8(entry.name).ok()?;
+                return Some(File { name: normalize(name), data: entry.data });
+            }
+        })
+    }
+
+    /// Look up a regular file by path (`bin/init`, not `/bin/init`).
+    pub fn lookup(&self, name: &str) -> Option<File<'a>> {
+        self.files().find(|file| file.name == name)
+    }
+}
+
+/// The boot archive, once found and validated.
+static INITRAMFS: Once<Archive<'static>> = Once::new();
+
+/// The initramfs, if the boot loader supplied a valid one.
+pub fn get() -> Option<&'static Archive<'static>> {
+    INITRAMFS.get()
+}
+
+/// Look up a file in the initramfs.
+pub fn lookup(name: &str) -> Option<File<'static>> {
+    get()?.lookup(name)
+}
+
+/// Find the initrd through the device tree, reserve its frames and
+/// validate it.
+///
+/// Must run right after the frame allocator and `fdt::init`, before
+/// anything allocates frames that may overlap the initrd. Missing or
+/// malformed archives are reported and ignored.
+pub fn init() {
+    let Some(dt) = fdt::boot() else {
+        kprintln!("[BOOT] No device tree found, no initramfs");
+        return;
+    };
+    let Some(range) = fdt::initrd_range(dt) else {
+        kprintln!("[BOOT] No initramfs");
+        return;
+    };
+    if !(PHYS_MEM_BASE..=PHYS_MEM_END).contains(&range.start)
+        || !(PHYS_MEM_BASE..=PHYS_MEM_END).contains(&range.end)
+    {
+        kprintln!("[BOOT] initramfs at 0x{:x}-0x{:x} is outside RAM, ignored", range.start, range.end);
+        return;
+    }
+
+    // Keep the archive away from the allocator even if it turns out to be
+    // malformed: something put it there on purpose
+    reserve_frames(PhysAddr::new(range.start), PhysAddr::new(range.end));
+
+    match Archive::parse(archive_bytes(&range)) {
+        Ok(archive) => {
+            let archive = INITRAMFS.call_once(|| archive);
+            kprintln!(
+                "[BOOT] initramfs at 0x{:x}: {} bytes, {} files",
+                range.start,
+                range.len(),
+                archive.files().count()
+            );
+        }
+        Err(e) => kprintln!("[BOOT] initramfs at 0x{:x} ignored: {}", range.start, e),
+    }
+}
+
+/// The initrd through the direct map.
+fn archive_bytes(range: &Range<usize>) -> &'static [u8] {
+    let virt = phys_to_kernel_virt(PhysAddr::new(range.start));
+    // SAFETY: The range lies in RAM, which the direct map covers, and its
+    // frames are reserved, so nothing else ever writes to them.
+    unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), range.len()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Append one newc entry to `out`.
+    fn push_entry(out: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [0, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        out.extend_from_slice(NEWC_MAGIC);
+        for field in fields {
+            out.extend_from_slice(alloc::format!("{:08X}", field).as_bytes());
+        }
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        out.resize(align4(out.len()).unwrap(), 0);
+        out.extend_from_slice(data);
+        out.resize(align4(out.len()).unwrap(), 0);
+    }
+
+    fn sample() -> Vec<u8> {
+        let mut out = Vec::new();
+        push_entry(&mut out, ".", 0o040755, &[]);
+        push_entry(&mut out, "./bin", 0o040755, &[]);
+        push_entry(&mut out, "./bin/init", 0o100755, b"\x7fELF...");
+        push_entry(&mut out, "./motd", 0o100644, b"hello\n");
+        push_entry(&mut out, "TRAILER!!!", 0, &[]);
+        out
+    }
+
+    #[test]
+    fn test_lists_regular_files() {
+        let bytes = sample();
+        let archive = Archive::parse(&bytes).unwrap();
+
+        let names: Vec<&str> = archive.files().map(|file| file.name).collect();
+        assert_eq!(names, ["bin/init", "motd"]);
+        assert_eq!(archive.lookup("motd").unwrap().data, b"hello\n");
+        assert_eq!(archive.lookup("bin/init").unwrap().data, b"\x7fELF...");
+        assert!(archive.lookup("bin").is_none());
+        assert!(archive.lookup("missing").is_none());
+    }
+
+    #[test]
+    fn test_rejects_malformed_archives() {
+        let bytes = sample();
+
+        // Cut anywhere before the trailer
+        for len in [0, 50, HEADER_SIZE, bytes.len() - HEADER_SIZE] {
+            assert!(Archive::parse(&bytes[..len]).is_err());
+        }
+
+        let mut bad = bytes.clone();
+        bad[0] = b'1';
+        assert_eq!(Archive::parse(&bad).unwrap_err(), CpioError::BadMagic);
+
+        // File size field pointing past the end
+        let mut bad = bytes.clone();
+        bad[6 + 6 * 8..6 + 7 * 8].copy_from_slice(b"7FFFFFFF");
+        assert_eq!(Archive::parse(&bad).unwrap_err(), CpioError::Truncated);
+
+        let mut bad = bytes;
+        bad[6 + 6 * 8] = b'x';
+        assert_eq!(Archive::parse(&bad).unwrap_err(), CpioError::BadField);
+    }
+}
@@ -0,0 +1,355 @@
+//! Endpoints and Reply Objects
+//!
+//! An `Endpoint` is a rendezvous point: a send blocks until a receiver
+//! arrives and the other way round, and the message is copied once they
+//! meet. A `Reply` is the one-shot answer to a `call`.
+//!
+//! # Security Properties
+//! - Threads of exited processes found in a queue are dropped, never
+//!   handed a message
+//! - Deleting the last capability to an endpoint fails every queued
+//!   operation with `Canceled`, so nobody sleeps on it forever
+//! - Deleting a reply capability unanswered wakes the caller with
+//!   `Canceled` and ends the priority loan
+//! - A receiver is taken off the queue by exactly one of a sender, a
+//!   bound notification or the endpoint's deletion, so a message is
+//!   never lost to a receive that already ended
+
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::{sleep, take_received, IpcError, Message, Received};
+use crate::cap::capability::{CapabilityType, Rights};
+use crate::cap::cspace::RawCapability;
+use crate::cap::object;
+use crate::lock_class;
+use crate::sched;
+use crate::sync::TicketLock;
+use crate::thread::Thread;
+
+/// Threads waiting on an endpoint; at most one side is non-empty.
+#[derive(Default)]
+struct Queue {
+    /// Blocked senders, with their message in their `IpcState`.
+    senders: VecDeque<Arc<Thread>>,
+    receivers: VecDeque<Arc<Thread>>,
+}
+
+/// Take the longest waiting thread whose process still runs; others are
+/// moved to `dead`, to be dropped once the lock is released.
+pub(super) fn pop_live(
+    queue: &mut VecDeque<Arc<Thread>>,
+    dead: &mut Vec<Arc<Thread>>,
+) -> Option<Arc<Thread>> {
+    while let Some(thread) = queue.pop_front() {
+        if !thread.process_exited() {
+            return Some(thread);
+        }
+        dead.push(thread);
+    }
+    None
+}
+
+/// Take the longest waiting receiver whose receive has not been ended by
+/// a notification meanwhile; the receive is now ours to end.
+fn pop_receiver(
+    queue: &mut VecDeque<Arc<Thread>>,
+    dead: &mut Vec<Arc<Thread>>,
+) -> Option<Arc<Thread>> {
+    while let Some(thread) = pop_live(queue, dead) {
+        if thread.ipc().receiving.take().is_some() {
+            return Some(thread);
+        }
+        dead.push(thread);
+    }
+    None
+}
+
+/// A synchronous IPC endpoint.
+pub struct Endpoint {
+    queue: TicketLock<Queue>,
+}
+
+impl Endpoint {
+    /// An endpoint with no waiting threads.
+    pub fn new() -> Self {
+        Self {
+            queue: TicketLock::new(Queue::default(), lock_class!("endpoint")),
+        }
+    }
+
+    /// Send `msg` with `badge` from the current thread, sleeping until a
+    /// receiver takes it.
+    pub fn send(self: Arc<Self>, msg: Message, badge: u64) -> Result<(), IpcError> {
+        self.transfer(msg, badge, false)
+    }
+
+    /// Send `msg` with `badge` from the current thread, then sleep until
+    /// the receiver replies; returns the reply.
+    pub fn call(self: Arc<Self>, msg: Message, badge: u64) -> Result<Received, IpcError> {
+        self.transfer(msg, badge, true)?;
+        Ok(take_received())
+    }
+
+    /// Receive a message on the current thread, sleeping until a sender
+    /// arrives or the thread's bound notification is signalled.
+    ///
+    /// `rights` are those of the endpoint capability received through;
+    /// they limit the capabilities a reply to a call can carry.
+    pub fn recv(self: Arc<Self>, rights: Rights) -> Result<Received, IpcError> {
+        let receiver = sched::current().expect("IPC without a current thread");
+        let bound = receiver.ipc().bound.clone();
+        let mut dead = Vec::new();
+        let mut queue = self.queue.lock();
+        match pop_live(&mut queue.senders, &mut dead) {
+            Some(sender) => {
+                let (msg, badge, call) = {
+                    let mut ipc = sender.ipc();
+                    (
+                        core::mem::replace(&mut ipc.msg, Message::empty()),
+                        ipc.badge,
+                        ipc.call,
+                    )
+                };
+                // A caller stays blocked until the reply
+                let (reply, woken) = if call {
+                    (Some(Reply::new(sender, &receiver, rights)), None)
+                } else {
+                    sender.ipc().status = Some(Ok(()));
+                    (None, Some(sender))
+                };
+                receiver.ipc().deliver(msg, badge, reply);
+                drop(queue);
+                if let Some(sender) = woken {
+                    sched::wake(sender);
+                }
+            }
+            None => {
+                // Pending signals end the receive at once; otherwise they
+                // find the receiver queued
+                let mut signals = bound.as_ref().map(|bound| bound.lock());
+                match signals.as_mut().and_then(|signals| signals.take()) {
+                    Some(bits) => {
+                        receiver.ipc().deliver(Message::notification(), bits, None);
+                        drop(signals);
+                        drop(queue);
+                    }
+                    None => {
+                        drop(sched::block_current());
+                        {
+                            let mut ipc = receiver.ipc();
+                            ipc.status = None;
+                            ipc.rights = rights;
+                            ipc.receiving = Some(Arc::downgrade(&self));
+                        }
+                        queue.receivers.push_back(receiver);
+                        drop(signals);
+                        drop(queue);
+                        drop(dead);
+                        drop(bound);
+                        drop(self);
+                        sleep()?;
+                    }
+                }
+            }
+        }
+        Ok(take_received())
+    }
+
+    /// Hand `msg` to a receiver, or queue the current thread with it;
+    /// with `call`, wait for the reply too.
+    fn transfer(self: Arc<Self>, msg: Message, badge: u64, call: bool) -> Result<(), IpcError> {
+        let mut dead = Vec::new();
+        let mut queue = self.queue.lock();
+        match pop_receiver(&mut queue.receivers, &mut dead) {
+            Some(receiver) => {
+                let reply = call.then(|| {
+                    let caller = sched::block_current();
+                    caller.ipc().status = None;
+                    let rights = receiver.ipc().rights;
+                    Reply::new(caller, &receiver, rights)
+                });
+                receiver.ipc().deliver(msg, badge, reply);
+                drop(queue);
+                sched::wake(receiver);
+                if !call {
+                    return Ok(());
+                }
+            }
+            None => {
+                let sender = sched::block_current();
+                {
+                    let mut ipc = sender.ipc();
+                    ipc.msg = msg;
+                    ipc.badge = badge;
+                    ipc.call = call;
+                    ipc.status = None;
+                }
+                queue.senders.push_back(sender);
+                drop(queue);
+            }
+        }
+        drop(dead);
+        drop(self);
+        // A message never delivered still holds its capabilities; they
+        // are deleted here, with no lock held
+        sleep().inspect_err(|_| drop(take_received()))
+    }
+
+    /// Take `thread` off the receive queue once a notification has ended
+    /// its receive.
+    pub(super) fn remove_receiver(&self, thread: &Arc<Thread>) {
+        let removed = {
+            let mut queue = self.queue.lock();
+            let index = queue.receivers.iter().position(|t| Arc::ptr_eq(t, thread));
+            index.and_then(|index| queue.receivers.remove(index))
+        };
+        drop(removed);
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let queue = core::mem::take(&mut *self.queue.lock());
+        for thread in queue.senders {
+            thread.ipc().status = Some(Err(IpcError::Canceled));
+            sched::wake(thread);
+        }
+        for thread in queue.receivers {
+            let mut ipc = thread.ipc();
+            // Unless a notification ended the receive already
+            if ipc.receiving.take().is_some() {
+                ipc.status = Some(Err(IpcError::Canceled));
+                drop(ipc);
+                sched::wake(thread);
+            }
+        }
+    }
+}
+
+/// The answer owed to a thread blocked in `call`.
+pub struct Reply {
+    /// Taken by whoever answers, so the caller is answered once.
+    caller: TicketLock<Option<Arc<Thread>>>,
+    /// Thread the caller's priority is lent to.
+    server: Weak<Thread>,
+    priority: u8,
+    /// Rights of the server's endpoint capability.
+    rights: Rights,
+}
+
+impl Reply {
+    /// The call of `caller` (blocked), answered by `server`, which
+    /// inherits the caller's priority meanwhile and received it through
+    /// an endpoint capability with `rights`.
+    fn new(caller: Arc<Thread>, server: &Arc<Thread>, rights: Rights) -> Arc<Self> {
+        let priority = caller.priority();
+        sched::inherit_priority(server, priority);
+        Arc::new(Self {
+            caller: TicketLock::new(Some(caller), lock_class!("reply")),
+            server: Arc::downgrade(server),
+            priority,
+            rights,
+        })
+    }
+
+    /// Rights capabilities sent with the reply are reduced to; without
+    /// GRANT none can be sent.
+    #[inline]
+    pub fn rights(&self) -> Rights {
+        self.rights
+    }
+
+    /// Answer the call with `msg`; returns false if it was answered
+    /// already.
+    pub fn reply(&self, msg: Message) -> bool {
+        self.finish(Ok(msg))
+    }
+
+    fn finish(&self, result: Result<Message, IpcError>) -> bool {
+        let Some(caller) = self.caller.lock().take() else {
+            return false;
+        };
+        if let Some(server) = self.server.upgrade() {
+            sched::release_priority(&server, self.priority);
+        }
+        {
+            let mut ipc = caller.ipc();
+            match result {
+                Ok(msg) => ipc.deliver(msg, 0, None),
+                Err(e) => ipc.status = Some(Err(e)),
+            }
+        }
+        sched::wake(caller);
+        true
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        self.finish(Err(IpcError::Canceled));
+    }
+}
+
+impl fmt::Debug for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reply")
+            .field("priority", &self.priority)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Wrap `endpoint` in a capability.
+///
+/// The returned capability owns one reference to the object.
+pub fn create_cap(endpoint: Endpoint, rights: Rights) -> RawCapability {
+    RawCapability {
+        cap_type: CapabilityType::Endpoint,
+        object_ptr: Arc::into_raw(Arc::new(endpoint)) as usize,
+        rights,
+        badge: 0,
+        generation: 0,
+    }
+}
+
+/// The endpoint a capability refers to, if it is an endpoint capability.
+pub fn from_cap(cap: &RawCapability) -> Option<Arc<Endpoint>> {
+    if cap.cap_type != CapabilityType::Endpoint {
+        return None;
+    }
+    let ptr = cap.object_ptr as *const Endpoint;
+    // SAFETY: Endpoint capabilities carry a pointer from Arc::into_raw
+    // and own a reference, so the count is at least one while `cap`
+    // exists.
+    unsafe {
+        Arc::increment_strong_count(ptr);
+        Some(Arc::from_raw(ptr))
+    }
+}
+
+/// Wrap `reply` in a reply capability, which can answer the call but not
+/// be copied.
+pub fn create_reply_cap(reply: Arc<Reply>) -> RawCapability {
+    RawCapability {
+        cap_type: CapabilityType::Reply,
+        object_ptr: Arc::into_raw(reply) as usize,
+        rights: Rights::WRITE,
+        badge: 0,
+        generation: 0,
+    }
+}
+
+/// Turn a reply capability taken out of its slot back into the object,
+/// taking over the capability's reference. Other capabilities are
+/// released.
+pub fn take_reply(cap: RawCapability) -> Option<Arc<Reply>> {
+    if cap.cap_type != CapabilityType::Reply {
+        object::release(cap);
+        return None;
+    }
+    // SAFETY: Reply capabilities carry a pointer from Arc::into_raw, and
+    // the caller hands over the reference the capability owned.
+    Some(unsafe { Arc::from_raw(cap.object_ptr as *const Reply) })
+}
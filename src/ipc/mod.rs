@@ -0,0 +1,292 @@
+//! Inter-Process Communication
+//!
+//! Synchronous message passing between threads through endpoint objects,
+//! and asynchronous signals through notification objects, in the style of
+//! seL4.
+//!
+//! # Messages
+//! ```text
+//! x0-x7    message words 0-7
+//! x9       endpoint cptr on entry, sender's badge on return
+//! x10      MessageInfo: label (bits 12-63), capabilities (bits 7-8),
+//!          length in words (bits 0-6)
+//! IPC buf  words 8.. of longer messages, at the same index in `msg`;
+//!          cptrs of capabilities to send at `BUFFER_CAPS`, and the
+//!          slot to receive capabilities into at `BUFFER_RECEIVE_SLOT`
+//! ```
+//! - A message has up to `MSG_MAX_WORDS` words. The first
+//!   `MSG_REGISTERS` travel in registers, the rest in the thread's IPC
+//!   buffer page (`tcb_set_ipc_buffer`)
+//! - The kernel copies the message into the receiving thread's
+//!   `IpcState`; each thread only ever touches its own registers and
+//!   IPC buffer, in its own address space
+//!
+//! # Design
+//! - An endpoint queues senders or receivers, whichever arrived first;
+//!   a send meets the oldest receiver and a receive the oldest sender
+//! - `call` sends and waits for an answer. The receiver gets a one-shot
+//!   reply capability in its reply slot (`CapSlot::REPLY`) and lends the
+//!   caller's priority until it replies, or the reply capability is
+//!   deleted
+//! - A notification is a word of signal bits: `signal` ORs in the badge
+//!   of the signaller's capability, `wait` takes the word (sleeping until
+//!   it is non-zero) and `poll` takes it without sleeping
+//! - A thread bound to a notification is also woken by its signals while
+//!   it waits to receive on an endpoint. It then gets an empty message
+//!   flagged `MessageInfo::is_notification`, with the bits as the badge
+//! - A message can carry up to `MAX_TRANSFER_CAPS` capabilities, derived
+//!   from the sender's CSpace when it sends and moved into the
+//!   receiver's when it receives (`cap::transfer`). The received count
+//!   replaces the sent one in the message info
+//! - A thread never holds references to itself, an endpoint or a
+//!   notification while it sleeps; whoever finishes the operation records
+//!   the outcome in its `IpcState` before waking it
+//!
+//! # Locking
+//! Endpoint lock, then notification lock, then a thread's `IpcState`
+//! lock, then scheduler locks.
+//!
+//! # Security Properties
+//! - Sending needs WRITE on the endpoint capability, receiving READ
+//! - The badge comes from the sender's capability, so a receiver can tell
+//!   clients apart; a sender cannot choose it
+//! - A reply capability answers exactly one call and cannot be copied
+//! - Capabilities are only sent through an endpoint capability with
+//!   GRANT, and arrive with at most its rights; a reply carries them only
+//!   if the server received the call through one with GRANT
+//! - The receiver chooses where received capabilities go, or refuses
+//!   them with a null receive slot
+
+pub mod endpoint;
+pub mod notification;
+
+use alloc::sync::{Arc, Weak};
+
+use crate::cap::transfer::{CapTransfer, MAX_TRANSFER_CAPS};
+use crate::cap::Rights;
+use crate::mm::PAGE_SIZE;
+use crate::sched;
+use endpoint::{Endpoint, Reply};
+use notification::Notification;
+
+/// Message words passed in registers (x0-x7).
+pub const MSG_REGISTERS: usize = 8;
+
+/// Longest message, in words.
+pub const MSG_MAX_WORDS: usize = 64;
+
+/// IPC buffer word index of the cptrs of capabilities to send.
+pub const BUFFER_CAPS: usize = MSG_MAX_WORDS;
+
+/// IPC buffer word index of the cptr of the first slot to receive
+/// capabilities into (0 for none); its depth follows (0 for 64).
+pub const BUFFER_RECEIVE_SLOT: usize = BUFFER_CAPS + MAX_TRANSFER_CAPS;
+
+/// IPC buffer words the kernel uses.
+pub const BUFFER_WORDS: usize = BUFFER_RECEIVE_SLOT + 2;
+
+const _: () = assert!(BUFFER_WORDS * 8 <= PAGE_SIZE);
+
+/// Label and length of a message, as passed in x10.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MessageInfo(u64);
+
+impl MessageInfo {
+    const LENGTH_BITS: u32 = 7;
+    const CAPS_SHIFT: u32 = 7;
+    const CAPS_MASK: u64 = 0b11 << Self::CAPS_SHIFT;
+    const NOTIFICATION: u64 = 1 << 11;
+    const LABEL_SHIFT: u32 = 12;
+
+    /// Decode a user-supplied message info word.
+    ///
+    /// Returns None if the length is too long or reserved bits are set.
+    pub fn from_raw(raw: u64) -> Option<Self> {
+        let info = Self(raw);
+        let reserved = (1 << Self::LABEL_SHIFT) - (1 << Self::LENGTH_BITS) - Self::CAPS_MASK;
+        let valid = info.length() <= MSG_MAX_WORDS && info.caps() <= MAX_TRANSFER_CAPS;
+        (valid && raw & reserved == 0).then_some(info)
+    }
+
+    /// Raw value for x10.
+    #[inline]
+    pub const fn raw(self) -> u64 {
+        self.0
+    }
+
+    /// Number of message words.
+    #[inline]
+    pub const fn length(self) -> usize {
+        (self.0 & ((1 << Self::LENGTH_BITS) - 1)) as usize
+    }
+
+    /// The same info with `length` words.
+    pub fn with_length(self, length: usize) -> Self {
+        debug_assert!(length <= MSG_MAX_WORDS);
+        Self(self.0 & !((1 << Self::LENGTH_BITS) - 1) | length as u64)
+    }
+
+    /// Number of capabilities sent with the message.
+    #[inline]
+    pub const fn caps(self) -> usize {
+        ((self.0 & Self::CAPS_MASK) >> Self::CAPS_SHIFT) as usize
+    }
+
+    /// The same info with `caps` capabilities.
+    pub fn with_caps(self, caps: usize) -> Self {
+        debug_assert!(caps <= MAX_TRANSFER_CAPS);
+        Self(self.0 & !Self::CAPS_MASK | (caps as u64) << Self::CAPS_SHIFT)
+    }
+}
+
+/// A message in transit.
+#[derive(Debug)]
+pub struct Message {
+    pub info: MessageInfo,
+    /// Words beyond `info.length()` are undefined.
+    pub words: [u64; MSG_MAX_WORDS],
+    /// Capabilities sent with the message, if any.
+    pub caps: Option<CapTransfer>,
+}
+
+impl Message {
+    /// An empty message.
+    pub const fn empty() -> Self {
+        Self {
+            info: MessageInfo(0),
+            words: [0; MSG_MAX_WORDS],
+            caps: None,
+        }
+    }
+
+    /// What a receive gets when a bound notification wakes it.
+    pub const fn notification() -> Self {
+        Self {
+            info: MessageInfo(MessageInfo::NOTIFICATION),
+            words: [0; MSG_MAX_WORDS],
+            caps: None,
+        }
+    }
+}
+
+/// Error type for IPC operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcError {
+    /// The endpoint or the caller went away before the message could be
+    /// delivered or answered.
+    Canceled,
+    /// The thread or notification is bound already.
+    Bound,
+}
+
+impl core::fmt::Display for IpcError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Canceled => write!(f, "IPC canceled"),
+            Self::Bound => write!(f, "Already bound"),
+        }
+    }
+}
+
+/// A thread's side of IPC: what it sends while queued, and what it
+/// received once woken.
+pub struct IpcState {
+    msg: Message,
+    badge: u64,
+    /// A queued sender waits for a reply after delivery.
+    call: bool,
+    /// Outcome, set by whoever completes the operation; `None` while the
+    /// thread waits.
+    status: Option<Result<(), IpcError>>,
+    /// Call to answer, handed to a receiver with the message.
+    reply: Option<Arc<Reply>>,
+    /// Rights of the endpoint capability a queued receiver receives
+    /// through.
+    rights: Rights,
+    /// Endpoint the thread is queued on to receive. Taken by whoever
+    /// ends the receive, so it is ended once.
+    receiving: Option<Weak<Endpoint>>,
+    /// Notification whose signals also end a receive.
+    bound: Option<Arc<Notification>>,
+}
+
+impl IpcState {
+    pub const fn new() -> Self {
+        Self {
+            msg: Message::empty(),
+            badge: 0,
+            call: false,
+            status: None,
+            reply: None,
+            rights: Rights::NONE,
+            receiving: None,
+            bound: None,
+        }
+    }
+
+    /// Take a message, its badge and the call to answer, if any.
+    fn deliver(&mut self, msg: Message, badge: u64, reply: Option<Arc<Reply>>) {
+        // Dropping an unanswered reply here would take a second IPC lock
+        debug_assert!(self.reply.is_none());
+        self.msg = msg;
+        self.badge = badge;
+        self.reply = reply;
+        self.status = Some(Ok(()));
+    }
+}
+
+/// Sleep until the IPC operation the current thread is blocked in has
+/// completed, and return its outcome.
+fn sleep() -> Result<(), IpcError> {
+    loop {
+        sched::schedule();
+        let current = sched::current().expect("IPC without a current thread");
+        let mut ipc = current.ipc();
+        if let Some(status) = ipc.status.take() {
+            return status;
+        }
+        // Woken for something else: keep waiting, under the lock the
+        // status is set under
+        drop(sched::block_current());
+    }
+}
+
+/// Take the message delivered to the current thread.
+fn take_received() -> Received {
+    let current = sched::current().expect("IPC without a current thread");
+    let mut ipc = current.ipc();
+    Received {
+        msg: core::mem::replace(&mut ipc.msg, Message::empty()),
+        badge: ipc.badge,
+        reply: ipc.reply.take(),
+    }
+}
+
+/// What a receive or call returns.
+#[derive(Debug)]
+pub struct Received {
+    pub msg: Message,
+    pub badge: u64,
+    /// For receives of a call: the one-shot capability to answer it.
+    pub reply: Option<Arc<Reply>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_info_round_trips() {
+        let raw = 0xBEEF << MessageInfo::LABEL_SHIFT | MSG_MAX_WORDS as u64;
+        let info = MessageInfo::from_raw(raw).unwrap();
+        assert_eq!((info.raw(), info.length()), (raw, MSG_MAX_WORDS));
+        assert!(MessageInfo::from_raw(MSG_MAX_WORDS as u64 + 1).is_none());
+        assert!(MessageInfo::from_raw(1 << 9).is_none());
+        assert_eq!(info.with_caps(MAX_TRANSFER_CAPS).caps(), MAX_TRANSFER_CAPS);
+        assert_eq!(info.with_caps(2).with_caps(1).caps(), 1);
+        assert_eq!(info.with_length(3).raw() >> MessageInfo::LABEL_SHIFT, 0xBEEF);
+        let info = Message::notification().info.with_length(3);
+        assert_eq!(info.raw(), MessageInfo::NOTIFICATION | 3);
+        assert!(MessageInfo::from_raw(info.raw()).is_none());
+    }
+}
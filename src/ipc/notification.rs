@@ -0,0 +1,192 @@
+//! Notifications
+//!
+//! A `Notification` is a word of signal bits for asynchronous events:
+//! signalling never blocks, and signals that arrive while nobody waits
+//! accumulate in the word until it is taken.
+//!
+//! # Security Properties
+//! - The bits a signal sets come from the signaller's capability badge,
+//!   so a waiter can tell signallers apart
+//! - A notification is bound to at most one thread and a thread to at
+//!   most one notification
+//! - Deleting the last capability to a notification fails every waiter
+//!   with `Canceled`
+
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use super::endpoint::pop_live;
+use super::{sleep, take_received, IpcError, Message};
+use crate::cap::capability::{CapabilityType, Rights};
+use crate::cap::cspace::RawCapability;
+use crate::lock_class;
+use crate::sched;
+use crate::sync::{TicketLock, TicketLockGuard};
+use crate::thread::Thread;
+
+/// Signal word and waiters of a notification.
+#[derive(Default)]
+pub(super) struct Signals {
+    /// Bits signalled since the word was last taken.
+    word: u64,
+    /// Threads blocked in `wait`; only queued while the word is zero.
+    waiters: VecDeque<Arc<Thread>>,
+    /// Thread woken from an endpoint receive by signals.
+    bound: Weak<Thread>,
+}
+
+impl Signals {
+    /// Take the pending bits, if any.
+    pub(super) fn take(&mut self) -> Option<u64> {
+        match core::mem::take(&mut self.word) {
+            0 => None,
+            bits => Some(bits),
+        }
+    }
+}
+
+/// A notification object.
+pub struct Notification {
+    signals: TicketLock<Signals>,
+}
+
+impl Notification {
+    /// A notification with no bits set and no waiters.
+    pub fn new() -> Self {
+        Self {
+            signals: TicketLock::new(Signals::default(), lock_class!("notification")),
+        }
+    }
+
+    pub(super) fn lock(&self) -> TicketLockGuard<'_, Signals> {
+        self.signals.lock()
+    }
+
+    /// Signal with `badge`: wake the longest waiter with it, or OR it into
+    /// the word, ending a receive of the bound thread if it is in one.
+    pub fn signal(&self, badge: u64) {
+        let mut dead = Vec::new();
+        let mut signals = self.signals.lock();
+        if let Some(waiter) = pop_live(&mut signals.waiters, &mut dead) {
+            waiter.ipc().deliver(Message::empty(), badge, None);
+            drop(signals);
+            sched::wake(waiter);
+            return;
+        }
+
+        signals.word |= badge;
+        let Some(thread) = signals.bound.upgrade() else {
+            return;
+        };
+        let endpoint = {
+            let mut ipc = thread.ipc();
+            let endpoint = ipc.receiving.take();
+            if endpoint.is_some() {
+                let bits = core::mem::take(&mut signals.word);
+                ipc.deliver(Message::notification(), bits, None);
+            }
+            endpoint
+        };
+        drop(signals);
+
+        if let Some(endpoint) = endpoint {
+            if let Some(endpoint) = endpoint.upgrade() {
+                endpoint.remove_receiver(&thread);
+            }
+            sched::wake(thread);
+        }
+    }
+
+    /// Take the signal bits, sleeping until there are some.
+    pub fn wait(self: Arc<Self>) -> Result<u64, IpcError> {
+        let mut signals = self.signals.lock();
+        if let Some(bits) = signals.take() {
+            return Ok(bits);
+        }
+        let waiter = sched::block_current();
+        waiter.ipc().status = None;
+        signals.waiters.push_back(waiter);
+        drop(signals);
+        drop(self);
+        sleep()?;
+        Ok(take_received().badge)
+    }
+
+    /// Take the signal bits without sleeping; 0 if there are none.
+    pub fn poll(&self) -> u64 {
+        self.signals.lock().take().unwrap_or(0)
+    }
+
+    /// Bind to `thread`, so that signals also end its endpoint receives.
+    pub fn bind(self: &Arc<Self>, thread: &Arc<Thread>) -> Result<(), IpcError> {
+        let mut signals = self.signals.lock();
+        let mut ipc = thread.ipc();
+        if signals.bound.strong_count() != 0 || ipc.bound.is_some() {
+            return Err(IpcError::Bound);
+        }
+        signals.bound = Arc::downgrade(thread);
+        ipc.bound = Some(self.clone());
+        Ok(())
+    }
+}
+
+/// Unbind `thread` from its notification, if it has one.
+pub fn unbind(thread: &Thread) {
+    let Some(notification) = thread.ipc().bound.clone() else {
+        return;
+    };
+    let mut signals = notification.signals.lock();
+    let unbound = {
+        let mut ipc = thread.ipc();
+        // Unless someone else got there first
+        match &ipc.bound {
+            Some(bound) if Arc::ptr_eq(bound, &notification) => ipc.bound.take(),
+            _ => None,
+        }
+    };
+    if unbound.is_some() {
+        signals.bound = Weak::new();
+    }
+    drop(signals);
+    drop(unbound);
+}
+
+impl Drop for Notification {
+    fn drop(&mut self) {
+        let signals = core::mem::take(&mut *self.signals.lock());
+        for thread in signals.waiters {
+            thread.ipc().status = Some(Err(IpcError::Canceled));
+            sched::wake(thread);
+        }
+    }
+}
+
+/// Wrap `notification` in a capability.
+///
+/// The returned capability owns one reference to the object.
+pub fn create_cap(notification: Notification, rights: Rights) -> RawCapability {
+    RawCapability {
+        cap_type: CapabilityType::Notification,
+        object_ptr: Arc::into_raw(Arc::new(notification)) as usize,
+        rights,
+        badge: 0,
+        generation: 0,
+    }
+}
+
+/// The notification a capability refers to, if it is a notification
+/// capability.
+pub fn from_cap(cap: &RawCapability) -> Option<Arc<Notification>> {
+    if cap.cap_type != CapabilityType::Notification {
+        return None;
+    }
+    let ptr = cap.object_ptr as *const Notification;
+    // SAFETY: Notification capabilities carry a pointer from
+    // Arc::into_raw and own a reference, so the count is at least one
+    // while `cap` exists.
+    unsafe {
+        Arc::increment_strong_count(ptr);
+        Some(Arc::from_raw(ptr))
+    }
+}
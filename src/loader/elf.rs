@@ -0,0 +1,346 @@
+//! ELF64 Parsing
+//!
+//! Reads just enough of an ELF64 AArch64 executable to load it: the file
+//! header and the `PT_LOAD` program headers. Section headers, symbols and
+//! dynamic linking are ignored.
+//!
+//! # Security Properties
+//! Everything in the file is untrusted. Before a segment is handed to the
+//! loader it has been checked that:
+//! - All offsets and sizes stay inside the file, with checked arithmetic
+//! - The segment lies inside the allowed user range (never the kernel
+//!   half or page 0) and `p_filesz <= p_memsz`
+//! - No two segments share a page
+//! - No segment is both writable and executable
+//! - The entry point lies in an executable segment
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+use crate::mm::address::PAGE_SIZE;
+
+/// `\x7fELF`
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+/// 64-bit objects.
+const ELFCLASS64: u8 = 2;
+/// Little-endian data.
+const ELFDATA2LSB: u8 = 1;
+/// Current ELF version.
+const EV_CURRENT: u8 = 1;
+/// Executable file.
+const ET_EXEC: u16 = 2;
+/// AArch64.
+const EM_AARCH64: u16 = 183;
+
+/// Size of the ELF64 file header.
+const EHDR_SIZE: usize = 64;
+/// Size of an ELF64 program header.
+const PHDR_SIZE: usize = 56;
+
+/// Loadable segment.
+const PT_LOAD: u32 = 1;
+
+/// Segment flags.
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+/// Most program headers accepted.
+const MAX_PHDRS: usize = 64;
+
+/// Errors from parsing an ELF image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// Shorter than the headers it claims to have.
+    Truncated,
+    /// Missing `\x7fELF`.
+    BadMagic,
+    /// Not a little-endian ELF64 AArch64 executable.
+    Unsupported,
+    /// Malformed program header table.
+    BadProgramHeaders,
+    /// Segment data outside the file, or `p_filesz > p_memsz`.
+    BadSegment,
+    /// Segment outside the allowed user address range.
+    BadAddress,
+    /// Two segments share a page.
+    Overlap,
+    /// Segment is both writable and executable.
+    WriteExecute,
+    /// No loadable segments.
+    NoSegments,
+    /// Entry point is not in an executable segment.
+    BadEntry,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "Truncated ELF file"),
+            Self::BadMagic => write!(f, "Not an ELF file"),
+            Self::Unsupported => write!(f, "Not an ELF64 AArch64 executable"),
+            Self::BadProgramHeaders => write!(f, "Malformed program headers"),
+            Self::BadSegment => write!(f, "Segment exceeds file"),
+            Self::BadAddress => write!(f, "Segment outside user space"),
+            Self::Overlap => write!(f, "Overlapping segments"),
+            Self::WriteExecute => write!(f, "Writable and executable segment"),
+            Self::NoSegments => write!(f, "No loadable segments"),
+            Self::BadEntry => write!(f, "Entry point not in executable segment"),
+        }
+    }
+}
+
+/// Access rights of a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    /// Read + execute.
+    Code,
+    /// Read + write.
+    Data,
+    /// Read only.
+    ReadOnly,
+}
+
+/// A validated `PT_LOAD` segment.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    /// Virtual address of the first byte.
+    pub vaddr: usize,
+    /// Size in memory; bytes past `data` are zero (.bss).
+    pub mem_size: usize,
+    /// File contents.
+    pub data: &'a [u8],
+    /// Access rights.
+    pub kind: SegmentKind,
+}
+
+impl Segment<'_> {
+    /// Page-aligned address range covered by the segment.
+    pub fn pages(&self) -> Range<usize> {
+        let start = self.vaddr & !(PAGE_SIZE - 1);
+        // Cannot overflow: the end was checked against the user range
+        let end = (self.vaddr + self.mem_size).next_multiple_of(PAGE_SIZE);
+        start..end
+    }
+}
+
+/// A validated ELF executable.
+#[derive(Debug)]
+pub struct ElfFile<'a> {
+    /// Entry point.
+    pub entry: usize,
+    /// Loadable segments, in file order.
+    pub segments: Vec<Segment<'a>>,
+}
+
+/// Read a little-endian integer at `offset`.
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N], ElfError> {
+    let end = offset.checked_add(N).ok_or(ElfError::Truncated)?;
+    let field = bytes.get(offset..end).ok_or(ElfError::Truncated)?;
+    // Length is exactly N by construction
+    Ok(field.try_into().unwrap_or([0; N]))
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    read(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    read(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn read_usize(bytes: &[u8], offset: usize) -> Result<usize, ElfError> {
+    let value = read(bytes, offset).map(u64::from_le_bytes)?;
+    usize::try_from(value).map_err(|_| ElfError::BadAddress)
+}
+
+impl<'a> ElfFile<'a> {
+    /// Parse and validate `bytes`, accepting segments inside `user`.
+    pub fn parse(bytes: &'a [u8], user: Range<usize>) -> Result<Self, ElfError> {
+        if bytes.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if bytes[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if bytes[4] != ELFCLASS64
+            || bytes[5] != ELFDATA2LSB
+            || bytes[6] != EV_CURRENT
+            || read_u16(bytes, 16)? != ET_EXEC
+            || read_u16(bytes, 18)? != EM_AARCH64
+        {
+            return Err(ElfError::Unsupported);
+        }
+
+        let entry = read_usize(bytes, 24)?;
+        let phoff = read_usize(bytes, 32)?;
+        let phentsize = read_u16(bytes, 54)? as usize;
+        let phnum = read_u16(bytes, 56)? as usize;
+
+        if phentsize != PHDR_SIZE || phnum == 0 || phnum > MAX_PHDRS {
+            return Err(ElfError::BadProgramHeaders);
+        }
+        let table_end = phnum
+            .checked_mul(PHDR_SIZE)
+            .and_then(|size| size.checked_add(phoff))
+            .ok_or(ElfError::BadProgramHeaders)?;
+        if table_end > bytes.len() {
+            return Err(ElfError::Truncated);
+        }
+
+        let mut segments: Vec<Segment<'a>> = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff + i * PHDR_SIZE;
+            if read_u32(bytes, ph)? != PT_LOAD {
+                continue;
+            }
+            let segment = Self::parse_segment(bytes, ph, &user)?;
+            if segment.mem_size == 0 {
+                continue;
+            }
+
+            let pages = segment.pages();
+            if segments
+                .iter()
+                .any(|other| other.pages().start < pages.end && pages.start < other.pages().end)
+            {
+                return Err(ElfError::Overlap);
+            }
+            segments.push(segment);
+        }
+
+        if segments.is_empty() {
+            return Err(ElfError::NoSegments);
+        }
+
+        let entry_ok = segments.iter().any(|s| {
+            s.kind == SegmentKind::Code && (s.vaddr..s.vaddr + s.mem_size).contains(&entry)
+        });
+        if !entry_ok || entry % 4 != 0 {
+            return Err(ElfError::BadEntry);
+        }
+
+        Ok(Self { entry, segments })
+    }
+
+    /// Validate the program header at `ph`.
+    fn parse_segment(bytes: &'a [u8], ph: usize, user: &Range<usize>) -> Result<Segment<'a>, ElfError> {
+        let flags = read_u32(bytes, ph + 4)?;
+        let offset = read_usize(bytes, ph + 8)?;
+        let vaddr = read_usize(bytes, ph + 16)?;
+        let file_size = read_usize(bytes, ph + 32)?;
+        let mem_size = read_usize(bytes, ph + 40)?;
+
+        if file_size > mem_size {
+            return Err(ElfError::BadSegment);
+        }
+        let data_end = offset.checked_add(file_size).ok_or(ElfError::BadSegment)?;
+        let data = bytes.get(offset..data_end).ok_or(ElfError::BadSegment)?;
+
+        let end = vaddr.checked_add(mem_size).ok_or(ElfError::BadAddress)?;
+        let page_end = end.checked_next_multiple_of(PAGE_SIZE).ok_or(ElfError::BadAddress)?;
+        if vaddr < user.start || page_end > user.end {
+            return Err(ElfError::BadAddress);
+        }
+
+        let kind = match (flags & PF_W != 0, flags & PF_X != 0) {
+            (true, true) => return Err(ElfError::WriteExecute),
+            (false, true) => SegmentKind::Code,
+            (true, false) => SegmentKind::Data,
+            (false, false) if flags & PF_R != 0 => SegmentKind::ReadOnly,
+            // No access at all: nothing useful to map
+            (false, false) => return Err(ElfError::BadSegment),
+        };
+
+        Ok(Segment {
+            vaddr,
+            mem_size,
+            data,
+            kind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: Range<usize> = 0x1000..0x0000_7FFF_FFFF_0000;
+
+    /// Minimal executable with the given segments `(vaddr, filesz, memsz, flags)`.
+    fn image(entry: u64, segments: &[(u64, u64, u64, u32)]) -> Vec<u8> {
+        let mut elf = Vec::new();
+        elf.extend_from_slice(&ELF_MAGIC);
+        elf.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, EV_CURRENT, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        elf.extend_from_slice(&ET_EXEC.to_le_bytes());
+        elf.extend_from_slice(&EM_AARCH64.to_le_bytes());
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        elf.extend_from_slice(&entry.to_le_bytes());
+        elf.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes()); // phoff
+        elf.extend_from_slice(&0u64.to_le_bytes()); // shoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // flags
+        elf.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+        elf.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        elf.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+        elf.extend_from_slice(&[0; 6]);
+
+        for &(vaddr, filesz, memsz, flags) in segments {
+            elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+            elf.extend_from_slice(&flags.to_le_bytes());
+            elf.extend_from_slice(&0u64.to_le_bytes()); // offset
+            elf.extend_from_slice(&vaddr.to_le_bytes());
+            elf.extend_from_slice(&vaddr.to_le_bytes());
+            elf.extend_from_slice(&filesz.to_le_bytes());
+            elf.extend_from_slice(&memsz.to_le_bytes());
+            elf.extend_from_slice(&(PAGE_SIZE as u64).to_le_bytes());
+        }
+        elf
+    }
+
+    #[test]
+    fn test_valid_executable() {
+        let elf = image(0x40_0000, &[(0x40_0000, 64, 64, PF_R | PF_X), (0x41_0000, 0, 0x2000, PF_R | PF_W)]);
+        let parsed = ElfFile::parse(&elf, USER).unwrap();
+        assert_eq!(parsed.entry, 0x40_0000);
+        assert_eq!(parsed.segments.len(), 2);
+        assert_eq!(parsed.segments[1].kind, SegmentKind::Data);
+        assert_eq!(parsed.segments[1].pages(), 0x41_0000..0x41_2000);
+    }
+
+    #[test]
+    fn test_rejects_unsafe_segments() {
+        let wx = image(0x40_0000, &[(0x40_0000, 64, 64, PF_R | PF_W | PF_X)]);
+        assert_eq!(ElfFile::parse(&wx, USER).unwrap_err(), ElfError::WriteExecute);
+
+        let kernel = image(0x40_0000, &[(0xFFFF_0000_4000_0000, 64, 64, PF_R | PF_X)]);
+        assert_eq!(ElfFile::parse(&kernel, USER).unwrap_err(), ElfError::BadAddress);
+
+        let wrap = image(0x40_0000, &[(0x40_0000, 64, u64::MAX, PF_R | PF_X)]);
+        assert_eq!(ElfFile::parse(&wrap, USER).unwrap_err(), ElfError::BadAddress);
+
+        let overlap = image(
+            0x40_0000,
+            &[(0x40_0000, 64, 64, PF_R | PF_X), (0x40_0800, 0, 64, PF_R | PF_W)],
+        );
+        assert_eq!(ElfFile::parse(&overlap, USER).unwrap_err(), ElfError::Overlap);
+
+        let past_file = image(0x40_0000, &[(0x40_0000, 0x10_0000, 0x10_0000, PF_R | PF_X)]);
+        assert_eq!(ElfFile::parse(&past_file, USER).unwrap_err(), ElfError::BadSegment);
+    }
+
+    #[test]
+    fn test_rejects_bad_headers() {
+        let mut elf = image(0x40_0000, &[(0x40_0000, 64, 64, PF_R | PF_X)]);
+        assert_eq!(ElfFile::parse(&elf[..40], USER).unwrap_err(), ElfError::Truncated);
+
+        let bad_entry = image(0x50_0000, &[(0x40_0000, 64, 64, PF_R | PF_X)]);
+        assert_eq!(ElfFile::parse(&bad_entry, USER).unwrap_err(), ElfError::BadEntry);
+
+        elf[56] = 0xFF; // phnum
+        assert_eq!(ElfFile::parse(&elf, USER).unwrap_err(), ElfError::BadProgramHeaders);
+
+        elf[0] = 0;
+        assert_eq!(ElfFile::parse(&elf, USER).unwrap_err(), ElfError::BadMagic);
+    }
+}
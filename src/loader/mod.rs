@@ -0,0 +1,349 @@
+//! User Program Loader
+//!
+//! Builds user processes from ELF64 executables: a fresh address space
+//! with the program's segments and a stack, a `Process` around it and a
+//! first thread that enters EL0 at the entry point. Programs come from
+//! the initramfs, with an init program built into the kernel as the
+//! fallback.
+//!
+//! # User Address Space Layout
+//! ```text
+//! 0x0000_0000_0000_0000 ┌─────────────────────┐
+//!                       │ unmapped            │  null guard
+//! 0x0000_0000_0000_1000 ├─────────────────────┤
+//!                       │ PT_LOAD segments    │  R-X / RW- / R--
+//!                       │ (at their p_vaddr)  │  from p_flags
+//!                       ├─────────────────────┤
+//!                       │ unmapped            │  at least one page
+//!                       ├─────────────────────┤
+//!                       │ stack (16 KiB)      │  RW-, grows down
+//! USER_STACK_TOP        └─────────────────────┘
+//! ```
+//!
+//! # Security Properties
+//! - Headers are validated before anything is mapped (see `elf`)
+//! - Segments are copied into fresh frames; user code never sees kernel
+//!   memory or another process's pages
+//! - Code is mapped read-only and executable, data writable and
+//!   non-executable (W^X), all PXN; .bss is zero-filled
+//! - The stack has no mapping below it, so overflowing it faults
+
+pub mod elf;
+
+use alloc::sync::Arc;
+use core::arch::global_asm;
+use core::fmt;
+use core::ops::Range;
+
+use crate::initramfs;
+use crate::kprintln;
+use crate::mm::address::{phys_to_kernel_virt, PAGE_SIZE};
+use crate::mm::frame::alloc_frame_zeroed;
+use crate::mm::untyped::{self, Untyped};
+use crate::cap::{object, CapSlot, Rights};
+use crate::mm::{free_frame, AddressSpace, MappingError, PageFlags, VirtAddr};
+use crate::process::{self, ExitReason, Process, ProcessError};
+use crate::sched;
+use crate::thread::{self, Thread, ThreadError, MAX_PRIORITY};
+
+use elf::{ElfError, ElfFile, SegmentKind};
+
+// Built-in init program
+global_asm!(include_str!("init.S"));
+
+/// One past the highest user stack address.
+pub const USER_STACK_TOP: usize = 0x0000_8000_0000_0000;
+
+/// Size of the initial user stack.
+pub const USER_STACK_SIZE: usize = 16 * 1024;
+
+/// CSpace slot of the capability to a program's own first thread.
+pub const THREAD_SLOT: CapSlot = CapSlot::FIRST_USER;
+
+/// CSpace slot of the first untyped memory capability given to init.
+pub const FIRST_UNTYPED_SLOT: CapSlot = match THREAD_SLOT.offset(1) {
+    Some(slot) => slot,
+    None => panic!("no room for untyped capabilities"),
+};
+
+/// Most untyped memory blocks given to init.
+const ROOT_UNTYPED_COUNT: usize = 4;
+
+/// Log2 of the size of each block given to init (4 MiB).
+const ROOT_UNTYPED_BITS: u8 = 22;
+
+/// Where program segments may be loaded: above the null guard page and
+/// below the stack, leaving an unmapped page under the stack.
+const USER_IMAGE_RANGE: Range<usize> = PAGE_SIZE..USER_STACK_TOP - USER_STACK_SIZE - PAGE_SIZE;
+
+extern "C" {
+    static __user_init_start: u8;
+    static __user_init_end: u8;
+}
+
+/// Errors from loading a user program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The image is not a loadable executable.
+    Elf(ElfError),
+    /// Building the address space failed.
+    Mapping(MappingError),
+    /// Creating the process failed.
+    Process(ProcessError),
+    /// Creating the first thread failed.
+    Thread(ThreadError),
+}
+
+impl From<ElfError> for LoadError {
+    fn from(e: ElfError) -> Self {
+        Self::Elf(e)
+    }
+}
+
+impl From<MappingError> for LoadError {
+    fn from(e: MappingError) -> Self {
+        Self::Mapping(e)
+    }
+}
+
+impl From<ProcessError> for LoadError {
+    fn from(e: ProcessError) -> Self {
+        Self::Process(e)
+    }
+}
+
+impl From<ThreadError> for LoadError {
+    fn from(e: ThreadError) -> Self {
+        Self::Thread(e)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Elf(e) => write!(f, "Invalid executable: {}", e),
+            Self::Mapping(e) => write!(f, "Mapping failed: {}", e),
+            Self::Process(e) => write!(f, "Process creation failed: {}", e),
+            Self::Thread(e) => write!(f, "Thread creation failed: {}", e),
+        }
+    }
+}
+
+/// The built-in init program.
+fn init_image() -> &'static [u8] {
+    // SAFETY: init.S defines both symbols around the program in .rodata.
+    unsafe {
+        let start = &raw const __user_init_start;
+        let end = &raw const __user_init_end;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// Make instructions written through the direct map visible to fetches.
+fn sync_icache(virt: VirtAddr, len: usize) {
+    // SAFETY: Cache maintenance on a mapped kernel range; no memory is
+    // changed. 64 bytes is the minimum line size on ARMv8 cores; lines
+    // are smaller than a page, so stepping by it covers every line.
+    unsafe {
+        let mut addr = virt.as_usize() & !63;
+        while addr < virt.as_usize() + len {
+            core::arch::asm!("dc cvau, {}", in(reg) addr, options(nostack, preserves_flags));
+            addr += 64;
+        }
+        core::arch::asm!("dsb ish", "ic ialluis", "dsb ish", "isb", options(nostack, preserves_flags));
+    }
+}
+
+/// Map fresh frames covering `size` bytes at the page-aligned `base`
+/// with `flags`, and copy `data` to `base + offset`.
+///
+/// Every other byte is zero. On failure, pages mapped so far stay in
+/// `vspace` and are released with it.
+pub fn map_bytes(
+    vspace: &mut AddressSpace,
+    base: VirtAddr,
+    offset: usize,
+    data: &[u8],
+    size: usize,
+    flags: PageFlags,
+) -> Result<(), MappingError> {
+    debug_assert!(offset + data.len() <= size);
+
+    for page in (0..size).step_by(PAGE_SIZE) {
+        let frame = alloc_frame_zeroed()?;
+
+        // Part of `data` that falls into this page
+        let from = page.max(offset);
+        let to = (page + PAGE_SIZE).min(offset + data.len());
+        if from < to {
+            let chunk = &data[from - offset..to - offset];
+            let kernel = phys_to_kernel_virt(frame).add(from - page);
+            // SAFETY: The frame is freshly allocated, direct-mapped and
+            // owned by us until it is mapped below; the chunk ends inside
+            // the page.
+            unsafe {
+                core::ptr::copy_nonoverlapping(chunk.as_ptr(), kernel.as_mut_ptr::<u8>(), chunk.len());
+            }
+        }
+        if flags.is_user_executable() {
+            sync_icache(phys_to_kernel_virt(frame), PAGE_SIZE);
+        }
+
+        // The mapping takes its own reference; drop the allocation's
+        let result = vspace.map_page(base.add(page), frame, flags);
+        free_frame(frame);
+        result?;
+    }
+
+    Ok(())
+}
+
+/// Map a zeroed user stack ending at `top`; returns the initial SP.
+fn map_stack(vspace: &mut AddressSpace, top: VirtAddr, size: usize) -> Result<VirtAddr, MappingError> {
+    let bottom = VirtAddr::new(top.as_usize() - size);
+    map_bytes(vspace, bottom, 0, &[], size, PageFlags::USER_DATA)?;
+    Ok(top)
+}
+
+/// Build a process from `vspace`, give it a stack and start a thread at
+/// `entry`.
+///
+/// The thread runs at the default priority, may raise priorities up to
+/// `mcp`, and finds a capability to itself in `THREAD_SLOT`.
+pub fn start_process(
+    name: &str,
+    mut vspace: AddressSpace,
+    entry: VirtAddr,
+    mcp: u8,
+) -> Result<Arc<Process>, LoadError> {
+    let stack = map_stack(&mut vspace, VirtAddr::new(USER_STACK_TOP), USER_STACK_SIZE)?;
+    let process = Process::new(name, vspace)?;
+
+    let thread = match Thread::new_user(process.clone(), entry, stack, 0) {
+        Ok(thread) => thread,
+        Err(e) => {
+            process.terminate(ExitReason::Killed);
+            return Err(e.into());
+        }
+    };
+    thread.set_mcp(mcp);
+    // The CSpace is fresh, so the slot is free
+    let cap = thread::create_cap(thread.clone(), Rights::ALL);
+    process.with_cspace(|cspace| cspace.insert(THREAD_SLOT, cap));
+    sched::spawn(thread);
+
+    Ok(process)
+}
+
+/// Load an ELF64 executable into a new process and start it at its
+/// entry point, with maximum controlled priority `mcp`.
+pub fn spawn_elf(name: &str, image: &[u8], mcp: u8) -> Result<Arc<Process>, LoadError> {
+    let elf = ElfFile::parse(image, USER_IMAGE_RANGE)?;
+
+    let mut vspace = AddressSpace::new()?;
+    for segment in &elf.segments {
+        let pages = segment.pages();
+        let flags = match segment.kind {
+            SegmentKind::Code => PageFlags::USER_CODE,
+            SegmentKind::Data => PageFlags::USER_DATA,
+            SegmentKind::ReadOnly => PageFlags::USER_RODATA,
+        };
+        // File bytes first, then zeros up to mem_size (.bss)
+        map_bytes(
+            &mut vspace,
+            VirtAddr::new(pages.start),
+            segment.vaddr - pages.start,
+            segment.data,
+            pages.len(),
+            flags,
+        )?;
+    }
+
+    start_process(name, vspace, VirtAddr::new(elf.entry), mcp)
+}
+
+/// Path of the first program in the initramfs.
+const INIT_PATH: &str = "init";
+
+/// Start the first user process: `/init` from the initramfs if there is
+/// one, the built-in init program otherwise. A kernel thread waits for
+/// it to exit and reports the reason.
+pub fn spawn_init() -> Result<Arc<Process>, LoadError> {
+    let (source, image) = match initramfs::lookup(INIT_PATH) {
+        Some(file) => ("initramfs", file.data),
+        None => ("built-in", init_image()),
+    };
+    // init hands out priorities to the rest of the system
+    let process = spawn_elf(INIT_PATH, image, MAX_PRIORITY)?;
+    kprintln!(
+        "[LOADER] init: {} byte ELF image ({}), stack top 0x{:x}",
+        image.len(),
+        source,
+        USER_STACK_TOP
+    );
+
+    let untyped = grant_untyped(&process);
+    kprintln!(
+        "[LOADER] init: {} x {} KiB untyped from slot {}",
+        untyped,
+        (1 << ROOT_UNTYPED_BITS) / 1024,
+        FIRST_UNTYPED_SLOT.cptr()
+    );
+
+    let arg = Arc::into_raw(process.clone()) as usize;
+    match Thread::new_kernel("init-watch", watch_init, arg) {
+        Ok(watcher) => sched::spawn(watcher),
+        Err(e) => {
+            // SAFETY: The watcher never ran, so the reference is still ours.
+            drop(unsafe { Arc::from_raw(arg as *const Process) });
+            return Err(e.into());
+        }
+    }
+    Ok(process)
+}
+
+/// Kernel thread that sleeps until init exits and reports why.
+///
+/// Nothing restarts init; the kernel keeps running whatever else is
+/// left.
+extern "C" fn watch_init(process: usize) -> ! {
+    // SAFETY: `spawn_init` passes a pointer from Arc::into_raw and hands
+    // its reference over to this thread.
+    let process = unsafe { Arc::from_raw(process as *const Process) };
+    let reason = process.wait();
+    kprintln!(
+        "[LOADER] init (pid {}) is gone: {}; {} processes left",
+        process.pid(),
+        reason,
+        process::count()
+    );
+    drop(process);
+    sched::exit_current()
+}
+
+/// Give `process` untyped memory capabilities in consecutive slots from
+/// `FIRST_UNTYPED_SLOT`, as many as there is memory for, and return how
+/// many.
+///
+/// Everything else the process (and whoever it hands memory to) makes
+/// comes out of these blocks.
+fn grant_untyped(process: &Process) -> usize {
+    let mut granted = 0;
+    while granted < ROOT_UNTYPED_COUNT {
+        let Ok(block) = Untyped::alloc(ROOT_UNTYPED_BITS) else {
+            break;
+        };
+        let slot = FIRST_UNTYPED_SLOT
+            .offset(granted as u64)
+            .expect("untyped slots fit the CSpace");
+        let cap = untyped::create_cap(block, Rights::ALL);
+        match process.with_cspace(|cspace| cspace.insert(slot, cap)) {
+            Some(Ok(())) => granted += 1,
+            _ => {
+                object::release(cap);
+                break;
+            }
+        }
+    }
+    granted
+}
@@ -26,31 +26,53 @@
 
 extern crate alloc;
 
+mod backtrace;
 mod cap;
 mod drivers;
 mod exception;
+mod fdt;
+mod fpsimd;
+mod initramfs;
+mod ipc;
+mod loader;
 mod mm;
+mod percpu;
+mod process;
+mod sched;
 mod security;
+mod smp;
+mod sync;
 mod syscall;
+mod thread;
+mod time;
 
 use core::arch::global_asm;
 use core::panic::PanicInfo;
 
 use drivers::uart::UART;
+use time::Duration;
 
 // Include boot assembly
 global_asm!(include_str!("boot.S"));
 
+// Include the context switch routines
+global_asm!(include_str!("switch.S"));
+
 /// Kernel version string
 const VERSION: &str = "0.2.0";
 
+/// Period of the system tick
+const TICK_PERIOD: Duration = Duration::from_millis(10);
+
 /// Kernel entry point called from boot.S
 ///
+/// `dtb` is the device tree address the boot loader passed in x0.
+///
 /// # Safety
 /// This function is called once from assembly after basic CPU setup.
 /// Stack and BSS are already initialized.
 #[no_mangle]
-pub extern "C" fn kernel_main() -> ! {
+pub extern "C" fn kernel_main(dtb: usize) -> ! {
     // Initialize UART for console output first
     // SAFETY: UART address is guaranteed valid by QEMU virt machine spec
     // Audited: 2025-01-04
@@ -72,14 +94,68 @@ pub extern "C" fn kernel_main() -> ! {
     let heap_size = mm::heap_size() / 1024;
     kprintln!("[BOOT] Heap initialized ({} KiB)", heap_size);
 
+    mm::init_frames();
+    kprintln!(
+        "[BOOT] Frame allocator initialized ({} KiB free)",
+        mm::free_frame_count() * mm::PAGE_SIZE / 1024
+    );
+
+    // Claim the device tree and initrd before anything else allocates
+    // frames
+    if let Some(dt) = fdt::init(dtb) {
+        kprintln!("[BOOT] Device tree found ({} bytes)", dt.size());
+    }
+    initramfs::init();
+
+    // Give the kernel half its own root table, so kernel-only mappings
+    // stay out of the identity map
+    // SAFETY: Called once, before any dynamic kernel mapping.
+    unsafe {
+        mm::mapper::adopt_boot_tables();
+    }
+
     // Initialize exception handling
     exception::init();
 
+    // Continue on a guarded kernel stack, with exceptions on their own
+    exception::stack::init(kernel_init);
+}
+
+/// Second boot stage, running on the guarded boot stack in EL1t.
+extern "C" fn kernel_init() -> ! {
+    kprintln!("[BOOT] Running on guarded kernel stack");
+
+    // Trap FP/SIMD until a thread claims the registers
+    fpsimd::init();
+    kprintln!("[BOOT] FP/SIMD lazy switching enabled");
+
+    // Initialize the interrupt controller (IRQs stay masked for now)
+    drivers::gic::init();
+
+    // Start the periodic system tick
+    if let Err(e) = drivers::timer::init() {
+        panic!("Timer initialization failed: {}", e);
+    }
+    drivers::timer::start_periodic(TICK_PERIOD);
+    kprintln!("[BOOT] System tick every {:?}", TICK_PERIOD);
+
+    // Threads and the idle thread; preemption follows the tick
+    sched::init();
+    kprintln!(
+        "[BOOT] Priority scheduler ready ({} levels, {} tick slices)",
+        thread::NUM_PRIORITIES,
+        thread::TIME_SLICE_TICKS
+    );
+
+    // Start the other CPUs; they park until the scheduler can use them
+    smp::init();
+
     // Report Phase 1 features
     kprintln!();
     kprintln!("[PHASE 1] The Fortress Foundation");
     kprintln!("  - Page Table Types: PageFlags, PageTableEntry");
     kprintln!("  - Address Types: PhysAddr, VirtAddr");
+    kprintln!("  - Address Spaces: AddressSpace, SharedMemory");
     kprintln!("  - Capability System: CSpace, Rights");
     kprintln!("  - Security: SecureWrapper<T>, Zeroize");
     kprintln!();
@@ -88,9 +164,13 @@ pub extern "C" fn kernel_main() -> ! {
     kprintln!();
     kprintln!("[BOOT] Kernel initialization complete");
 
-    // Halt the CPU
-    kprintln!("[BOOT] Halting CPU...");
-    halt();
+    // First user process; it runs once the scheduler starts
+    if let Err(e) = loader::spawn_init() {
+        panic!("Failed to start init: {}", e);
+    }
+
+    // Hand the CPU to the scheduler; idle runs until there is work
+    sched::start();
 }
 
 /// Halt the CPU in a low-power state
@@ -127,6 +207,12 @@ fn panic(info: &PanicInfo) -> ! {
     }
 
     kprintln!("Message: {}", info.message());
+    kprintln!("CPU: {}", smp::cpu_id());
+    kprintln!();
+    backtrace::print();
+    kprintln!();
+    sched::report_stats();
+    process::report();
 
     kprintln!();
     kprintln!("System halted.");
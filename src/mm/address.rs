@@ -27,6 +27,9 @@ pub const KERNEL_VIRT_BASE: usize = 0xFFFF_0000_0000_0000;
 /// Physical memory base for QEMU virt machine
 pub const PHYS_MEM_BASE: usize = 0x4000_0000;
 
+/// End of physical RAM (128MB QEMU virt configuration)
+pub const PHYS_MEM_END: usize = 0x4800_0000;
+
 /// Kernel physical load address
 pub const KERNEL_PHYS_BASE: usize = 0x4008_0000;
 
@@ -147,10 +150,18 @@ impl VirtAddr {
 
     /// Convert an address to canonical form.
     ///
-    /// If bit 47 is set, bits 48-63 must all be 1.
-    /// If bit 47 is clear, bits 48-63 must all be 0.
+    /// With T0SZ = T1SZ = 16, bits 63:48 select the translation table:
+    /// all zeros walk TTBR0 (user), all ones walk TTBR1 (kernel). Such
+    /// addresses are already canonical and are kept as-is, so
+    /// `0xFFFF_0000_xxxx_xxxx` stays a kernel address even though bit 47
+    /// is clear. Anything else is sign-extended from bit 47.
     #[inline]
     const fn make_canonical(addr: usize) -> usize {
+        let top = addr >> 48;
+        if top == 0 || top == 0xFFFF {
+            return addr;
+        }
+
         // Sign-extend from bit 47
         let bit47 = (addr >> 47) & 1;
         if bit47 == 1 {
@@ -259,21 +270,30 @@ impl fmt::Display for VirtAddr {
     }
 }
 
+/// Size of the kernel direct map established by `boot.S`.
+///
+/// TTBR1 maps physical 0..2GB (MMIO and RAM) 1:1 at `KERNEL_VIRT_BASE`.
+pub const DIRECT_MAP_SIZE: usize = 0x8000_0000;
+
 /// Convert a kernel virtual address to its corresponding physical address.
 ///
 /// This only works for addresses in the direct-mapped kernel region.
 #[inline]
 pub const fn kernel_virt_to_phys(virt: VirtAddr) -> PhysAddr {
     debug_assert!(virt.is_kernel());
-    PhysAddr::new_unchecked(virt.as_usize() - KERNEL_VIRT_BASE + PHYS_MEM_BASE)
+    PhysAddr::new_unchecked(virt.as_usize() - KERNEL_VIRT_BASE)
 }
 
 /// Convert a physical address to its kernel virtual address.
 ///
-/// This creates an address in the direct-mapped kernel region.
+/// This creates an address in the direct-mapped kernel region. Unlike the
+/// boot identity map in TTBR0, the direct map stays valid while a user
+/// address space is active, so the kernel must use it for all accesses
+/// to page tables and frames.
 #[inline]
 pub const fn phys_to_kernel_virt(phys: PhysAddr) -> VirtAddr {
-    VirtAddr::new(phys.as_usize() - PHYS_MEM_BASE + KERNEL_VIRT_BASE)
+    debug_assert!(phys.as_usize() < DIRECT_MAP_SIZE);
+    VirtAddr::new(phys.as_usize() + KERNEL_VIRT_BASE)
 }
 
 #[cfg(test)]
@@ -291,6 +311,14 @@ mod tests {
         assert!(kernel.is_kernel());
     }
 
+    #[test]
+    fn test_direct_map_roundtrip() {
+        let phys = PhysAddr::new(0x4020_3000);
+        let virt = phys_to_kernel_virt(phys);
+        assert_eq!(virt.as_usize(), 0xFFFF_0000_4020_3000);
+        assert_eq!(kernel_virt_to_phys(virt), phys);
+    }
+
     #[test]
     fn test_page_alignment() {
         let addr = PhysAddr::new(0x4008_1234);
@@ -1,23 +1,115 @@
 //! Kernel Heap Allocator
 //!
-//! Uses `linked_list_allocator` for heap management.
+//! Uses `linked_list_allocator` for heap management, behind an
+//! `IrqSpinLock`: code running with IRQs masked (the scheduler) and IRQ
+//! handlers allocate too, so a plain spinlock could deadlock.
 //!
 //! # Memory Layout
 //! The heap region is defined by linker symbols:
 //! - `__heap_start`: Beginning of heap
 //! - `__heap_end`: End of available RAM
 //!
+//! # Placement
+//! Kernel objects made from untyped memory (see `cap::retype`) keep
+//! their `Arc` in that memory rather than on the heap, so user space
+//! cannot exhaust the heap by making objects. `with_placement` hands one
+//! allocation the carved block instead of heap memory; freeing a block
+//! outside the heap drops the frame references it holds.
+//!
 //! # Security Considerations
 //! - Heap is initialized once during boot
 //! - All allocations go through Rust's global allocator
 //! - linked_list_allocator provides bounds checking
 
-use core::alloc::Layout;
-use linked_list_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use linked_list_allocator::Heap;
+
+use super::address::{kernel_virt_to_phys, VirtAddr, PAGE_SIZE};
+use super::frame::free_frame;
+use crate::exception;
+use crate::lock_class;
+use crate::percpu::{self, PerCpu};
+use crate::smp::MAX_CPUS;
+use crate::sync::IrqSpinLock;
+
+/// The kernel heap behind an interrupt-safe lock.
+struct KernelHeap(IrqSpinLock<Heap>);
+
+// SAFETY: `Heap` hands out non-overlapping blocks of the requested
+// layout, and the lock serializes every use of it.
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if PLACING.load(Ordering::Acquire) != 0 {
+            if let Some(block) = PLACEMENT.with(|placement| placement.claim(layout)) {
+                return block;
+            }
+        }
+        self.0
+            .lock()
+            .allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !in_heap(ptr) {
+            // A placed block: give up the references on its frames
+            let start = VirtAddr::new(ptr as usize).align_down();
+            let end = VirtAddr::new(ptr as usize + layout.size());
+            for page in (start.as_usize()..end.as_usize()).step_by(PAGE_SIZE) {
+                free_frame(kernel_virt_to_phys(VirtAddr::new(page)));
+            }
+            return;
+        }
+        // SAFETY: The caller passes a block from `alloc` with its layout.
+        unsafe { self.0.lock().deallocate(NonNull::new_unchecked(ptr), layout) };
+    }
+}
+
+/// A block waiting for the next allocation of its layout on one CPU.
+struct Placement {
+    /// Kernel virtual address; 0 if none.
+    addr: AtomicUsize,
+    size: AtomicUsize,
+    align: AtomicUsize,
+}
+
+impl Placement {
+    const fn new() -> Self {
+        Self {
+            addr: AtomicUsize::new(0),
+            size: AtomicUsize::new(0),
+            align: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take the block if it was set aside for `layout`.
+    fn claim(&self, layout: Layout) -> Option<*mut u8> {
+        let addr = self.addr.load(Ordering::Relaxed);
+        if addr == 0
+            || self.size.load(Ordering::Relaxed) != layout.size()
+            || self.align.load(Ordering::Relaxed) != layout.align()
+        {
+            return None;
+        }
+        self.addr.store(0, Ordering::Relaxed);
+        Some(addr as *mut u8)
+    }
+}
+
+/// Block set aside on each CPU by `with_placement`.
+static PLACEMENT: PerCpu<Placement> = PerCpu::new([const { Placement::new() }; MAX_CPUS]);
+
+/// Number of `with_placement` calls in progress, so `alloc` only looks
+/// at its CPU's placement while one may be set.
+static PLACING: AtomicUsize = AtomicUsize::new(0);
 
 /// Global heap allocator instance
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelHeap =
+    KernelHeap(IrqSpinLock::new(Heap::empty(), lock_class!("heap")));
 
 /// Maximum heap size (64 KiB for now, conservative for testing)
 const HEAP_SIZE: usize = 64 * 1024;
@@ -44,10 +136,47 @@ pub fn init_heap() {
     // Audited: 2025-01-04
     unsafe {
         let heap_start = HEAP_MEMORY.as_mut_ptr();
-        ALLOCATOR.lock().init(heap_start, HEAP_SIZE);
+        ALLOCATOR.0.lock().init(heap_start, HEAP_SIZE);
     }
 }
 
+/// Whether `ptr` points into the heap region.
+fn in_heap(ptr: *mut u8) -> bool {
+    let start = ptr::addr_of!(HEAP_MEMORY) as usize;
+    (start..start + HEAP_SIZE).contains(&(ptr as usize))
+}
+
+/// Run `f`, giving the first allocation of `layout` it makes the block at
+/// `block` instead of heap memory. Returns `f`'s result and whether the
+/// block was used.
+///
+/// # Safety
+/// `block` must be a direct map address outside the heap, aligned and
+/// large enough for `layout`, and unused. Each frame under the first
+/// `layout.size()` bytes must carry a reference that freeing the
+/// allocation may drop; if the block goes unused, the references stay
+/// with the caller.
+pub unsafe fn with_placement<R>(block: VirtAddr, layout: Layout, f: impl FnOnce() -> R) -> (R, bool) {
+    debug_assert!(block.as_usize().is_multiple_of(layout.align()));
+    debug_assert!(!in_heap(block.as_usize() as *mut u8));
+    // IRQ handlers allocate too and must not take the block
+    let irqs = exception::save_and_disable_irqs();
+    let guard = percpu::preempt_disable();
+    let placement = PLACEMENT.get(&guard);
+    placement.size.store(layout.size(), Ordering::Relaxed);
+    placement.align.store(layout.align(), Ordering::Relaxed);
+    placement.addr.store(block.as_usize(), Ordering::Relaxed);
+    PLACING.fetch_add(1, Ordering::AcqRel);
+
+    let result = f();
+
+    PLACING.fetch_sub(1, Ordering::AcqRel);
+    let used = placement.addr.swap(0, Ordering::Relaxed) == 0;
+    drop(guard);
+    exception::restore_irqs(irqs);
+    (result, used)
+}
+
 /// Get the size of the kernel heap
 pub fn heap_size() -> usize {
     HEAP_SIZE
@@ -6,15 +6,18 @@
 //! - Each bit in the bitmap represents one 4KB frame
 //! - Bit = 0: frame is free
 //! - Bit = 1: frame is allocated
+//! - A parallel reference count tracks how many owners (page tables,
+//!   kernel objects) hold the frame; it is freed when the count drops to 0
 //!
 //! # Security Properties
 //! - All allocated frames are zeroed before returning
 //! - Double-free is detected and causes a panic
-//! - The allocator is protected by a spinlock
+//! - Shared frames are never freed while still mapped somewhere
+//! - The allocator is protected by a fair ticket lock
 
-use spin::Mutex;
-
-use super::address::{PhysAddr, PAGE_SIZE, PAGE_SHIFT};
+use super::address::{phys_to_kernel_virt, PhysAddr, PAGE_SIZE, PAGE_SHIFT};
+use crate::lock_class;
+use crate::sync::TicketLock;
 
 /// Size of the frame bitmap in bytes.
 /// This covers 64MB of physical memory (enough for early boot).
@@ -32,6 +35,8 @@ const FRAME_START: usize = 0x4020_0000;
 struct FrameAllocatorInner {
     /// Bitmap tracking allocated frames (1 = allocated, 0 = free).
     bitmap: [u8; BITMAP_SIZE],
+    /// Reference count of each allocated frame.
+    refcounts: [u16; MAX_FRAMES],
     /// Number of free frames remaining.
     free_count: usize,
     /// Total frames under management.
@@ -44,6 +49,7 @@ impl FrameAllocatorInner {
     const fn new() -> Self {
         Self {
             bitmap: [0; BITMAP_SIZE],
+            refcounts: [0; MAX_FRAMES],
             free_count: 0,
             total_frames: 0,
             initialized: false,
@@ -67,8 +73,8 @@ impl FrameAllocatorInner {
         self.free_count = self.total_frames;
 
         // Mark all frames as free initially (bitmap is already zeroed)
-        // But mark frames before our usable range as allocated
-        for i in 0..start_frame {
+        // But mark frames outside our usable range as allocated
+        for i in (0..start_frame).chain(end_frame..MAX_FRAMES) {
             self.set_bit(i, true);
         }
 
@@ -124,6 +130,7 @@ impl FrameAllocatorInner {
 
                     if !self.is_allocated(frame) {
                         self.set_bit(frame, true);
+                        self.refcounts[frame] = 1;
                         self.free_count -= 1;
 
                         let addr = PhysAddr::new(FRAME_START + (frame << PAGE_SHIFT));
@@ -136,7 +143,67 @@ impl FrameAllocatorInner {
         None
     }
 
-    /// Free a previously allocated frame.
+    /// Take the free frames in `[start, end)` out of the pool for good.
+    ///
+    /// Each one is left allocated with a single reference that is never
+    /// dropped, so later `frame_ref` / `free_frame` pairs still balance.
+    /// Returns the number of frames reserved.
+    fn reserve(&mut self, start: PhysAddr, end: PhysAddr) -> usize {
+        if !self.initialized {
+            return 0;
+        }
+
+        let mut reserved = 0;
+        let mut addr = start.align_down();
+        while addr < end {
+            if let Some(frame) = Self::frame_index(addr) {
+                if !self.is_allocated(frame) {
+                    self.set_bit(frame, true);
+                    self.refcounts[frame] = 1;
+                    self.free_count -= 1;
+                    reserved += 1;
+                }
+            }
+            addr = addr.add(PAGE_SIZE);
+        }
+        reserved
+    }
+
+    /// Allocate `pages` contiguous frames, aligned to their total size
+    /// (`pages` is a power of two). The frames are not zeroed.
+    fn alloc_aligned(&mut self, pages: usize) -> Option<PhysAddr> {
+        if !self.initialized || self.free_count < pages {
+            return None;
+        }
+
+        let size = pages << PAGE_SHIFT;
+        let mut addr = PhysAddr::new((FRAME_START + size - 1) & !(size - 1));
+        while let Some(first) = Self::frame_index(addr) {
+            let frames = first..first + pages;
+            if frames.end > MAX_FRAMES {
+                break;
+            }
+            if !frames.clone().any(|frame| self.is_allocated(frame)) {
+                for frame in frames {
+                    self.set_bit(frame, true);
+                    self.refcounts[frame] = 1;
+                }
+                self.free_count -= pages;
+                return Some(addr);
+            }
+            addr = addr.add(size);
+        }
+        None
+    }
+
+    /// Get the bitmap index of a managed frame.
+    #[inline]
+    fn frame_index(addr: PhysAddr) -> Option<usize> {
+        let frame = addr.as_usize().checked_sub(FRAME_START)? >> PAGE_SHIFT;
+        (frame < MAX_FRAMES).then_some(frame)
+    }
+
+    /// Drop one reference to a frame, freeing it when none remain.
     fn free(&mut self, addr: PhysAddr) {
         if !self.initialized {
             return;
@@ -146,18 +213,47 @@ impl FrameAllocatorInner {
             panic!("Attempted to free unaligned address: {:?}", addr);
         }
 
-        let frame = (addr.as_usize() - FRAME_START) >> PAGE_SHIFT;
+        let frame = match Self::frame_index(addr) {
+            Some(frame) => frame,
+            None => panic!("Attempted to free frame outside managed range: {:?}", addr),
+        };
 
-        if frame >= MAX_FRAMES {
-            panic!("Attempted to free frame outside managed range: {:?}", addr);
+        if !self.is_allocated(frame) || self.refcounts[frame] == 0 {
+            panic!("Double free detected for frame: {:?}", addr);
         }
 
-        if !self.is_allocated(frame) {
-            panic!("Double free detected for frame: {:?}", addr);
+        self.refcounts[frame] -= 1;
+        if self.refcounts[frame] == 0 {
+            self.set_bit(frame, false);
+            self.free_count += 1;
         }
+    }
 
-        self.set_bit(frame, false);
-        self.free_count += 1;
+    /// Take an additional reference to an allocated frame.
+    ///
+    /// Returns false for frames the allocator does not manage (MMIO,
+    /// kernel image); those are never reference counted.
+    fn get(&mut self, addr: PhysAddr) -> bool {
+        let frame = match Self::frame_index(addr) {
+            Some(frame) if self.initialized && self.refcounts[frame] != 0 => frame,
+            _ => return false,
+        };
+
+        self.refcounts[frame] = self.refcounts[frame]
+            .checked_add(1)
+            .expect("frame reference count overflow");
+        true
+    }
+
+    /// Get the reference count of a frame (0 if free or unmanaged).
+    fn ref_count(&self, addr: PhysAddr) -> usize {
+        Self::frame_index(addr).map_or(0, |frame| self.refcounts[frame] as usize)
+    }
+
+    /// Whether each of the `pages` frames from `start` has exactly `refs`
+    /// references.
+    fn all_held(&self, start: PhysAddr, pages: usize, refs: usize) -> bool {
+        (0..pages).all(|i| self.ref_count(start.add(i * PAGE_SIZE)) == refs)
     }
 
     /// Get the number of free frames.
@@ -167,7 +263,10 @@ impl FrameAllocatorInner {
 }
 
 /// Global frame allocator instance.
-static FRAME_ALLOCATOR: Mutex<FrameAllocatorInner> = Mutex::new(FrameAllocatorInner::new());
+///
+/// Never used from IRQ handlers, so a fair `TicketLock` is enough.
+static FRAME_ALLOCATOR: TicketLock<FrameAllocatorInner> =
+    TicketLock::new(FrameAllocatorInner::new(), lock_class!("frame allocator"));
 
 /// Initialize the frame allocator with the given memory range.
 ///
@@ -178,18 +277,28 @@ pub fn init_frame_allocator(mem_start: PhysAddr, mem_end: PhysAddr) {
     FRAME_ALLOCATOR.lock().init(mem_start, mem_end);
 }
 
+/// Keep the frames covering `[start, end)` away from `alloc_frame`.
+///
+/// Used for memory the boot loader handed over (such as the initrd)
+/// that must survive until the kernel is done with it. Frames that are
+/// already allocated or outside the managed range are left alone.
+/// Returns the number of frames reserved.
+pub fn reserve_frames(start: PhysAddr, end: PhysAddr) -> usize {
+    FRAME_ALLOCATOR.lock().reserve(start, end)
+}
+
 /// Allocate a single physical frame.
 ///
 /// Returns `None` if no frames are available.
-/// The returned frame is zeroed.
+/// The returned frame is zeroed and has a reference count of 1.
 pub fn alloc_frame() -> Option<PhysAddr> {
     let addr = FRAME_ALLOCATOR.lock().alloc()?;
 
     // Zero the frame for security
     // SAFETY: The frame was just allocated so we have exclusive access.
-    // The address is valid and aligned.
+    // The address is valid and aligned, and the direct map covers all RAM.
     unsafe {
-        unsafe { core::ptr::write_bytes(addr.as_usize() as *mut u8, 0, PAGE_SIZE) };
+        core::ptr::write_bytes(phys_to_kernel_virt(addr).as_mut_ptr::<u8>(), 0, PAGE_SIZE);
     }
 
     Some(addr)
@@ -200,8 +309,21 @@ pub fn alloc_frame_zeroed() -> Result<PhysAddr, super::paging::MappingError> {
     alloc_frame().ok_or(super::paging::MappingError::OutOfMemory)
 }
 
+/// Allocate `pages` physically contiguous frames aligned to their total
+/// size, for memory handed out as untyped (see `mm::untyped`).
+///
+/// `pages` must be a power of two. Each frame has a reference count of
+/// 1; unlike `alloc_frame`, the frames are not zeroed.
+pub fn alloc_frames_aligned(pages: usize) -> Option<PhysAddr> {
+    debug_assert!(pages.is_power_of_two());
+    FRAME_ALLOCATOR.lock().alloc_aligned(pages)
+}
+
 /// Free a physical frame.
 ///
+/// This drops one reference; the frame only returns to the free pool
+/// once every holder taken with `frame_ref` has released it too.
+///
 /// # Panics
 /// Panics if:
 /// - The address is not page-aligned
@@ -211,6 +333,21 @@ pub fn free_frame(addr: PhysAddr) {
     FRAME_ALLOCATOR.lock().free(addr);
 }
 
+/// Take an additional reference to an allocated frame.
+///
+/// Each successful call must be balanced by a `free_frame`.
+/// Returns false if the frame is not managed by the allocator, in which
+/// case no reference was taken and none must be dropped.
+pub fn frame_ref(addr: PhysAddr) -> bool {
+    FRAME_ALLOCATOR.lock().get(addr)
+}
+
+/// Check that each of the `pages` frames from `start` is held by exactly
+/// `refs` owners, so nobody beyond the expected ones shares it.
+pub fn frames_held(start: PhysAddr, pages: usize, refs: usize) -> bool {
+    FRAME_ALLOCATOR.lock().all_held(start, pages, refs)
+}
+
 /// Get the number of free frames remaining.
 pub fn free_frame_count() -> usize {
     FRAME_ALLOCATOR.lock().free_frames()
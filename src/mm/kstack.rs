@@ -0,0 +1,188 @@
+//! Guarded Kernel Stacks
+//!
+//! Kernel stacks live in a dedicated region of the kernel half, one
+//! fixed-size slot per stack. Only the top of each slot is mapped; the
+//! rest stays unmapped as a guard, so running off the bottom of a stack
+//! faults instead of silently corrupting whatever lies below.
+//!
+//! ```text
+//! slot n    ┌───────────────────┐ ← top()
+//!           │   stack (16 KiB)  │   mapped, grows down
+//!           ├───────────────────┤ ← bottom()
+//!           │   guard (16 KiB)  │   never mapped
+//! slot n-1  ├───────────────────┤
+//!           │        ...        │
+//! ```
+//!
+//! # Security Properties
+//! - Every kernel stack has at least one unmapped guard page below it
+//! - Stack frames are zeroed on allocation and never user-accessible
+//! - Guard hits are recognisable from the address alone (`is_guard`),
+//!   so the fault path needs no locks or allocation
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::address::{PhysAddr, VirtAddr, PAGE_SIZE};
+use super::frame::{alloc_frame_zeroed, free_frame};
+use super::mapper::{map_kernel_page, unmap_kernel_page};
+use super::paging::{MappingError, PageFlags};
+
+/// Base of the kernel stack region (L0 index 1 of the kernel half).
+pub const KSTACK_REGION_BASE: usize = 0xFFFF_0080_0000_0000;
+
+/// Number of stack slots in the region.
+pub const KSTACK_SLOTS: usize = 1024;
+
+/// Size of one slot: stack plus guard.
+pub const KSTACK_SLOT_SIZE: usize = 32 * 1024;
+
+/// Usable size of every kernel stack.
+pub const KSTACK_SIZE: usize = 16 * 1024;
+
+/// Size of the unmapped guard below every stack.
+pub const KSTACK_GUARD_SIZE: usize = KSTACK_SLOT_SIZE - KSTACK_SIZE;
+
+/// End of the kernel stack region.
+pub const KSTACK_REGION_END: usize = KSTACK_REGION_BASE + KSTACK_SLOTS * KSTACK_SLOT_SIZE;
+
+const _: () = assert!(KSTACK_GUARD_SIZE >= PAGE_SIZE);
+
+/// Slot bookkeeping: never-used slots above `next`, recycled ones in `free`.
+struct SlotAllocator {
+    next: usize,
+    free: Vec<usize>,
+}
+
+static SLOTS: Mutex<SlotAllocator> = Mutex::new(SlotAllocator {
+    next: 0,
+    free: Vec::new(),
+});
+
+fn alloc_slot() -> Option<usize> {
+    let mut slots = SLOTS.lock();
+    if let Some(slot) = slots.free.pop() {
+        return Some(slot);
+    }
+    if slots.next == KSTACK_SLOTS {
+        return None;
+    }
+    // Reserve room up front so freeing a slot never allocates
+    let additional = slots.next + 1 - slots.free.len();
+    slots.free.try_reserve(additional).ok()?;
+    slots.next += 1;
+    Some(slots.next - 1)
+}
+
+fn free_slot(slot: usize) {
+    SLOTS.lock().free.push(slot);
+}
+
+/// Pages per kernel stack.
+pub const KSTACK_PAGES: usize = KSTACK_SIZE / PAGE_SIZE;
+
+/// A kernel stack with an unmapped guard below it.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+    /// Backing frames, lowest page first; `None` until mapped.
+    frames: [Option<PhysAddr>; KSTACK_PAGES],
+}
+
+impl KernelStack {
+    /// Allocate and map a new zeroed kernel stack.
+    pub fn new() -> Result<Self, MappingError> {
+        let mut frames = [PhysAddr::new_unchecked(0); KSTACK_PAGES];
+        for i in 0..KSTACK_PAGES {
+            match alloc_frame_zeroed() {
+                Ok(frame) => frames[i] = frame,
+                Err(e) => {
+                    frames[..i].iter().copied().for_each(free_frame);
+                    return Err(e);
+                }
+            }
+        }
+        Self::with_frames(frames)
+    }
+
+    /// Map a kernel stack onto `frames` (lowest page first), which the
+    /// caller has zeroed.
+    ///
+    /// Takes over one reference to each frame, dropped again on failure
+    /// or when the stack is dropped.
+    pub fn with_frames(frames: [PhysAddr; KSTACK_PAGES]) -> Result<Self, MappingError> {
+        let Some(slot) = alloc_slot() else {
+            frames.iter().copied().for_each(free_frame);
+            return Err(MappingError::OutOfMemory);
+        };
+        // Drop unmaps and frees whatever was mapped if a later page fails
+        let mut stack = Self {
+            slot,
+            frames: [None; KSTACK_PAGES],
+        };
+
+        for (i, &frame) in frames.iter().enumerate() {
+            if let Err(e) = map_kernel_page(stack.page(i), frame, PageFlags::KERNEL_DATA) {
+                frames[i..].iter().copied().for_each(free_frame);
+                return Err(e);
+            }
+            stack.frames[i] = Some(frame);
+        }
+
+        Ok(stack)
+    }
+
+    /// Virtual address of stack page `i` (0 = lowest).
+    #[inline]
+    fn page(&self, i: usize) -> VirtAddr {
+        self.bottom().add(i * PAGE_SIZE)
+    }
+
+    /// Initial stack pointer (one past the highest byte).
+    #[inline]
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new(KSTACK_REGION_BASE + (self.slot + 1) * KSTACK_SLOT_SIZE)
+    }
+
+    /// Lowest mapped address of the stack.
+    #[inline]
+    pub fn bottom(&self) -> VirtAddr {
+        VirtAddr::new(self.top().as_usize() - KSTACK_SIZE)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        for i in 0..KSTACK_PAGES {
+            if let Some(frame) = self.frames[i].take() {
+                let _ = unmap_kernel_page(self.page(i));
+                free_frame(frame);
+            }
+        }
+        free_slot(self.slot);
+    }
+}
+
+/// Check whether `addr` lies in the guard area of some kernel stack.
+pub fn is_guard(addr: usize) -> bool {
+    (KSTACK_REGION_BASE..KSTACK_REGION_END).contains(&addr)
+        && (addr - KSTACK_REGION_BASE) % KSTACK_SLOT_SIZE < KSTACK_GUARD_SIZE
+}
+
+/// Check whether `addr` lies in the usable part of some kernel stack.
+pub fn is_stack(addr: usize) -> bool {
+    (KSTACK_REGION_BASE..KSTACK_REGION_END).contains(&addr) && !is_guard(addr)
+}
+
+/// Bounds `(bottom, top)` of the stack whose slot contains `addr`.
+///
+/// For a guard address this is the stack directly above the guard, i.e.
+/// the one that overflowed.
+pub fn stack_bounds(addr: usize) -> Option<(usize, usize)> {
+    if !(KSTACK_REGION_BASE..KSTACK_REGION_END).contains(&addr) {
+        return None;
+    }
+    let slot = (addr - KSTACK_REGION_BASE) / KSTACK_SLOT_SIZE;
+    let top = KSTACK_REGION_BASE + (slot + 1) * KSTACK_SLOT_SIZE;
+    Some((top - KSTACK_SIZE, top))
+}
@@ -8,9 +8,15 @@
 //! - Kernel addresses cannot be mapped with user permissions
 //! - The mapper validates all inputs before modifying page tables
 
-use super::address::{PhysAddr, VirtAddr, KERNEL_VIRT_BASE};
+use spin::Mutex;
+
+use super::address::{kernel_virt_to_phys, phys_to_kernel_virt, PhysAddr, VirtAddr, KERNEL_VIRT_BASE};
+use super::frame::alloc_frame_zeroed;
 use super::paging::{MappingError, PageFlags, PageTable, PageTableEntry};
 
+/// Serializes modifications of the kernel page tables.
+static KERNEL_MAP_LOCK: Mutex<()> = Mutex::new(());
+
 /// The kernel's root page table (TTBR1_EL1).
 ///
 /// This is statically allocated and 4KB aligned.
@@ -127,10 +133,93 @@ pub fn kernel_ttbr1() -> PhysAddr {
     // SAFETY: We're just reading the address, not modifying anything
     unsafe {
         let ptr = &raw const KERNEL_PAGE_TABLE.l0;
-        PhysAddr::new(ptr as usize)
+        kernel_virt_to_phys(VirtAddr::new(ptr as usize))
     }
 }
 
+/// Move the kernel half off the boot page tables.
+///
+/// boot.S installs one L0 table for both TTBR0 (identity map) and TTBR1.
+/// This copies the kernel's L0 entry into `KERNEL_PAGE_TABLE` and points
+/// TTBR1_EL1 at it, so mappings added with `map_kernel_page` never show
+/// up in the lower half.
+///
+/// # Safety
+/// Must be called once, before `map_kernel_page`, while nothing else
+/// touches the kernel page tables.
+pub unsafe fn adopt_boot_tables() {
+    let _guard = KERNEL_MAP_LOCK.lock();
+    let kernel_index = VirtAddr::new(KERNEL_VIRT_BASE).page_table_indices().0;
+
+    // SAFETY: TTBR1_EL1 holds the boot L0 table, which lives in RAM and is
+    // reachable through the direct map. KERNEL_PAGE_TABLE is only touched
+    // under KERNEL_MAP_LOCK.
+    unsafe {
+        let boot_l0_phys: u64;
+        core::arch::asm!("mrs {}, ttbr1_el1", out(reg) boot_l0_phys, options(nomem, nostack));
+        let boot_l0 = &*phys_to_kernel_virt(PhysAddr::new(boot_l0_phys as usize & !0xFFF))
+            .as_ptr::<PageTable>();
+
+        let l0 = &raw mut KERNEL_PAGE_TABLE.l0;
+        let l0 = &mut *l0;
+        l0[kernel_index] = boot_l0[kernel_index];
+
+        core::arch::asm!(
+            "dsb ishst",
+            "msr ttbr1_el1, {}",
+            "isb",
+            in(reg) kernel_ttbr1().as_u64(),
+            options(nostack)
+        );
+        invalidate_tlb_all();
+    }
+}
+
+/// Access a kernel page table frame through the direct map.
+///
+/// # Safety
+/// `phys` must be a page table reachable from `KERNEL_PAGE_TABLE`, and
+/// the caller must hold `KERNEL_MAP_LOCK`.
+#[inline]
+unsafe fn kernel_table<'a>(phys: PhysAddr) -> &'a mut PageTable {
+    // SAFETY: Kernel table frames are RAM covered by the direct map.
+    unsafe { &mut *phys_to_kernel_virt(phys).as_mut_ptr::<PageTable>() }
+}
+
+/// Find the L3 entry for a kernel address, optionally allocating tables.
+///
+/// Fails with `AlreadyMapped` if the walk runs into a block mapping.
+///
+/// # Safety
+/// The caller must hold `KERNEL_MAP_LOCK`.
+unsafe fn kernel_walk<'a>(
+    virt: VirtAddr,
+    create: bool,
+) -> Result<&'a mut PageTableEntry, MappingError> {
+    let (l0, l1, l2, l3) = virt.page_table_indices();
+    let mut table = kernel_ttbr1();
+
+    for index in [l0, l1, l2] {
+        // SAFETY: `table` is the kernel root or a table reached from it.
+        let entry = unsafe { &mut kernel_table(table)[index] };
+
+        if !entry.is_valid() {
+            if !create {
+                return Err(MappingError::NotMapped);
+            }
+            *entry = PageTableEntry::table(alloc_frame_zeroed()?);
+        } else if !entry.is_table() {
+            // Covered by a boot block mapping
+            return Err(MappingError::AlreadyMapped);
+        }
+
+        table = entry.addr();
+    }
+
+    // SAFETY: As above, `table` is a kernel L3 table.
+    Ok(unsafe { &mut kernel_table(table)[l3] })
+}
+
 /// Map a single page in the kernel address space.
 ///
 /// # Arguments
@@ -154,24 +243,55 @@ pub fn map_kernel_page(
         return Err(MappingError::MisalignedAddress);
     }
 
-    // Suppress unused variable warning
-    let _ = flags;
+    if flags.is_user() {
+        return Err(MappingError::InvalidPermissions);
+    }
+
+    let _guard = KERNEL_MAP_LOCK.lock();
+
+    // SAFETY: KERNEL_MAP_LOCK is held.
+    let entry = unsafe { kernel_walk(virt, true)? };
+    if entry.is_valid() {
+        return Err(MappingError::AlreadyMapped);
+    }
+    *entry = PageTableEntry::page(phys, flags);
 
-    // For now, we only support the initial boot mappings
-    // Dynamic kernel mapping would require walking the page tables
-    // and allocating intermediate tables as needed
+    // Make the new descriptor visible to the table walker.
+    // SAFETY: Barriers have no memory-safety implications.
+    unsafe {
+        core::arch::asm!("dsb ishst", "isb", options(nostack, preserves_flags));
+    }
 
-    // TODO: Implement dynamic page table walking and allocation
     Ok(())
 }
 
 /// Unmap a page from the kernel address space.
+///
+/// Only pages mapped with `map_kernel_page` can be unmapped; the frame
+/// is not freed.
 pub fn unmap_kernel_page(virt: VirtAddr) -> Result<(), MappingError> {
     if !virt.is_kernel() {
         return Err(MappingError::InvalidPermissions);
     }
 
-    // TODO: Implement page table walking and unmapping
+    if !virt.is_aligned() {
+        return Err(MappingError::MisalignedAddress);
+    }
+
+    let _guard = KERNEL_MAP_LOCK.lock();
+
+    // SAFETY: KERNEL_MAP_LOCK is held.
+    let entry = unsafe { kernel_walk(virt, false)? };
+    if !entry.is_valid() {
+        return Err(MappingError::NotMapped);
+    }
+    entry.clear();
+
+    // SAFETY: The entry was cleared above; stale translations must go.
+    unsafe {
+        invalidate_tlb(virt);
+    }
+
     Ok(())
 }
 
@@ -5,6 +5,9 @@
 //! - Page table management (ARM64 VMSA)
 //! - Physical frame allocation
 //! - Kernel heap allocation
+//! - Per-process address spaces and shared memory objects
+//! - Guarded kernel stacks
+//! - Untyped memory, retyped into kernel objects by user space
 //!
 //! # Security Principles
 //! - Type-safe address handling prevents mixing physical/virtual
@@ -15,14 +18,21 @@
 pub mod address;
 pub mod allocator;
 pub mod frame;
+pub mod kstack;
 pub mod mapper;
 pub mod paging;
+pub mod shm;
+pub mod tlb;
+pub mod untyped;
+pub mod vspace;
 
-pub use address::{PhysAddr, VirtAddr, PAGE_SIZE, KERNEL_VIRT_BASE};
+pub use address::{kernel_virt_to_phys, PhysAddr, VirtAddr, PAGE_SIZE, KERNEL_VIRT_BASE};
 pub use allocator::{heap_size, init_heap};
-pub use frame::{alloc_frame, free_frame, init_frame_allocator, PhysFrame};
+pub use frame::{alloc_frame, free_frame, free_frame_count, init_frame_allocator, PhysFrame};
+pub use kstack::KernelStack;
 pub use mapper::{init_kernel_page_tables, kernel_ttbr1, map_kernel_page};
 pub use paging::{MappingError, PageFlags, PageTable, PageTableEntry};
+pub use vspace::AddressSpace;
 
 /// Initialize all memory management subsystems.
 ///
@@ -31,13 +41,7 @@ pub use paging::{MappingError, PageFlags, PageTable, PageTableEntry};
 /// # Safety
 /// Must be called once before any memory operations.
 pub unsafe fn init() {
-    // Initialize the frame allocator with available memory
-    // QEMU virt machine has RAM from 0x4000_0000
-    // We reserve the first 2MB for kernel and start allocating after
-    let mem_start = PhysAddr::new(0x4020_0000);
-    let mem_end = PhysAddr::new(0x4800_0000); // 128MB total
-
-    init_frame_allocator(mem_start, mem_end);
+    init_frames();
 
     // Initialize kernel page tables
     // SAFETY: We are in early boot, MMU is off (or strict checking is disabled).
@@ -52,6 +56,23 @@ pub unsafe fn init() {
     }
 }
 
+/// Initialize the physical frame allocator.
+///
+/// QEMU virt machine has RAM from 0x4000_0000. Everything below
+/// 0x4020_0000 and below the end of the kernel image (whichever is
+/// higher) stays reserved; the rest of the 128MB is handed out as frames.
+pub fn init_frames() {
+    extern "C" {
+        static __kernel_end: u8;
+    }
+
+    let kernel_end = kernel_virt_to_phys(VirtAddr::new(&raw const __kernel_end as usize));
+    let mem_start = kernel_end.align_up().max(PhysAddr::new(0x4020_0000));
+    let mem_end = PhysAddr::new(address::PHYS_MEM_END);
+
+    init_frame_allocator(mem_start, mem_end);
+}
+
 /// Memory region descriptor.
 #[derive(Debug, Clone, Copy)]
 pub struct MemoryRegion {
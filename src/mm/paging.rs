@@ -121,6 +121,12 @@ impl PageFlags {
         Self::ATTR_NORMAL.0 | Self::AP_RW_ALL.0 | Self::PXN.0 | Self::UXN.0 | Self::NG.0
     );
 
+    /// User read-only data: readable by user, not writable, not executable.
+    pub const USER_RODATA: Self = Self(
+        Self::PAGE.0 | Self::AF.0 | Self::SH_INNER.0 |
+        Self::ATTR_NORMAL.0 | Self::AP_RO_ALL.0 | Self::PXN.0 | Self::UXN.0 | Self::NG.0
+    );
+
     /// Table entry pointing to next level.
     pub const TABLE_ENTRY: Self = Self(Self::TABLE.0 | Self::AF.0);
 
@@ -163,6 +169,24 @@ impl PageFlags {
         self.0 & 0b11 == 0b11
     }
 
+    /// Check if EL0 may access pages with these flags (AP[1] set).
+    #[inline]
+    pub const fn is_user(self) -> bool {
+        self.0 & Self::AP_RW_ALL.0 != 0
+    }
+
+    /// Check if pages with these flags are writable (AP[2] clear).
+    #[inline]
+    pub const fn is_writable(self) -> bool {
+        self.0 & Self::AP_RO_EL1.0 == 0
+    }
+
+    /// Check if EL0 may execute pages with these flags.
+    #[inline]
+    pub const fn is_user_executable(self) -> bool {
+        self.0 & Self::UXN.0 == 0
+    }
+
     /// Combine two flag sets.
     #[inline]
     pub const fn union(self, other: Self) -> Self {
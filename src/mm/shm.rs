@@ -0,0 +1,235 @@
+//! Shared Memory Objects
+//!
+//! A `SharedMemory` object is a fixed run of physical frames that can be
+//! mapped into several address spaces at once, giving processes a way to
+//! exchange bulk data without copying through the kernel. Objects are
+//! made by retyping untyped memory (see `cap::retype`) and mapped with
+//! the `shm_map` / `shm_unmap` syscalls.
+//!
+//! # Lifetime
+//! - The object holds one reference on each of its frames, handed out by
+//!   `Untyped::carve`
+//! - Every mapping holds its own frame references (taken by `AddressSpace`)
+//! - Capabilities hold references on the object (see `cap::object`)
+//!
+//! The frames therefore return to the untyped block only after the last
+//! capability and the last mapping have disappeared, in either order.
+//!
+//! # Security Properties
+//! - Carved memory is zeroed, so no stale data crosses processes
+//! - Mapping rights must be a subset of the capability's rights
+//! - Mappings are READ or READ|WRITE; EXECUTE only if the capability
+//!   grants it, and never together with WRITE
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::cap::capability::{CapabilityType, Rights};
+use crate::cap::cspace::RawCapability;
+
+use super::address::{PhysAddr, VirtAddr, PAGE_SHIFT, PAGE_SIZE};
+use super::frame::free_frame;
+use super::paging::{MappingError, PageFlags};
+use super::vspace::{AddressSpace, StalePage};
+
+/// Log2 of the largest shared memory object (4 MiB).
+pub const SHM_MAX_BITS: u8 = PAGE_SHIFT as u8 + 10;
+
+/// Rights that are meaningful for a mapping.
+pub const MAPPING_RIGHTS: Rights = Rights::from_bits(
+    Rights::READ.bits() | Rights::WRITE.bits() | Rights::EXECUTE.bits(),
+);
+
+/// Error type for shared memory operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    /// The requested rights do not form a valid mapping (no READ, or W+X).
+    InvalidRights,
+    /// The range is not mapped to this object.
+    NotMapped,
+    /// The page table operation failed.
+    Mapping(MappingError),
+}
+
+impl From<MappingError> for ShmError {
+    fn from(e: MappingError) -> Self {
+        Self::Mapping(e)
+    }
+}
+
+impl core::fmt::Display for ShmError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidRights => write!(f, "invalid mapping rights"),
+            Self::NotMapped => write!(f, "range not mapped to this object"),
+            Self::Mapping(e) => write!(f, "mapping failed: {}", e),
+        }
+    }
+}
+
+/// Compute page flags for a user mapping with the given rights.
+///
+/// - READ → read-only, non-executable
+/// - READ | WRITE → read/write, non-executable
+/// - READ | EXECUTE → read-only, executable
+pub fn mapping_flags(rights: Rights) -> Result<PageFlags, ShmError> {
+    if !rights.contains(Rights::READ) {
+        return Err(ShmError::InvalidRights);
+    }
+
+    let writable = rights.contains(Rights::WRITE);
+    let executable = rights.contains(Rights::EXECUTE);
+
+    match (writable, executable) {
+        (true, true) => Err(ShmError::InvalidRights),
+        (true, false) => Ok(PageFlags::USER_DATA),
+        (false, true) => Ok(PageFlags::USER_CODE),
+        (false, false) => Ok(PageFlags::USER_RODATA),
+    }
+}
+
+/// A shared memory kernel object.
+#[derive(Debug)]
+pub struct SharedMemory {
+    /// First backing frame; the object owns one reference on each.
+    base: PhysAddr,
+    pages: usize,
+}
+
+impl SharedMemory {
+    /// Take over the `2^size_bits` bytes of zeroed memory at `base`, whose
+    /// frames each carry one reference for the object (as
+    /// `Untyped::carve` hands them out).
+    pub fn adopt(base: PhysAddr, size_bits: u8) -> Self {
+        Self {
+            base,
+            pages: 1 << (size_bits - PAGE_SHIFT as u8),
+        }
+    }
+
+    /// Size in bytes.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    /// The backing frames, in order.
+    fn frames(&self) -> impl Iterator<Item = PhysAddr> + '_ {
+        (0..self.pages).map(|i| self.base.add(i * PAGE_SIZE))
+    }
+
+    /// Map the whole object at `base` in `vspace`.
+    ///
+    /// `rights` is the per-mapping access (see `mapping_flags`). On failure
+    /// nothing stays mapped; the pages taken back go to `stale`, to be
+    /// flushed by the caller.
+    pub fn map(
+        &self,
+        vspace: &mut AddressSpace,
+        base: VirtAddr,
+        rights: Rights,
+        stale: &mut Vec<StalePage>,
+    ) -> Result<(), ShmError> {
+        let flags = mapping_flags(rights)?;
+
+        for (i, frame) in self.frames().enumerate() {
+            let virt = base.add(i * PAGE_SIZE);
+            if let Err(e) = vspace.map_page(virt, frame, flags) {
+                // Roll back the pages mapped so far; user threads may
+                // have touched them already
+                stale.extend(
+                    (0..i).filter_map(|j| vspace.unmap_page(base.add(j * PAGE_SIZE)).ok()),
+                );
+                return Err(e.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unmap the object from `base` in `vspace`, returning the pages to
+    /// flush.
+    ///
+    /// Fails without changing anything unless every page of the range is
+    /// currently mapped to this object's frames.
+    pub fn unmap(
+        &self,
+        vspace: &mut AddressSpace,
+        base: VirtAddr,
+    ) -> Result<Vec<StalePage>, ShmError> {
+        if !base.is_aligned() {
+            return Err(MappingError::MisalignedAddress.into());
+        }
+
+        let mapped = self.frames().enumerate().all(|(i, frame)| {
+            matches!(vspace.translate(base.add(i * PAGE_SIZE)), Some((phys, _)) if phys == frame)
+        });
+        if !mapped {
+            return Err(ShmError::NotMapped);
+        }
+
+        // Checked above, so every page unmaps
+        Ok((0..self.pages)
+            .filter_map(|i| vspace.unmap_page(base.add(i * PAGE_SIZE)).ok())
+            .collect())
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        self.frames().for_each(free_frame);
+    }
+}
+
+/// Wrap `shm` in a capability.
+///
+/// The returned capability owns one reference to the object.
+pub fn create_cap(shm: SharedMemory, rights: Rights) -> RawCapability {
+    RawCapability {
+        cap_type: CapabilityType::SharedMemory,
+        object_ptr: Arc::into_raw(Arc::new(shm)) as usize,
+        rights,
+        badge: 0,
+        generation: 0,
+    }
+}
+
+/// The shared memory object a capability refers to, if it is a shared
+/// memory capability.
+pub fn from_cap(cap: &RawCapability) -> Option<Arc<SharedMemory>> {
+    if cap.cap_type != CapabilityType::SharedMemory {
+        return None;
+    }
+    let ptr = cap.object_ptr as *const SharedMemory;
+    // SAFETY: Shared memory capabilities carry a pointer from
+    // Arc::into_raw and own a reference, so the count is at least one
+    // while `cap` exists.
+    unsafe {
+        Arc::increment_strong_count(ptr);
+        Some(Arc::from_raw(ptr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapping_flags() {
+        assert_eq!(mapping_flags(Rights::READ), Ok(PageFlags::USER_RODATA));
+        assert_eq!(mapping_flags(Rights::READ_WRITE), Ok(PageFlags::USER_DATA));
+        assert_eq!(
+            mapping_flags(Rights::READ.union(Rights::EXECUTE)),
+            Ok(PageFlags::USER_CODE)
+        );
+    }
+
+    #[test]
+    fn test_mapping_flags_rejects_invalid() {
+        assert_eq!(mapping_flags(Rights::WRITE), Err(ShmError::InvalidRights));
+        assert_eq!(
+            mapping_flags(Rights::READ_WRITE.union(Rights::EXECUTE)),
+            Err(ShmError::InvalidRights)
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! TLB Shootdown
+//!
+//! Without ASIDs every CPU flushes its whole TLB when it loads a new
+//! TTBR0, so stale user translations can only live on CPUs that have the
+//! changed address space loaded *right now*. Changes to a user address
+//! space therefore flush the local TLB and interrupt exactly those CPUs
+//! (`Ipi::TlbShootdown`), then wait until each one has done its part.
+//!
+//! Kernel (TTBR1) mappings are shared by all CPUs and keep using the
+//! broadcast invalidations in `mapper`.
+//!
+//! # Protocol
+//! ```text
+//! initiator                               target (root loaded)
+//! ─────────                               ────────────────────
+//! take SHOOTDOWN (serving own requests
+//!   while waiting), flush locally
+//! post request, pending += 1 ──SGI──────► take request
+//!                                         flush (or leave the space)
+//! wait for pending == 0 ◄──────────────── pending -= 1
+//! ```
+//!
+//! Targets answer from their IPI handler, so they must be able to take
+//! the IRQ. The initiator therefore holds no spinlock: a target spinning
+//! on it with IRQs masked would never answer. Two initiators cannot wait
+//! for each other either, since one spinning for `SHOOTDOWN` keeps
+//! serving its own requests.
+//!
+//! # Security Properties
+//! - An unmapped page is unreachable on every CPU before `flush` returns,
+//!   so its frame can be reused at once
+//! - A dying address space is unloaded everywhere before its tables are
+//!   freed, so no CPU walks freed memory
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use super::address::{PhysAddr, VirtAddr};
+use super::vspace;
+use crate::exception;
+use crate::lock_class;
+use crate::percpu::PerCpu;
+use crate::smp::{self, Ipi, MAX_CPUS};
+use crate::sync::{self, TicketLock};
+
+/// What a shootdown asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flush {
+    /// Drop the translation of one page.
+    Page(VirtAddr),
+    /// Stop using the address space (it is about to be freed).
+    Leave,
+}
+
+/// A posted shootdown request.
+#[derive(Debug, Clone, Copy)]
+struct Request {
+    root: usize,
+    flush: Flush,
+}
+
+/// TTBR0 root loaded on each CPU (physical address).
+static ACTIVE_ROOT: PerCpu<AtomicUsize> = PerCpu::new([const { AtomicUsize::new(0) }; MAX_CPUS]);
+
+/// Request waiting for each CPU.
+static PENDING: PerCpu<Mutex<Option<Request>>> =
+    PerCpu::new([const { Mutex::new(None) }; MAX_CPUS]);
+
+/// Targets of the current shootdown that have not finished yet.
+static OUTSTANDING: AtomicUsize = AtomicUsize::new(0);
+
+/// One shootdown at a time.
+static SHOOTDOWN: TicketLock<()> = TicketLock::new((), lock_class!("TLB shootdown"));
+
+/// Invalidate the calling CPU's translations of `virt`.
+fn flush_local_page(virt: VirtAddr) {
+    // SAFETY: TLB maintenance only; the local variant is enough because
+    // the other CPUs are handled by the shootdown.
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vaae1, {addr}",
+            "dsb nsh",
+            "isb",
+            addr = in(reg) virt.as_usize() >> 12,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Invalidate all of the calling CPU's EL1&0 translations.
+pub(super) fn flush_local_all() {
+    // SAFETY: TLB maintenance only.
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vmalle1",
+            "dsb nsh",
+            "isb",
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Record the root the calling CPU just loaded into TTBR0.
+///
+/// Called with the new root in place and the TLB flushed.
+pub(super) fn set_active(root: PhysAddr) {
+    ACTIVE_ROOT.with(|active| active.store(root.as_usize(), Ordering::Release));
+}
+
+/// Carry out `flush` on the calling CPU, if `root` is loaded here.
+fn apply(root: usize, flush: Flush) {
+    if ACTIVE_ROOT.with(|active| active.load(Ordering::Acquire)) != root {
+        return;
+    }
+    match flush {
+        Flush::Page(virt) => flush_local_page(virt),
+        Flush::Leave => vspace::deactivate(),
+    }
+}
+
+/// Serve the request posted for the calling CPU, if any.
+fn serve_pending() {
+    let request = PENDING.with(|pending| pending.lock().take());
+    if let Some(request) = request {
+        apply(request.root, request.flush);
+        OUTSTANDING.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// `Ipi::TlbShootdown` handler (IRQ context).
+pub fn handle_ipi() {
+    serve_pending();
+}
+
+/// Make a change to the address space rooted at `root` visible on every
+/// CPU.
+///
+/// Returns once no CPU can use a translation the change removed. Must
+/// be called with no spinlock held (see the module docs).
+pub fn flush(root: PhysAddr, flush: Flush) {
+    sync::assert_none_held("TLB shootdown");
+    let root = root.as_usize();
+    let irqs = exception::save_and_disable_irqs();
+
+    // Another CPU may be waiting for us while we wait for the lock
+    let guard = loop {
+        if let Some(guard) = SHOOTDOWN.try_lock() {
+            break guard;
+        }
+        serve_pending();
+        core::hint::spin_loop();
+    };
+
+    let this = smp::cpu_id();
+    apply(root, flush);
+
+    for cpu in (0..MAX_CPUS).filter(|&cpu| cpu != this) {
+        if ACTIVE_ROOT.remote(cpu).load(Ordering::Acquire) != root {
+            continue;
+        }
+        OUTSTANDING.fetch_add(1, Ordering::AcqRel);
+        *PENDING.remote(cpu).lock() = Some(Request { root, flush });
+        smp::send_ipi(cpu, Ipi::TlbShootdown);
+    }
+
+    while OUTSTANDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+
+    drop(guard);
+    exception::restore_irqs(irqs);
+}
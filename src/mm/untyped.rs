@@ -0,0 +1,316 @@
+//! Untyped Memory
+//!
+//! An `Untyped` object is a naturally aligned, power-of-two block of
+//! physical memory that user space turns into kernel objects by
+//! retyping it (see `cap::retype`). Whoever holds an untyped capability
+//! decides what the memory becomes, so kernel memory spent on behalf of a
+//! process comes out of memory that process was given.
+//!
+//! # Design
+//! - The block's frames come from the frame allocator once (at boot, or
+//!   when a larger untyped is retyped into smaller ones) and hold one
+//!   reference owned by the `Untyped`
+//! - An untyped retyped from another keeps its parent alive, so its
+//!   frames also carry one reference per untyped it is nested in
+//! - Objects are carved at a watermark, each aligned to its own size.
+//!   Every carved frame gets an extra reference, owned by the object
+//! - Once every frame carved so far is back to the references of the
+//!   untyped and its parents, all objects made from the block are gone
+//!   and carving starts over at the base
+//! - Dropping the `Untyped` drops its references; frames still used by
+//!   objects return to the allocator when those objects go
+//! - The `Arc` of each object lives in an `ObjectBlock` carved next to
+//!   its memory (see `mm::allocator`), so making objects uses no heap
+//!
+//! # Security Properties
+//! - Carved memory is zeroed, so nothing leaks from earlier objects
+//! - Memory is never reused while any object (or mapping) made from it
+//!   still holds a frame
+
+use alloc::sync::Arc;
+use core::alloc::Layout;
+use core::sync::atomic::AtomicUsize;
+
+use super::address::{phys_to_kernel_virt, PhysAddr, PAGE_SHIFT, PAGE_SIZE};
+use super::allocator;
+use super::frame::{alloc_frames_aligned, frame_ref, frames_held, free_frame};
+use crate::cap::capability::{CapabilityType, Rights};
+use crate::cap::cspace::RawCapability;
+use crate::lock_class;
+use crate::sync::TicketLock;
+
+/// Smallest untyped block (one page).
+pub const UNTYPED_MIN_BITS: u8 = PAGE_SHIFT as u8;
+
+/// Largest untyped block (16 MiB).
+pub const UNTYPED_MAX_BITS: u8 = 24;
+
+/// Error type for untyped memory operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UntypedError {
+    /// The requested size is out of range.
+    InvalidSize,
+    /// Not enough untyped memory left.
+    OutOfMemory,
+}
+
+impl core::fmt::Display for UntypedError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidSize => write!(f, "invalid object size"),
+            Self::OutOfMemory => write!(f, "untyped memory exhausted"),
+        }
+    }
+}
+
+/// A block of physical memory waiting to be retyped.
+pub struct Untyped {
+    base: PhysAddr,
+    size_bits: u8,
+    /// The untyped this one was retyped from, kept alive so its
+    /// references on our frames stay.
+    _parent: Option<Arc<Untyped>>,
+    /// References each frame carries while nothing carved from it is
+    /// left: one for this untyped and one per parent.
+    refs: usize,
+    /// Offset of the first byte not yet carved.
+    watermark: TicketLock<usize>,
+}
+
+impl Untyped {
+    /// Allocate a fresh block of `2^size_bits` bytes from the frame
+    /// allocator.
+    pub fn alloc(size_bits: u8) -> Result<Self, UntypedError> {
+        if !(UNTYPED_MIN_BITS..=UNTYPED_MAX_BITS).contains(&size_bits) {
+            return Err(UntypedError::InvalidSize);
+        }
+        let pages = 1 << (size_bits - UNTYPED_MIN_BITS);
+        let base = alloc_frames_aligned(pages).ok_or(UntypedError::OutOfMemory)?;
+        Ok(Self::with_parent(base, size_bits, None))
+    }
+
+    /// Wrap the `2^size_bits` bytes at `base`, carved from `parent`, whose
+    /// frames each carry one reference for the new object (as `carve`
+    /// hands them out).
+    pub fn adopt(base: PhysAddr, size_bits: u8, parent: Arc<Untyped>) -> Self {
+        Self::with_parent(base, size_bits, Some(parent))
+    }
+
+    fn with_parent(base: PhysAddr, size_bits: u8, parent: Option<Arc<Untyped>>) -> Self {
+        Self {
+            base,
+            size_bits,
+            refs: parent.as_ref().map_or(1, |parent| parent.refs + 1),
+            _parent: parent,
+            watermark: TicketLock::new(0, lock_class!("untyped")),
+        }
+    }
+
+    /// Size in bytes.
+    #[inline]
+    pub fn size(&self) -> usize {
+        1 << self.size_bits
+    }
+
+    /// Carve `count` zeroed blocks of `2^size_bits` bytes, each aligned to
+    /// its size, and return the base of the first; the others follow it.
+    ///
+    /// Each frame of the blocks gets one reference for the caller, who
+    /// must drop it with `free_frame` when the object goes.
+    pub fn carve(&self, size_bits: u8, count: usize) -> Result<PhysAddr, UntypedError> {
+        if !(UNTYPED_MIN_BITS..=self.size_bits).contains(&size_bits) || count == 0 {
+            return Err(UntypedError::InvalidSize);
+        }
+        let block = 1usize << size_bits;
+        let total = block
+            .checked_mul(count)
+            .filter(|&total| total <= self.size())
+            .ok_or(UntypedError::InvalidSize)?;
+
+        let mut watermark = self.watermark.lock();
+        let mut start = watermark.next_multiple_of(block);
+        if start + total > self.size() {
+            // Start over once everything carved so far is gone
+            if !frames_held(self.base, *watermark / PAGE_SIZE, self.refs) {
+                return Err(UntypedError::OutOfMemory);
+            }
+            start = 0;
+        }
+        *watermark = start + total;
+
+        let first = self.base.add(start);
+        for page in (0..total).step_by(PAGE_SIZE) {
+            let frame = first.add(page);
+            frame_ref(frame);
+            // SAFETY: Nothing else references the carved frames, and the
+            // direct map covers all RAM.
+            unsafe {
+                core::ptr::write_bytes(phys_to_kernel_virt(frame).as_mut_ptr::<u8>(), 0, PAGE_SIZE);
+            }
+        }
+        Ok(first)
+    }
+
+    /// Carve `count` `ObjectBlock`s of `2^bits` bytes (see `block_bits`)
+    /// and return the base of the first; the others follow it.
+    ///
+    /// Blocks smaller than a page share pages, each holding a reference
+    /// on its own page; the caller takes over the blocks with
+    /// `ObjectBlock::new`.
+    pub fn carve_blocks(&self, bits: u8, count: usize) -> Result<PhysAddr, UntypedError> {
+        let carve_bits = bits.max(UNTYPED_MIN_BITS);
+        let bytes = count.checked_mul(1 << bits).ok_or(UntypedError::InvalidSize)?;
+        let pages = bytes.div_ceil(PAGE_SIZE);
+        let first = self.carve(carve_bits, pages.div_ceil(1 << (carve_bits - UNTYPED_MIN_BITS)))?;
+
+        if bits < UNTYPED_MIN_BITS {
+            // One reference per block instead of the one per page `carve`
+            // handed out
+            for i in 0..count {
+                frame_ref(first.add(i << bits).align_down());
+            }
+            for page in 0..pages {
+                free_frame(first.add(page * PAGE_SIZE));
+            }
+        }
+        Ok(first)
+    }
+}
+
+impl Drop for Untyped {
+    fn drop(&mut self) {
+        for page in (0..self.size()).step_by(PAGE_SIZE) {
+            free_frame(self.base.add(page));
+        }
+    }
+}
+
+/// One page carved from untyped memory, backing a frame or page table
+/// capability.
+#[derive(Debug)]
+pub struct Page {
+    addr: PhysAddr,
+}
+
+impl Page {
+    /// Take over the reference `carve` handed out for `addr`.
+    pub fn new(addr: PhysAddr) -> Self {
+        Self { addr }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        free_frame(self.addr);
+    }
+}
+
+/// Layout of the allocation behind an `Arc<T>` (`ArcInner` in `alloc`).
+#[repr(C)]
+struct ArcAllocation<T> {
+    _strong: AtomicUsize,
+    _weak: AtomicUsize,
+    _data: T,
+}
+
+/// Log2 of the `ObjectBlock` an `Arc<T>` needs: its allocation rounded
+/// up to a power of two.
+pub fn block_bits<T>() -> u8 {
+    Layout::new::<ArcAllocation<T>>()
+        .pad_to_align()
+        .size()
+        .next_power_of_two()
+        .trailing_zeros() as u8
+}
+
+/// Carved memory for the `Arc` of one kernel object, holding a reference
+/// on each frame it covers until the `Arc` takes them over.
+#[derive(Debug)]
+pub struct ObjectBlock {
+    addr: PhysAddr,
+    bits: u8,
+}
+
+impl ObjectBlock {
+    /// Take over the references `Untyped::carve_blocks` handed out for the
+    /// block of `2^bits` bytes at `addr`.
+    pub fn new(addr: PhysAddr, bits: u8) -> Self {
+        Self { addr, bits }
+    }
+
+    /// Run `make`, building the `Arc<T>` it creates in this block.
+    ///
+    /// If `make` never creates one (it failed first), the block is freed.
+    ///
+    /// # Panics
+    /// If the block is too small for `Arc<T>`.
+    pub fn place<T, R>(self, make: impl FnOnce() -> R) -> R {
+        let layout = Layout::new::<ArcAllocation<T>>();
+        assert!(layout.size() <= 1 << self.bits, "object block too small");
+        // SAFETY: The block is carved memory, naturally aligned to its
+        // size and so to `layout`, and covered by the references it holds,
+        // which freeing the `Arc` drops.
+        let (result, used) =
+            unsafe { allocator::with_placement(phys_to_kernel_virt(self.addr), layout, make) };
+        if used {
+            // The `Arc` holds the pages under its allocation; free the rest
+            let end = self.addr.add(layout.size()).align_up();
+            for page in (end.as_usize()..self.addr.as_usize() + (1 << self.bits)).step_by(PAGE_SIZE) {
+                free_frame(PhysAddr::new_unchecked(page));
+            }
+            core::mem::forget(self);
+        }
+        result
+    }
+}
+
+impl Drop for ObjectBlock {
+    fn drop(&mut self) {
+        for page in (0..1usize << self.bits).step_by(PAGE_SIZE) {
+            free_frame(self.addr.align_down().add(page));
+        }
+    }
+}
+
+/// Wrap `untyped` in a capability.
+///
+/// The returned capability owns one reference to the object.
+pub fn create_cap(untyped: Untyped, rights: Rights) -> RawCapability {
+    RawCapability {
+        cap_type: CapabilityType::Untyped,
+        object_ptr: Arc::into_raw(Arc::new(untyped)) as usize,
+        rights,
+        badge: 0,
+        generation: 0,
+    }
+}
+
+/// Wrap `page` in a frame or page table capability.
+pub fn create_page_cap(page: Page, cap_type: CapabilityType, rights: Rights) -> RawCapability {
+    debug_assert!(matches!(
+        cap_type,
+        CapabilityType::Frame | CapabilityType::PageTable
+    ));
+    RawCapability {
+        cap_type,
+        object_ptr: Arc::into_raw(Arc::new(page)) as usize,
+        rights,
+        badge: 0,
+        generation: 0,
+    }
+}
+
+/// The untyped object a capability refers to, if it is an untyped
+/// capability.
+pub fn from_cap(cap: &RawCapability) -> Option<Arc<Untyped>> {
+    if cap.cap_type != CapabilityType::Untyped {
+        return None;
+    }
+    let ptr = cap.object_ptr as *const Untyped;
+    // SAFETY: Untyped capabilities carry a pointer from Arc::into_raw and
+    // own a reference, so the count is at least one while `cap` exists.
+    unsafe {
+        Arc::increment_strong_count(ptr);
+        Some(Arc::from_raw(ptr))
+    }
+}
@@ -0,0 +1,313 @@
+//! User Address Spaces
+//!
+//! An `AddressSpace` owns one TTBR0 translation table hierarchy: the
+//! lower-half mappings of a single process. The kernel half (TTBR1) is
+//! shared by every address space and never touched here.
+//!
+//! # Ownership
+//! - Table frames are allocated on demand and freed when the space drops
+//! - Each leaf mapping holds one frame reference (see `frame_ref`), so a
+//!   frame mapped into several spaces lives until its last mapping is gone
+//! - All table accesses go through the kernel direct map, so they keep
+//!   working while a different TTBR0 is active
+//! - Dropping a space first makes every CPU that has it loaded switch to
+//!   an empty table (`tlb::flush`), so the hardware never walks freed
+//!   tables
+//! - Unmapping hands back a `StalePage`; flushing it shoots the
+//!   translation down on every CPU using the space and only then drops the
+//!   mapping's frame reference
+//!
+//! # Security Properties
+//! - Only user (lower-half), page-aligned addresses can be mapped
+//! - Every mapping must be EL0-accessible and PXN, so the kernel can
+//!   never execute user memory
+//! - Writable and executable mappings are rejected (W^X)
+
+use super::address::{kernel_virt_to_phys, phys_to_kernel_virt, PhysAddr, VirtAddr};
+use super::frame::{alloc_frame_zeroed, frame_ref, free_frame};
+use super::tlb::{self, Flush};
+use super::paging::{MappingError, PageFlags, PageTable, PageTableEntry};
+
+/// Software PTE bit: the leaf holds a reference on its frame.
+///
+/// Frames outside the allocator (MMIO) are mapped without a reference
+/// and must not be released on unmap.
+const PTE_FRAME_REF: PageFlags = PageFlags::SW0;
+
+/// Root table used while no user address space is active.
+///
+/// All entries are invalid, so any lower-half access faults.
+static EMPTY_ROOT: PageTable = PageTable::new();
+
+/// Read the current TTBR0_EL1 table address.
+#[inline]
+fn current_ttbr0() -> PhysAddr {
+    let ttbr0: u64;
+    // SAFETY: Reading TTBR0_EL1 has no side effects.
+    unsafe {
+        core::arch::asm!("mrs {}, ttbr0_el1", out(reg) ttbr0, options(nomem, nostack));
+    }
+    PhysAddr::new(ttbr0 as usize & !0xFFF)
+}
+
+/// Load `root` into TTBR0_EL1 and drop all stale user translations.
+///
+/// # Safety
+/// `root` must be a valid L0 table that outlives its time in TTBR0.
+unsafe fn set_ttbr0(root: PhysAddr) {
+    // SAFETY: Guaranteed by the caller.
+    unsafe {
+        core::arch::asm!(
+            "dsb ishst",
+            "msr ttbr0_el1, {}",
+            "isb",
+            in(reg) root.as_u64(),
+            options(nostack)
+        );
+    }
+    // No ASIDs yet, so this CPU's whole TLB is flushed on every switch
+    tlb::flush_local_all();
+    tlb::set_active(root);
+}
+
+/// Switch to the empty lower half (no user address space).
+pub fn deactivate() {
+    let empty = kernel_virt_to_phys(VirtAddr::new(&raw const EMPTY_ROOT as usize));
+    // SAFETY: EMPTY_ROOT is a static, all-invalid L0 table. The kernel
+    // only uses TTBR1 addresses once boot has moved to the high half.
+    unsafe {
+        set_ttbr0(empty);
+    }
+}
+
+/// A user virtual address space (TTBR0 table hierarchy).
+#[derive(Debug)]
+pub struct AddressSpace {
+    /// Physical address of the L0 table.
+    root: PhysAddr,
+}
+
+impl AddressSpace {
+    /// Create a new, empty address space.
+    pub fn new() -> Result<Self, MappingError> {
+        Ok(Self {
+            root: alloc_frame_zeroed()?,
+        })
+    }
+
+    /// Whether this space is the one currently loaded in TTBR0.
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        current_ttbr0() == self.root
+    }
+
+    /// Load this address space into TTBR0_EL1.
+    ///
+    /// # Safety
+    /// The space must stay alive until another space is activated or
+    /// `deactivate` is called (Drop takes care of the latter).
+    pub unsafe fn activate(&self) {
+        // SAFETY: The root table is owned by `self`, which the caller
+        // keeps alive while it is active.
+        unsafe {
+            set_ttbr0(self.root);
+        }
+    }
+
+    /// Access a table frame through the direct map.
+    ///
+    /// # Safety
+    /// `phys` must be a table frame owned by this address space, and the
+    /// caller must not create aliasing mutable references to it.
+    #[inline]
+    unsafe fn table<'a>(phys: PhysAddr) -> &'a mut PageTable {
+        // SAFETY: Table frames are direct-mapped RAM owned by this space.
+        unsafe { &mut *phys_to_kernel_virt(phys).as_mut_ptr::<PageTable>() }
+    }
+
+    /// Find the L3 entry for `virt`, optionally allocating missing tables.
+    fn walk(&mut self, virt: VirtAddr, create: bool) -> Result<&mut PageTableEntry, MappingError> {
+        let (l0, l1, l2, l3) = virt.page_table_indices();
+        let mut table = self.root;
+
+        for index in [l0, l1, l2] {
+            // SAFETY: `table` is the root or was reached through a table
+            // descriptor we installed, so it is one of our table frames.
+            let entry = unsafe { &mut Self::table(table)[index] };
+
+            if !entry.is_valid() {
+                if !create {
+                    return Err(MappingError::NotMapped);
+                }
+                *entry = PageTableEntry::table(alloc_frame_zeroed()?);
+            }
+
+            table = entry.addr();
+        }
+
+        // SAFETY: As above, `table` is one of our L3 tables.
+        Ok(unsafe { &mut Self::table(table)[l3] })
+    }
+
+    /// Map a single 4 KiB page.
+    ///
+    /// Takes a reference on `phys` if the frame allocator manages it.
+    pub fn map_page(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        flags: PageFlags,
+    ) -> Result<(), MappingError> {
+        if !virt.is_aligned() || !phys.is_aligned() {
+            return Err(MappingError::MisalignedAddress);
+        }
+
+        if !virt.is_user()
+            || !flags.is_user()
+            || !flags.contains(PageFlags::PXN)
+            || (flags.is_writable() && flags.is_user_executable())
+        {
+            return Err(MappingError::InvalidPermissions);
+        }
+
+        let entry = self.walk(virt, true)?;
+        if entry.is_valid() {
+            return Err(MappingError::AlreadyMapped);
+        }
+
+        let flags = if frame_ref(phys) {
+            flags.union(PTE_FRAME_REF)
+        } else {
+            flags
+        };
+        *entry = PageTableEntry::page(phys, flags);
+
+        // Make the new descriptor visible to the table walker.
+        // SAFETY: Barriers have no memory-safety implications.
+        unsafe {
+            core::arch::asm!("dsb ishst", "isb", options(nostack, preserves_flags));
+        }
+
+        Ok(())
+    }
+
+    /// Unmap a single 4 KiB page.
+    ///
+    /// Other CPUs may still use the translation until the returned
+    /// `StalePage` is flushed, which also drops the mapping's frame
+    /// reference.
+    pub fn unmap_page(&mut self, virt: VirtAddr) -> Result<StalePage, MappingError> {
+        if !virt.is_user() {
+            return Err(MappingError::InvalidPermissions);
+        }
+        if !virt.is_aligned() {
+            return Err(MappingError::MisalignedAddress);
+        }
+
+        let entry = self.walk(virt, false)?;
+        if !entry.is_valid() {
+            return Err(MappingError::NotMapped);
+        }
+
+        let old = *entry;
+        entry.clear();
+
+        Ok(StalePage {
+            root: self.root,
+            virt,
+            frame: old.flags().contains(PTE_FRAME_REF).then(|| old.addr()),
+        })
+    }
+
+    /// Translate a user virtual address.
+    ///
+    /// Returns the physical address (including page offset) and the
+    /// leaf flags, or `None` if the address is not mapped.
+    pub fn translate(&self, virt: VirtAddr) -> Option<(PhysAddr, PageFlags)> {
+        if !virt.is_user() {
+            return None;
+        }
+
+        let (_, _, _, l3) = virt.page_table_indices();
+        let table = Self::leaf_table(self.root, virt)?;
+
+        // SAFETY: Read-only access to one of our L3 tables.
+        let leaf = unsafe { Self::table(table)[l3] };
+        if !leaf.is_valid() {
+            return None;
+        }
+
+        Some((leaf.addr().add(virt.page_offset()), leaf.flags()))
+    }
+
+    /// Find the L3 table covering `virt` without allocating.
+    fn leaf_table(root: PhysAddr, virt: VirtAddr) -> Option<PhysAddr> {
+        let (l0, l1, l2, _) = virt.page_table_indices();
+        let mut table = root;
+
+        for index in [l0, l1, l2] {
+            // SAFETY: Read-only walk over table frames reachable from root.
+            let entry = unsafe { Self::table(table)[index] };
+            if !entry.is_valid() {
+                return None;
+            }
+            table = entry.addr();
+        }
+
+        Some(table)
+    }
+
+    /// Release every leaf reference and table below `table`.
+    ///
+    /// `level` is the level of `table` (0 = root).
+    fn free_level(table: PhysAddr, level: usize) {
+        // SAFETY: Called only from Drop on tables owned by this space.
+        let entries = unsafe { Self::table(table) };
+
+        for (_, entry) in entries.iter_valid() {
+            if level < 3 {
+                Self::free_level(entry.addr(), level + 1);
+            } else if entry.flags().contains(PTE_FRAME_REF) {
+                free_frame(entry.addr());
+            }
+        }
+
+        free_frame(table);
+    }
+}
+
+/// A page unmapped from an address space that CPUs may still have in
+/// their TLBs.
+///
+/// The shootdown waits for other CPUs, so it must not run under a
+/// spinlock (see `tlb::flush`): unmap with the process locked, flush
+/// once the lock is released.
+#[must_use = "the translation stays cached and the frame referenced until flushed"]
+#[derive(Debug)]
+pub struct StalePage {
+    root: PhysAddr,
+    virt: VirtAddr,
+    /// Frame whose reference the mapping held.
+    frame: Option<PhysAddr>,
+}
+
+impl StalePage {
+    /// Shoot the translation down everywhere, then drop the frame
+    /// reference, so the frame is unreachable before it can be reused.
+    pub fn flush(self) {
+        tlb::flush(self.root, Flush::Page(self.virt));
+        if let Some(frame) = self.frame {
+            free_frame(frame);
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // Never free tables the MMU may still walk. Leaving the space
+        // also flushes each CPU's TLB, so no stale translation survives.
+        tlb::flush(self.root, Flush::Leave);
+
+        Self::free_level(self.root, 0);
+    }
+}
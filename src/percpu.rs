@@ -0,0 +1,100 @@
+//! Per-CPU Data
+//!
+//! `PerCpu<T>` holds one `T` per possible CPU. The running CPU finds its
+//! own slot through TPIDR_EL1, which points at its `CpuLocal` (smp.rs),
+//! so no lock is needed to reach it and CPUs never contend for it.
+//!
+//! # Design
+//! - A reference to the local slot is only handed out against a
+//!   `PreemptGuard`: while it is held the thread cannot be switched out,
+//!   so it cannot resume on another CPU with a stale reference
+//! - `T` must be `Sync`: IRQ handlers on the same CPU, and other CPUs
+//!   through `remote`, may see the same slot, so slots use atomics or
+//!   locks
+//! - The preemption count lives in `CpuLocal`; `sched::schedule` refuses
+//!   to switch while it is non-zero
+
+use core::marker::PhantomData;
+use core::sync::atomic::Ordering;
+
+use crate::smp::{self, CpuLocal, MAX_CPUS};
+
+/// One `T` per possible CPU.
+pub struct PerCpu<T> {
+    slots: [T; MAX_CPUS],
+}
+
+impl<T: Sync> PerCpu<T> {
+    /// Wrap one value per CPU, indexed by logical CPU number.
+    pub const fn new(slots: [T; MAX_CPUS]) -> Self {
+        Self { slots }
+    }
+
+    /// The calling CPU's slot.
+    #[inline]
+    pub fn get<'a>(&'a self, guard: &'a PreemptGuard) -> &'a T {
+        &self.slots[guard.cpu.id()]
+    }
+
+    /// Run `f` on the calling CPU's slot with preemption disabled.
+    #[inline]
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let guard = preempt_disable();
+        f(self.get(&guard))
+    }
+
+    /// The slot of CPU `cpu`.
+    ///
+    /// # Panics
+    /// If `cpu` is not below `MAX_CPUS`.
+    #[inline]
+    pub fn remote(&self, cpu: usize) -> &T {
+        &self.slots[cpu]
+    }
+
+    /// All slots, by CPU number.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter()
+    }
+}
+
+/// Keeps the current thread on its CPU while alive.
+///
+/// Not `Send`: it must be dropped on the CPU that created it.
+pub struct PreemptGuard {
+    cpu: &'static CpuLocal,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl PreemptGuard {
+    /// Logical number of the CPU the guard pins.
+    #[inline]
+    pub fn cpu_id(&self) -> usize {
+        self.cpu.id()
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        self.cpu.preempt_count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Disable preemption until the guard is dropped. Guards nest.
+#[inline]
+pub fn preempt_disable() -> PreemptGuard {
+    // Only the running thread changes the count, and it cannot move before
+    // the increment has taken effect
+    let cpu = smp::this_cpu();
+    cpu.preempt_count.fetch_add(1, Ordering::Relaxed);
+    PreemptGuard {
+        cpu,
+        _not_send: PhantomData,
+    }
+}
+
+/// Number of live `PreemptGuard`s on the calling CPU.
+#[inline]
+pub fn preempt_count() -> u32 {
+    smp::this_cpu().preempt_count.load(Ordering::Relaxed)
+}
@@ -0,0 +1,294 @@
+//! User Processes
+//!
+//! A `Process` owns everything a user program holds: its address space
+//! and its capability space. Threads run inside a process; when the
+//! process exits or faults, the kernel tears it down and schedules the
+//! next thread instead of halting the machine.
+//!
+//! # Lifecycle
+//! ```text
+//! new() ──► Running ──► terminate(reason) ──► Exited ──► last Arc dropped
+//!                         │ record ExitReason
+//!                         │ release CSpace (kernel objects)
+//!                         │ release AddressSpace (frames, tables)
+//!                         │ leave the process table
+//!                         └ wake exit waiters
+//! ```
+//!
+//! An exited process keeps only its name, PID and exit reason; it is
+//! freed once the last thread and waiter holding it let go, so nothing
+//! has to reap it.
+//!
+//! # Security Properties
+//! - Teardown drops every capability the process held, so kernel objects
+//!   only it referenced are freed and shared ones lose a reference
+//! - The dying address space is switched out of TTBR0 before its tables
+//!   are freed (see `AddressSpace::drop`)
+//! - Termination is idempotent: the first exit reason wins, a second
+//!   fault during teardown cannot release anything twice
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::cap::CSpace;
+use crate::exception::{Esr, ExceptionContext};
+use crate::mm::{AddressSpace, MappingError};
+use crate::sync::{RwSpinLock, TicketLock, WaitQueue};
+use crate::{kprintln, lock_class, sched};
+
+/// Process identifier. Never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u32);
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Why a process stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The program called `exit(status)`.
+    Exited(i32),
+    /// A synchronous exception the kernel does not recover from.
+    Fault {
+        /// ESR_EL1 of the fault.
+        esr: u64,
+        /// FAR_EL1 (meaningful only if the syndrome says so).
+        far: u64,
+        /// Faulting instruction.
+        elr: u64,
+    },
+    /// An asynchronous error (SError) taken while the process ran.
+    SError {
+        /// ESR_EL1 of the SError.
+        esr: u64,
+    },
+    /// Stopped by the kernel, e.g. because it could not be started.
+    Killed,
+}
+
+impl ExitReason {
+    /// Build a fault reason from a saved exception context.
+    pub fn fault(ctx: &ExceptionContext) -> Self {
+        Self::Fault {
+            esr: ctx.esr,
+            far: ctx.far,
+            elr: ctx.elr,
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Exited(status) => write!(f, "exited with status {}", status),
+            Self::Fault { esr, far, elr } => {
+                let esr = Esr::new(esr);
+                write!(f, "killed by fault: {} at pc 0x{:x}", esr, elr)?;
+                if esr.abort().is_some_and(|abort| abort.far_valid) {
+                    write!(f, ", address 0x{:x}", far)?;
+                }
+                Ok(())
+            }
+            Self::SError { esr } => write!(f, "killed by SError (ESR 0x{:x})", esr),
+            Self::Killed => write!(f, "killed by the kernel"),
+        }
+    }
+}
+
+/// Errors from process management.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    /// Could not allocate the address space or bookkeeping.
+    OutOfMemory,
+}
+
+impl From<MappingError> for ProcessError {
+    fn from(_: MappingError) -> Self {
+        Self::OutOfMemory
+    }
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfMemory => write!(f, "Out of memory"),
+        }
+    }
+}
+
+/// Mutable process state.
+struct ProcessInner {
+    /// Lower-half mappings; `None` once torn down.
+    vspace: Option<AddressSpace>,
+    /// Capability space; `None` once torn down.
+    cspace: Option<Box<CSpace>>,
+    /// Set exactly once, on termination.
+    exit: Option<ExitReason>,
+}
+
+/// A user process.
+pub struct Process {
+    pid: Pid,
+    name: String,
+    /// Set with `inner.exit`, readable without the lock (the scheduler
+    /// checks it with its own lock held).
+    exited: AtomicBool,
+    /// Threads sleeping in `wait`.
+    exit_waiters: WaitQueue,
+    inner: TicketLock<ProcessInner>,
+}
+
+/// Next PID to hand out.
+static NEXT_PID: AtomicU32 = AtomicU32::new(1);
+
+/// Every process that has not exited.
+static PROCESSES: RwSpinLock<BTreeMap<Pid, Arc<Process>>> =
+    RwSpinLock::new(BTreeMap::new(), lock_class!("process table"));
+
+impl Process {
+    /// Create a process running in `vspace`, with an empty capability
+    /// space.
+    pub fn new(name: &str, vspace: AddressSpace) -> Result<Arc<Self>, ProcessError> {
+        let cspace = Box::new(CSpace::new());
+
+        let pid = Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed));
+        let process = Arc::new(Self {
+            pid,
+            name: String::from(name),
+            exited: AtomicBool::new(false),
+            exit_waiters: WaitQueue::new(),
+            inner: TicketLock::new(
+                ProcessInner {
+                    vspace: Some(vspace),
+                    cspace: Some(cspace),
+                    exit: None,
+                },
+                lock_class!("process"),
+            ),
+        });
+
+        PROCESSES.write().insert(pid, process.clone());
+        kprintln!("[PROCESS] Created {} (pid {})", process.name, pid);
+        Ok(process)
+    }
+
+    /// Process identifier.
+    #[inline]
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Name given at creation.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Why the process exited, or `None` while it still runs.
+    pub fn exit_reason(&self) -> Option<ExitReason> {
+        self.inner.lock().exit
+    }
+
+    /// Whether the process has exited; takes no lock.
+    #[inline]
+    pub fn has_exited(&self) -> bool {
+        self.exited.load(Ordering::Acquire)
+    }
+
+    /// Run `f` on the address space, unless the process is torn down.
+    pub fn with_vspace<R>(&self, f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+        self.inner.lock().vspace.as_mut().map(f)
+    }
+
+    /// Run `f` on the capability space, unless the process is torn down.
+    pub fn with_cspace<R>(&self, f: impl FnOnce(&mut CSpace) -> R) -> Option<R> {
+        self.inner.lock().cspace.as_deref_mut().map(f)
+    }
+
+    /// Sleep until the process exits, and return why it did.
+    ///
+    /// Returns at once if it has exited already. Thread context only,
+    /// with no spinlock held.
+    pub fn wait(&self) -> ExitReason {
+        self.exit_waiters.wait_until(|| self.has_exited());
+        self.exit_reason()
+            .expect("exited process without an exit reason")
+    }
+
+    /// Tear the process down and record why.
+    ///
+    /// Releases the capability space (and with it every kernel object
+    /// only this process referenced), then the address space, removes
+    /// the process from the process table and wakes all exit waiters.
+    /// Later calls are ignored.
+    pub fn terminate(&self, reason: ExitReason) {
+        let (cspace, vspace) = {
+            let mut inner = self.inner.lock();
+            if inner.exit.is_some() {
+                return;
+            }
+            inner.exit = Some(reason);
+            self.exited.store(true, Ordering::Release);
+            (inner.cspace.take(), inner.vspace.take())
+        };
+
+        kprintln!("[PROCESS] {} (pid {}) {}", self.name, self.pid, reason);
+
+        // Capabilities first: objects may still reference user mappings
+        drop(cspace);
+        drop(vspace);
+
+        // The caller's reference keeps `self` alive; threads and waiters
+        // holding the process keep it until they let go
+        let entry = PROCESSES.write().remove(&self.pid);
+        drop(entry);
+
+        // `exited` was set above, so no waiter can miss this
+        self.exit_waiters.wake_all();
+    }
+}
+
+/// Number of processes that have not exited.
+pub fn count() -> usize {
+    PROCESSES.read().len()
+}
+
+/// Print every process that has not exited.
+///
+/// For the panic path: gives up instead of waiting if a writer holds
+/// the table, which may be the panicking CPU itself.
+pub fn report() {
+    let Some(processes) = PROCESSES.try_read() else {
+        kprintln!("[PROCESS] Process table busy");
+        return;
+    };
+    for process in processes.values() {
+        kprintln!("[PROCESS] {} (pid {})", process.name, process.pid);
+    }
+}
+
+/// The process of the thread running on this CPU, if any.
+pub fn current() -> Option<Arc<Process>> {
+    sched::current().and_then(|thread| thread.process().cloned())
+}
+
+/// Terminate the current process and run something else.
+///
+/// Called from exception and syscall context on behalf of the thread
+/// that trapped; never returns to it. Other threads of the process are
+/// dropped by the scheduler instead of being run.
+pub fn exit_current(reason: ExitReason) -> ! {
+    match current() {
+        Some(process) => process.terminate(reason),
+        None => kprintln!("[PROCESS] No current process to terminate ({})", reason),
+    }
+
+    sched::exit_current()
+}
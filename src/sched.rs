@@ -0,0 +1,933 @@
+//! Fixed-Priority Preemptive Scheduler
+//!
+//! Always runs the highest-priority ready `Thread`; threads of equal
+//! priority share the CPU round-robin, one time slice at a time.
+//!
+//! # Design
+//! - One FIFO queue per priority level plus a bitmap of non-empty levels,
+//!   so picking the next thread is a find-first-set, independent of the
+//!   number of threads; the running thread is `current`
+//! - A thread that becomes ready with a higher priority than `current`
+//!   sets `NEED_RESCHED`; the preempted thread goes back to the *front*
+//!   of its level and keeps the rest of its slice
+//! - The timer tick counts down the current slice and sets
+//!   `NEED_RESCHED` when it runs out (or when work arrives for idle); an
+//!   expired thread only gives way to threads of the same or higher
+//!   priority, and moves to the back of its level
+//! - Threads with a time slice of 0 are never sliced (real-time FIFO
+//!   class): they run until they block, yield or are preempted
+//! - Priority inheritance: a thread that waits on another (such as an
+//!   IPC client waiting for the server's reply) lends it its priority
+//!   with `inherit_priority` until `release_priority`, so a low-priority
+//!   server cannot hold up a high-priority client behind medium-priority
+//!   work
+//! - The kernel is not preemptible: a pending reschedule takes effect on
+//!   the way back to EL0 (`preempt`), or when a kernel thread calls
+//!   `schedule` / `yield_now` itself
+//! - The idle thread runs when nothing else is ready; it sleeps in WFI
+//!   and never sits in the run queue. An idle CPU stops ticking: its
+//!   timer is set to a one-shot deadline for the next balancing pass, or
+//!   masked if no CPU has threads to spare, and the periodic tick resumes
+//!   once the CPU has work
+//! - A thread cannot be released while its stack is in use, so the
+//!   thread switched away from stays in `prev` until the next thread has
+//!   taken over (`finish_switch`); exited threads are dropped there
+//!
+//! # Multiprocessing
+//! - Every CPU has its own `Scheduler` (run queue, `current`, idle
+//!   thread), `NEED_RESCHED` flag, load figures and statistics, kept in
+//!   `PerCpu` slots
+//! - A thread that becomes ready goes to the least loaded CPU its
+//!   affinity mask allows, preferring the one it last ran on; a remote
+//!   CPU that should preempt its current thread gets an `Ipi::Reschedule`
+//! - A CPU about to go idle steals the best ready thread it may run from
+//!   another CPU, and every `BALANCE_INTERVAL` ticks a CPU pulls one
+//!   thread from the busiest CPU if the difference is more than one (an
+//!   idle CPU on every tick, which it only takes that often)
+//! - A thread is marked `on_cpu` until the CPU leaving it has saved its
+//!   context; another CPU picking it waits for that
+//!
+//! # Locking
+//! A CPU's scheduler lock is also taken by its tick handler, so it is an
+//! `IrqSpinLock`. Two scheduler locks are only taken together in CPU
+//! order (`lock_nested`), or with `try_lock` for the second one, so CPUs
+//! cannot deadlock on each other. A thread's `cpu` only changes while
+//! the lock of the CPU it leaves is held. `schedule` must not be called
+//! with preemption disabled, with a spinlock held or from an IRQ handler.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::drivers::timer;
+use crate::exception;
+use crate::fpsimd;
+use crate::{kprintln, lock_class};
+use crate::percpu::{self, PerCpu};
+use crate::smp::{self, Ipi, MAX_CPUS};
+use crate::sync::{self, IrqSpinLock, IrqSpinLockGuard};
+use crate::thread::{cpu_switch_to, KernelContext, Thread, ThreadState, NUM_PRIORITIES};
+use crate::time::Instant;
+
+/// Number of words in the priority bitmap.
+const BITMAP_WORDS: usize = NUM_PRIORITIES / 64;
+
+/// Ticks between periodic load balancing passes on each CPU.
+const BALANCE_INTERVAL: u64 = 10;
+
+/// Ready threads by priority.
+struct RunQueue {
+    /// One FIFO per priority level.
+    levels: [VecDeque<Arc<Thread>>; NUM_PRIORITIES],
+    /// Bit `p % 64` of word `p / 64` is set iff level `p` is non-empty.
+    bitmap: [u64; BITMAP_WORDS],
+    /// Number of queued threads.
+    len: usize,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            levels: [const { VecDeque::new() }; NUM_PRIORITIES],
+            bitmap: [0; BITMAP_WORDS],
+            len: 0,
+        }
+    }
+
+    /// Highest priority with a ready thread.
+    fn highest(&self) -> Option<u8> {
+        let word = (0..BITMAP_WORDS)
+            .rev()
+            .find(|&word| self.bitmap[word] != 0)?;
+        let bit = 63 - self.bitmap[word].leading_zeros() as usize;
+        Some((word * 64 + bit) as u8)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn mark(&mut self, priority: u8) {
+        let priority = priority as usize;
+        if self.levels[priority].is_empty() {
+            self.bitmap[priority / 64] &= !(1 << (priority % 64));
+        } else {
+            self.bitmap[priority / 64] |= 1 << (priority % 64);
+        }
+    }
+
+    /// Queue `thread` behind the others of its priority.
+    fn push_back(&mut self, thread: Arc<Thread>) {
+        let priority = thread.priority();
+        self.levels[priority as usize].push_back(thread);
+        self.len += 1;
+        self.mark(priority);
+    }
+
+    /// Queue `thread` ahead of the others of its priority.
+    fn push_front(&mut self, thread: Arc<Thread>) {
+        let priority = thread.priority();
+        self.levels[priority as usize].push_front(thread);
+        self.len += 1;
+        self.mark(priority);
+    }
+
+    /// Take the first thread of the highest non-empty level.
+    fn pop(&mut self) -> Option<Arc<Thread>> {
+        let priority = self.highest()?;
+        let thread = self.levels[priority as usize].pop_front();
+        self.len -= 1;
+        self.mark(priority);
+        thread
+    }
+
+    /// Take `thread` out of its level; returns whether it was queued.
+    fn remove(&mut self, thread: &Arc<Thread>) -> bool {
+        let priority = thread.priority();
+        let level = &mut self.levels[priority as usize];
+        let Some(index) = level.iter().position(|queued| Arc::ptr_eq(queued, thread)) else {
+            return false;
+        };
+        level.remove(index);
+        self.len -= 1;
+        self.mark(priority);
+        true
+    }
+
+    /// Take the highest-priority thread allowed on `cpu`, most recently
+    /// queued first (the one that waited least loses least by moving).
+    fn take_allowed(&mut self, cpu: usize) -> Option<Arc<Thread>> {
+        let priority = (0..NUM_PRIORITIES).rev().find(|&priority| {
+            self.bitmap[priority / 64] & (1 << (priority % 64)) != 0
+                && self.levels[priority]
+                    .iter()
+                    .any(|thread| thread.allowed_on(cpu))
+        })?;
+        let level = &mut self.levels[priority];
+        let index = level.iter().rposition(|thread| thread.allowed_on(cpu))?;
+        let thread = level.remove(index);
+        self.len -= 1;
+        self.mark(priority as u8);
+        thread
+    }
+}
+
+/// Scheduler state of one CPU.
+struct Scheduler {
+    /// Logical number of the CPU.
+    cpu: usize,
+    /// Ready threads.
+    ready: RunQueue,
+    /// Thread on the CPU.
+    current: Option<Arc<Thread>>,
+    /// Runs when nothing else is ready.
+    idle: Option<Arc<Thread>>,
+    /// Thread switched away from, until the switch has completed.
+    prev: Option<Arc<Thread>>,
+    /// `prev`, when it has to move to a CPU its affinity allows.
+    migrating: Option<Arc<Thread>>,
+}
+
+/// Load figures of one CPU, readable without its scheduler lock.
+struct CpuLoad {
+    /// Threads in the run queue.
+    queued: AtomicUsize,
+    /// Whether a thread other than idle is running.
+    busy: AtomicBool,
+}
+
+impl CpuLoad {
+    const fn new() -> Self {
+        Self {
+            queued: AtomicUsize::new(0),
+            busy: AtomicBool::new(false),
+        }
+    }
+
+    fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Runnable threads assigned to the CPU.
+    fn total(&self) -> usize {
+        self.queued() + self.busy.load(Ordering::Relaxed) as usize
+    }
+}
+
+/// Scheduling counters of one CPU.
+struct SchedStats {
+    /// Timer ticks taken.
+    ticks: AtomicU64,
+    /// Ticks that found the idle thread running.
+    idle_ticks: AtomicU64,
+    /// Context switches.
+    switches: AtomicU64,
+    /// Threads taken over from other CPUs.
+    migrations: AtomicU64,
+}
+
+impl SchedStats {
+    const fn new() -> Self {
+        Self {
+            ticks: AtomicU64::new(0),
+            idle_ticks: AtomicU64::new(0),
+            switches: AtomicU64::new(0),
+            migrations: AtomicU64::new(0),
+        }
+    }
+}
+
+/// One scheduler per CPU, all of one lock class.
+const fn schedulers() -> [IrqSpinLock<Scheduler>; MAX_CPUS] {
+    let class = lock_class!("scheduler");
+    let mut scheds = [const { MaybeUninit::uninit() }; MAX_CPUS];
+    let mut cpu = 0;
+    while cpu < MAX_CPUS {
+        scheds[cpu] = MaybeUninit::new(IrqSpinLock::new(Scheduler::new(cpu), class));
+        cpu += 1;
+    }
+    // SAFETY: The loop initialized every element, and `MaybeUninit<T>`
+    // has the layout of `T`.
+    unsafe { core::mem::transmute(scheds) }
+}
+
+static SCHED: PerCpu<IrqSpinLock<Scheduler>> = PerCpu::new(schedulers());
+
+/// Set when the CPU's current thread should give up the CPU.
+static NEED_RESCHED: PerCpu<AtomicBool> = PerCpu::new([const { AtomicBool::new(false) }; MAX_CPUS]);
+
+static LOAD: PerCpu<CpuLoad> = PerCpu::new([const { CpuLoad::new() }; MAX_CPUS]);
+
+static STATS: PerCpu<SchedStats> = PerCpu::new([const { SchedStats::new() }; MAX_CPUS]);
+
+/// CPUs running the scheduler (bit n = CPU n).
+static ACTIVE_CPUS: AtomicU64 = AtomicU64::new(0);
+
+/// Lock the calling CPU's scheduler. IRQs must be masked, which also
+/// keeps the caller on this CPU while it holds the lock.
+fn local_sched() -> IrqSpinLockGuard<'static, Scheduler> {
+    let guard = percpu::preempt_disable();
+    SCHED.remote(guard.cpu_id()).lock()
+}
+
+/// Run `f` with the calling CPU's scheduler locked and IRQs masked.
+fn with_sched<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    let irqs = exception::save_and_disable_irqs();
+    let result = f(&mut local_sched());
+    exception::restore_irqs(irqs);
+    result
+}
+
+/// Run `f` with the scheduler of the CPU `thread` is assigned to locked
+/// and IRQs masked.
+fn with_thread_sched<R>(thread: &Thread, f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    let irqs = exception::save_and_disable_irqs();
+    let result = loop {
+        let cpu = thread.cpu();
+        let mut sched = SCHED.remote(cpu).lock();
+        // It may have moved before we got the lock, but not since
+        if thread.cpu() == cpu {
+            break f(&mut sched);
+        }
+    };
+    exception::restore_irqs(irqs);
+    result
+}
+
+/// Whether the calling CPU has a reschedule pending.
+fn need_resched() -> bool {
+    NEED_RESCHED.with(|flag| flag.load(Ordering::Relaxed))
+}
+
+/// Whether CPU `cpu` runs the scheduler.
+fn is_active(cpu: usize) -> bool {
+    ACTIVE_CPUS.load(Ordering::Acquire) & (1 << cpu) != 0
+}
+
+/// CPU a ready `thread` should be queued on.
+///
+/// The least loaded active CPU its affinity allows; the CPU it last ran
+/// on wins ties, since its caches may still be warm.
+fn select_cpu(thread: &Thread) -> usize {
+    let last = thread.cpu();
+    (0..MAX_CPUS)
+        .filter(|&cpu| is_active(cpu) && thread.allowed_on(cpu))
+        .min_by_key(|&cpu| (LOAD.remote(cpu).total(), cpu != last))
+        // Affinity masks are checked against active CPUs when set
+        .unwrap_or_else(smp::cpu_id)
+}
+
+/// Queue a ready `thread` on the CPU chosen for it.
+///
+/// The thread must be in no run queue and not running.
+fn enqueue(thread: Arc<Thread>) {
+    enqueue_if(thread, |_| true);
+}
+
+/// Queue `thread` as `enqueue` does, if `ready` still holds with the
+/// scheduler of the CPU it is assigned to locked; returns whether it was
+/// queued.
+fn enqueue_if(thread: Arc<Thread>, ready: impl Fn(&Thread) -> bool) -> bool {
+    let irqs = exception::save_and_disable_irqs();
+    let queued = loop {
+        let old = thread.cpu();
+        let new = select_cpu(&thread);
+
+        // Both locks, in CPU order
+        let first = SCHED.remote(old.min(new)).lock();
+        let second = (old != new).then(|| SCHED.remote(old.max(new)).lock_nested());
+        if thread.cpu() != old {
+            continue;
+        }
+        if !ready(&thread) {
+            break false;
+        }
+
+        let (mut target, _other) = match second {
+            Some(second) if new > old => (second, Some(first)),
+            Some(second) => (first, Some(second)),
+            None => (first, None),
+        };
+        target.enqueue(thread);
+        break true;
+    };
+    exception::restore_irqs(irqs);
+    queued
+}
+
+impl Scheduler {
+    const fn new(cpu: usize) -> Self {
+        Self {
+            cpu,
+            ready: RunQueue::new(),
+            current: None,
+            idle: None,
+            prev: None,
+            migrating: None,
+        }
+    }
+
+    fn is_idle(&self, thread: &Arc<Thread>) -> bool {
+        self.idle
+            .as_ref()
+            .is_some_and(|idle| Arc::ptr_eq(idle, thread))
+    }
+
+    fn is_current(&self, thread: &Arc<Thread>) -> bool {
+        self.current
+            .as_ref()
+            .is_some_and(|current| Arc::ptr_eq(current, thread))
+    }
+
+    /// Ask this scheduler's CPU to reschedule, interrupting it if it is
+    /// not the calling CPU.
+    fn request_resched(&self) {
+        NEED_RESCHED.remote(self.cpu).store(true, Ordering::Relaxed);
+        if self.cpu != smp::cpu_id() {
+            smp::send_ipi(self.cpu, Ipi::Reschedule);
+        }
+    }
+
+    /// Make the CPU's load visible to the others.
+    fn publish_load(&self) {
+        let load = LOAD.remote(self.cpu);
+        load.queued.store(self.ready.len, Ordering::Relaxed);
+        let busy = self
+            .current
+            .as_ref()
+            .is_some_and(|current| !self.is_idle(current));
+        load.busy.store(busy, Ordering::Relaxed);
+    }
+
+    /// Next runnable thread, skipping threads of exited processes.
+    fn pick_next(&mut self) -> Option<Arc<Thread>> {
+        while let Some(thread) = self.ready.pop() {
+            if thread.process_exited() {
+                thread.set_state(ThreadState::Exited);
+                continue;
+            }
+            return Some(thread);
+        }
+        None
+    }
+
+    /// Whether a ready thread should take the CPU from `current`.
+    fn should_preempt(&self, current: &Arc<Thread>) -> bool {
+        if self.is_idle(current) {
+            return !self.ready.is_empty();
+        }
+        match self.ready.highest() {
+            Some(priority) if priority > current.priority() => true,
+            Some(priority) => priority == current.priority() && current.slice_expired(),
+            None => false,
+        }
+    }
+
+    /// Make `thread` ready here with a fresh slice, preempting if it
+    /// outranks the running thread.
+    fn enqueue(&mut self, thread: Arc<Thread>) {
+        thread.set_cpu(self.cpu);
+        thread.set_state(ThreadState::Ready);
+        thread.reset_slice();
+        self.ready.push_back(thread);
+        self.publish_load();
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|current| self.should_preempt(current))
+        {
+            self.request_resched();
+        }
+    }
+
+    /// Take a ready thread that may run here from `victim`'s run queue.
+    fn pull_from(&mut self, victim: &mut Scheduler) -> Option<Arc<Thread>> {
+        let thread = victim.ready.take_allowed(self.cpu)?;
+        thread.set_cpu(self.cpu);
+        victim.publish_load();
+        STATS
+            .remote(self.cpu)
+            .migrations
+            .fetch_add(1, Ordering::Relaxed);
+        Some(thread)
+    }
+
+    /// Steal a thread from another CPU, busiest first, without waiting
+    /// for any other scheduler lock.
+    fn steal(&mut self) -> Option<Arc<Thread>> {
+        let mut tried = 0u64;
+        loop {
+            let victim = (0..MAX_CPUS)
+                .filter(|&cpu| cpu != self.cpu && tried & (1 << cpu) == 0 && is_active(cpu))
+                .filter(|&cpu| LOAD.remote(cpu).queued() > 0)
+                .max_by_key(|&cpu| LOAD.remote(cpu).queued())?;
+            tried |= 1 << victim;
+
+            if let Some(mut victim) = SCHED.remote(victim).try_lock() {
+                if let Some(thread) = self.pull_from(&mut victim) {
+                    return Some(thread);
+                }
+            }
+        }
+    }
+
+    /// Bring the effective priority of `thread` up to date after its base
+    /// or inherited priorities changed.
+    fn update_priority(&mut self, thread: &Arc<Thread>) {
+        let priority = thread.computed_priority();
+        if priority == thread.priority() {
+            return;
+        }
+
+        // A queued thread moves to its new level
+        if self.ready.remove(thread) {
+            thread.set_priority(priority);
+            self.ready.push_back(thread.clone());
+        } else {
+            thread.set_priority(priority);
+        }
+
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|current| self.should_preempt(current))
+        {
+            self.request_resched();
+        }
+    }
+}
+
+/// Create the boot CPU's idle thread and hook the scheduler into the
+/// timer tick.
+pub fn init() {
+    init_cpu();
+    timer::set_tick_handler(tick);
+}
+
+/// Create the calling CPU's idle thread and let it take threads.
+///
+/// `start` then begins scheduling on it.
+pub fn init_cpu() {
+    let cpu = smp::cpu_id();
+    let idle = match Thread::new_kernel("idle", idle_thread, 0) {
+        Ok(thread) => thread,
+        Err(e) => panic!("Failed to create idle thread for CPU{}: {}", cpu, e),
+    };
+    idle.set_affinity(1 << cpu);
+    idle.set_cpu(cpu);
+    with_sched(|sched| sched.idle = Some(idle));
+    ACTIVE_CPUS.fetch_or(1 << cpu, Ordering::AcqRel);
+}
+
+/// Make `thread` runnable.
+pub fn spawn(thread: Arc<Thread>) {
+    enqueue(thread);
+}
+
+/// Set the base priority of `thread`.
+///
+/// The caller checks the priority against the authority's MCP. Takes
+/// effect immediately: a ready thread moves to its new level, and the
+/// thread's CPU is rescheduled if the change lets a thread outrank its
+/// current one.
+pub fn set_priority(thread: &Arc<Thread>, priority: u8) {
+    with_thread_sched(thread, |sched| {
+        thread.set_base_priority(priority);
+        sched.update_priority(thread);
+    });
+}
+
+/// Lend `priority` to `thread` while some thread waits on it.
+///
+/// Each call must be paired with a `release_priority` of the same value.
+pub fn inherit_priority(thread: &Arc<Thread>, priority: u8) {
+    with_thread_sched(thread, |sched| {
+        thread.add_inherited(priority);
+        sched.update_priority(thread);
+    });
+}
+
+/// Return a priority lent with `inherit_priority`.
+pub fn release_priority(thread: &Arc<Thread>, priority: u8) {
+    with_thread_sched(thread, |sched| {
+        thread.remove_inherited(priority);
+        sched.update_priority(thread);
+    });
+}
+
+/// Whether `mask` names at least one CPU that runs the scheduler.
+pub fn valid_affinity(mask: u64) -> bool {
+    mask & ACTIVE_CPUS.load(Ordering::Acquire) != 0
+}
+
+/// Restrict `thread` to the CPUs in `mask`.
+///
+/// The caller checks the mask with `valid_affinity`. A queued thread
+/// moves to an allowed CPU at once; a running one leaves its CPU at the
+/// next reschedule, which is requested here.
+pub fn set_affinity(thread: &Arc<Thread>, mask: u64) {
+    let requeue = with_thread_sched(thread, |sched| {
+        thread.set_affinity(mask);
+        if thread.allowed_on(sched.cpu) {
+            return false;
+        }
+        if sched.ready.remove(thread) {
+            sched.publish_load();
+            return true;
+        }
+        if sched.is_current(thread) {
+            sched.request_resched();
+        }
+        false
+    });
+    if requeue {
+        enqueue(thread.clone());
+    }
+}
+
+/// The thread running on this CPU.
+pub fn current() -> Option<Arc<Thread>> {
+    with_sched(|sched| sched.current.clone())
+}
+
+/// Mark the current thread blocked and return it, for whoever will
+/// `wake` it.
+///
+/// It keeps running until it calls `schedule`; a `wake` before then
+/// just lets it continue.
+pub fn block_current() -> Arc<Thread> {
+    with_sched(|sched| {
+        let current = sched.current.clone().expect("no current thread");
+        debug_assert!(!sched.is_idle(&current), "idle thread blocked");
+        current.set_state(ThreadState::Blocked);
+        current
+    })
+}
+
+/// Make a thread blocked with `block_current` runnable again.
+///
+/// Does nothing if it has been woken already, or has exited.
+pub fn wake(thread: Arc<Thread>) {
+    // Checked under the lock `block_current` sets the state under
+    enqueue_if(thread, |thread| thread.state() == ThreadState::Blocked);
+}
+
+/// Timer tick (IRQ context): account the running thread's slice and
+/// balance the load now and then.
+fn tick() {
+    let balance_due = {
+        let sched = local_sched();
+        let Some(current) = sched.current.as_ref() else {
+            return;
+        };
+
+        let stats = STATS.remote(sched.cpu);
+        let ticks = stats.ticks.fetch_add(1, Ordering::Relaxed) + 1;
+        let idle = sched.is_idle(current);
+        if idle {
+            stats.idle_ticks.fetch_add(1, Ordering::Relaxed);
+        } else {
+            current.consume_tick();
+        }
+        if sched.should_preempt(current) {
+            sched.request_resched();
+        }
+        // Idle CPUs only tick at their balancing deadline
+        idle || ticks.is_multiple_of(BALANCE_INTERVAL)
+    };
+
+    if balance_due {
+        balance();
+    }
+}
+
+/// Pull one thread from the busiest CPU if it has at least two more
+/// runnable threads than this one (IRQ context).
+fn balance() {
+    let mut sched = local_sched();
+    let local = LOAD.remote(sched.cpu).total();
+    let Some(busiest) = (0..MAX_CPUS)
+        .filter(|&cpu| cpu != sched.cpu && is_active(cpu))
+        .max_by_key(|&cpu| LOAD.remote(cpu).total())
+    else {
+        return;
+    };
+    if LOAD.remote(busiest).total() < local + 2 {
+        return;
+    }
+
+    // Never wait for a second scheduler lock here
+    let Some(mut victim) = SCHED.remote(busiest).try_lock() else {
+        return;
+    };
+    if let Some(thread) = sched.pull_from(&mut victim) {
+        drop(victim);
+        sched.enqueue(thread);
+    }
+}
+
+/// Reschedule if the tick asked for it.
+///
+/// Called on the way back to user mode.
+pub fn preempt() {
+    if need_resched() {
+        schedule();
+    }
+}
+
+/// Give up the rest of the time slice.
+pub fn yield_now() {
+    if let Some(current) = current() {
+        current.expire_slice();
+    }
+    schedule();
+}
+
+/// Switch to the highest-priority ready thread.
+///
+/// A current thread that is still running keeps the CPU unless a ready
+/// thread outranks it, or matches it after its slice ran out, or its
+/// affinity no longer allows this CPU; otherwise it is queued again (at
+/// the back if its slice expired, on another CPU if it has to move). A
+/// blocked thread stays off the run queue, an exited one is destroyed
+/// after the switch. Returns when the current thread is scheduled again,
+/// possibly on another CPU.
+pub fn schedule() {
+    debug_assert_eq!(
+        percpu::preempt_count(),
+        0,
+        "schedule with preemption disabled"
+    );
+    debug_assert!(!exception::in_irq(), "schedule in IRQ context");
+    sync::assert_none_held("schedule");
+
+    let irqs = exception::save_and_disable_irqs();
+    NEED_RESCHED.with(|flag| flag.store(false, Ordering::Relaxed));
+
+    let switch = {
+        let mut sched = local_sched();
+        match sched.current.clone() {
+            Some(prev) => pick_and_prepare(&mut sched, prev),
+            None => None,
+        }
+    };
+
+    if let Some((prev, next)) = switch {
+        // SAFETY: Both contexts belong to live threads: `next` is
+        // `current`, `prev` is held in `Scheduler::prev` until
+        // `finish_switch`, and no other CPU resumes it before then
+        // (`on_cpu`). IRQs are masked until we are back.
+        unsafe { cpu_switch_to(prev, next) };
+        finish_switch();
+    }
+
+    exception::restore_irqs(irqs);
+}
+
+/// Choose the successor of `prev` and make it current.
+///
+/// Returns the contexts to switch between, or `None` to keep running
+/// `prev`.
+fn pick_and_prepare(
+    sched: &mut Scheduler,
+    prev: Arc<Thread>,
+) -> Option<(*mut KernelContext, *const KernelContext)> {
+    let prev_runnable = prev.state() == ThreadState::Running && !sched.is_idle(&prev);
+    let prev_stays = prev_runnable && prev.allowed_on(sched.cpu);
+
+    let next = if prev_stays && !sched.should_preempt(&prev) {
+        None
+    } else if prev_stays {
+        sched.pick_next()
+    } else {
+        // About to go idle otherwise: look for work elsewhere
+        sched.pick_next().or_else(|| sched.steal())
+    };
+    let next = match next {
+        Some(next) => next,
+        None if prev_stays => {
+            // Nothing outranks it: keep running, on a new slice if needed
+            if prev.slice_expired() {
+                prev.reset_slice();
+            }
+            return None;
+        }
+        None if sched.is_idle(&prev) && prev.state() == ThreadState::Running => return None,
+        None => sched.idle.clone()?,
+    };
+    if Arc::ptr_eq(&next, &prev) {
+        // Woken before it got off the CPU
+        next.set_state(ThreadState::Running);
+        sched.publish_load();
+        return None;
+    }
+
+    if prev_runnable {
+        prev.set_state(ThreadState::Ready);
+        if !prev_stays {
+            // Queued elsewhere once its context is saved
+            sched.migrating = Some(prev.clone());
+        } else if prev.slice_expired() {
+            prev.reset_slice();
+            sched.ready.push_back(prev.clone());
+        } else {
+            // Preempted: first in line at its level, rest of the slice
+            sched.ready.push_front(prev.clone());
+        }
+    }
+
+    // The CPU it ran on before may still be saving its context
+    while next.on_cpu() {
+        core::hint::spin_loop();
+    }
+    next.set_on_cpu(true);
+    next.set_state(ThreadState::Running);
+    switch_address_space(&next);
+    // SAFETY: The save area lives in `next`, and Thread::drop releases
+    // it before it goes away.
+    unsafe { fpsimd::switch_to(next.fp_state_ptr()) };
+
+    let contexts = (prev.context_ptr(), next.context_ptr() as *const _);
+    sched.prev = Some(prev);
+    sched.current = Some(next);
+    sched.publish_load();
+    STATS
+        .remote(sched.cpu)
+        .switches
+        .fetch_add(1, Ordering::Relaxed);
+    Some(contexts)
+}
+
+/// Load the user address space of `next`.
+///
+/// Kernel threads keep whatever TTBR0 holds: they never touch the lower
+/// half, and a dying address space switches itself out.
+fn switch_address_space(next: &Thread) {
+    if let Some(process) = next.process() {
+        process.with_vspace(|vspace| {
+            if !vspace.is_active() {
+                // SAFETY: The process (and so the space) stays alive while
+                // its thread runs; teardown deactivates it before freeing.
+                unsafe { vspace.activate() };
+            }
+        });
+    }
+}
+
+/// Complete a switch on the new thread's stack.
+///
+/// The previous thread's context is saved now: other CPUs may resume it,
+/// a thread that has to move is queued on its new CPU, and one that
+/// exited is dropped, now that its kernel stack is no longer in use.
+fn finish_switch() {
+    let (prev, migrating) = {
+        let mut sched = local_sched();
+        (sched.prev.take(), sched.migrating.take())
+    };
+    if let Some(prev) = prev.as_ref() {
+        prev.set_on_cpu(false);
+    }
+    if let Some(thread) = migrating {
+        enqueue(thread);
+    }
+    drop(prev);
+}
+
+/// First call of every new thread, from `thread_start` (IRQs masked).
+#[no_mangle]
+extern "C" fn schedule_tail() {
+    finish_switch();
+}
+
+/// Terminate the current thread and run the next one.
+pub fn exit_current() -> ! {
+    exception::disable_irqs();
+    with_sched(|sched| {
+        if let Some(current) = sched.current.as_ref() {
+            current.set_state(ThreadState::Exited);
+        }
+    });
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Leave the boot context and start running threads on this CPU.
+///
+/// The boot stack is abandoned; this never returns.
+pub fn start() -> ! {
+    exception::disable_irqs();
+
+    let (cpu, next) = {
+        let mut sched = local_sched();
+        let next = match sched.pick_next() {
+            Some(thread) => thread,
+            None => sched.idle.clone().expect("scheduler not initialized"),
+        };
+        while next.on_cpu() {
+            core::hint::spin_loop();
+        }
+        next.set_on_cpu(true);
+        next.set_state(ThreadState::Running);
+        switch_address_space(&next);
+        // SAFETY: As in `pick_and_prepare`.
+        unsafe { fpsimd::switch_to(next.fp_state_ptr()) };
+        let context = next.context_ptr() as *const KernelContext;
+        sched.current = Some(next);
+        sched.publish_load();
+        (sched.cpu, context)
+    };
+
+    kprintln!("[SCHED] Starting scheduler on CPU{}", cpu);
+    let mut boot = KernelContext::default();
+    // SAFETY: `next` is current and alive; the boot context is never
+    // resumed, so it may live on the abandoned stack.
+    unsafe { cpu_switch_to(&mut boot, next) };
+    unreachable!("boot context resumed");
+}
+
+/// When an idle CPU should next try to pull a thread: after a balancing
+/// interval if another CPU has threads to spare, otherwise never (work
+/// is sent to idle CPUs first, and wakes them).
+fn idle_deadline() -> Option<Instant> {
+    let this = smp::cpu_id();
+    (0..MAX_CPUS)
+        .any(|cpu| cpu != this && is_active(cpu) && LOAD.remote(cpu).total() >= 2)
+        .then(|| Instant::now() + timer::period() * BALANCE_INTERVAL as u32)
+}
+
+/// Idle thread: wait for interrupts until something becomes runnable.
+extern "C" fn idle_thread(_: usize) -> ! {
+    loop {
+        // Check and sleep with IRQs masked, so a wakeup between the two
+        // still ends the WFI
+        exception::disable_irqs();
+        if need_resched() {
+            timer::resume_periodic();
+            schedule();
+        } else {
+            timer::set_oneshot(idle_deadline());
+            // SAFETY: WFI is always safe
+            unsafe {
+                core::arch::asm!("wfi", options(nostack, nomem));
+            }
+        }
+        exception::enable_irqs();
+    }
+}
+
+/// Print the scheduling counters of every online CPU.
+pub fn report_stats() {
+    for cpu in (0..MAX_CPUS).filter(|&cpu| smp::cpu(cpu).is_some_and(|cpu| cpu.is_online())) {
+        let stats = STATS.remote(cpu);
+        kprintln!(
+            "[SCHED] CPU{}: {} ticks ({} idle), {} switches, {} migrations, {} IRQs",
+            cpu,
+            stats.ticks.load(Ordering::Relaxed),
+            stats.idle_ticks.load(Ordering::Relaxed),
+            stats.switches.load(Ordering::Relaxed),
+            stats.migrations.load(Ordering::Relaxed),
+            exception::irq_count(cpu)
+        );
+    }
+}
@@ -0,0 +1,359 @@
+//! Symmetric Multiprocessing
+//!
+//! Brings up the secondary CPUs listed in the device tree through PSCI
+//! CPU_ON and gives every CPU its own local state.
+//!
+//! # Per-CPU State
+//! Each CPU has a `CpuLocal` in `CPU_LOCAL`, indexed by its logical CPU
+//! number; TPIDR_EL1 points at the running CPU's entry. The boot CPU is
+//! CPU 0 and sets TPIDR_EL1 in boot.S before any Rust code runs.
+//! Subsystems keep their own per-CPU data in `PerCpu<T>` (percpu.rs).
+//!
+//! # Secondary Bring-up
+//! ```text
+//! boot CPU                               secondary CPU
+//! ─────────                              ─────────────
+//! allocate exception stack
+//! CPU_ON(mpidr, secondary_entry, &local)
+//!                                        MMU on (boot tables + kernel TTBR1)
+//!                                        TPIDR_EL1 = local, SP = exception stack
+//!                                        secondary_main: vectors, TTBR0 empty
+//!                                        guarded stacks, FP/SIMD, GIC, timer
+//!   wait for `online` ◄─────────────────── mark online, report
+//!                                        idle thread, tick, scheduler
+//! ```
+//!
+//! # Inter-Processor Interrupts
+//! CPUs poke each other with GIC software generated interrupts (`Ipi`):
+//! the scheduler to make a remote CPU reschedule, `mm::tlb` to shoot down
+//! stale translations.
+//!
+//! # Security Properties
+//! - Only CPUs named in the device tree are started, at a fixed kernel
+//!   entry point; the context passed along is a kernel pointer
+//! - Each CPU runs on its own guarded stacks, so one CPU's overflow is
+//!   caught before it reaches another CPU's stack
+//! - Every secondary starts with an empty TTBR0: no user mapping is
+//!   visible until a process is switched in
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::drivers::gic::{self, IrqError};
+use crate::drivers::{psci, timer};
+use crate::exception::{self, stack};
+use crate::fdt;
+use crate::fpsimd;
+use crate::kprintln;
+use crate::mm::{kernel_ttbr1, kernel_virt_to_phys, tlb, vspace, VirtAddr};
+use crate::sched;
+use crate::time::{Duration, Instant};
+
+/// Most CPUs supported (the GICv2 limit, and QEMU virt's default cap).
+pub const MAX_CPUS: usize = 8;
+
+/// Size of each CPU's emergency stack; must match boot.S.
+const EMERGENCY_STACK_SIZE: usize = 8192;
+
+/// Affinity fields of MPIDR_EL1 (Aff3, Aff2, Aff1, Aff0).
+const MPIDR_AFFINITY_MASK: u64 = 0xFF_00FF_FFFF;
+
+/// How long to wait for a secondary to report in.
+const ONLINE_TIMEOUT: Duration = Duration::from_millis(100);
+
+extern "C" {
+    /// Physical entry point of secondary CPUs (boot.S).
+    fn secondary_entry();
+    /// Base of the per-CPU emergency stacks (boot.S).
+    static __emergency_stacks: u8;
+}
+
+/// Inter-processor interrupts, by SGI number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Ipi {
+    /// The target's scheduler has a reschedule pending.
+    Reschedule = 0,
+    /// The target has a TLB shootdown request waiting.
+    TlbShootdown = 1,
+}
+
+impl Ipi {
+    const ALL: [Ipi; 2] = [Ipi::Reschedule, Ipi::TlbShootdown];
+}
+
+/// Kernel root table for secondaries, read by boot.S with the MMU off.
+#[no_mangle]
+static SECONDARY_TTBR1: AtomicU64 = AtomicU64::new(0);
+
+/// State private to one CPU.
+///
+/// The first fields are read by the exception entry code in boot.S.
+#[repr(C)]
+pub struct CpuLocal {
+    /// Top of the exception stack; 0 until the CPU has one.
+    exception_stack_top: AtomicUsize,
+    /// Top of the emergency stack.
+    emergency_stack_top: AtomicUsize,
+    /// Logical CPU number.
+    id: usize,
+    /// Affinity (MPIDR_EL1 without the flag bits).
+    mpidr: AtomicU64,
+    /// Set by the CPU itself once it is fully initialized.
+    online: AtomicBool,
+    /// Live `PreemptGuard`s (percpu.rs).
+    pub(crate) preempt_count: AtomicU32,
+}
+
+// boot.S hard-codes these offsets.
+const _: () = assert!(core::mem::offset_of!(CpuLocal, exception_stack_top) == 0);
+const _: () = assert!(core::mem::offset_of!(CpuLocal, emergency_stack_top) == 8);
+
+impl CpuLocal {
+    const fn new(id: usize) -> Self {
+        Self {
+            exception_stack_top: AtomicUsize::new(0),
+            emergency_stack_top: AtomicUsize::new(0),
+            id,
+            mpidr: AtomicU64::new(0),
+            online: AtomicBool::new(false),
+            preempt_count: AtomicU32::new(0),
+        }
+    }
+
+    /// Logical CPU number.
+    #[inline]
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Affinity value used to address the CPU.
+    #[inline]
+    pub fn mpidr(&self) -> u64 {
+        self.mpidr.load(Ordering::Relaxed)
+    }
+
+    /// Whether the CPU has finished bring-up.
+    #[inline]
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    /// Top of this CPU's exception stack, or 0 before it has one.
+    #[inline]
+    pub fn exception_stack_top(&self) -> usize {
+        self.exception_stack_top.load(Ordering::Relaxed)
+    }
+
+    /// Install the exception stack used by the entry code.
+    #[inline]
+    pub fn set_exception_stack_top(&self, top: usize) {
+        self.exception_stack_top.store(top, Ordering::Relaxed);
+    }
+}
+
+const fn cpu_locals() -> [CpuLocal; MAX_CPUS] {
+    let mut locals = [const { CpuLocal::new(0) }; MAX_CPUS];
+    let mut id = 0;
+    while id < MAX_CPUS {
+        locals[id] = CpuLocal::new(id);
+        id += 1;
+    }
+    locals
+}
+
+/// Per-CPU state of every possible CPU.
+#[no_mangle]
+static CPU_LOCAL: [CpuLocal; MAX_CPUS] = cpu_locals();
+
+/// The calling CPU's state.
+#[inline]
+pub fn this_cpu() -> &'static CpuLocal {
+    let local: usize;
+    // SAFETY: Reading TPIDR_EL1 has no side effects.
+    unsafe { asm!("mrs {}, tpidr_el1", out(reg) local, options(nomem, nostack, preserves_flags)) };
+    // SAFETY: boot.S points TPIDR_EL1 at an entry of CPU_LOCAL before any
+    // Rust code runs on the CPU, and nothing changes it afterwards.
+    unsafe { &*(local as *const CpuLocal) }
+}
+
+/// Logical number of the calling CPU.
+#[inline]
+pub fn cpu_id() -> usize {
+    this_cpu().id()
+}
+
+/// State of CPU `id`, if it exists.
+pub fn cpu(id: usize) -> Option<&'static CpuLocal> {
+    CPU_LOCAL.get(id)
+}
+
+/// Number of CPUs that have come online.
+pub fn online_cpus() -> usize {
+    CPU_LOCAL.iter().filter(|cpu| cpu.is_online()).count()
+}
+
+/// Interrupt CPU `cpu`.
+pub fn send_ipi(cpu: usize, ipi: Ipi) {
+    if let Err(e) = gic::send_sgi(ipi as u32, cpu) {
+        kprintln!("[SMP] {:?} IPI to CPU{} failed: {}", ipi, cpu, e);
+    }
+}
+
+/// SGI handler for every `Ipi`.
+fn handle_ipi(sgi: u32) {
+    if sgi == Ipi::TlbShootdown as u32 {
+        tlb::handle_ipi();
+    }
+    // Reschedule: `NEED_RESCHED` is already set; the interrupt only has
+    // to wake the CPU, and the return path does the rest
+}
+
+/// Unmask the IPIs on the calling CPU (SGI enables are per CPU).
+fn enable_ipis() -> Result<(), IrqError> {
+    Ipi::ALL.iter().try_for_each(|&ipi| gic::enable_irq(ipi as u32))
+}
+
+/// Affinity of the calling CPU.
+fn read_mpidr() -> u64 {
+    let mpidr: u64;
+    // SAFETY: Reading MPIDR_EL1 has no side effects.
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack, preserves_flags)) };
+    mpidr & MPIDR_AFFINITY_MASK
+}
+
+/// Top of the emergency stack of CPU `id`.
+fn emergency_stack_top(id: usize) -> usize {
+    &raw const __emergency_stacks as usize + (id + 1) * EMERGENCY_STACK_SIZE
+}
+
+/// Clean the cache line holding `addr` to the point of coherency, for a
+/// CPU that reads it with caches off.
+fn clean_dcache_line(addr: usize) {
+    // SAFETY: Cache maintenance by VA on a mapped kernel address; no data
+    // changes.
+    unsafe { asm!("dc cvac, {}", "dsb sy", in(reg) addr, options(nostack, preserves_flags)) };
+}
+
+/// Affinities of the CPUs in the device tree, in order.
+fn dt_cpus() -> impl Iterator<Item = u64> {
+    fdt::boot()
+        .and_then(|dt| dt.find_node(b"cpus"))
+        .into_iter()
+        .flat_map(|cpus| cpus.children())
+        .filter(|node| node.has_string(b"device_type", b"cpu"))
+        .filter_map(|node| fdt::cell_value(node.property(b"reg")?))
+        .map(|mpidr| mpidr & MPIDR_AFFINITY_MASK)
+}
+
+/// Start every secondary CPU in the device tree.
+///
+/// Called once on the boot CPU after memory management, the GIC, the
+/// timer and the scheduler are up. CPUs that fail to start are reported
+/// and skipped.
+pub fn init() {
+    let boot = this_cpu();
+    boot.mpidr.store(read_mpidr(), Ordering::Relaxed);
+    boot.online.store(true, Ordering::Release);
+
+    let ipis = Ipi::ALL
+        .iter()
+        .try_for_each(|&ipi| gic::register_irq(ipi as u32, handle_ipi))
+        .and_then(|_| enable_ipis());
+    if let Err(e) = ipis {
+        panic!("IPI setup failed: {}", e);
+    }
+
+    match psci::init() {
+        Ok((major, minor)) => kprintln!(
+            "[BOOT] PSCI v{}.{} ({:?} conduit)",
+            major,
+            minor,
+            psci::conduit().expect("PSCI without conduit")
+        ),
+        Err(e) => {
+            kprintln!("[BOOT] PSCI unavailable ({}); running on one CPU", e);
+            return;
+        }
+    }
+
+    // Read by secondaries before their MMU and caches are on
+    SECONDARY_TTBR1.store(kernel_ttbr1().as_u64(), Ordering::Relaxed);
+    clean_dcache_line(SECONDARY_TTBR1.as_ptr() as usize);
+
+    let mut present = 1;
+    let mut next_id = 1;
+    for mpidr in dt_cpus().filter(|&mpidr| mpidr != boot.mpidr()) {
+        present += 1;
+        let Some(cpu) = cpu(next_id) else {
+            kprintln!("[SMP] CPU MPIDR 0x{:x} ignored: more than {} CPUs", mpidr, MAX_CPUS);
+            continue;
+        };
+        next_id += 1;
+
+        if let Err(e) = start_cpu(cpu, mpidr) {
+            kprintln!("[SMP] CPU{} (MPIDR 0x{:x}) failed to start: {}", cpu.id, mpidr, e);
+        }
+    }
+
+    kprintln!("[BOOT] SMP: {} of {} CPUs online", online_cpus(), present);
+}
+
+/// Power on one secondary and wait until it reports in.
+fn start_cpu(cpu: &'static CpuLocal, mpidr: u64) -> Result<(), psci::PsciError> {
+    cpu.mpidr.store(mpidr, Ordering::Relaxed);
+    cpu.emergency_stack_top.store(emergency_stack_top(cpu.id), Ordering::Relaxed);
+    cpu.set_exception_stack_top(stack::prepare(cpu.id));
+
+    let entry = kernel_virt_to_phys(VirtAddr::new(secondary_entry as *const () as usize));
+    psci::cpu_on(mpidr, entry.as_u64(), cpu as *const CpuLocal as u64)?;
+
+    let start = Instant::now();
+    while !cpu.is_online() {
+        if start.elapsed() > ONLINE_TIMEOUT {
+            let state = psci::affinity_info(mpidr);
+            kprintln!("[SMP] CPU{} did not come online (power state {:?})", cpu.id, state);
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// First Rust code on a secondary CPU (from boot.S).
+///
+/// Runs on the exception stack (SP_EL1) with the boot identity map still
+/// in TTBR0 and IRQs masked.
+#[no_mangle]
+extern "C" fn secondary_main() -> ! {
+    // Nothing below the kernel half from here on
+    vspace::deactivate();
+    exception::init_cpu();
+    stack::init(secondary_init);
+}
+
+/// Second stage of secondary bring-up, on the CPU's guarded boot stack.
+extern "C" fn secondary_init() -> ! {
+    let cpu = this_cpu();
+
+    fpsimd::init();
+    let irqs = gic::init_cpu()
+        .and_then(|_| enable_ipis())
+        .and_then(|_| timer::init_cpu());
+    if let Err(e) = irqs {
+        panic!("CPU{}: interrupt setup failed: {}", cpu.id, e);
+    }
+    sched::init_cpu();
+
+    kprintln!(
+        "[SMP] CPU{} online (MPIDR 0x{:x}, exception stack top 0x{:x})",
+        cpu.id,
+        cpu.mpidr(),
+        cpu.exception_stack_top()
+    );
+    cpu.online.store(true, Ordering::Release);
+
+    // Same tick as the boot CPU, then run whatever load balancing sends
+    timer::start_secondary();
+    sched::start();
+}
@@ -0,0 +1,111 @@
+//! Interrupt-Safe Spinlock
+//!
+//! An IRQ handler that takes a lock its own CPU already holds spins
+//! forever. `IrqSpinLock` masks IRQs (DAIF.I) on the holding CPU for as
+//! long as the lock is held, and restores the previous mask on release,
+//! so it can be shared between threads and handlers and can nest inside
+//! sections that already run with IRQs masked.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+
+use super::lockdep::{self, Acquire, LockClass};
+use super::ticket::RawTicketLock;
+use crate::exception;
+
+/// Ticket lock that masks IRQs while held.
+pub struct IrqSpinLock<T> {
+    raw: RawTicketLock,
+    class: &'static LockClass,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: The lock hands out one guard at a time, so the data is only
+// reached from one CPU at a time; IRQs masked on the holder keep its own
+// handlers out.
+unsafe impl<T: Send> Sync for IrqSpinLock<T> {}
+// SAFETY: Moving the lock moves the data with it.
+unsafe impl<T: Send> Send for IrqSpinLock<T> {}
+
+impl<T> IrqSpinLock<T> {
+    /// A lock of `class` (see `lock_class!`).
+    pub const fn new(data: T, class: &'static LockClass) -> Self {
+        Self {
+            raw: RawTicketLock::new(),
+            class,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Mask IRQs, then wait for the lock.
+    #[track_caller]
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        self.lock_as(Acquire::Blocking)
+    }
+
+    /// Like `lock`, while this CPU holds another lock of the same class.
+    ///
+    /// The caller must take such locks in a fixed order (for example by
+    /// address or CPU number), which lockdep cannot check.
+    #[track_caller]
+    pub fn lock_nested(&self) -> IrqSpinLockGuard<'_, T> {
+        self.lock_as(Acquire::Nested)
+    }
+
+    #[track_caller]
+    fn lock_as(&self, how: Acquire) -> IrqSpinLockGuard<'_, T> {
+        let irqs = exception::save_and_disable_irqs();
+        lockdep::acquire(self.class, Location::caller(), how, false);
+        self.raw.acquire();
+        IrqSpinLockGuard { lock: self, irqs }
+    }
+
+    /// Mask IRQs and take the lock if it is free right now; the mask is
+    /// restored if it is not.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let irqs = exception::save_and_disable_irqs();
+        if self.raw.try_acquire() {
+            lockdep::acquire(self.class, Location::caller(), Acquire::Try, false);
+            Some(IrqSpinLockGuard { lock: self, irqs })
+        } else {
+            exception::restore_irqs(irqs);
+            None
+        }
+    }
+}
+
+/// Access to the data of a held `IrqSpinLock`; releases it and restores
+/// the IRQ mask when dropped.
+pub struct IrqSpinLockGuard<'a, T> {
+    lock: &'a IrqSpinLock<T>,
+    /// Whether IRQs were unmasked before `lock`.
+    irqs: bool,
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The guard proves the lock is held.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard proves the lock is held, and `&mut self`
+        // makes this the only reference.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock before unmasking, so a handler can take it at once
+        self.lock.raw.release();
+        lockdep::release(self.lock.class);
+        exception::restore_irqs(self.irqs);
+    }
+}
@@ -0,0 +1,458 @@
+//! Lock Order Validation
+//!
+//! Debug builds watch every spinlock acquisition and report orderings
+//! that can deadlock, before they ever do.
+//!
+//! # Design
+//! - Locks belong to a `LockClass`, declared with `lock_class!` where the
+//!   lock is created; all locks made at one place (such as every CPU's
+//!   scheduler lock) share a class
+//! - Each CPU keeps the stack of classes it holds, with where each was
+//!   taken. A blocking acquisition of class B while holding A records the
+//!   edge A → B in a global graph, along with both locations
+//! - A new edge A → B closes a cycle if B →* A is already known; the
+//!   report shows the chain that established B →* A and the current one
+//! - Taking a class it already holds is reported, unless the caller says
+//!   it orders such locks itself (`IrqSpinLock::lock_nested`)
+//! - Try-locks cannot deadlock, so they add no edges, but locks taken
+//!   while holding them do
+//! - A class taken in an IRQ handler and also taken with IRQs unmasked
+//!   can deadlock against itself on one CPU; both places are reported
+//! - Taking the CPU off the current thread (`schedule`) with a spinlock
+//!   held is reported too
+//! - After the first report validation turns itself off, like Linux's
+//!   lockdep, since the state it reasons about may no longer be coherent
+//!
+//! In release builds the hooks compile to nothing.
+
+use core::panic::Location;
+
+/// A set of locks that follow the same ordering rules.
+///
+/// Identified by address, so each must be a `static` (`lock_class!`).
+pub struct LockClass {
+    name: &'static str,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> Self {
+        Self { name }
+    }
+
+    /// Name used in reports.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Declare the lock class for the locks created here.
+#[macro_export]
+macro_rules! lock_class {
+    ($name:expr) => {{
+        static CLASS: $crate::sync::LockClass = $crate::sync::LockClass::new($name);
+        &CLASS
+    }};
+}
+
+/// How a lock is being taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acquire {
+    /// Wait for the lock.
+    Blocking,
+    /// Wait for a lock of a class this CPU already holds, in an order the
+    /// caller guarantees.
+    Nested,
+    /// Take the lock only if it is free (already taken when reported).
+    Try,
+}
+
+/// Record that the calling CPU is about to take (or, for `Try`, has
+/// taken) a lock of `class` at `location`. `irqs_enabled` is the IRQ mask
+/// the lock will be held under.
+#[inline]
+pub fn acquire(
+    class: &'static LockClass,
+    location: &'static Location<'static>,
+    how: Acquire,
+    irqs_enabled: bool,
+) {
+    #[cfg(debug_assertions)]
+    validator::acquire(class, location, how, irqs_enabled);
+    #[cfg(not(debug_assertions))]
+    let _ = (class, location, how, irqs_enabled);
+}
+
+/// Record that the calling CPU released a lock of `class`.
+#[inline]
+pub fn release(class: &'static LockClass) {
+    #[cfg(debug_assertions)]
+    validator::release(class);
+    #[cfg(not(debug_assertions))]
+    let _ = class;
+}
+
+/// Report spinlocks still held by the calling CPU; `what` names the
+/// operation that must not happen under them.
+#[inline]
+pub fn assert_none_held(what: &str) {
+    #[cfg(debug_assertions)]
+    validator::assert_none_held(what);
+    #[cfg(not(debug_assertions))]
+    let _ = what;
+}
+
+#[cfg(debug_assertions)]
+mod validator {
+    use core::panic::Location;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use spin::Mutex;
+
+    use super::{Acquire, LockClass};
+    use crate::exception;
+    use crate::kprintln;
+    use crate::percpu::PerCpu;
+    use crate::smp::{self, MAX_CPUS};
+
+    /// Most lock classes tracked.
+    const MAX_CLASSES: usize = 32;
+
+    /// Deepest lock nesting tracked per CPU.
+    const MAX_HELD: usize = 16;
+
+    type Site = &'static Location<'static>;
+
+    /// Cleared by the first report.
+    static ENABLED: AtomicBool = AtomicBool::new(true);
+
+    /// One edge of the order graph: `to` was taken at `to_site` while
+    /// holding `from`, taken at `from_site`.
+    #[derive(Clone, Copy)]
+    struct Edge {
+        from_site: Site,
+        to_site: Site,
+    }
+
+    /// Where a class was first seen in each IRQ-relevant context.
+    #[derive(Clone, Copy)]
+    struct IrqUsage {
+        in_irq: Option<Site>,
+        irqs_enabled: Option<Site>,
+    }
+
+    /// Every class seen and the orders observed between them.
+    struct Graph {
+        classes: [Option<&'static LockClass>; MAX_CLASSES],
+        count: usize,
+        /// Bit `b` of `after[a]`: `b` was taken while holding `a`.
+        after: [u32; MAX_CLASSES],
+        /// First acquisition pair that established each edge.
+        edges: [[Option<Edge>; MAX_CLASSES]; MAX_CLASSES],
+        usage: [IrqUsage; MAX_CLASSES],
+    }
+
+    // Held with IRQs masked, and never across a tracked acquisition
+    static GRAPH: Mutex<Graph> = Mutex::new(Graph {
+        classes: [None; MAX_CLASSES],
+        count: 0,
+        after: [0; MAX_CLASSES],
+        edges: [[None; MAX_CLASSES]; MAX_CLASSES],
+        usage: [IrqUsage {
+            in_irq: None,
+            irqs_enabled: None,
+        }; MAX_CLASSES],
+    });
+
+    /// A lock held by a CPU.
+    #[derive(Clone, Copy)]
+    struct Held {
+        class: usize,
+        site: Site,
+    }
+
+    /// Locks held by one CPU, in acquisition order.
+    struct HeldLocks {
+        entries: [Option<Held>; MAX_HELD],
+        len: usize,
+    }
+
+    impl HeldLocks {
+        fn iter(&self) -> impl Iterator<Item = Held> + '_ {
+            self.entries[..self.len].iter().flatten().copied()
+        }
+    }
+
+    static HELD: PerCpu<Mutex<HeldLocks>> = PerCpu::new(
+        [const {
+            Mutex::new(HeldLocks {
+                entries: [None; MAX_HELD],
+                len: 0,
+            })
+        }; MAX_CPUS],
+    );
+
+    impl Graph {
+        /// Index of `class`, registering it on first sight.
+        fn index(&mut self, class: &'static LockClass) -> Option<usize> {
+            let known = self.classes[..self.count]
+                .iter()
+                .position(|known| known.is_some_and(|known| core::ptr::eq(known, class)));
+            if known.is_some() || self.count == MAX_CLASSES {
+                return known;
+            }
+            self.classes[self.count] = Some(class);
+            self.count += 1;
+            Some(self.count - 1)
+        }
+
+        fn name(&self, class: usize) -> &'static str {
+            self.classes[class].map_or("?", LockClass::name)
+        }
+
+        /// Classes from `from` to `to` along known edges, if connected.
+        fn path(&self, from: usize, to: usize) -> Option<([usize; MAX_CLASSES], usize)> {
+            let mut parent = [usize::MAX; MAX_CLASSES];
+            let mut stack = [0; MAX_CLASSES];
+            let mut depth = 1;
+            stack[0] = from;
+            parent[from] = from;
+            while depth > 0 {
+                depth -= 1;
+                let class = stack[depth];
+                if class == to {
+                    // Walk back from `to`, then reverse
+                    let mut path = [0; MAX_CLASSES];
+                    let mut len = 0;
+                    let mut at = to;
+                    while at != from {
+                        path[len] = at;
+                        len += 1;
+                        at = parent[at];
+                    }
+                    path[len] = from;
+                    len += 1;
+                    path[..len].reverse();
+                    return Some((path, len));
+                }
+                for next in (0..self.count).filter(|&next| self.after[class] & (1 << next) != 0) {
+                    if parent[next] == usize::MAX {
+                        parent[next] = class;
+                        stack[depth] = next;
+                        depth += 1;
+                    }
+                }
+            }
+            None
+        }
+
+        fn add_edge(&mut self, from: Held, to: usize, to_site: Site) {
+            if self.after[from.class] & (1 << to) == 0 {
+                self.after[from.class] |= 1 << to;
+                self.edges[from.class][to] = Some(Edge {
+                    from_site: from.site,
+                    to_site,
+                });
+            }
+        }
+    }
+
+    /// Turn validation off; returns whether this caller did it, and so
+    /// owns the report.
+    fn disable() -> bool {
+        ENABLED.swap(false, Ordering::Relaxed)
+    }
+
+    fn print_held(graph: &Graph, held: &HeldLocks) {
+        for lock in held.iter() {
+            kprintln!("    \"{}\" taken at {}", graph.name(lock.class), lock.site);
+        }
+    }
+
+    pub(super) fn acquire(class: &'static LockClass, site: Site, how: Acquire, irqs_enabled: bool) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let irqs = exception::save_and_disable_irqs();
+        let in_irq = exception::in_irq();
+        let cpu = smp::cpu_id();
+        let mut graph = GRAPH.lock();
+        let mut held = HELD.remote(cpu).lock();
+
+        match graph.index(class) {
+            Some(index) => {
+                if check_irq_usage(&mut graph, cpu, index, site, in_irq, irqs_enabled) {
+                    check_order(&mut graph, &mut held, cpu, index, site, how);
+                }
+            }
+            None if disable() => kprintln!(
+                "[LOCKDEP] More than {} lock classes, validation off",
+                MAX_CLASSES
+            ),
+            None => {}
+        }
+
+        drop(held);
+        drop(graph);
+        exception::restore_irqs(irqs);
+    }
+
+    /// Record the IRQ context of this use of `class`; false if it was
+    /// reported.
+    fn check_irq_usage(
+        graph: &mut Graph,
+        cpu: usize,
+        class: usize,
+        site: Site,
+        in_irq: bool,
+        irqs_enabled: bool,
+    ) -> bool {
+        // A lock its own CPU's IRQ handler may take must be held with IRQs
+        // masked
+        let usage = &mut graph.usage[class];
+        if in_irq {
+            usage.in_irq.get_or_insert(site);
+        } else if irqs_enabled {
+            usage.irqs_enabled.get_or_insert(site);
+        }
+        let usage = graph.usage[class];
+        let (Some(in_irq), Some(enabled)) = (usage.in_irq, usage.irqs_enabled) else {
+            return true;
+        };
+        if disable() {
+            kprintln!(
+                "[LOCKDEP] IRQ-unsafe lock \"{}\" on CPU{}",
+                graph.name(class),
+                cpu
+            );
+            kprintln!("  taken in an IRQ handler at {}", in_irq);
+            kprintln!("  and with IRQs enabled at {}", enabled);
+            kprintln!("  an IRQ arriving while it is held there deadlocks its CPU");
+        }
+        false
+    }
+
+    /// Check taking `class` against the locks this CPU holds, record the
+    /// new orders and push it.
+    fn check_order(
+        graph: &mut Graph,
+        held: &mut HeldLocks,
+        cpu: usize,
+        class: usize,
+        site: Site,
+        how: Acquire,
+    ) {
+        for lock in held.iter() {
+            if lock.class == class {
+                if how == Acquire::Blocking && disable() {
+                    kprintln!(
+                        "[LOCKDEP] Recursive locking of \"{}\" on CPU{}",
+                        graph.name(class),
+                        cpu
+                    );
+                    kprintln!("  taken again at {}; held locks:", site);
+                    print_held(graph, held);
+                }
+                if how == Acquire::Blocking {
+                    return;
+                }
+                continue;
+            }
+            if how == Acquire::Try {
+                continue;
+            }
+            if let Some((path, len)) = graph.path(class, lock.class) {
+                if disable() {
+                    report_cycle(graph, held, cpu, class, site, &path[..len]);
+                }
+                return;
+            }
+            graph.add_edge(lock, class, site);
+        }
+
+        if held.len == MAX_HELD {
+            if disable() {
+                kprintln!(
+                    "[LOCKDEP] More than {} locks held on CPU{}, validation off",
+                    MAX_HELD,
+                    cpu
+                );
+            }
+            return;
+        }
+        held.entries[held.len] = Some(Held { class, site });
+        held.len += 1;
+    }
+
+    fn report_cycle(
+        graph: &Graph,
+        held: &HeldLocks,
+        cpu: usize,
+        class: usize,
+        site: Site,
+        path: &[usize],
+    ) {
+        kprintln!(
+            "[LOCKDEP] Possible deadlock: lock order inversion on CPU{}",
+            cpu
+        );
+        kprintln!(
+            "  CPU{} takes \"{}\" at {} while holding:",
+            cpu,
+            graph.name(class),
+            site
+        );
+        print_held(graph, held);
+        kprintln!("  but the opposite order was seen before:");
+        for pair in path.windows(2) {
+            if let Some(edge) = graph.edges[pair[0]][pair[1]] {
+                kprintln!(
+                    "    \"{}\" taken at {} while holding \"{}\" taken at {}",
+                    graph.name(pair[1]),
+                    edge.to_site,
+                    graph.name(pair[0]),
+                    edge.from_site
+                );
+            }
+        }
+    }
+
+    pub(super) fn release(class: &'static LockClass) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let irqs = exception::save_and_disable_irqs();
+        let index = GRAPH.lock().index(class);
+        let mut held = HELD.remote(smp::cpu_id()).lock();
+
+        // Guards may be dropped out of order: remove the latest entry
+        let len = held.len;
+        if let Some(at) = held.entries[..len]
+            .iter()
+            .rposition(|lock| lock.is_some_and(|lock| Some(lock.class) == index))
+        {
+            held.entries.copy_within(at + 1..len, at);
+            held.entries[len - 1] = None;
+            held.len -= 1;
+        }
+
+        drop(held);
+        exception::restore_irqs(irqs);
+    }
+
+    pub(super) fn assert_none_held(what: &str) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let irqs = exception::save_and_disable_irqs();
+        let cpu = smp::cpu_id();
+        let graph = GRAPH.lock();
+        let held = HELD.remote(cpu).lock();
+        if held.len != 0 && disable() {
+            kprintln!("[LOCKDEP] {} on CPU{} with spinlocks held:", what, cpu);
+            print_held(&graph, &held);
+        }
+        drop(held);
+        drop(graph);
+        exception::restore_irqs(irqs);
+    }
+}
@@ -0,0 +1,37 @@
+//! Kernel Synchronization Primitives
+//!
+//! Provides:
+//! - `TicketLock`: fair spinlock, CPUs get the lock in arrival order
+//! - `IrqSpinLock`: ticket lock that also masks IRQs on the holding CPU,
+//!   for state shared with interrupt handlers
+//! - `RwSpinLock`: spinning reader-writer lock for read-mostly state
+//! - `WaitQueue`: threads sleeping until a condition holds
+//!
+//! # Choosing a Primitive
+//! - Touched from an IRQ handler (console, heap): `IrqSpinLock`. A plain
+//!   spinlock deadlocks as soon as a handler interrupts its own CPU's
+//!   holder
+//! - Short critical sections never entered from IRQ context (frame
+//!   allocator): `TicketLock`
+//! - Many readers, rare writers: `RwSpinLock`
+//! - Long waits, or waits for another thread: `WaitQueue`. It puts the
+//!   thread to sleep and may only be used from thread context with no
+//!   spinlock held
+//!
+//! # Security Properties
+//! - Ticket order bounds how long a CPU waits for a spinlock, so no CPU
+//!   can be starved by the others
+//! - A sleeping waiter is queued under the same lock its condition is
+//!   checked with, so a wakeup can never be lost
+
+mod irq;
+mod lockdep;
+mod rwlock;
+mod ticket;
+mod wait;
+
+pub use irq::{IrqSpinLock, IrqSpinLockGuard};
+pub use lockdep::{assert_none_held, LockClass};
+pub use rwlock::RwSpinLock;
+pub use ticket::{TicketLock, TicketLockGuard};
+pub use wait::WaitQueue;
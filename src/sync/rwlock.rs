@@ -0,0 +1,216 @@
+//! Reader-Writer Spinlock
+//!
+//! Any number of readers, or one writer. Writers take precedence: once a
+//! writer waits, new readers hold back until it has been in, so a steady
+//! stream of readers cannot starve it.
+//!
+//! Does not mask IRQs: never take it from an interrupt handler.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::lockdep::{self, Acquire, LockClass};
+use crate::exception;
+
+/// A writer holds the lock.
+const WRITER: u32 = 1 << 31;
+/// A writer waits for the readers to leave.
+const WRITER_WAITING: u32 = 1 << 30;
+/// Number of readers inside.
+const READERS: u32 = WRITER_WAITING - 1;
+
+/// Reader-writer spinlock protecting a `T`.
+pub struct RwSpinLock<T> {
+    state: AtomicU32,
+    class: &'static LockClass,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: Readers only get `&T` (hence `T: Sync`), and a writer excludes
+// everyone else (hence `T: Send`).
+unsafe impl<T: Send + Sync> Sync for RwSpinLock<T> {}
+// SAFETY: Moving the lock moves the data with it.
+unsafe impl<T: Send> Send for RwSpinLock<T> {}
+
+impl<T> RwSpinLock<T> {
+    /// A lock of `class` (see `lock_class!`).
+    pub const fn new(data: T, class: &'static LockClass) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            class,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Wait for shared access.
+    ///
+    /// Readers are validated like writers: a waiting writer holds back
+    /// new readers, so even taking it twice for reading can deadlock.
+    #[track_caller]
+    pub fn read(&self) -> RwSpinLockReadGuard<'_, T> {
+        lockdep::acquire(
+            self.class,
+            Location::caller(),
+            Acquire::Blocking,
+            exception::irqs_enabled(),
+        );
+        while !self.try_enter_read() {
+            core::hint::spin_loop();
+        }
+        RwSpinLockReadGuard { lock: self }
+    }
+
+    /// Take shared access if no writer holds or waits for the lock.
+    #[track_caller]
+    pub fn try_read(&self) -> Option<RwSpinLockReadGuard<'_, T>> {
+        if !self.try_enter_read() {
+            return None;
+        }
+        lockdep::acquire(
+            self.class,
+            Location::caller(),
+            Acquire::Try,
+            exception::irqs_enabled(),
+        );
+        Some(RwSpinLockReadGuard { lock: self })
+    }
+
+    fn try_enter_read(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | WRITER_WAITING) != 0 || state & READERS == READERS {
+            return false;
+        }
+        self.state
+            .compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Wait for exclusive access.
+    #[track_caller]
+    pub fn write(&self) -> RwSpinLockWriteGuard<'_, T> {
+        lockdep::acquire(
+            self.class,
+            Location::caller(),
+            Acquire::Blocking,
+            exception::irqs_enabled(),
+        );
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & (WRITER | READERS) == 0 {
+                // Clears our waiting bit; other waiting writers set it again
+                if self
+                    .state
+                    .compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return RwSpinLockWriteGuard { lock: self };
+                }
+            } else if state & WRITER_WAITING == 0 {
+                self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Take exclusive access if the lock is free right now.
+    #[cfg(test)]
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwSpinLockWriteGuard<'_, T>> {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        lockdep::acquire(
+            self.class,
+            Location::caller(),
+            Acquire::Try,
+            exception::irqs_enabled(),
+        );
+        Some(RwSpinLockWriteGuard { lock: self })
+    }
+}
+
+/// Shared access to the data of an `RwSpinLock`.
+pub struct RwSpinLockReadGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+}
+
+impl<T> Deref for RwSpinLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: Readers exclude writers, so nobody holds `&mut T`.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwSpinLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        lockdep::release(self.lock.class);
+    }
+}
+
+/// Exclusive access to the data of an `RwSpinLock`.
+pub struct RwSpinLockWriteGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+}
+
+impl<T> Deref for RwSpinLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The writer excludes everyone else.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwSpinLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The writer excludes everyone else, and `&mut self`
+        // makes this the only reference.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwSpinLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        // Keep the waiting bit of writers that arrived meanwhile
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+        lockdep::release(self.lock.class);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock_class;
+
+    #[test]
+    fn test_readers_share_writers_exclude() {
+        let lock = RwSpinLock::new(1, lock_class!("test"));
+        let first = lock.read();
+        let second = lock.try_read().expect("readers share");
+        assert_eq!(*first + *second, 2);
+        assert!(lock.try_write().is_none());
+        drop((first, second));
+
+        let mut writer = lock.try_write().expect("lock is free");
+        *writer = 2;
+        assert!(lock.try_read().is_none());
+        drop(writer);
+        assert_eq!(*lock.read(), 2);
+    }
+
+    #[test]
+    fn test_waiting_writer_holds_back_readers() {
+        let lock = RwSpinLock::new((), lock_class!("test"));
+        let reader = lock.read();
+        lock.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+        assert!(lock.try_read().is_none());
+        drop(reader);
+        drop(lock.write());
+        assert!(lock.try_read().is_some());
+    }
+}
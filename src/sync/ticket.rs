@@ -0,0 +1,164 @@
+//! Ticket Spinlock
+//!
+//! Each CPU takes a ticket and waits until it is served, so the lock is
+//! handed out in arrival order. Waiters spin with WFE and are woken by
+//! the release, rather than hammering the cache line.
+
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::lockdep::{self, Acquire, LockClass};
+use crate::exception;
+
+/// The ticket counters, without the data.
+pub(super) struct RawTicketLock {
+    /// Next ticket to hand out.
+    next: AtomicU32,
+    /// Ticket allowed in.
+    serving: AtomicU32,
+}
+
+impl RawTicketLock {
+    pub(super) const fn new() -> Self {
+        Self {
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+        }
+    }
+
+    /// Wait for our turn.
+    pub(super) fn acquire(&self) {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            wait_for_event();
+        }
+    }
+
+    /// Take the lock only if nobody holds or waits for it.
+    pub(super) fn try_acquire(&self) -> bool {
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.next
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    /// Let the next ticket in.
+    pub(super) fn release(&self) {
+        // Only the holder writes `serving`
+        let next = self.serving.load(Ordering::Relaxed).wrapping_add(1);
+        self.serving.store(next, Ordering::Release);
+        send_event();
+    }
+}
+
+/// Sleep until an event.
+#[inline]
+fn wait_for_event() {
+    // SAFETY: WFE only waits for an event; SEV, an interrupt or the
+    // event stream ends it, and the caller re-checks the lock.
+    unsafe {
+        asm!("wfe", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Wake the CPUs waiting in `wait_for_event`.
+#[inline]
+fn send_event() {
+    // SAFETY: SEV only signals an event; the release store is ordered
+    // before it by the barrier.
+    unsafe {
+        asm!("dsb ishst", "sev", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Fair spinlock protecting a `T`.
+///
+/// Does not mask IRQs: never take it from an interrupt handler (use
+/// `IrqSpinLock` for that).
+pub struct TicketLock<T> {
+    raw: RawTicketLock,
+    class: &'static LockClass,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: The lock hands out one guard at a time, so the data is only
+// reached from one CPU at a time.
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+// SAFETY: Moving the lock moves the data with it.
+unsafe impl<T: Send> Send for TicketLock<T> {}
+
+impl<T> TicketLock<T> {
+    /// A lock of `class` (see `lock_class!`).
+    pub const fn new(data: T, class: &'static LockClass) -> Self {
+        Self {
+            raw: RawTicketLock::new(),
+            class,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Wait for the lock.
+    #[track_caller]
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        lockdep::acquire(
+            self.class,
+            Location::caller(),
+            Acquire::Blocking,
+            exception::irqs_enabled(),
+        );
+        self.raw.acquire();
+        TicketLockGuard { lock: self }
+    }
+
+    /// Take the lock if it is free right now.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        if !self.raw.try_acquire() {
+            return None;
+        }
+        lockdep::acquire(
+            self.class,
+            Location::caller(),
+            Acquire::Try,
+            exception::irqs_enabled(),
+        );
+        Some(TicketLockGuard { lock: self })
+    }
+}
+
+/// Access to the data of a held `TicketLock`; releases it when dropped.
+pub struct TicketLockGuard<'a, T> {
+    lock: &'a TicketLock<T>,
+}
+
+impl<T> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The guard proves the lock is held.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard proves the lock is held, and `&mut self`
+        // makes this the only reference.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.release();
+        lockdep::release(self.lock.class);
+    }
+}
@@ -0,0 +1,63 @@
+//! Sleeping Primitives
+//!
+//! A `WaitQueue` parks threads until a condition holds, such as a
+//! process having exited (`Process::wait`). Waiting gives the CPU to
+//! other threads instead of spinning, so it suits long or unbounded
+//! waits.
+//!
+//! # Design
+//! - The condition is checked, and the thread queued and marked blocked,
+//!   all under the queue lock; a waker changes the state the condition
+//!   reads *before* taking the lock. Either the waiter sees the change or
+//!   the waker finds the waiter, so no wakeup is lost
+//! - Waking only makes the thread ready again (`sched::wake`); it checks
+//!   its condition again when it runs, since another thread may have got
+//!   there first
+//! - Waking is allowed from IRQ handlers; waiting is not (`schedule`
+//!   checks), and a waiter must not hold a spinlock, which would stay
+//!   held while it sleeps
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+use super::irq::IrqSpinLock;
+use crate::lock_class;
+use crate::sched;
+use crate::thread::Thread;
+
+/// Threads waiting for a condition, in arrival order.
+pub struct WaitQueue {
+    waiters: IrqSpinLock<VecDeque<Arc<Thread>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqSpinLock::new(VecDeque::new(), lock_class!("wait queue")),
+        }
+    }
+
+    /// Sleep until `condition` returns true.
+    ///
+    /// `condition` runs with the queue lock held (and IRQs masked), so it
+    /// must be short and must not wait itself.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            let mut waiters = self.waiters.lock();
+            if condition() {
+                return;
+            }
+            waiters.push_back(sched::block_current());
+            drop(waiters);
+            sched::schedule();
+        }
+    }
+
+    /// Wake every waiting thread; returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        let count = waiters.len();
+        waiters.into_iter().for_each(sched::wake);
+        count
+    }
+}
@@ -7,29 +7,86 @@
 //! - Unknown syscalls return ENOSYS
 //! - Parameters are validated before use
 
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::cap::retype::{self, ObjectType, RetypeError};
+use crate::cap::cnode::Guard;
+use crate::cap::{object, CSpace, CSpaceError, CapSlot, Rights};
 use crate::exception::ExceptionContext;
+use crate::initramfs;
+use crate::cap::cspace::RawCapability;
+use crate::cap::transfer::CapTransfer;
+use crate::ipc::endpoint::{self, Endpoint, Reply};
+use crate::ipc::notification::{self, Notification};
+use crate::ipc::{
+    IpcError, Message, MessageInfo, Received, BUFFER_CAPS, BUFFER_RECEIVE_SLOT, MSG_REGISTERS,
+};
+use crate::mm::shm::{self, ShmError, MAPPING_RIGHTS};
+use crate::mm::vspace::StalePage;
+use crate::mm::{MappingError, PAGE_SIZE};
+use crate::mm::VirtAddr;
+use crate::process::{self, ExitReason};
+use crate::sched;
+use crate::thread::{self, Thread};
 use crate::{kprintln, kprint};
 
+use super::validate::regions::{USER_END, USER_START};
 use super::validate::{self, UserBuffer};
 
 /// System call numbers
 pub mod numbers {
     pub const SYS_EXIT: usize = 0;
     pub const SYS_WRITE: usize = 1;
+    pub const SYS_FILE_READ: usize = 2;
+    pub const SYS_TCB_SET_PRIORITY: usize = 3;
+    pub const SYS_TCB_SET_AFFINITY: usize = 4;
+    pub const SYS_CAP_DERIVE: usize = 5;
+    pub const SYS_CAP_DELETE: usize = 6;
+    pub const SYS_CAP_REVOKE: usize = 7;
+    pub const SYS_UNTYPED_RETYPE: usize = 8;
+    pub const SYS_TCB_START: usize = 9;
+    pub const SYS_CSPACE_SET_GUARD: usize = 10;
+    pub const SYS_SEND: usize = 11;
+    pub const SYS_RECV: usize = 12;
+    pub const SYS_CALL: usize = 13;
+    pub const SYS_REPLY_RECV: usize = 14;
+    pub const SYS_TCB_SET_IPC_BUFFER: usize = 15;
+    pub const SYS_SIGNAL: usize = 16;
+    pub const SYS_WAIT: usize = 17;
+    pub const SYS_POLL: usize = 18;
+    pub const SYS_TCB_BIND_NOTIFICATION: usize = 19;
+    pub const SYS_SHM_MAP: usize = 20;
+    pub const SYS_SHM_UNMAP: usize = 21;
+    pub const SYS_YIELD: usize = 22;
+    pub const SYS_TCB_SET_TIME_SLICE: usize = 23;
 }
 
+/// Longest path accepted by `file_read`
+const MAX_PATH_LEN: usize = 256;
+
 /// System call error codes
 #[repr(i64)]
 #[derive(Debug, Clone, Copy)]
 pub enum SyscallError {
+    /// Operation not permitted
+    Eperm = -1,
+    /// No such file
+    Enoent = -2,
+    /// Resource busy
+    Ebusy = -16,
     /// Invalid system call number
     Enosys = -38,
     /// Bad file descriptor
     Ebadf = -9,
     /// Bad address (invalid pointer)
     Efault = -14,
+    /// Out of memory
+    Enomem = -12,
     /// Invalid argument
     Einval = -22,
+    /// IPC partner or endpoint gone
+    Epipe = -32,
 }
 
 /// Dispatch a system call
@@ -52,6 +109,85 @@ pub fn dispatch(syscall_num: usize, ctx: &mut ExceptionContext) -> i64 {
             ctx.gpr[1] as usize, // buf
             ctx.gpr[2] as usize, // len
         ),
+        numbers::SYS_FILE_READ => sys_file_read(
+            ctx.gpr[0] as usize, // path
+            ctx.gpr[1] as usize, // path_len
+            ctx.gpr[2] as usize, // offset
+            ctx.gpr[3] as usize, // buf
+            ctx.gpr[4] as usize, // len
+        ),
+        numbers::SYS_TCB_SET_PRIORITY => sys_tcb_set_priority(
+            ctx.gpr[0] as usize, // tcb slot
+            ctx.gpr[1] as usize, // authority slot
+            ctx.gpr[2] as usize, // priority
+        ),
+        numbers::SYS_TCB_SET_AFFINITY => sys_tcb_set_affinity(
+            ctx.gpr[0] as usize, // tcb slot
+            ctx.gpr[1],          // CPU mask
+        ),
+        numbers::SYS_CAP_DERIVE => sys_cap_derive(
+            ctx.gpr[0] as usize, // source slot
+            ctx.gpr[1] as usize, // destination slot
+            ctx.gpr[2] as usize, // rights
+            ctx.gpr[3],          // badge
+            ctx.gpr[4] as usize, // depth
+        ),
+        numbers::SYS_CAP_DELETE => sys_cap_delete(
+            ctx.gpr[0] as usize, // slot
+            ctx.gpr[1] as usize, // depth
+        ),
+        numbers::SYS_CAP_REVOKE => sys_cap_revoke(
+            ctx.gpr[0] as usize, // slot
+            ctx.gpr[1] as usize, // depth
+        ),
+        numbers::SYS_UNTYPED_RETYPE => sys_untyped_retype(
+            ctx.gpr[0] as usize, // untyped slot
+            ctx.gpr[1] as usize, // object type
+            ctx.gpr[2] as usize, // size bits
+            ctx.gpr[3] as usize, // first destination slot
+            ctx.gpr[4] as usize, // count
+            ctx.gpr[5] as usize, // depth
+        ),
+        numbers::SYS_TCB_START => sys_tcb_start(
+            ctx.gpr[0] as usize, // tcb slot
+            ctx.gpr[1] as usize, // entry point
+            ctx.gpr[2] as usize, // stack pointer
+            ctx.gpr[3],          // argument
+        ),
+        numbers::SYS_CSPACE_SET_GUARD => sys_cspace_set_guard(
+            ctx.gpr[0] as usize, // guard bits
+            ctx.gpr[1],          // guard value
+        ),
+        // IPC: message in x0-x7, endpoint in x9, message info in x10
+        numbers::SYS_SEND => sys_send(ctx),
+        numbers::SYS_RECV => sys_recv(ctx),
+        numbers::SYS_CALL => sys_call(ctx),
+        numbers::SYS_REPLY_RECV => sys_reply_recv(ctx),
+        numbers::SYS_TCB_SET_IPC_BUFFER => sys_tcb_set_ipc_buffer(
+            ctx.gpr[0] as usize, // tcb slot
+            ctx.gpr[1] as usize, // buffer address
+        ),
+        numbers::SYS_SIGNAL => sys_signal(ctx.gpr[0]), // notification cptr
+        numbers::SYS_WAIT => sys_wait(ctx),
+        numbers::SYS_POLL => sys_poll(ctx),
+        numbers::SYS_TCB_BIND_NOTIFICATION => sys_tcb_bind_notification(
+            ctx.gpr[0] as usize, // tcb slot
+            ctx.gpr[1] as usize, // notification slot, 0 to unbind
+        ),
+        numbers::SYS_SHM_MAP => sys_shm_map(
+            ctx.gpr[0],          // shared memory slot
+            ctx.gpr[1] as usize, // base address
+            ctx.gpr[2] as usize, // rights
+        ),
+        numbers::SYS_SHM_UNMAP => sys_shm_unmap(
+            ctx.gpr[0],          // shared memory slot
+            ctx.gpr[1] as usize, // base address
+        ),
+        numbers::SYS_YIELD => sys_yield(),
+        numbers::SYS_TCB_SET_TIME_SLICE => sys_tcb_set_time_slice(
+            ctx.gpr[0] as usize, // tcb slot
+            ctx.gpr[1] as usize, // ticks
+        ),
         _ => {
             kprintln!("[SYSCALL] Unknown syscall: {}", syscall_num);
             SyscallError::Enosys as i64
@@ -61,25 +197,18 @@ pub fn dispatch(syscall_num: usize, ctx: &mut ExceptionContext) -> i64 {
 
 /// Exit system call
 ///
-/// Terminates the current process with the given status code.
+/// Terminates the current process with the given status code. Its
+/// resources are released and the CPU moves on to other work; this
+/// never returns to the caller.
 ///
 /// # Arguments
 /// * `status` - Exit status code
 ///
 /// # Security
 /// No validation needed - any status code is acceptable
-fn sys_exit(status: i32) -> i64 {
+fn sys_exit(status: i32) -> ! {
     kprintln!("[SYSCALL] exit({})", status);
-    kprintln!("[PROCESS] Process exited with status {}", status);
-
-    // In a real kernel, we'd terminate the process and schedule another
-    // For now, halt the system
-    loop {
-        // SAFETY: WFI is always safe
-        unsafe {
-            core::arch::asm!("wfi", options(nostack, nomem));
-        }
-    }
+    process::exit_current(ExitReason::Exited(status))
 }
 
 /// Write system call
@@ -115,9 +244,1039 @@ fn sys_write(fd: i32, buf: usize, len: usize) -> i64 {
     };
 
     // Perform the write
-    for &byte in user_buf.as_bytes() {
+    for &byte in user_buf.chunks().flatten() {
         kprint!("{}", byte as char);
     }
 
     len as i64
 }
+
+/// File read system call
+///
+/// Copies part of a file from the initramfs into a user buffer.
+///
+/// # Arguments
+/// * `path` - User-space path (UTF-8, e.g. "bin/init", no NUL)
+/// * `path_len` - Path length in bytes
+/// * `offset` - Byte offset into the file
+/// * `buf` - User-space destination buffer
+/// * `len` - Buffer length in bytes
+///
+/// # Returns
+/// Number of bytes copied (0 at or past the end of the file) on success,
+/// negative error code on failure
+///
+/// # Security
+/// - Path and buffer are validated to be mapped in user space, the
+///   buffer writable
+/// - Path length is capped at MAX_PATH_LEN
+/// - Files are read-only; the archive itself is never exposed
+fn sys_file_read(path: usize, path_len: usize, offset: usize, buf: usize, len: usize) -> i64 {
+    if path_len == 0 || path_len > MAX_PATH_LEN {
+        return SyscallError::Einval as i64;
+    }
+
+    let user_path = match validate::validate_user_read(path, path_len) {
+        Ok(path) => path,
+        Err(e) => return e as i64,
+    };
+    let path_bytes = user_path.to_vec();
+    let Ok(name) = core::str::from_utf8(&path_bytes) else {
+        return SyscallError::Einval as i64;
+    };
+    let Some(file) = initramfs::lookup(name) else {
+        return SyscallError::Enoent as i64;
+    };
+
+    let mut user_buf = match validate::validate_user_write(buf, len) {
+        Ok(buf) => buf,
+        Err(e) => return e as i64,
+    };
+
+    let data = file.data.get(offset..).unwrap_or(&[]);
+    let count = data.len().min(len);
+    user_buf.copy_from(&data[..count]);
+
+    count as i64
+}
+
+/// Convert a user-supplied cptr, resolved over all 64 bits.
+fn cap_slot(slot: usize) -> CapSlot {
+    CapSlot::new(slot as u64)
+}
+
+/// Convert a user-supplied cptr and depth; depth 0 means 64.
+fn cap_slot_at(slot: usize, depth: usize) -> Result<CapSlot, SyscallError> {
+    let depth = match depth {
+        0 => 64,
+        depth => u8::try_from(depth).map_err(|_| SyscallError::Einval)?,
+    };
+    CapSlot::with_depth(slot as u64, depth).ok_or(SyscallError::Einval)
+}
+
+/// Fail unless `slot` may be written by user space.
+fn check_writable(cspace: &CSpace, slot: CapSlot) -> Result<(), CSpaceError> {
+    match cspace.is_reserved(slot)? {
+        true => Err(CSpaceError::InvalidSlot),
+        false => Ok(()),
+    }
+}
+
+/// Run `f` on the caller's CSpace.
+fn with_own_cspace<R>(
+    f: impl FnOnce(&mut CSpace) -> Result<R, CSpaceError>,
+) -> Result<R, SyscallError> {
+    let process = process::current().ok_or(SyscallError::Einval)?;
+    match process.with_cspace(f) {
+        Some(Ok(value)) => Ok(value),
+        Some(Err(e)) => Err(cspace_error(e)),
+        None => Err(SyscallError::Einval),
+    }
+}
+
+/// Map a CSpace error to the syscall error user space sees.
+fn cspace_error(e: CSpaceError) -> SyscallError {
+    match e {
+        CSpaceError::InsufficientRights => SyscallError::Eperm,
+        _ => SyscallError::Einval,
+    }
+}
+
+/// Look up a thread capability with `rights` in the caller's CSpace.
+fn lookup_thread(slot: usize, rights: Rights) -> Result<Arc<Thread>, SyscallError> {
+    let slot = cap_slot(slot);
+    let process = process::current().ok_or(SyscallError::Einval)?;
+    process
+        .with_cspace(|cspace| {
+            cspace.with_cap(slot, |cap| {
+                if !cap.rights.contains(rights) {
+                    return None;
+                }
+                thread::from_cap(cap)
+            })
+        })
+        .and_then(Result::ok)
+        .flatten()
+        .ok_or(SyscallError::Einval)
+}
+
+/// Set thread priority system call
+///
+/// Sets the base priority of the thread behind a TCB capability.
+///
+/// # Arguments
+/// * `tcb` - CSpace slot of the target thread (needs WRITE)
+/// * `authority` - CSpace slot of a thread (needs WRITE) whose maximum
+///   controlled priority (MCP) bounds the new priority; may be the
+///   caller's own
+/// * `priority` - New priority, 0 (lowest) to 255 (highest)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+///
+/// # Security
+/// - Both slots must hold thread capabilities
+/// - A thread can never raise a priority above its authority's MCP, so
+///   holding a TCB capability alone does not grant CPU time
+/// - Lending an MCP needs WRITE on the authority, so rights-stripped
+///   copies of a capability to a high-MCP thread confer nothing
+fn sys_tcb_set_priority(tcb: usize, authority: usize, priority: usize) -> i64 {
+    let target = match lookup_thread(tcb, Rights::WRITE) {
+        Ok(thread) => thread,
+        Err(e) => return e as i64,
+    };
+    let authority = match lookup_thread(authority, Rights::WRITE) {
+        Ok(thread) => thread,
+        Err(e) => return e as i64,
+    };
+
+    let Ok(priority) = u8::try_from(priority) else {
+        return SyscallError::Einval as i64;
+    };
+    if priority > authority.mcp() {
+        return SyscallError::Eperm as i64;
+    }
+
+    sched::set_priority(&target, priority);
+    0
+}
+
+/// Set thread CPU affinity system call
+///
+/// Restricts the thread behind a TCB capability to a set of CPUs.
+///
+/// # Arguments
+/// * `tcb` - CSpace slot of the target thread (needs WRITE)
+/// * `mask` - Allowed CPUs, bit n = logical CPU n
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+///
+/// # Security
+/// - The slot must hold a thread capability with WRITE
+/// - The mask must include a running CPU, so no thread can be left
+///   without a CPU to run on
+fn sys_tcb_set_affinity(tcb: usize, mask: u64) -> i64 {
+    let target = match lookup_thread(tcb, Rights::WRITE) {
+        Ok(thread) => thread,
+        Err(e) => return e as i64,
+    };
+    if !sched::valid_affinity(mask) {
+        return SyscallError::Einval as i64;
+    }
+
+    sched::set_affinity(&target, mask);
+    0
+}
+
+/// Set thread time slice system call
+///
+/// Sets how many ticks the thread behind a TCB capability runs before
+/// threads of its priority get a turn; 0 puts it in the real-time FIFO
+/// class, never sliced. Takes effect from its next slice.
+///
+/// # Arguments
+/// * `tcb` - CSpace slot of the target thread (needs WRITE)
+/// * `ticks` - Slice length in timer ticks
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+///
+/// # Security
+/// - The slot must hold a thread capability with WRITE
+/// - An unsliced thread only holds back threads of its own priority,
+///   which is already bounded by an MCP (see `sys_tcb_set_priority`)
+fn sys_tcb_set_time_slice(tcb: usize, ticks: usize) -> i64 {
+    let target = match lookup_thread(tcb, Rights::WRITE) {
+        Ok(thread) => thread,
+        Err(e) => return e as i64,
+    };
+    let Ok(ticks) = u32::try_from(ticks) else {
+        return SyscallError::Einval as i64;
+    };
+
+    target.set_time_slice(ticks);
+    0
+}
+
+/// Yield system call
+///
+/// Gives up the rest of the caller's time slice, letting ready threads
+/// of the same priority run first.
+///
+/// # Returns
+/// Always 0
+fn sys_yield() -> i64 {
+    sched::yield_now();
+    0
+}
+
+/// Derive capability system call
+///
+/// Copies a capability into an empty slot of the caller's CSpace with
+/// fewer (or equal) rights and a new badge. The copy is recorded as a
+/// child of the source, so revoking the source deletes it.
+///
+/// # Arguments
+/// * `src` - CSpace slot of the source capability (needs GRANT)
+/// * `dst` - Empty CSpace slot for the copy
+/// * `rights` - Rights of the copy, a subset of the source's
+/// * `badge` - Badge of the copy; the guard for CNode capabilities
+/// * `depth` - Cptr bits to resolve for both slots (0 means 64)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+///
+/// # Security
+/// - Rights can only shrink, never grow
+/// - The reserved slots below `CapSlot::FIRST_USER` cannot be written
+fn sys_cap_derive(src: usize, dst: usize, rights: usize, badge: u64, depth: usize) -> i64 {
+    let (src, dst) = match (cap_slot_at(src, depth), cap_slot_at(dst, depth)) {
+        (Ok(src), Ok(dst)) => (src, dst),
+        _ => return SyscallError::Einval as i64,
+    };
+    let Ok(bits) = u32::try_from(rights) else {
+        return SyscallError::Einval as i64;
+    };
+    let rights = Rights::from_bits(bits);
+    if rights.bits() != bits {
+        return SyscallError::Einval as i64;
+    }
+
+    match with_own_cspace(|cspace| {
+        check_writable(cspace, dst)?;
+        cspace.derive(src, dst, rights, badge)
+    }) {
+        Ok(()) => 0,
+        Err(e) => e as i64,
+    }
+}
+
+/// Delete capability system call
+///
+/// Empties a slot of the caller's CSpace. Capabilities derived from the
+/// deleted one stay valid and move up to its parent.
+///
+/// # Arguments
+/// * `slot` - CSpace slot to empty
+/// * `depth` - Cptr bits to resolve (0 means 64)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+///
+/// # Security
+/// - Only the caller's own CSpace is affected
+/// - The object is destroyed once its last capability is gone; a CNode
+///   capability can never be stored below its own CNode, so CNodes are
+///   no exception
+fn sys_cap_delete(slot: usize, depth: usize) -> i64 {
+    let slot = match cap_slot_at(slot, depth) {
+        Ok(slot) => slot,
+        Err(e) => return e as i64,
+    };
+
+    match with_own_cspace(|cspace| cspace.delete(slot)) {
+        Ok(()) => 0,
+        Err(e) => e as i64,
+    }
+}
+
+/// Revoke capability system call
+///
+/// Deletes every capability derived from one in the caller's CSpace,
+/// in whichever CSpace it ended up, keeping the capability itself.
+///
+/// # Arguments
+/// * `slot` - CSpace slot of the capability (needs REVOKE)
+/// * `depth` - Cptr bits to resolve (0 means 64)
+///
+/// # Returns
+/// Number of capabilities deleted on success, negative error code on
+/// failure
+///
+/// # Security
+/// - Requires REVOKE on the capability
+/// - Atomic: no derived capability can be copied out of the subtree
+///   while it is torn down
+fn sys_cap_revoke(slot: usize, depth: usize) -> i64 {
+    let slot = match cap_slot_at(slot, depth) {
+        Ok(slot) => slot,
+        Err(e) => return e as i64,
+    };
+
+    match with_own_cspace(|cspace| cspace.revoke(slot)) {
+        Ok(count) => count as i64,
+        Err(e) => e as i64,
+    }
+}
+
+/// Retype untyped memory system call
+///
+/// Carves new kernel objects out of an untyped memory capability and
+/// places capabilities to them, with all rights, into consecutive empty
+/// slots of the caller's CSpace.
+///
+/// # Arguments
+/// * `untyped` - CSpace slot of the untyped capability (needs WRITE)
+/// * `object` - Object type, numbered as `CapabilityType` (untyped 10,
+///   thread 5, frame 7, page table 8, CNode 4, shared memory 11)
+/// * `size_bits` - Log2 of the size for untyped and shared memory
+///   objects, of the number of slots for CNodes
+/// * `dst` - First destination slot
+/// * `count` - Number of objects
+/// * `depth` - Cptr bits to resolve for all slots (0 means 64)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+///
+/// # Security
+/// - All memory comes from the untyped object, never from the kernel's
+///   own pools, so a process can only use memory it was given
+/// - The new capabilities are children of the untyped capability, so
+///   revoking it destroys them
+/// - New threads join the caller's process and stay inactive until
+///   `tcb_start`
+fn sys_untyped_retype(
+    untyped: usize,
+    object: usize,
+    size_bits: usize,
+    dst: usize,
+    count: usize,
+    depth: usize,
+) -> i64 {
+    let (untyped, dst) = match (cap_slot_at(untyped, depth), cap_slot_at(dst, depth)) {
+        (Ok(untyped), Ok(dst)) => (untyped, dst),
+        _ => return SyscallError::Einval as i64,
+    };
+    let (Some(object), Ok(size_bits)) = (ObjectType::from_raw(object), u8::try_from(size_bits))
+    else {
+        return SyscallError::Einval as i64;
+    };
+    let Some(process) = process::current() else {
+        return SyscallError::Einval as i64;
+    };
+    if count > retype::RETYPE_MAX_COUNT {
+        return SyscallError::Einval as i64;
+    }
+    let writable = (0..count as u64)
+        .map_while(|i| dst.offset(i))
+        .try_for_each(|slot| with_own_cspace(|cspace| check_writable(cspace, slot)));
+    if let Err(e) = writable {
+        return e as i64;
+    }
+
+    match retype::retype(&process, untyped, object, size_bits, dst, count) {
+        Ok(()) => 0,
+        Err(RetypeError::CSpace(e)) => cspace_error(e) as i64,
+        Err(RetypeError::Untyped(_) | RetypeError::OutOfMemory) => SyscallError::Enomem as i64,
+        Err(_) => SyscallError::Einval as i64,
+    }
+}
+
+/// Start thread system call
+///
+/// Starts an inactive thread made by `untyped_retype` at EL0.
+///
+/// # Arguments
+/// * `tcb` - CSpace slot of the thread (needs WRITE)
+/// * `entry` - User entry point
+/// * `stack` - Initial user stack pointer
+/// * `arg` - Value passed in x0
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+///
+/// # Security
+/// - A thread can be started only once; running threads cannot have
+///   their registers replaced this way
+/// - Addresses are user addresses of the thread's own process; bad ones
+///   fault in that process, not in the kernel
+fn sys_tcb_start(tcb: usize, entry: usize, stack: usize, arg: u64) -> i64 {
+    let target = match lookup_thread(tcb, Rights::WRITE) {
+        Ok(thread) => thread,
+        Err(e) => return e as i64,
+    };
+    if !(USER_START..USER_END).contains(&entry) || !(USER_START..=USER_END).contains(&stack) {
+        return SyscallError::Efault as i64;
+    }
+
+    if !target.start_user(VirtAddr::new(entry), VirtAddr::new(stack), arg) {
+        return SyscallError::Einval as i64;
+    }
+    sched::wake(target);
+    0
+}
+
+/// Set CSpace guard system call
+///
+/// Replaces the guard of the caller's root CNode. A shorter guard leaves
+/// cptr bits to address CNodes stored in the root, so capabilities in
+/// them can be used; cptrs of root slots change to match.
+///
+/// # Arguments
+/// * `bits` - Guard size, at most 58
+/// * `guard` - Value the top cptr bits must match
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+///
+/// # Security
+/// - Only changes how the caller names its own capabilities, never
+///   which capabilities it holds
+fn sys_cspace_set_guard(bits: usize, guard: u64) -> i64 {
+    let Some(guard) = u8::try_from(bits).ok().and_then(|bits| Guard::new(bits, guard)) else {
+        return SyscallError::Einval as i64;
+    };
+
+    match with_own_cspace(|cspace| cspace.set_guard(guard)) {
+        Ok(()) => 0,
+        Err(e) => e as i64,
+    }
+}
+
+/// Register holding the endpoint cptr, and the badge on return.
+const IPC_CPTR_REG: usize = 9;
+
+/// Register holding the message info, or an error code on return.
+const IPC_INFO_REG: usize = 10;
+
+/// Look up a capability with `rights` in the caller's CSpace and get its
+/// object with `from_cap`; returns the object and a copy of the
+/// capability.
+fn lookup_object<T>(
+    cptr: u64,
+    rights: Rights,
+    from_cap: fn(&RawCapability) -> Option<Arc<T>>,
+) -> Result<(Arc<T>, RawCapability), SyscallError> {
+    let slot = CapSlot::new(cptr);
+    with_own_cspace(|cspace| {
+        cspace
+            .with_cap(slot, |cap| {
+                if !cap.rights.contains(rights) {
+                    return Err(CSpaceError::InsufficientRights);
+                }
+                let object = from_cap(cap).ok_or(CSpaceError::TypeMismatch)?;
+                Ok((object, *cap))
+            })
+            .and_then(|found| found)
+    })
+}
+
+/// Look up an endpoint capability with `rights` in the caller's CSpace;
+/// returns the endpoint and a copy of the capability.
+fn lookup_endpoint(
+    cptr: u64,
+    rights: Rights,
+) -> Result<(Arc<Endpoint>, RawCapability), SyscallError> {
+    lookup_object(cptr, rights, endpoint::from_cap)
+}
+
+/// Look up a notification capability with `rights` in the caller's
+/// CSpace; returns the notification and the capability's badge.
+fn lookup_notification(
+    cptr: u64,
+    rights: Rights,
+) -> Result<(Arc<Notification>, u64), SyscallError> {
+    lookup_object(cptr, rights, notification::from_cap).map(|(found, cap)| (found, cap.badge))
+}
+
+/// User address of the current thread's IPC buffer.
+fn ipc_buffer() -> Result<usize, SyscallError> {
+    sched::current()
+        .and_then(|thread| thread.ipc_buffer())
+        .map(VirtAddr::as_usize)
+        .ok_or(SyscallError::Einval)
+}
+
+/// Read the message the caller sends: the words in x0-x7 and, past
+/// those, in its IPC buffer.
+fn load_message(ctx: &ExceptionContext) -> Result<Message, SyscallError> {
+    let info = MessageInfo::from_raw(ctx.gpr[IPC_INFO_REG]).ok_or(SyscallError::Einval)?;
+    let length = info.length();
+    let mut msg = Message::empty();
+    msg.info = info;
+
+    let registers = length.min(MSG_REGISTERS);
+    msg.words[..registers].copy_from_slice(&ctx.gpr[..registers]);
+    if length > MSG_REGISTERS {
+        let words = (length - MSG_REGISTERS) * 8;
+        let buffer = validate::validate_user_read(ipc_buffer()? + MSG_REGISTERS * 8, words)?;
+        for (word, bytes) in msg.words[MSG_REGISTERS..length]
+            .iter_mut()
+            .zip(buffer.to_vec().as_chunks::<8>().0)
+        {
+            *word = u64::from_ne_bytes(*bytes);
+        }
+    }
+    Ok(msg)
+}
+
+/// Derive the capabilities the caller sends with `msg`, named by cptrs in
+/// its IPC buffer, with their rights reduced to `rights`.
+///
+/// Without GRANT in `rights` the message goes without them.
+fn load_caps(msg: &mut Message, rights: Rights) -> Result<(), SyscallError> {
+    let count = msg.info.caps();
+    if count == 0 || !rights.contains(Rights::GRANT) {
+        msg.info = msg.info.with_caps(0);
+        return Ok(());
+    }
+
+    let buffer = validate::validate_user_read(ipc_buffer()? + BUFFER_CAPS * 8, count * 8)?;
+    let slots: Vec<CapSlot> = buffer
+        .to_vec()
+        .as_chunks::<8>()
+        .0
+        .iter()
+        .map(|bytes| CapSlot::new(u64::from_ne_bytes(*bytes)))
+        .collect();
+    msg.caps = Some(with_own_cspace(|cspace| cspace.send_caps(&slots, rights))?);
+    Ok(())
+}
+
+/// Move capabilities received with a message into the slots the caller
+/// names in its IPC buffer, and return how many arrived.
+///
+/// With no receive slot (or no IPC buffer) they are deleted.
+fn receive_caps(transfer: CapTransfer) -> usize {
+    let slot = ipc_buffer()
+        .and_then(|buffer| validate::validate_user_read(buffer + BUFFER_RECEIVE_SLOT * 8, 16))
+        .and_then(|buffer| {
+            let bytes = buffer.to_vec();
+            let (words, _) = bytes.as_chunks::<8>();
+            let [cptr, depth] = [0, 1].map(|i| u64::from_ne_bytes(words[i]) as usize);
+            match cptr {
+                0 => Err(SyscallError::Einval),
+                cptr => cap_slot_at(cptr, depth),
+            }
+        });
+    match slot {
+        Ok(slot) => with_own_cspace(|cspace| Ok(cspace.receive_caps(transfer, slot))).unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+/// Hand a received message to the caller: words in x0-x7 and its IPC
+/// buffer, the badge in x9 and the message info in x10. Returns x0.
+///
+/// Words that do not fit (no IPC buffer, or not writable) are dropped,
+/// and the length in x10 says so.
+fn store_message(ctx: &mut ExceptionContext, msg: &Message, badge: u64) -> i64 {
+    let mut length = msg.info.length();
+    if length > MSG_REGISTERS {
+        let words = (length - MSG_REGISTERS) * 8;
+        let buffer = ipc_buffer()
+            .and_then(|buffer| validate::validate_user_write(buffer + MSG_REGISTERS * 8, words));
+        match buffer {
+            Ok(mut buffer) => {
+                let bytes: Vec<u8> = msg.words[MSG_REGISTERS..length]
+                    .iter()
+                    .flat_map(|word| word.to_ne_bytes())
+                    .collect();
+                buffer.copy_from(&bytes);
+            }
+            Err(_) => length = MSG_REGISTERS,
+        }
+    }
+
+    let registers = length.min(MSG_REGISTERS);
+    ctx.gpr[..registers].copy_from_slice(&msg.words[..registers]);
+    ctx.gpr[IPC_CPTR_REG] = badge;
+    ctx.gpr[IPC_INFO_REG] = msg.info.with_length(length).raw();
+    ctx.gpr[0] as i64
+}
+
+/// Report a failed receive: the error code goes to x0 and x10.
+fn ipc_failed(ctx: &mut ExceptionContext, e: SyscallError) -> i64 {
+    ctx.gpr[IPC_INFO_REG] = e as i64 as u64;
+    e as i64
+}
+
+/// Map an IPC error to the syscall error user space sees.
+fn ipc_error(e: IpcError) -> SyscallError {
+    match e {
+        IpcError::Canceled => SyscallError::Epipe,
+        IpcError::Bound => SyscallError::Ebusy,
+    }
+}
+
+/// Put a reply capability for `reply` into the caller's reply slot,
+/// deleting the one there.
+fn install_reply(reply: Arc<Reply>) {
+    let cap = endpoint::create_reply_cap(reply);
+    let replaced = process::current().and_then(|process| {
+        process.with_cspace(|cspace| cspace.replace_reserved(CapSlot::REPLY, cap))
+    });
+    match replaced {
+        Some(old) => old.into_iter().for_each(object::release),
+        // Torn down meanwhile: nobody can answer
+        None => object::release(cap),
+    }
+}
+
+/// Deliver a completed receive to the caller, putting a reply
+/// capability for a call into its reply slot and capabilities sent with
+/// the message into its receive slots.
+///
+/// An unanswered reply capability already in the slot is deleted, which
+/// fails that call.
+fn finish_receive(ctx: &mut ExceptionContext, received: Received) -> i64 {
+    let Received {
+        mut msg,
+        badge,
+        reply,
+    } = received;
+    if let Some(reply) = reply {
+        install_reply(reply);
+    }
+    let caps = msg.caps.take().map_or(0, receive_caps);
+    msg.info = msg.info.with_caps(caps);
+    store_message(ctx, &msg, badge)
+}
+
+/// Send system call
+///
+/// Sends a message through an endpoint, sleeping until a receiver takes
+/// it.
+///
+/// # Arguments
+/// * `x0-x7` - Message words 0-7; the rest come from the IPC buffer
+/// * `x9` - CSpace slot of the endpoint (needs WRITE)
+/// * `x10` - Message info (label, capability count, length); the
+///   capabilities are named in the IPC buffer
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+///
+/// # Security
+/// - The receiver sees the badge of the sender's capability, not a value
+///   the sender chooses
+/// - Capabilities are only sent if the endpoint capability has GRANT,
+///   and arrive with at most its rights
+fn sys_send(ctx: &mut ExceptionContext) -> i64 {
+    let mut msg = match load_message(ctx) {
+        Ok(msg) => msg,
+        Err(e) => return e as i64,
+    };
+    let (endpoint, cap) = match lookup_endpoint(ctx.gpr[IPC_CPTR_REG], Rights::WRITE) {
+        Ok(found) => found,
+        Err(e) => return e as i64,
+    };
+    if let Err(e) = load_caps(&mut msg, cap.rights) {
+        return e as i64;
+    }
+
+    match endpoint.send(msg, cap.badge) {
+        Ok(()) => 0,
+        Err(e) => ipc_error(e) as i64,
+    }
+}
+
+/// Receive system call
+///
+/// Waits on an endpoint for a message. For a message sent with `call`, a
+/// reply capability is placed in the reply slot.
+///
+/// # Arguments
+/// * `x9` - CSpace slot of the endpoint (needs READ)
+///
+/// # Returns
+/// The message in x0-x7 and the IPC buffer, the sender's badge in x9 and
+/// the message info in x10; on failure the negative error code in x0 and
+/// x10. If a signal of the thread's bound notification ends the wait, x10
+/// has the notification flag set, no words, and x9 the signal bits.
+/// Capabilities sent with the message go to the receive slots named in
+/// the IPC buffer; the count in x10 says how many arrived
+///
+/// # Security
+/// - Until it replies, the receiver runs at least at the priority of a
+///   caller it serves
+fn sys_recv(ctx: &mut ExceptionContext) -> i64 {
+    let (endpoint, cap) = match lookup_endpoint(ctx.gpr[IPC_CPTR_REG], Rights::READ) {
+        Ok(found) => found,
+        Err(e) => return ipc_failed(ctx, e),
+    };
+
+    match endpoint.recv(cap.rights) {
+        Ok(received) => finish_receive(ctx, received),
+        Err(e) => ipc_failed(ctx, ipc_error(e)),
+    }
+}
+
+/// Call system call
+///
+/// Sends a message through an endpoint and sleeps until the receiver
+/// answers it through its reply capability.
+///
+/// # Arguments
+/// As for `send`.
+///
+/// # Returns
+/// The reply as for `recv` (badge 0); on failure the negative error code
+/// in x0 and x10
+///
+/// # Security
+/// - The caller's priority is lent to the receiver only until it answers
+/// - Deleting the reply capability unanswered fails the call with EPIPE
+fn sys_call(ctx: &mut ExceptionContext) -> i64 {
+    let mut msg = match load_message(ctx) {
+        Ok(msg) => msg,
+        Err(e) => return ipc_failed(ctx, e),
+    };
+    let (endpoint, cap) = match lookup_endpoint(ctx.gpr[IPC_CPTR_REG], Rights::WRITE) {
+        Ok(found) => found,
+        Err(e) => return ipc_failed(ctx, e),
+    };
+    if let Err(e) = load_caps(&mut msg, cap.rights) {
+        return ipc_failed(ctx, e);
+    }
+
+    match endpoint.call(msg, cap.badge) {
+        Ok(received) => finish_receive(ctx, received),
+        Err(e) => ipc_failed(ctx, ipc_error(e)),
+    }
+}
+
+/// Reply and receive system call
+///
+/// Answers the call in the reply slot with a message, if there is one,
+/// then waits on an endpoint as `recv` does. A server loops on this.
+///
+/// # Arguments
+/// * `x0-x7`, `x10` - Reply message, as for `send`
+/// * `x9` - CSpace slot of the endpoint to receive on (needs READ)
+///
+/// # Returns
+/// As for `recv`
+///
+/// # Security
+/// - The reply capability is used up by the reply; the caller's priority
+///   loan ends with it
+/// - The reply carries capabilities only if the call was received
+///   through an endpoint capability with GRANT, with at most its rights
+fn sys_reply_recv(ctx: &mut ExceptionContext) -> i64 {
+    let mut msg = match load_message(ctx) {
+        Ok(msg) => msg,
+        Err(e) => return ipc_failed(ctx, e),
+    };
+    let (endpoint, cap) = match lookup_endpoint(ctx.gpr[IPC_CPTR_REG], Rights::READ) {
+        Ok(found) => found,
+        Err(e) => return ipc_failed(ctx, e),
+    };
+
+    let reply = process::current()
+        .and_then(|process| process.with_cspace(|cspace| cspace.take_reserved(CapSlot::REPLY)))
+        .flatten()
+        .and_then(endpoint::take_reply);
+    if let Some(reply) = reply {
+        if let Err(e) = load_caps(&mut msg, reply.rights()) {
+            // The call stays open for another try
+            install_reply(reply);
+            return ipc_failed(ctx, e);
+        }
+        reply.reply(msg);
+    }
+
+    match endpoint.recv(cap.rights) {
+        Ok(received) => finish_receive(ctx, received),
+        Err(e) => ipc_failed(ctx, ipc_error(e)),
+    }
+}
+
+/// Set IPC buffer system call
+///
+/// Sets the page a thread's IPC messages beyond the register words are
+/// read from and written to.
+///
+/// # Arguments
+/// * `tcb` - CSpace slot of the thread (needs WRITE)
+/// * `addr` - Page-aligned user address, or 0 for none
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+///
+/// # Security
+/// - The address is checked on every message, in the thread's own
+///   address space; a bad one only loses that thread's message words
+fn sys_tcb_set_ipc_buffer(tcb: usize, addr: usize) -> i64 {
+    let target = match lookup_thread(tcb, Rights::WRITE) {
+        Ok(thread) => thread,
+        Err(e) => return e as i64,
+    };
+    let buffer = match addr {
+        0 => None,
+        addr if addr % PAGE_SIZE == 0 && (USER_START..USER_END).contains(&addr) => {
+            Some(VirtAddr::new(addr))
+        }
+        _ => return SyscallError::Einval as i64,
+    };
+
+    target.set_ipc_buffer(buffer);
+    0
+}
+
+/// Signal system call
+///
+/// ORs the badge of a notification capability into the notification's
+/// word, or hands it to a thread waiting on it. Never blocks.
+///
+/// # Arguments
+/// * `ntfn` - CSpace slot of the notification (needs WRITE)
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+///
+/// # Security
+/// - The bits come from the capability's badge, not from the caller
+fn sys_signal(ntfn: u64) -> i64 {
+    match lookup_notification(ntfn, Rights::WRITE) {
+        Ok((notification, badge)) => {
+            notification.signal(badge);
+            0
+        }
+        Err(e) => e as i64,
+    }
+}
+
+/// Wait system call
+///
+/// Takes a notification's signal bits, sleeping until there are some.
+///
+/// # Arguments
+/// * `x0` - CSpace slot of the notification (needs READ)
+///
+/// # Returns
+/// 0 with the bits in x9 on success, negative error code on failure
+fn sys_wait(ctx: &mut ExceptionContext) -> i64 {
+    let notification = match lookup_notification(ctx.gpr[0], Rights::READ) {
+        Ok((notification, _)) => notification,
+        Err(e) => return e as i64,
+    };
+
+    match notification.wait() {
+        Ok(bits) => {
+            ctx.gpr[IPC_CPTR_REG] = bits;
+            0
+        }
+        Err(e) => ipc_error(e) as i64,
+    }
+}
+
+/// Poll system call
+///
+/// Takes a notification's signal bits without sleeping.
+///
+/// # Arguments
+/// * `x0` - CSpace slot of the notification (needs READ)
+///
+/// # Returns
+/// 0 with the bits (0 if none were pending) in x9 on success, negative
+/// error code on failure
+fn sys_poll(ctx: &mut ExceptionContext) -> i64 {
+    match lookup_notification(ctx.gpr[0], Rights::READ) {
+        Ok((notification, _)) => {
+            ctx.gpr[IPC_CPTR_REG] = notification.poll();
+            0
+        }
+        Err(e) => e as i64,
+    }
+}
+
+/// Bind notification system call
+///
+/// Binds a notification to a thread, so that its signals also end the
+/// thread's endpoint receives; or unbinds the thread's notification.
+///
+/// # Arguments
+/// * `tcb` - CSpace slot of the thread (needs WRITE)
+/// * `ntfn` - CSpace slot of the notification (needs READ), or 0 to
+///   unbind
+///
+/// # Returns
+/// 0 on success, EBUSY if either is bound already, other negative error
+/// codes on failure
+///
+/// # Security
+/// - Binding needs the right to wait on the notification, since the
+///   thread's receives then take its signals
+fn sys_tcb_bind_notification(tcb: usize, ntfn: usize) -> i64 {
+    let target = match lookup_thread(tcb, Rights::WRITE) {
+        Ok(thread) => thread,
+        Err(e) => return e as i64,
+    };
+    if ntfn == 0 {
+        notification::unbind(&target);
+        return 0;
+    }
+    let notification = match lookup_notification(ntfn as u64, Rights::READ) {
+        Ok((notification, _)) => notification,
+        Err(e) => return e as i64,
+    };
+
+    match notification.bind(&target) {
+        Ok(()) => 0,
+        Err(e) => ipc_error(e) as i64,
+    }
+}
+
+/// Map a shared memory error to the syscall error user space sees.
+fn shm_error(e: ShmError) -> SyscallError {
+    match e {
+        ShmError::Mapping(MappingError::AlreadyMapped) => SyscallError::Ebusy,
+        ShmError::Mapping(MappingError::OutOfMemory) => SyscallError::Enomem,
+        ShmError::Mapping(MappingError::InvalidPermissions) => SyscallError::Efault,
+        _ => SyscallError::Einval,
+    }
+}
+
+/// Map shared memory system call
+///
+/// Maps the whole of a shared memory object into the caller's address
+/// space.
+///
+/// # Arguments
+/// * `cptr` - CSpace slot of the shared memory object (needs `rights`)
+/// * `base` - Page-aligned user address of the first page; the whole
+///   object must fit in user space
+/// * `rights` - Mapping rights: READ, with WRITE or EXECUTE optionally
+///
+/// # Returns
+/// 0 on success, EBUSY if part of the range is mapped already, other
+/// negative error codes on failure
+///
+/// # Security
+/// - The range is checked as a whole before anything is mapped, so it
+///   cannot wrap around to page 0
+/// - A mapping never gets more access than the capability grants
+/// - Writable mappings are never executable (W^X)
+/// - Existing mappings are never replaced; on failure nothing is mapped
+fn sys_shm_map(cptr: u64, base: usize, rights: usize) -> i64 {
+    let Ok(bits) = u32::try_from(rights) else {
+        return SyscallError::Einval as i64;
+    };
+    let rights = Rights::from_bits(bits);
+    if rights.bits() != bits || !MAPPING_RIGHTS.contains(rights) {
+        return SyscallError::Einval as i64;
+    }
+    let Some(process) = process::current() else {
+        return SyscallError::Einval as i64;
+    };
+    let shm = match lookup_object(cptr, rights, shm::from_cap) {
+        Ok((shm, _)) => shm,
+        Err(e) => return e as i64,
+    };
+    if let Err(e) = validate::validate_user_pages(base, shm.size()) {
+        return e as i64;
+    }
+
+    let mut stale = Vec::new();
+    let mapped = process.with_vspace(|vspace| {
+        shm.map(vspace, VirtAddr::new(base), rights, &mut stale)
+    });
+    // Rolled back pages are shot down with the process unlocked
+    stale.into_iter().for_each(StalePage::flush);
+
+    match mapped {
+        Some(Ok(())) => 0,
+        Some(Err(e)) => shm_error(e) as i64,
+        None => SyscallError::Einval as i64,
+    }
+}
+
+/// Unmap shared memory system call
+///
+/// Removes a mapping made by `shm_map` from the caller's address space.
+///
+/// # Arguments
+/// * `cptr` - CSpace slot of the shared memory object (needs READ)
+/// * `base` - Address the object is mapped at
+///
+/// # Returns
+/// 0 on success, negative error code on failure
+///
+/// # Security
+/// - Only a range mapped to exactly this object's frames is unmapped,
+///   so the call cannot take down other mappings
+/// - The pages are unreachable on every CPU before the call returns
+fn sys_shm_unmap(cptr: u64, base: usize) -> i64 {
+    let Some(process) = process::current() else {
+        return SyscallError::Einval as i64;
+    };
+    let shm = match lookup_object(cptr, Rights::READ, shm::from_cap) {
+        Ok((shm, _)) => shm,
+        Err(e) => return e as i64,
+    };
+    if let Err(e) = validate::validate_user_pages(base, shm.size()) {
+        return e as i64;
+    }
+
+    let unmapped = process.with_vspace(|vspace| shm.unmap(vspace, VirtAddr::new(base)));
+    match unmapped {
+        Some(Ok(stale)) => {
+            stale.into_iter().for_each(StalePage::flush);
+            0
+        }
+        Some(Err(e)) => shm_error(e) as i64,
+        None => SyscallError::Einval as i64,
+    }
+}
@@ -11,6 +11,38 @@
 //! # Current Syscalls
 //! - 0: exit(status) - terminate the current process
 //! - 1: write(fd, buf, len) - write to a file descriptor
+//! - 2: file_read(path, path_len, offset, buf, len) - read an initramfs file
+//! - 3: tcb_set_priority(tcb, authority, priority) - set a thread's priority
+//! - 4: tcb_set_affinity(tcb, mask) - restrict a thread to a set of CPUs
+//! - 5: cap_derive(src, dst, rights, badge, depth) - copy a capability with
+//!   fewer rights
+//! - 6: cap_delete(slot, depth) - empty a capability slot
+//! - 7: cap_revoke(slot, depth) - delete every capability derived from one
+//! - 8: untyped_retype(untyped, type, size_bits, dst, count, depth) - make
+//!   kernel objects from untyped memory
+//! - 9: tcb_start(tcb, entry, stack, arg) - start a thread made by retype
+//! - 10: cspace_set_guard(bits, guard) - set the guard of the root CNode
+//! - 11: send(x0-x7, x9 = endpoint, x10 = info) - send a message
+//! - 12: recv(x9 = endpoint) - receive a message
+//! - 13: call(x0-x7, x9 = endpoint, x10 = info) - send and wait for a reply
+//! - 14: reply_recv(x0-x7, x9 = endpoint, x10 = info) - answer the last
+//!   call, then receive
+//! - 15: tcb_set_ipc_buffer(tcb, addr) - set a thread's IPC buffer page
+//! - 16: signal(ntfn) - OR the capability's badge into a notification
+//! - 17: wait(ntfn) - take a notification's bits (x9), sleeping until set
+//! - 18: poll(ntfn) - take a notification's bits (x9) without sleeping
+//! - 19: tcb_bind_notification(tcb, ntfn) - let a notification also end
+//!   the thread's receives (0 unbinds)
+//! - 20: shm_map(shm, base, rights) - map a shared memory object
+//! - 21: shm_unmap(shm, base) - unmap a shared memory object
+//!
+//! Slots are capability pointers; a `depth` of 0 resolves all 64 bits,
+//! which with the default root guard names root slot `n` by cptr `n`.
+//!
+//! IPC calls return the message words in x0-x7, the badge in x9 and the
+//! `MessageInfo` in x10 (see `ipc`); on failure x10 holds the error code.
+//! Capabilities sent with a message are named in the sender's IPC
+//! buffer, and land in the slots the receiver names in its own.
 
 mod handler;
 mod validate;
@@ -11,55 +11,135 @@
 //!   - TOCTOU races (copy to kernel space)
 //!   - Null pointer dereference (explicit checks)
 
+use alloc::vec::Vec;
+
+use crate::mm::address::phys_to_kernel_virt;
+use crate::mm::frame::frame_ref;
+use crate::mm::{free_frame, PhysAddr, VirtAddr, PAGE_SIZE};
+use crate::process;
+
 use super::handler::SyscallError;
 
 /// User-space memory regions
-/// 
-/// In a real system, these would be per-process and managed by the VMM.
-/// For now, we define a simple user space region.
+///
+/// The bounds of the lower half (TTBR0). Whether a range inside them is
+/// usable is decided by the calling process's page tables.
 pub mod regions {
-    /// Start of user-accessible memory
-    /// (Kernel is at 0x40080000, user space is below)
-    pub const USER_START: usize = 0x40000000;
-    /// End of user-accessible memory (before kernel)
-    pub const USER_END: usize = 0x40080000;
+    /// Start of user-accessible memory (page 0 is never mapped)
+    pub const USER_START: usize = 0x1000;
+    /// End of user-accessible memory (48-bit TTBR0 range)
+    pub const USER_END: usize = 0x0001_0000_0000_0000;
+}
+
+/// User pages pinned for the duration of a copy.
+///
+/// Each page holds a reference to its frame, so the frame stays
+/// allocated even if another thread of the process unmaps it, or the
+/// process exits, while the syscall runs. The bytes are reached through
+/// the kernel's direct map rather than the user address, so a mapping
+/// that disappears after the check cannot fault the kernel either.
+#[derive(Debug)]
+struct PinnedPages {
+    /// Frame of each page the range touches, in address order.
+    frames: Vec<PhysAddr>,
+    /// Offset of the range into the first page.
+    offset: usize,
+    len: usize,
+}
+
+impl PinnedPages {
+    /// Pin every page of `[ptr, ptr + len)`, which must be mapped for EL0
+    /// in the current process, and writable if `write` is set.
+    fn pin(ptr: usize, len: usize, write: bool) -> Result<Self, SyscallError> {
+        let mut pinned = Self {
+            frames: Vec::new(),
+            offset: ptr % PAGE_SIZE,
+            len,
+        };
+        if len == 0 {
+            return Ok(pinned);
+        }
+
+        let process = process::current().ok_or(SyscallError::Efault)?;
+        let first = ptr - pinned.offset;
+        let mapped = process.with_vspace(|vspace| {
+            for page in (first..ptr + len).step_by(PAGE_SIZE) {
+                let frame = match vspace.translate(VirtAddr::new(page)) {
+                    Some((phys, flags)) if flags.is_user() && (!write || flags.is_writable()) => {
+                        PhysAddr::new(phys.as_usize() & !(PAGE_SIZE - 1))
+                    }
+                    _ => return false,
+                };
+                // Only allocator frames can be pinned; syscalls do not
+                // copy to or from device memory
+                if !frame_ref(frame) {
+                    return false;
+                }
+                pinned.frames.push(frame);
+            }
+            true
+        });
+
+        // Pages pinned before a failure are released by the drop
+        if mapped == Some(true) {
+            Ok(pinned)
+        } else {
+            Err(SyscallError::Efault)
+        }
+    }
+
+    /// The pinned range, page by page, as direct-map pointers and lengths.
+    fn pieces(&self) -> impl Iterator<Item = (*mut u8, usize)> + '_ {
+        let end = self.offset + self.len;
+        self.frames.iter().enumerate().map(move |(i, &frame)| {
+            let page = i * PAGE_SIZE;
+            let start = page.max(self.offset);
+            let stop = (page + PAGE_SIZE).min(end);
+            let base = phys_to_kernel_virt(frame).as_usize();
+            ((base + start - page) as *mut u8, stop - start)
+        })
+    }
+}
+
+impl Drop for PinnedPages {
+    fn drop(&mut self) {
+        self.frames.drain(..).for_each(free_frame);
+    }
 }
 
 /// A validated user-space buffer
 ///
 /// This type guarantees that:
 /// - The buffer is within user-space bounds
-/// - The buffer is properly aligned
 /// - The length doesn't overflow
+/// - Every page was mapped user-readable when it was validated, and its
+///   frame stays allocated until the buffer is dropped
 ///
 /// # Safety
 /// This struct is only constructed after validation passes.
 #[derive(Debug)]
 pub struct UserBuffer {
-    ptr: *const u8,
-    len: usize,
+    pages: PinnedPages,
 }
 
 impl UserBuffer {
-    /// Get the buffer as a byte slice
-    ///
-    /// # Safety
-    /// This is safe because the buffer was validated during construction.
-    /// However, the contents may change if we don't copy them (TOCTOU).
+    /// The buffer's contents, one piece per page.
     ///
-    /// SAFETY AUDIT: 2025-01-04
-    /// - Pointer and length were validated in validate_user_read
-    /// - User memory is mapped and accessible from kernel
-    pub fn as_bytes(&self) -> &[u8] {
-        if self.len == 0 {
-            return &[];
-        }
-        // SAFETY:
-        // - Pointer is validated to be in user space
-        // - Length is validated to not overflow
-        // - Memory is guaranteed mapped by hypervisor
-        // Audited: 2025-01-04
-        unsafe { core::slice::from_raw_parts(self.ptr, self.len) }
+    /// The user can change them at any time (TOCTOU): copy what has to
+    /// stay consistent.
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> {
+        self.pages.pieces().map(|(ptr, len)| {
+            // SAFETY: The piece lies within one pinned frame, which stays
+            // allocated and direct-mapped while `self` lives.
+            unsafe { core::slice::from_raw_parts(ptr as *const u8, len) }
+        })
+    }
+
+    /// Copy the buffer into kernel memory.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.pages.len);
+        self.chunks().for_each(|chunk| bytes.extend_from_slice(chunk));
+        bytes
     }
 }
 
@@ -77,14 +157,21 @@ impl UserBuffer {
 /// 1. Pointer is within user space
 /// 2. Pointer + length doesn't overflow
 /// 3. End address is within user space
-/// 4. Alignment is acceptable (no strict requirement for bytes)
+/// 4. Every page is mapped user-readable in the current process; its
+///    frame is pinned until the buffer is dropped
+/// 5. Alignment is acceptable (no strict requirement for bytes)
 pub fn validate_user_read(ptr: usize, len: usize) -> Result<UserBuffer, SyscallError> {
-    // Zero-length reads are valid
+    validate_range(ptr, len)?;
+    Ok(UserBuffer {
+        pages: PinnedPages::pin(ptr, len, false)?,
+    })
+}
+
+/// Bounds checks shared by reads and writes; returns the end address.
+fn validate_range(ptr: usize, len: usize) -> Result<usize, SyscallError> {
+    // Zero-length buffers are valid
     if len == 0 {
-        return Ok(UserBuffer {
-            ptr: ptr as *const u8,
-            len: 0,
-        });
+        return Ok(ptr);
     }
 
     // Check null pointer
@@ -105,43 +192,58 @@ pub fn validate_user_read(ptr: usize, len: usize) -> Result<UserBuffer, SyscallE
         return Err(SyscallError::Efault);
     }
 
-    Ok(UserBuffer {
-        ptr: ptr as *const u8,
-        len,
-    })
+    Ok(end)
+}
+
+/// Validate a page-aligned range of user addresses to map or unmap
+///
+/// The whole range must lie in user space: past `USER_END` addresses wrap
+/// around to page 0 when made canonical, so checking the start is not
+/// enough.
+pub fn validate_user_pages(base: usize, size: usize) -> Result<(), SyscallError> {
+    if !base.is_multiple_of(PAGE_SIZE) || base < regions::USER_START {
+        return Err(SyscallError::Einval);
+    }
+    match base.checked_add(size) {
+        Some(end) if end <= regions::USER_END => Ok(()),
+        _ => Err(SyscallError::Einval),
+    }
 }
 
 /// Validate a user-space write buffer
 ///
-/// Same as read validation, but the buffer will be written to.
+/// Same as read validation, but every page must also be user-writable.
 pub fn validate_user_write(ptr: usize, len: usize) -> Result<UserBufferMut, SyscallError> {
-    // Reuse read validation logic
-    let read_buf = validate_user_read(ptr, len)?;
-    
+    validate_range(ptr, len)?;
     Ok(UserBufferMut {
-        ptr: read_buf.ptr as *mut u8,
-        len: read_buf.len,
+        pages: PinnedPages::pin(ptr, len, true)?,
     })
 }
 
 /// A validated mutable user-space buffer
 #[derive(Debug)]
 pub struct UserBufferMut {
-    ptr: *mut u8,
-    len: usize,
+    pages: PinnedPages,
 }
 
 impl UserBufferMut {
-    /// Get the buffer as a mutable byte slice
+    /// Copy `data` to the start of the buffer.
     ///
-    /// # Safety
-    /// Same considerations as UserBuffer::as_bytes, plus mutability.
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        if self.len == 0 {
-            return &mut [];
+    /// # Panics
+    /// Panics if `data` is longer than the buffer.
+    pub fn copy_from(&mut self, mut data: &[u8]) {
+        assert!(data.len() <= self.pages.len, "copy past the end of a user buffer");
+        for (ptr, len) in self.pages.pieces() {
+            if data.is_empty() {
+                break;
+            }
+            let (head, rest) = data.split_at(len.min(data.len()));
+            // SAFETY: The piece lies within one pinned frame, which stays
+            // allocated and direct-mapped while `self` lives; `&mut self`
+            // keeps other kernel writers out.
+            unsafe { core::ptr::copy_nonoverlapping(head.as_ptr(), ptr, head.len()) };
+            data = rest;
         }
-        // SAFETY: Same as UserBuffer::as_bytes
-        unsafe { core::slice::from_raw_parts_mut(self.ptr, self.len) }
     }
 }
 
@@ -167,4 +269,13 @@ mod tests {
     fn test_overflow() {
         assert!(validate_user_read(usize::MAX - 10, 100).is_err());
     }
+
+    #[test]
+    fn test_user_pages_wrap() {
+        let last = regions::USER_END - PAGE_SIZE;
+        assert!(validate_user_pages(last, PAGE_SIZE).is_ok());
+        // A second page would wrap around to address 0
+        assert!(validate_user_pages(last, 2 * PAGE_SIZE).is_err());
+        assert!(validate_user_pages(usize::MAX & !(PAGE_SIZE - 1), 2 * PAGE_SIZE).is_err());
+    }
 }
@@ -0,0 +1,609 @@
+//! Thread Control Blocks
+//!
+//! A `Thread` is the unit the scheduler runs. User threads belong to a
+//! `Process`, which supplies their address space and capability space;
+//! kernel threads (such as the idle thread) have no process and never
+//! leave EL1.
+//!
+//! # Saved State
+//! ```text
+//! kernel stack top ┌────────────────────────┐
+//!                  │ ExceptionContext       │  user registers, saved by
+//!                  │ (user threads only)    │  the lower-EL entry code
+//!                  ├────────────────────────┤
+//!                  │ kernel frames          │  handler / scheduler calls
+//!                  │          ...           │
+//!                  └────────────────────────┘
+//! TCB: KernelContext (x19-x30, sp) while switched out, FpState (lazy)
+//! ```
+//!
+//! User state lives at a fixed place at the top of the thread's kernel
+//! stack: every exception from EL0 saves it there and `ret_to_user`
+//! restores it, so switching threads only has to swap kernel contexts.
+//!
+//! # Priorities
+//! Threads have one of 256 fixed priorities (255 is the highest). The
+//! scheduler runs the highest *effective* priority: the thread's own
+//! (base) priority, raised by any priority it inherits from threads
+//! waiting on it. How far a thread may raise priorities is bounded by its
+//! maximum controlled priority (MCP).
+//!
+//! # CPU Affinity
+//! A thread's affinity mask lists the CPUs it may run on (all by
+//! default). The scheduler only queues it on, and migrates it between,
+//! CPUs in the mask.
+//!
+//! # Security Properties
+//! - Every thread has its own guarded kernel stack
+//! - New user threads start at EL0 with a zeroed register file (apart
+//!   from the entry argument), IRQs unmasked and zeroed FP/SIMD state
+//! - A `Thread` holds a reference on its process, so the address space
+//!   and CSpace outlive every thread that may run in them until teardown
+
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::cap::cspace::RawCapability;
+use crate::cap::{CapabilityType, Rights};
+use crate::exception::ExceptionContext;
+use crate::fpsimd::{self, FpState};
+use crate::ipc::IpcState;
+use crate::lock_class;
+use crate::mm::{KernelStack, MappingError, VirtAddr};
+use crate::process::Process;
+use crate::smp::MAX_CPUS;
+use crate::sync::{TicketLock, TicketLockGuard};
+
+/// Default time slice of a thread, in timer ticks.
+pub const TIME_SLICE_TICKS: u32 = 5;
+
+/// Number of priority levels.
+pub const NUM_PRIORITIES: usize = 256;
+
+/// Affinity mask allowing every CPU.
+pub const ALL_CPUS: u64 = (1 << MAX_CPUS) - 1;
+
+/// Highest priority.
+pub const MAX_PRIORITY: u8 = (NUM_PRIORITIES - 1) as u8;
+
+/// Priority (and MCP) of new threads.
+pub const DEFAULT_PRIORITY: u8 = 128;
+
+/// SPSR for a fresh user thread: EL0t, AArch64, DAIF all clear.
+const SPSR_EL0T: u64 = 0;
+
+extern "C" {
+    /// Save `prev`, load `next` (switch.S).
+    pub fn cpu_switch_to(prev: *mut KernelContext, next: *const KernelContext);
+    /// Entry trampoline of new threads (switch.S).
+    fn thread_start();
+}
+
+/// Thread identifier. Never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tid(u32);
+
+impl fmt::Display for Tid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Next TID to hand out.
+static NEXT_TID: AtomicU32 = AtomicU32::new(1);
+
+/// Scheduling state of a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ThreadState {
+    /// Created or preempted, waiting in the run queue.
+    Ready = 0,
+    /// On a CPU.
+    Running = 1,
+    /// Waiting for an event; not in the run queue.
+    Blocked = 2,
+    /// Finished; never runs again.
+    Exited = 3,
+    /// Made from untyped memory and not started yet (`start_user`); not
+    /// in the run queue.
+    Inactive = 4,
+}
+
+impl ThreadState {
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Ready,
+            1 => Self::Running,
+            2 => Self::Blocked,
+            4 => Self::Inactive,
+            _ => Self::Exited,
+        }
+    }
+}
+
+/// Callee-saved registers of a switched-out thread (see switch.S).
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct KernelContext {
+    /// x19-x28
+    pub regs: [u64; 10],
+    /// x29
+    pub fp: u64,
+    /// x30: where `cpu_switch_to` returns to
+    pub lr: u64,
+    /// Kernel stack pointer
+    pub sp: u64,
+}
+
+// switch.S hard-codes these offsets.
+const _: () = assert!(offset_of!(KernelContext, fp) == 80);
+const _: () = assert!(offset_of!(KernelContext, lr) == 88);
+const _: () = assert!(offset_of!(KernelContext, sp) == 96);
+
+// The entry code stores FRAME_SIZE bytes; ret_to_user needs 16-byte SP.
+const _: () = assert!(size_of::<ExceptionContext>() == 36 * 8);
+const _: () = assert!(size_of::<ExceptionContext>() % 16 == 0);
+
+/// Errors from thread creation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadError {
+    /// No memory for the kernel stack or TCB.
+    OutOfMemory,
+    /// The owning process has already exited.
+    ProcessExited,
+}
+
+impl From<MappingError> for ThreadError {
+    fn from(_: MappingError) -> Self {
+        Self::OutOfMemory
+    }
+}
+
+impl fmt::Display for ThreadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfMemory => write!(f, "Out of memory"),
+            Self::ProcessExited => write!(f, "Process has exited"),
+        }
+    }
+}
+
+/// Thread control block.
+pub struct Thread {
+    tid: Tid,
+    /// Name of a kernel thread; user threads go by their process's.
+    name: &'static str,
+    /// Owning process; `None` for kernel threads.
+    process: Option<Arc<Process>>,
+    kstack: KernelStack,
+    state: AtomicU8,
+    /// Priority set for the thread itself.
+    base_priority: AtomicU8,
+    /// Priority the scheduler uses: the base priority or the highest
+    /// inherited one.
+    priority: AtomicU8,
+    /// Highest priority this thread may give itself or others.
+    mcp: AtomicU8,
+    /// Priorities lent by threads blocked on this one (one entry per
+    /// waiter; changed by the scheduler only).
+    inherited: Mutex<Vec<u8>>,
+    /// Length of a time slice in ticks; 0 runs until the thread blocks or
+    /// yields (FIFO within its priority).
+    time_slice: AtomicU32,
+    /// Ticks left in the current time slice.
+    slice: AtomicU32,
+    /// CPUs the thread may run on (bit n = CPU n).
+    affinity: AtomicU64,
+    /// CPU whose run queue holds the thread, or that last ran it.
+    cpu: AtomicUsize,
+    /// Set while a CPU is running the thread or still switching away
+    /// from it; no other CPU may resume it until this clears.
+    on_cpu: AtomicBool,
+    /// Saved callee-saved registers while switched out.
+    context: UnsafeCell<KernelContext>,
+    /// FP/SIMD save area (switched lazily by `fpsimd`).
+    fp: UnsafeCell<FpState>,
+    /// Message state of IPC in progress.
+    ipc: TicketLock<IpcState>,
+    /// User address of the IPC buffer page; 0 if none.
+    ipc_buffer: AtomicUsize,
+}
+
+// SAFETY: `context` is only accessed by the scheduler with IRQs masked,
+// while the thread is not running; `fp` only through `fpsimd`, which
+// serializes access to the register owner.
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    /// Allocate a TCB running on `kstack`.
+    fn alloc(name: &'static str, process: Option<Arc<Process>>, kstack: KernelStack) -> Self {
+        Self {
+            tid: Tid(NEXT_TID.fetch_add(1, Ordering::Relaxed)),
+            name,
+            process,
+            kstack,
+            state: AtomicU8::new(ThreadState::Ready as u8),
+            base_priority: AtomicU8::new(DEFAULT_PRIORITY),
+            priority: AtomicU8::new(DEFAULT_PRIORITY),
+            mcp: AtomicU8::new(DEFAULT_PRIORITY),
+            inherited: Mutex::new(Vec::new()),
+            time_slice: AtomicU32::new(TIME_SLICE_TICKS),
+            slice: AtomicU32::new(TIME_SLICE_TICKS),
+            affinity: AtomicU64::new(ALL_CPUS),
+            cpu: AtomicUsize::new(0),
+            on_cpu: AtomicBool::new(false),
+            context: UnsafeCell::new(KernelContext::default()),
+            fp: UnsafeCell::new(FpState::new()),
+            ipc: TicketLock::new(IpcState::new(), lock_class!("ipc state")),
+            ipc_buffer: AtomicUsize::new(0),
+        }
+    }
+
+    /// Create a kernel thread that runs `entry(arg)` at EL1.
+    pub fn new_kernel(
+        name: &'static str,
+        entry: extern "C" fn(usize) -> !,
+        arg: usize,
+    ) -> Result<Arc<Self>, ThreadError> {
+        let mut thread = Self::alloc(name, None, KernelStack::new()?);
+
+        let context = thread.context.get_mut();
+        context.regs[0] = entry as usize as u64;
+        context.regs[1] = arg as u64;
+        context.lr = thread_start as *const () as u64;
+        context.sp = thread.kstack.top().as_u64();
+
+        Ok(Arc::new(thread))
+    }
+
+    /// Create a user thread in `process`, entering EL0 at `entry` with
+    /// stack pointer `stack` and `arg` in x0.
+    pub fn new_user(
+        process: Arc<Process>,
+        entry: VirtAddr,
+        stack: VirtAddr,
+        arg: u64,
+    ) -> Result<Arc<Self>, ThreadError> {
+        if process.exit_reason().is_some() {
+            return Err(ThreadError::ProcessExited);
+        }
+
+        let thread = Self::alloc("", Some(process), KernelStack::new()?);
+        // SAFETY: The thread is not shared yet.
+        unsafe { thread.set_user_entry(entry, stack, arg) };
+
+        Ok(Arc::new(thread))
+    }
+
+    /// Create a user thread in `process` on `kstack` that stays inactive
+    /// until `start_user` gives it registers.
+    pub fn new_inactive(process: Arc<Process>, kstack: KernelStack) -> Result<Arc<Self>, ThreadError> {
+        if process.exit_reason().is_some() {
+            return Err(ThreadError::ProcessExited);
+        }
+
+        let thread = Self::alloc("", Some(process), kstack);
+        thread.set_state(ThreadState::Inactive);
+        Ok(Arc::new(thread))
+    }
+
+    /// Set up an inactive thread to enter EL0 as `new_user` describes,
+    /// and leave it blocked for `sched::wake` to start.
+    ///
+    /// Returns false, changing nothing, unless the thread is inactive.
+    pub(crate) fn start_user(&self, entry: VirtAddr, stack: VirtAddr, arg: u64) -> bool {
+        let claimed = self
+            .state
+            .compare_exchange(
+                ThreadState::Inactive as u8,
+                ThreadState::Blocked as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok();
+        if claimed {
+            // SAFETY: An inactive thread has never run and is in no queue,
+            // and the state change above lets only one caller get here.
+            unsafe { self.set_user_entry(entry, stack, arg) };
+        }
+        claimed
+    }
+
+    /// Write the initial user registers and a kernel context that enters
+    /// EL0 through `thread_start`.
+    ///
+    /// # Safety
+    /// The thread must never have run, and nothing else may access its
+    /// saved state meanwhile.
+    unsafe fn set_user_entry(&self, entry: VirtAddr, stack: VirtAddr, arg: u64) {
+        let frame = self.user_context();
+        let mut user = ExceptionContext {
+            gpr: [0; 31],
+            elr: entry.as_u64(),
+            spsr: SPSR_EL0T,
+            esr: 0,
+            far: 0,
+            sp: stack.as_u64(),
+        };
+        user.gpr[0] = arg;
+        // SAFETY: The frame lies inside the thread's own kernel stack,
+        // and the context is not in use (see above).
+        unsafe {
+            frame.write(user);
+            // x19 = 0: thread_start goes straight to ret_to_user
+            let context = &mut *self.context.get();
+            context.lr = thread_start as *const () as u64;
+            context.sp = frame as u64;
+        }
+    }
+
+    /// Name of the thread: its process's, or the one given to a kernel
+    /// thread.
+    #[inline]
+    pub fn name(&self) -> &str {
+        self.process.as_deref().map_or(self.name, Process::name)
+    }
+
+    /// Owning process, or `None` for kernel threads.
+    #[inline]
+    pub fn process(&self) -> Option<&Arc<Process>> {
+        self.process.as_ref()
+    }
+
+    /// Current scheduling state.
+    #[inline]
+    pub fn state(&self) -> ThreadState {
+        ThreadState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    /// Change the scheduling state (scheduler only).
+    #[inline]
+    pub(crate) fn set_state(&self, state: ThreadState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Effective scheduling priority.
+    #[inline]
+    pub fn priority(&self) -> u8 {
+        self.priority.load(Ordering::Relaxed)
+    }
+
+    /// Priority set for the thread itself, without inheritance.
+    #[inline]
+    pub fn base_priority(&self) -> u8 {
+        self.base_priority.load(Ordering::Relaxed)
+    }
+
+    /// Maximum controlled priority.
+    #[inline]
+    pub fn mcp(&self) -> u8 {
+        self.mcp.load(Ordering::Relaxed)
+    }
+
+    /// Set the maximum controlled priority.
+    #[inline]
+    pub(crate) fn set_mcp(&self, mcp: u8) {
+        self.mcp.store(mcp, Ordering::Relaxed);
+    }
+
+    /// Set the base priority (scheduler only; see `sched::set_priority`).
+    #[inline]
+    pub(crate) fn set_base_priority(&self, priority: u8) {
+        self.base_priority.store(priority, Ordering::Relaxed);
+    }
+
+    /// Set the effective priority (scheduler only).
+    #[inline]
+    pub(crate) fn set_priority(&self, priority: u8) {
+        self.priority.store(priority, Ordering::Relaxed);
+    }
+
+    /// Lend `priority` to this thread (scheduler only).
+    pub(crate) fn add_inherited(&self, priority: u8) {
+        self.inherited.lock().push(priority);
+    }
+
+    /// Take back one loan of `priority` (scheduler only).
+    pub(crate) fn remove_inherited(&self, priority: u8) {
+        let mut inherited = self.inherited.lock();
+        if let Some(index) = inherited.iter().position(|&p| p == priority) {
+            inherited.swap_remove(index);
+        }
+    }
+
+    /// Effective priority implied by the base and inherited priorities.
+    pub(crate) fn computed_priority(&self) -> u8 {
+        let inherited = self.inherited.lock().iter().copied().max();
+        inherited.map_or(self.base_priority(), |p| p.max(self.base_priority()))
+    }
+
+    /// Set the time slice length in ticks (0: no time slicing), from the
+    /// next slice on.
+    #[inline]
+    pub fn set_time_slice(&self, ticks: u32) {
+        self.time_slice.store(ticks, Ordering::Relaxed);
+    }
+
+    /// Start a new time slice.
+    #[inline]
+    pub(crate) fn reset_slice(&self) {
+        let ticks = match self.time_slice.load(Ordering::Relaxed) {
+            0 => u32::MAX,
+            ticks => ticks,
+        };
+        self.slice.store(ticks, Ordering::Relaxed);
+    }
+
+    /// Account one timer tick; returns `true` once the slice is used up.
+    #[inline]
+    pub(crate) fn consume_tick(&self) -> bool {
+        if self.time_slice.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        let left = self.slice.load(Ordering::Relaxed).saturating_sub(1);
+        self.slice.store(left, Ordering::Relaxed);
+        left == 0
+    }
+
+    /// Whether the current time slice is used up.
+    #[inline]
+    pub(crate) fn slice_expired(&self) -> bool {
+        self.slice.load(Ordering::Relaxed) == 0
+    }
+
+    /// Give up the rest of the time slice.
+    #[inline]
+    pub(crate) fn expire_slice(&self) {
+        self.slice.store(0, Ordering::Relaxed);
+    }
+
+    /// CPUs the thread may run on.
+    #[inline]
+    pub fn affinity(&self) -> u64 {
+        self.affinity.load(Ordering::Relaxed)
+    }
+
+    /// Restrict the thread to the CPUs in `mask` (scheduler only; see
+    /// `sched::set_affinity`).
+    #[inline]
+    pub(crate) fn set_affinity(&self, mask: u64) {
+        self.affinity.store(mask & ALL_CPUS, Ordering::Relaxed);
+    }
+
+    /// Whether the thread may run on CPU `cpu`.
+    #[inline]
+    pub fn allowed_on(&self, cpu: usize) -> bool {
+        self.affinity() & (1 << cpu) != 0
+    }
+
+    /// CPU whose run queue holds the thread, or that last ran it.
+    #[inline]
+    pub fn cpu(&self) -> usize {
+        self.cpu.load(Ordering::Acquire)
+    }
+
+    /// Record the CPU the thread is assigned to (scheduler only).
+    #[inline]
+    pub(crate) fn set_cpu(&self, cpu: usize) {
+        self.cpu.store(cpu, Ordering::Release);
+    }
+
+    /// Whether a CPU is running the thread or switching away from it.
+    #[inline]
+    pub(crate) fn on_cpu(&self) -> bool {
+        self.on_cpu.load(Ordering::Acquire)
+    }
+
+    /// Mark the thread as running somewhere, or fully switched out
+    /// (scheduler only).
+    #[inline]
+    pub(crate) fn set_on_cpu(&self, on_cpu: bool) {
+        self.on_cpu.store(on_cpu, Ordering::Release);
+    }
+
+    /// Whether the owning process has been torn down.
+    pub fn process_exited(&self) -> bool {
+        self.process
+            .as_ref()
+            .is_some_and(|process| process.has_exited())
+    }
+
+    /// IPC state (`ipc` only).
+    #[inline]
+    pub(crate) fn ipc(&self) -> TicketLockGuard<'_, IpcState> {
+        self.ipc.lock()
+    }
+
+    /// User address of the IPC buffer page, if one is set.
+    #[inline]
+    pub fn ipc_buffer(&self) -> Option<VirtAddr> {
+        match self.ipc_buffer.load(Ordering::Relaxed) {
+            0 => None,
+            addr => Some(VirtAddr::new(addr)),
+        }
+    }
+
+    /// Set the IPC buffer page (`None` to clear); the caller checks that
+    /// it is a page-aligned user address.
+    #[inline]
+    pub fn set_ipc_buffer(&self, addr: Option<VirtAddr>) {
+        let addr = addr.map_or(0, VirtAddr::as_usize);
+        self.ipc_buffer.store(addr, Ordering::Relaxed);
+    }
+
+    /// Saved kernel context, for `cpu_switch_to`.
+    #[inline]
+    pub(crate) fn context_ptr(&self) -> *mut KernelContext {
+        self.context.get()
+    }
+
+    /// FP/SIMD save area for `fpsimd::switch_to` (null for kernel threads).
+    #[inline]
+    pub(crate) fn fp_state_ptr(&self) -> *mut FpState {
+        if self.process.is_some() {
+            self.fp.get()
+        } else {
+            core::ptr::null_mut()
+        }
+    }
+
+    /// Location of the saved user registers (top of the kernel stack).
+    ///
+    /// Only meaningful while the thread is in the kernel on behalf of
+    /// EL0, or before a new user thread first runs.
+    #[inline]
+    pub fn user_context(&self) -> *mut ExceptionContext {
+        (self.kstack.top().as_usize() - size_of::<ExceptionContext>()) as *mut ExceptionContext
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        fpsimd::release(self.fp.get());
+    }
+}
+
+impl fmt::Debug for Thread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Thread")
+            .field("tid", &self.tid)
+            .field("name", &self.name())
+            .field("state", &self.state())
+            .field("priority", &self.priority())
+            .field("cpu", &self.cpu())
+            .finish()
+    }
+}
+
+/// The thread a capability refers to, if it is a thread capability.
+pub fn from_cap(cap: &RawCapability) -> Option<Arc<Thread>> {
+    if cap.cap_type != CapabilityType::Thread {
+        return None;
+    }
+    let ptr = cap.object_ptr as *const Thread;
+    // SAFETY: Thread capabilities carry a pointer from Arc::into_raw and
+    // own a reference, so the count is at least one while `cap` exists.
+    unsafe {
+        Arc::increment_strong_count(ptr);
+        Some(Arc::from_raw(ptr))
+    }
+}
+
+/// Wrap `thread` in a capability.
+///
+/// The returned capability owns one reference to the TCB.
+pub fn create_cap(thread: Arc<Thread>, rights: Rights) -> RawCapability {
+    RawCapability {
+        cap_type: CapabilityType::Thread,
+        object_ptr: Arc::into_raw(thread) as usize,
+        rights,
+        badge: 0,
+        generation: 0,
+    }
+}
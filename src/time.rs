@@ -0,0 +1,135 @@
+//! Monotonic Time
+//!
+//! A clock API built on the generic timer counter (CNTVCT_EL0). The
+//! counter is 64 bits wide and never goes backwards, so `Instant` is
+//! simply a counter value; conversions to `Duration` use CNTFRQ_EL0.
+//!
+//! # Example
+//! ```no_run
+//! let start = Instant::now();
+//! // ... work ...
+//! kprintln!("took {:?}", start.elapsed());
+//! ```
+
+use core::ops::{Add, Sub};
+
+pub use core::time::Duration;
+
+use crate::drivers::timer;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Convert counter ticks to nanoseconds at the given frequency.
+#[inline]
+pub const fn ticks_to_nanos(ticks: u64, freq: u64) -> u64 {
+    if freq == 0 {
+        return 0;
+    }
+    let nanos = ticks as u128 * NANOS_PER_SEC / freq as u128;
+    if nanos > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        nanos as u64
+    }
+}
+
+/// Convert nanoseconds to counter ticks at the given frequency.
+///
+/// Rounds up so a timeout never expires early.
+#[inline]
+pub const fn nanos_to_ticks(nanos: u128, freq: u64) -> u64 {
+    let ticks = (nanos * freq as u128).div_ceil(NANOS_PER_SEC);
+    if ticks > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        ticks as u64
+    }
+}
+
+/// Convert counter ticks to a `Duration`.
+#[inline]
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks_to_nanos(ticks, timer::frequency()))
+}
+
+/// Convert a `Duration` to counter ticks (rounded up, saturating).
+#[inline]
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    nanos_to_ticks(duration.as_nanos(), timer::frequency())
+}
+
+/// A point in time on the monotonic counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The current time.
+    #[inline]
+    pub fn now() -> Self {
+        Self(timer::counter())
+    }
+
+    /// Raw counter value.
+    #[inline]
+    pub const fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Time elapsed from `earlier` to `self` (zero if `earlier` is later).
+    #[inline]
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Time elapsed since this instant.
+    #[inline]
+    pub fn elapsed(self) -> Duration {
+        Self::now().duration_since(self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// Saturates at the end of the counter range.
+    fn add(self, rhs: Duration) -> Instant {
+        Self(self.0.saturating_add(duration_to_ticks(rhs)))
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    /// Saturates at the counter origin.
+    fn sub(self, rhs: Duration) -> Instant {
+        Self(self.0.saturating_sub(duration_to_ticks(rhs)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tick_conversion() {
+        // QEMU virt runs the counter at 62.5 MHz (16 ns per tick)
+        assert_eq!(ticks_to_nanos(62_500_000, 62_500_000), 1_000_000_000);
+        assert_eq!(ticks_to_nanos(1, 62_500_000), 16);
+        assert_eq!(nanos_to_ticks(1_000_000, 62_500_000), 62_500);
+    }
+
+    #[test]
+    fn test_conversion_rounds_up() {
+        // 1 ns is less than a tick but must not become a zero timeout
+        assert_eq!(nanos_to_ticks(1, 62_500_000), 1);
+        assert_eq!(ticks_to_nanos(5, 0), 0);
+    }
+}